@@ -0,0 +1,99 @@
+use log::info;
+
+#[derive(Clone, Debug, Default)]
+pub struct AdapterTrimmer {
+    adapter: Vec<u8>,
+    min_overlap: usize,
+    error_rate: f64,
+}
+
+impl AdapterTrimmer {
+    pub fn new(adapter: &String, min_overlap: usize, error_rate: f64) -> Self {
+        if adapter.is_empty() {
+            panic!("Adapter sequence can not be empty!");
+        }
+        if !adapter
+            .bytes()
+            .all(|base| matches!(base, b'A' | b'C' | b'G' | b'T'))
+        {
+            panic!(
+                "Adapter sequence ({}) should only contain the bases A, C, G and T!",
+                adapter
+            );
+        }
+        if min_overlap == 0 {
+            panic!("Minimum adapter overlap should be at least 1!");
+        }
+        if adapter.len() < min_overlap {
+            panic!(
+                "Adapter sequence ({}) should not be shorter than the minimum overlap ({})!",
+                adapter, min_overlap
+            );
+        }
+        if !(0.0..1.0).contains(&error_rate) {
+            panic!("Adapter error rate should be between 0 and 1!");
+        }
+        info!(
+            "Adapter {} will be trimmed with minimum overlap of {} and error rate of {}.",
+            adapter, min_overlap, error_rate
+        );
+        Self {
+            adapter: adapter.as_bytes().to_vec(),
+            min_overlap,
+            error_rate,
+        }
+    }
+
+    pub fn find_adapter(&self, sequence: &[u8]) -> usize {
+        /*
+        Returns the length of the sequence to keep.
+        The adapter is searched from the start of the read, it can be fully contained in the read
+        or partially overlap its 3' end. A position is accepted when the overlap is at least
+        min_overlap and the mismatches are within the error rate of the overlap.
+         */
+        if sequence.len() < self.min_overlap {
+            return sequence.len();
+        }
+        for start in 0..sequence.len() - self.min_overlap + 1 {
+            let overlap = (sequence.len() - start).min(self.adapter.len());
+            let allowed_mismatches = (overlap as f64 * self.error_rate) as usize;
+            let mut mismatches = 0;
+            for (read_base, adapter_base) in sequence[start..start + overlap]
+                .iter()
+                .zip(self.adapter.iter())
+            {
+                if read_base != adapter_base {
+                    mismatches += 1;
+                    if mismatches > allowed_mismatches {
+                        break;
+                    }
+                }
+            }
+            if mismatches <= allowed_mismatches {
+                return start;
+            }
+        }
+        sequence.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_adapter() {
+        let trimmer = AdapterTrimmer::new(&String::from("AGATCGGAAG"), 3, 0.1);
+        assert_eq!(trimmer.find_adapter(b"ACGTACGTAGATCGGAAGAGC"), 8);
+        assert_eq!(trimmer.find_adapter(b"ACGTACGTACGTAGATC"), 12);
+        assert_eq!(trimmer.find_adapter(b"ACGTACGTAGTTCGGAAG"), 8);
+        assert_eq!(trimmer.find_adapter(b"ACGTACGTACGTACGTAG"), 18);
+        assert_eq!(trimmer.find_adapter(b"AC"), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "should not be shorter than the minimum overlap")]
+    fn test_adapter_shorter_than_min_overlap() {
+        AdapterTrimmer::new(&String::from("AGATC"), 6, 0.1);
+    }
+}
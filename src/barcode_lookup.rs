@@ -0,0 +1,610 @@
+use crate::sample_manager::{get_all_mismatches, TemplateData};
+use log::info;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+// Bases are packed in 2 bits each and the N positions in a mask above them, all in a u64.
+pub const MAX_PACKED_INDEX_LENGTH: usize = 21;
+const N_MASK_SHIFT: usize = 2 * MAX_PACKED_INDEX_LENGTH;
+// Above this number of precomputed barcodes for an index set, the seed matcher is used.
+const MAX_ENUMERATED_BARCODES: usize = 2_000_000;
+
+// The (start, end) range of every seed in the index and the indexes having each packed seed.
+type Seeds = (Vec<(usize, usize)>, Vec<HashMap<u64, Vec<u32>>>);
+
+pub fn pack_barcode(barcode: &[u8]) -> Option<u64> {
+    /*
+    Packs a barcode into 2 bits per base (A: 0, C: 1, G: 2, T: 3) with the positions of N
+    marked in a separate mask. Barcodes with any other letter, or longer than
+    MAX_PACKED_INDEX_LENGTH, can not be packed.
+     */
+    if barcode.len() > MAX_PACKED_INDEX_LENGTH {
+        return None;
+    }
+    let mut key: u64 = 0;
+    for (pos, base) in barcode.iter().enumerate() {
+        match base {
+            b'A' => {}
+            b'C' => key |= 1 << (2 * pos),
+            b'G' => key |= 2 << (2 * pos),
+            b'T' => key |= 3 << (2 * pos),
+            b'N' => key |= 1 << (N_MASK_SHIFT + pos),
+            _ => return None,
+        }
+    }
+    Some(key)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct IndexLookup {
+    // (packed barcode, first match, number of matches, mismatches), sorted by the packed barcode.
+    entries: Vec<(u64, u32, u32, u32)>,
+    matches: Vec<u32>,
+    indexes: Vec<String>,
+    // Pigeonhole seeds, used instead of the enumerated barcodes when they would be too many, and
+    // to find all the indexes close to a barcode.
+    seed_ranges: Vec<(usize, usize)>,
+    seeds: Vec<HashMap<u64, Vec<u32>>>,
+    // Every barcode within the allowed mismatches, used when the indexes can not be packed.
+    unpacked_entries: HashMap<Vec<u8>, (Vec<u32>, usize)>,
+    allowed_mismatches: usize,
+}
+
+impl IndexLookup {
+    pub fn new(all_indexes: &HashSet<String>, allowed_mismatches: usize) -> Self {
+        if all_indexes
+            .iter()
+            .any(|index| pack_barcode(index.as_bytes()).is_none())
+        {
+            info!(
+                "Indexes longer than {} bases or with letters other than A, C, G, T and N are matched without packing.",
+                MAX_PACKED_INDEX_LENGTH
+            );
+            return Self::new_unpacked(all_indexes, allowed_mismatches);
+        }
+        let index_length = all_indexes
+            .iter()
+            .map(|index| index.len())
+            .max()
+            .unwrap_or(0);
+        let enumerated_barcodes =
+            count_enumerated_barcodes(all_indexes.len(), index_length, allowed_mismatches);
+        if enumerated_barcodes > MAX_ENUMERATED_BARCODES {
+            info!(
+                "Matching {} indexes of length {} with {} mismatches using seeds instead of {} precomputed barcodes.",
+                all_indexes.len(),
+                index_length,
+                allowed_mismatches,
+                enumerated_barcodes
+            );
+            Self::new_seeded(all_indexes, allowed_mismatches)
+        } else {
+            Self::new_enumerated(all_indexes, allowed_mismatches)
+        }
+    }
+
+    pub fn new_enumerated(all_indexes: &HashSet<String>, allowed_mismatches: usize) -> Self {
+        /*
+        Precomputes every barcode within the allowed mismatches of the indexes, with the ids of
+        the closest indexes. Index ids are positions in `indexes`.
+         */
+        let indexes: Vec<String> = all_indexes.iter().cloned().collect();
+        let index_ids = get_index_ids(&indexes);
+        let mut entries = Vec::new();
+        let mut matches = Vec::new();
+        for (barcode, (barcode_matches, mismatches)) in
+            get_all_mismatches(all_indexes, allowed_mismatches)
+        {
+            let key = match pack_barcode(&barcode) {
+                Some(key) => key,
+                None => panic_unpacked_index(&barcode),
+            };
+            entries.push((
+                key,
+                matches.len() as u32,
+                barcode_matches.len() as u32,
+                mismatches as u32,
+            ));
+            matches.extend(barcode_matches.iter().map(|index| index_ids[index]));
+        }
+        entries.sort_unstable_by_key(|entry| entry.0);
+        let (seed_ranges, seeds) = build_seeds(&indexes, allowed_mismatches).unwrap_or_default();
+        Self {
+            entries,
+            matches,
+            indexes,
+            seed_ranges,
+            seeds,
+            allowed_mismatches,
+            ..Default::default()
+        }
+    }
+
+    pub fn new_unpacked(all_indexes: &HashSet<String>, allowed_mismatches: usize) -> Self {
+        // The barcodes are hashed as they are, as before the packed tables.
+        let indexes: Vec<String> = all_indexes.iter().cloned().collect();
+        let index_ids = get_index_ids(&indexes);
+        let unpacked_entries = get_all_mismatches(all_indexes, allowed_mismatches)
+            .into_iter()
+            .map(|(barcode, (barcode_matches, mismatches))| {
+                (
+                    barcode,
+                    (
+                        barcode_matches
+                            .iter()
+                            .map(|index| index_ids[*index])
+                            .collect(),
+                        mismatches,
+                    ),
+                )
+            })
+            .collect();
+        Self {
+            indexes,
+            unpacked_entries,
+            allowed_mismatches,
+            ..Default::default()
+        }
+    }
+
+    pub fn new_seeded(all_indexes: &HashSet<String>, allowed_mismatches: usize) -> Self {
+        // Only the indexes sharing a seed with the barcode are compared.
+        let indexes: Vec<String> = all_indexes.iter().cloned().collect();
+        let index_length = indexes.iter().map(|index| index.len()).max().unwrap_or(0);
+        for index in &indexes {
+            if pack_barcode(index.as_bytes()).is_none() || index.len() != index_length {
+                panic_unpacked_index(index.as_bytes());
+            }
+        }
+        let (seed_ranges, seeds) = build_seeds(&indexes, allowed_mismatches).unwrap();
+        Self {
+            indexes,
+            seed_ranges,
+            seeds,
+            allowed_mismatches,
+            ..Default::default()
+        }
+    }
+
+    pub fn get(&self, barcode: &[u8]) -> Option<(Cow<'_, [u32]>, usize)> {
+        if !self.unpacked_entries.is_empty() {
+            return self
+                .unpacked_entries
+                .get(barcode)
+                .map(|(index_ids, mismatches)| (Cow::Borrowed(&index_ids[..]), *mismatches));
+        }
+        let key = pack_barcode(barcode)?;
+        if self.entries.is_empty() {
+            return self.get_by_seeds(barcode);
+        }
+        match self.entries.binary_search_by_key(&key, |entry| entry.0) {
+            Ok(pos) => {
+                let (_, first, count, mismatches) = self.entries[pos];
+                Some((
+                    Cow::Borrowed(&self.matches[first as usize..(first + count) as usize]),
+                    mismatches as usize,
+                ))
+            }
+            Err(_) => None,
+        }
+    }
+
+    fn get_by_seeds(&self, barcode: &[u8]) -> Option<(Cow<'_, [u32]>, usize)> {
+        let mut best_mismatches = self.allowed_mismatches;
+        let mut best_matches: Vec<u32> = Vec::new();
+        for (seed_itr, &(start, end)) in self.seed_ranges.iter().enumerate() {
+            let index_ids = match self.seeds[seed_itr].get(&pack_barcode(&barcode[start..end])?) {
+                Some(index_ids) => index_ids,
+                None => continue,
+            };
+            for &index_id in index_ids {
+                let mismatches = count_mismatches(
+                    barcode,
+                    self.indexes[index_id as usize].as_bytes(),
+                    best_mismatches,
+                );
+                if mismatches > best_mismatches {
+                    continue;
+                }
+                if mismatches < best_mismatches || best_matches.is_empty() {
+                    best_mismatches = mismatches;
+                    best_matches.clear();
+                    best_matches.push(index_id);
+                } else if !best_matches.contains(&index_id) {
+                    best_matches.push(index_id);
+                }
+            }
+            if best_mismatches == 0 && !best_matches.is_empty() {
+                // Indexes are unique, so nothing else can match exactly.
+                break;
+            }
+        }
+        if best_matches.is_empty() {
+            None
+        } else {
+            Some((Cow::Owned(best_matches), best_mismatches))
+        }
+    }
+
+    pub fn get_all(&self, barcode: &[u8], max_mismatches: usize, matches: &mut Vec<(u32, usize)>) {
+        /*
+        All indexes within max_mismatches of the barcode, not only the closest ones, sorted by
+        their ids. Only the indexes sharing a seed with the barcode can be that close, all of
+        them are compared when there are no seeds.
+         */
+        matches.clear();
+        let seeds_length = self.seed_ranges.last().map_or(0, |seed_range| seed_range.1);
+        if self.seeds.is_empty()
+            || max_mismatches > self.allowed_mismatches
+            || barcode.len() != seeds_length
+        {
+            for (index_id, index) in self.indexes.iter().enumerate() {
+                let mismatches = count_mismatches(barcode, index.as_bytes(), max_mismatches);
+                if mismatches <= max_mismatches {
+                    matches.push((index_id as u32, mismatches));
+                }
+            }
+            return;
+        }
+        for (seed_itr, &(start, end)) in self.seed_ranges.iter().enumerate() {
+            let index_ids = match pack_barcode(&barcode[start..end])
+                .and_then(|key| self.seeds[seed_itr].get(&key))
+            {
+                Some(index_ids) => index_ids,
+                None => continue,
+            };
+            for &index_id in index_ids {
+                if matches
+                    .iter()
+                    .any(|&(matched_id, _)| matched_id == index_id)
+                {
+                    continue;
+                }
+                let mismatches = count_mismatches(barcode, self.index(index_id), max_mismatches);
+                if mismatches <= max_mismatches {
+                    matches.push((index_id, mismatches));
+                }
+            }
+        }
+        matches.sort_unstable();
+    }
+
+    pub fn index(&self, index_id: u32) -> &[u8] {
+        self.indexes[index_id as usize].as_bytes()
+    }
+}
+
+fn build_seeds(indexes: &[String], allowed_mismatches: usize) -> Option<Seeds> {
+    /*
+    Splits the indexes into allowed_mismatches + 1 seeds. An index within the allowed
+    mismatches of a barcode must match at least one of its seeds exactly (pigeonhole). Indexes
+    of different lengths can not be split the same way.
+     */
+    let index_length = indexes.iter().map(|index| index.len()).max().unwrap_or(0);
+    if indexes.iter().any(|index| index.len() != index_length) {
+        return None;
+    }
+    let seed_ranges: Vec<(usize, usize)> = (0..allowed_mismatches + 1)
+        .map(|seed_itr| {
+            (
+                seed_itr * index_length / (allowed_mismatches + 1),
+                (seed_itr + 1) * index_length / (allowed_mismatches + 1),
+            )
+        })
+        .collect();
+    let mut seeds = vec![HashMap::new(); seed_ranges.len()];
+    for (index_id, index) in indexes.iter().enumerate() {
+        for (seed_itr, &(start, end)) in seed_ranges.iter().enumerate() {
+            seeds[seed_itr]
+                .entry(pack_barcode(&index.as_bytes()[start..end])?)
+                .or_insert(Vec::new())
+                .push(index_id as u32);
+        }
+    }
+    Some((seed_ranges, seeds))
+}
+
+fn get_index_ids(indexes: &[String]) -> HashMap<&String, u32> {
+    indexes
+        .iter()
+        .enumerate()
+        .map(|(index_id, index)| (index, index_id as u32))
+        .collect()
+}
+
+fn count_enumerated_barcodes(
+    total_indexes: usize,
+    index_length: usize,
+    allowed_mismatches: usize,
+) -> usize {
+    // Every mismatch position can be replaced by 4 letters (A, C, G, T and N except the original).
+    let mut barcodes_per_index: usize = 0;
+    let mut combinations: usize = 1;
+    for mismatch_itr in 0..allowed_mismatches.min(index_length) + 1 {
+        barcodes_per_index = barcodes_per_index.saturating_add(
+            combinations.saturating_mul(4_usize.saturating_pow(mismatch_itr as u32)),
+        );
+        combinations =
+            combinations.saturating_mul(index_length - mismatch_itr) / (mismatch_itr + 1);
+    }
+    barcodes_per_index.saturating_mul(total_indexes)
+}
+
+fn count_mismatches(barcode: &[u8], index: &[u8], max_mismatches: usize) -> usize {
+    // Stops counting once the mismatches are more than max_mismatches.
+    let mut mismatches = 0;
+    for (base, index_base) in barcode.iter().zip(index.iter()) {
+        if base != index_base {
+            mismatches += 1;
+            if mismatches > max_mismatches {
+                break;
+            }
+        }
+    }
+    mismatches
+}
+
+fn panic_unpacked_index(index: &[u8]) -> ! {
+    panic!(
+        "Indexes must only contain A, C, G, T and N and be at most {} bases! found '{}'",
+        MAX_PACKED_INDEX_LENGTH,
+        String::from_utf8_lossy(index)
+    );
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TemplateLookup {
+    i7: IndexLookup,
+    i5: IndexLookup,
+    // Sample id of every i7 id, used by single index templates.
+    i7_samples: Vec<usize>,
+    // ((i7 id << 32) | i5 id, sample id), sorted, used by dual index templates.
+    dual_samples: Vec<(u64, usize)>,
+}
+
+impl TemplateLookup {
+    pub fn new(
+        i7s: &HashSet<String>,
+        i5s: &HashSet<String>,
+        sample_info: &HashMap<String, (usize, HashMap<String, usize>)>,
+        allowed_mismatches: usize,
+    ) -> Self {
+        let i7 = IndexLookup::new(i7s, allowed_mismatches);
+        let i5 = IndexLookup::new(i5s, allowed_mismatches);
+        let i7_samples = i7
+            .indexes
+            .iter()
+            .map(|index| match sample_info.get(index) {
+                Some(tmp) => tmp.0,
+                None => panic!("There should be a value here"),
+            })
+            .collect();
+        let i7_ids = get_index_ids(&i7.indexes);
+        let i5_ids = get_index_ids(&i5.indexes);
+        let mut dual_samples = Vec::new();
+        for (i7_index, (_, i5_samples)) in sample_info {
+            let i7_id = i7_ids[i7_index] as u64;
+            for (i5_index, sample_id) in i5_samples {
+                if let Some(i5_id) = i5_ids.get(i5_index) {
+                    dual_samples.push(((i7_id << 32) | *i5_id as u64, *sample_id));
+                }
+            }
+        }
+        dual_samples.sort_unstable();
+        Self {
+            i7,
+            i5,
+            i7_samples,
+            dual_samples,
+        }
+    }
+
+    pub fn get_i7(&self, barcode: &[u8]) -> Option<(Cow<'_, [u32]>, usize)> {
+        self.i7.get(barcode)
+    }
+
+    pub fn get_i5(&self, barcode: &[u8]) -> Option<(Cow<'_, [u32]>, usize)> {
+        self.i5.get(barcode)
+    }
+
+    pub fn get_all_i7(
+        &self,
+        barcode: &[u8],
+        max_mismatches: usize,
+        matches: &mut Vec<(u32, usize)>,
+    ) {
+        self.i7.get_all(barcode, max_mismatches, matches);
+    }
+
+    pub fn get_all_i5(
+        &self,
+        barcode: &[u8],
+        max_mismatches: usize,
+        matches: &mut Vec<(u32, usize)>,
+    ) {
+        self.i5.get_all(barcode, max_mismatches, matches);
+    }
+
+    pub fn i7_index(&self, i7_id: u32) -> &[u8] {
+        self.i7.index(i7_id)
+    }
+
+    pub fn i5_index(&self, i5_id: u32) -> &[u8] {
+        self.i5.index(i5_id)
+    }
+
+    pub fn i7_sample(&self, i7_id: u32) -> usize {
+        self.i7_samples[i7_id as usize]
+    }
+
+    pub fn dual_sample(&self, i7_id: u32, i5_id: u32) -> Option<usize> {
+        let key = ((i7_id as u64) << 32) | i5_id as u64;
+        match self
+            .dual_samples
+            .binary_search_by_key(&key, |entry| entry.0)
+        {
+            Ok(pos) => Some(self.dual_samples[pos].1),
+            Err(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BarcodeLookup {
+    templates: Vec<TemplateLookup>,
+}
+
+impl BarcodeLookup {
+    pub fn new(all_template_data: &[TemplateData], allowed_mismatches: usize) -> Self {
+        /*
+        Builds the lookup tables of all templates once, they are immutable afterwards and
+        shared by all processing threads.
+         */
+        Self {
+            templates: all_template_data
+                .iter()
+                .map(|template_details| {
+                    TemplateLookup::new(
+                        &template_details.1,
+                        &template_details.2,
+                        &template_details.4,
+                        allowed_mismatches,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    pub fn template(&self, template_itr: usize) -> &TemplateLookup {
+        &self.templates[template_itr]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_barcode_lookup() {
+        assert_eq!(pack_barcode(b"AAAA"), Some(0));
+        assert_ne!(pack_barcode(b"AANA"), pack_barcode(b"AAAA"));
+        assert_eq!(pack_barcode(b"ACGX"), None);
+        assert_eq!(pack_barcode(&[b'A'; MAX_PACKED_INDEX_LENGTH + 1]), None);
+
+        let i7s: HashSet<String> = ["ACGTAC", "TTGCAA"].iter().map(|x| x.to_string()).collect();
+        let i5s: HashSet<String> = ["GGATCC"].iter().map(|x| x.to_string()).collect();
+        let mut sample_info = HashMap::new();
+        sample_info.insert(
+            String::from("ACGTAC"),
+            (1000, HashMap::from([(String::from("GGATCC"), 0)])),
+        );
+        sample_info.insert(
+            String::from("TTGCAA"),
+            (1000, HashMap::from([(String::from("GGATCC"), 1)])),
+        );
+        let template_lookup = TemplateLookup::new(&i7s, &i5s, &sample_info, 1);
+        let (i7_ids, i7_mismatches) = template_lookup.get_i7(b"ACGTAN").unwrap();
+        assert_eq!(i7_mismatches, 1);
+        let (i5_ids, i5_mismatches) = template_lookup.get_i5(b"GGATCC").unwrap();
+        assert_eq!(i5_mismatches, 0);
+        assert_eq!(template_lookup.dual_sample(i7_ids[0], i5_ids[0]), Some(0));
+        assert_eq!(template_lookup.get_i7(b"TTTTTT"), None);
+        let (i7_ids, _) = template_lookup.get_i7(b"TTGCAA").unwrap();
+        assert_eq!(template_lookup.dual_sample(i7_ids[0], i5_ids[0]), Some(1));
+        assert_eq!(template_lookup.i7_sample(i7_ids[0]), 1000);
+
+        // Indexes that can not be packed are matched through the hashed barcodes.
+        let long_index = "ACGTACGTACGTACGTACGTACGT".to_string();
+        let i7s: HashSet<String> = [long_index.clone(), "ACGTAX".to_string()]
+            .into_iter()
+            .collect();
+        let unpacked = IndexLookup::new(&i7s, 1);
+        assert!(!unpacked.unpacked_entries.is_empty());
+        let (index_ids, mismatches) = unpacked.get(b"ACGTACGTACGTACGTACGTACGA").unwrap();
+        assert_eq!(
+            (unpacked.index(index_ids[0]), mismatches),
+            (long_index.as_bytes(), 1)
+        );
+        let (index_ids, mismatches) = unpacked.get(b"ACGTAX").unwrap();
+        assert_eq!(
+            (unpacked.index(index_ids[0]), mismatches),
+            (&b"ACGTAX"[..], 0)
+        );
+        assert_eq!(unpacked.get(b"TTTTTT"), None);
+    }
+
+    #[test]
+    fn test_seeded_matching() {
+        // Pseudo random indexes and barcodes so both matchers see the same inputs every run.
+        let mut state: u64 = 42;
+        let mut random_sequence = |length: usize, letters: &[u8]| -> Vec<u8> {
+            (0..length)
+                .map(|_| {
+                    state = state
+                        .wrapping_mul(6364136223846793005)
+                        .wrapping_add(1442695040888963407);
+                    letters[((state >> 33) % letters.len() as u64) as usize]
+                })
+                .collect()
+        };
+        assert_eq!(count_enumerated_barcodes(1, 10, 1), 41);
+        assert_eq!(count_enumerated_barcodes(2, 10, 2), 2 * (41 + 45 * 16));
+        for (index_length, allowed_mismatches) in [(6, 1), (8, 2), (8, 3), (5, 4)] {
+            let all_indexes: HashSet<String> = (0..40)
+                .map(|_| String::from_utf8(random_sequence(index_length, b"ACGT")).unwrap())
+                .collect();
+            let enumerated = IndexLookup::new_enumerated(&all_indexes, allowed_mismatches);
+            let seeded = IndexLookup::new_seeded(&all_indexes, allowed_mismatches);
+            assert!(!enumerated.entries.is_empty() && seeded.entries.is_empty());
+            let mut barcodes: Vec<Vec<u8>> = (0..3000)
+                .map(|_| random_sequence(index_length, b"ACGTN"))
+                .collect();
+            for index in &all_indexes {
+                let mut barcode = index.as_bytes().to_vec();
+                for _ in 0..allowed_mismatches {
+                    barcodes.push(barcode.clone());
+                    let pos = random_sequence(1, b"0123456789")[0] as usize % index_length;
+                    barcode[pos] = random_sequence(1, b"ACGTN")[0];
+                }
+            }
+            barcodes.push(b"ACGTAX".to_vec());
+            for barcode in &barcodes {
+                let expected = enumerated.get(barcode).map(|(index_ids, mismatches)| {
+                    let mut index_ids: Vec<String> = index_ids
+                        .iter()
+                        .map(|&index_id| enumerated.indexes[index_id as usize].clone())
+                        .collect();
+                    index_ids.sort();
+                    (index_ids, mismatches)
+                });
+                let found = seeded.get(barcode).map(|(index_ids, mismatches)| {
+                    let mut index_ids: Vec<String> = index_ids
+                        .iter()
+                        .map(|&index_id| seeded.indexes[index_id as usize].clone())
+                        .collect();
+                    index_ids.sort();
+                    (index_ids, mismatches)
+                });
+                assert_eq!(expected, found, "{}", String::from_utf8_lossy(barcode));
+
+                // The seeds find the same indexes as comparing the barcode with all of them.
+                let mut expected_all = Vec::new();
+                for (index_id, index) in enumerated.indexes.iter().enumerate() {
+                    let mismatches =
+                        count_mismatches(barcode, index.as_bytes(), allowed_mismatches);
+                    if mismatches <= allowed_mismatches {
+                        expected_all.push((index_id as u32, mismatches));
+                    }
+                }
+                let mut found_all = Vec::new();
+                enumerated.get_all(barcode, allowed_mismatches, &mut found_all);
+                assert_eq!(
+                    expected_all,
+                    found_all,
+                    "{}",
+                    String::from_utf8_lossy(barcode)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+// Probability of a base under the random (unknown) hypothesis.
+const RANDOM_BASE_PROBABILITY: f64 = 0.25;
+// Quality scores above this value are treated as this value.
+const MAX_MODEL_QUALITY: usize = 93;
+
+#[derive(Clone, Debug, Default)]
+pub struct PosteriorBuffers {
+    /*
+    Kept by every processing thread and reused for all its reads. The candidates are
+    (sample id, template, mismatches), with their log likelihoods and posteriors at the same
+    positions.
+     */
+    pub candidates: Vec<(usize, usize, usize)>,
+    pub log_likelihoods: Vec<f64>,
+    pub posteriors: Vec<f64>,
+    pub i7_matches: Vec<(u32, usize)>,
+    pub i5_matches: Vec<(u32, usize)>,
+    pub i5_log_likelihoods: Vec<f64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PosteriorModel {
+    threshold: f64,
+    unknown_prior: f64,
+    quality_offset: u8,
+    // ln(P(read base | index base)) for a matching and a mismatching base at every quality score.
+    match_log_probabilities: Vec<f64>,
+    mismatch_log_probabilities: Vec<f64>,
+}
+
+impl PosteriorModel {
+    pub fn new(threshold: f64, unknown_prior: f64, quality_offset: u8) -> Self {
+        if threshold <= 0.0 || threshold > 1.0 {
+            panic!("Posterior threshold must be greater than 0 and at most 1!");
+        }
+        if !(0.0..1.0).contains(&unknown_prior) {
+            panic!("Unknown prior must be at least 0 and less than 1!");
+        }
+        let mut match_log_probabilities = Vec::new();
+        let mut mismatch_log_probabilities = Vec::new();
+        for quality in 0..MAX_MODEL_QUALITY + 1 {
+            // A base can not be less reliable than a random base.
+            let error_probability = 10_f64.powf(-(quality as f64) / 10.0).min(0.75);
+            match_log_probabilities.push((1.0 - error_probability).ln());
+            mismatch_log_probabilities.push((error_probability / 3.0).ln());
+        }
+        Self {
+            threshold,
+            unknown_prior,
+            quality_offset,
+            match_log_probabilities,
+            mismatch_log_probabilities,
+        }
+    }
+
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    pub fn index_log_likelihood(&self, bases: &[u8], qualities: &[u8], index: &[u8]) -> f64 {
+        /*
+        ln(P(bases | index)) where every base is correct with probability 1 - e and is each of the
+        other three bases with probability e / 3, e being the error probability of its quality.
+        N bases carry no information.
+         */
+        let mut log_likelihood = 0.0;
+        for ((&base, &quality), &index_base) in bases.iter().zip(qualities).zip(index) {
+            let quality =
+                (quality.saturating_sub(self.quality_offset) as usize).min(MAX_MODEL_QUALITY);
+            log_likelihood += if base == b'N' {
+                RANDOM_BASE_PROBABILITY.ln()
+            } else if base == index_base {
+                self.match_log_probabilities[quality]
+            } else {
+                self.mismatch_log_probabilities[quality]
+            };
+        }
+        log_likelihood
+    }
+
+    pub fn random_log_likelihood(&self, length: usize) -> f64 {
+        length as f64 * RANDOM_BASE_PROBABILITY.ln()
+    }
+
+    pub fn posteriors(
+        &self,
+        log_likelihoods: &[f64],
+        barcode_length: usize,
+        total_samples: usize,
+        posteriors: &mut Vec<f64>,
+    ) {
+        /*
+        Posterior probability of every candidate, given their log likelihoods over the whole
+        barcode. Samples share 1 - unknown_prior equally and the unknown hypothesis (a barcode
+        of none of the samples) has unknown_prior and random bases.
+         */
+        let sample_log_prior = ((1.0 - self.unknown_prior) / total_samples.max(1) as f64).ln();
+        let unknown_log_term = self.unknown_prior.ln() + self.random_log_likelihood(barcode_length);
+        let max_log_term = log_likelihoods
+            .iter()
+            .map(|log_likelihood| sample_log_prior + log_likelihood)
+            .fold(unknown_log_term, f64::max);
+        posteriors.clear();
+        posteriors.extend(
+            log_likelihoods
+                .iter()
+                .map(|log_likelihood| (sample_log_prior + log_likelihood - max_log_term).exp()),
+        );
+        let total = posteriors.iter().sum::<f64>() + (unknown_log_term - max_log_term).exp();
+        for posterior in posteriors.iter_mut() {
+            *posterior /= total;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_posteriors() {
+        let posterior_model = PosteriorModel::new(0.9, 0.05, 33);
+        let index = b"ACGTACGT";
+        // The last base mismatches with a high quality (I: 40) and a low quality (#: 2).
+        let high_quality = posterior_model.index_log_likelihood(b"ACGTACGA", b"IIIIIIII", index);
+        let low_quality = posterior_model.index_log_likelihood(b"ACGTACGA", b"IIIIIII#", index);
+        let exact = posterior_model.index_log_likelihood(b"ACGTACGT", b"IIIIIIII", index);
+        assert!(low_quality > high_quality);
+        assert!(exact > low_quality);
+        assert_eq!(
+            posterior_model.index_log_likelihood(b"ACGTACGN", b"IIIIIII#", index),
+            posterior_model.index_log_likelihood(b"ACGTACG", b"IIIIIII", index)
+                + RANDOM_BASE_PROBABILITY.ln()
+        );
+
+        let mut posteriors = Vec::new();
+        posterior_model.posteriors(&[exact], 8, 96, &mut posteriors);
+        assert!(posteriors[0] > 0.99);
+        // Two samples explaining the barcode equally well split the posterior.
+        posterior_model.posteriors(&[low_quality, low_quality], 8, 96, &mut posteriors);
+        assert_eq!(posteriors.len(), 2);
+        assert!((posteriors[0] - posteriors[1]).abs() < 1e-12);
+        assert!(posteriors[0] < 0.5);
+        // A high quality mismatch is less likely than the unknown hypothesis for short barcodes.
+        posterior_model.posteriors(&[high_quality], 8, 96, &mut posteriors);
+        assert!(posteriors[0] < posterior_model.threshold());
+    }
+}
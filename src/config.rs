@@ -0,0 +1,388 @@
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use toml::{Table, Value};
+
+// Environment variables of the options are this prefix followed by the option, e.g. MGIKIT_SAMPLE_SHEET.
+const ENV_PREFIX: &str = "MGIKIT_";
+// Options that are not read from the configuration file nor written to the dumped configuration.
+const CONFIG_OPTIONS: [&str; 2] = ["config", "dump-config"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValue {
+    Text(String),
+    Boolean(bool),
+    List(Vec<ConfigValue>),
+}
+
+#[derive(Default, Debug)]
+pub struct Configuration {
+    // Options outside of any section apply to all commands, sections are named after the commands.
+    global: HashMap<String, ConfigValue>,
+    sections: HashMap<String, HashMap<String, ConfigValue>>,
+}
+
+impl Configuration {
+    pub fn from_file(config_path: &str) -> Self {
+        let content = fs::read_to_string(config_path).unwrap_or_else(|err| {
+            panic!(
+                "Can not read the configuration file {}: {}",
+                config_path, err
+            )
+        });
+        Self::parse(&content)
+            .unwrap_or_else(|err| panic!("Invalid configuration file {}: {}", config_path, err))
+    }
+
+    pub fn parse(content: &str) -> Result<Self, String> {
+        /*
+        Reads the options from TOML content, where the tables are the sections of the commands.
+        Keys are the long options and '_' can be used instead of '-'.
+         */
+        let table = content.parse::<Table>().map_err(|err| err.to_string())?;
+        let mut configuration = Self::default();
+        for (key, value) in table {
+            match value {
+                Value::Table(section) => {
+                    let values = configuration.sections.entry(key).or_default();
+                    for (key, value) in section {
+                        insert_option(values, &key, value)?;
+                    }
+                }
+                value => insert_option(&mut configuration.global, &key, value)?,
+            }
+        }
+        Ok(configuration)
+    }
+
+    pub fn get(&self, command_name: &str, key: &str) -> Option<&ConfigValue> {
+        // The section of the command overrides the options outside of the sections.
+        match self.sections.get(command_name) {
+            Some(values) if values.contains_key(key) => values.get(key),
+            _ => self.global.get(key),
+        }
+    }
+}
+
+fn insert_option(
+    values: &mut HashMap<String, ConfigValue>,
+    key: &str,
+    value: Value,
+) -> Result<(), String> {
+    let option = key.replace('_', "-");
+    let value = config_value(value).map_err(|err| format!("'{}': {}", key, err))?;
+    if values.insert(option.clone(), value).is_some() {
+        return Err(format!("'{}' is defined twice!", option));
+    }
+    Ok(())
+}
+
+fn config_value(value: Value) -> Result<ConfigValue, String> {
+    match value {
+        Value::String(text) => Ok(ConfigValue::Text(text)),
+        Value::Integer(number) => Ok(ConfigValue::Text(number.to_string())),
+        Value::Float(number) => Ok(ConfigValue::Text(number.to_string())),
+        Value::Boolean(value) => Ok(ConfigValue::Boolean(value)),
+        Value::Datetime(value) => Ok(ConfigValue::Text(value.to_string())),
+        Value::Array(items) => {
+            let mut values = Vec::new();
+            for item in items {
+                match config_value(item)? {
+                    ConfigValue::List(_) => {
+                        return Err(String::from("nested arrays are not supported!"))
+                    }
+                    item => values.push(item),
+                }
+            }
+            Ok(ConfigValue::List(values))
+        }
+        Value::Table(_) => Err(String::from(
+            "tables are only supported as command sections!",
+        )),
+    }
+}
+
+fn toml_value(value: &str) -> Value {
+    // Numbers are kept as numbers when they are written back the same way.
+    if let Ok(number) = value.parse::<i64>() {
+        if number.to_string() == value {
+            return Value::Integer(number);
+        }
+    }
+    if let Ok(number) = value.parse::<f64>() {
+        if number.is_finite() && number.to_string() == value {
+            return Value::Float(number);
+        }
+    }
+    Value::String(value.to_string())
+}
+
+fn env_name(long: &str) -> String {
+    format!("{}{}", ENV_PREFIX, long.to_uppercase().replace('-', "_"))
+}
+
+fn command_options<'a>(command: &'a Command, command_name: &str) -> Vec<&'a Arg> {
+    // The options of the command, with the global options of the main command.
+    command
+        .get_arguments()
+        .filter(|arg| arg.is_global_set())
+        .chain(
+            command
+                .find_subcommand(command_name)
+                .unwrap()
+                .get_arguments(),
+        )
+        .filter(|arg| match arg.get_long() {
+            Some(long) => !CONFIG_OPTIONS.contains(&long),
+            None => false,
+        })
+        .collect()
+}
+
+fn option_arguments(arg: &Arg, value: &ConfigValue, source: &str) -> Vec<OsString> {
+    let long = arg.get_long().unwrap();
+    match (arg.get_action(), value) {
+        (ArgAction::SetTrue, ConfigValue::Boolean(true)) => {
+            vec![OsString::from(format!("--{}", long))]
+        }
+        (ArgAction::SetTrue, ConfigValue::Boolean(false)) => Vec::new(),
+        (ArgAction::SetTrue, _) => panic!("'{}' in {} must be true or false!", long, source),
+        (_, ConfigValue::List(values)) => {
+            if !matches!(arg.get_action(), ArgAction::Append) && values.len() != 1 {
+                panic!("'{}' in {} takes a single value!", long, source);
+            }
+            values
+                .iter()
+                .flat_map(|value| option_arguments(arg, value, source))
+                .collect()
+        }
+        (_, ConfigValue::Text(value)) => vec![OsString::from(format!("--{}={}", long, value))],
+        (_, ConfigValue::Boolean(value)) => vec![OsString::from(format!("--{}={}", long, value))],
+    }
+}
+
+pub fn option_environment(command: &Command, matches: &ArgMatches) -> Vec<(String, String)> {
+    // The environment variables of the options of the running command, other MGIKIT_ variables are not options.
+    let command_name = match matches.subcommand_name() {
+        Some(command_name) => command_name,
+        None => return Vec::new(),
+    };
+    command_options(command, command_name)
+        .iter()
+        .filter_map(|arg| {
+            let name = env_name(arg.get_long().unwrap());
+            env::var(&name).ok().map(|value| (name, value))
+        })
+        .collect()
+}
+
+pub fn get_matches_with_config(command: Command) -> ArgMatches {
+    /*
+    Parses the command line, then adds the options of the command that are not on the command line
+    from the environment variables (MGIKIT_ followed by the option) and then from the configuration
+    file of '--config'. The command line overrides the environment, which overrides the file, with a
+    warning as the variable may be left over from another run.
+     */
+    let args: Vec<OsString> = env::args_os().collect();
+    // Required options may come from the configuration, so they are only checked at the final parsing.
+    let matches = command
+        .clone()
+        .mut_subcommands(|subcommand| subcommand.mut_args(|arg| arg.required(false)))
+        .get_matches_from(&args);
+    let (command_name, command_matches) = match matches.subcommand() {
+        Some((command_name, command_matches)) => (command_name, command_matches),
+        None => return command.get_matches_from(&args),
+    };
+    let config_path = command_matches
+        .get_one::<String>("arg_config")
+        .cloned()
+        .unwrap_or_default();
+    let configuration = if config_path.is_empty() {
+        Configuration::default()
+    } else {
+        Configuration::from_file(&config_path)
+    };
+
+    let options = command_options(&command, command_name);
+    let mut extra_args = Vec::new();
+    for arg in &options {
+        if command_matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let long = arg.get_long().unwrap();
+        if let Ok(value) = env::var(env_name(long)) {
+            // The logger is not set up yet, as its level may come from the configuration.
+            if configuration.get(command_name, long).is_some() {
+                eprintln!(
+                    "Warning: {} overrides '{}' of the configuration file {}!",
+                    env_name(long),
+                    long,
+                    config_path
+                );
+            }
+            let value = match arg.get_action() {
+                ArgAction::SetTrue => ConfigValue::Boolean(value == "true" || value == "1"),
+                _ => ConfigValue::Text(value),
+            };
+            extra_args.extend(option_arguments(arg, &value, &env_name(long)));
+        } else if let Some(value) = configuration.get(command_name, long) {
+            extra_args.extend(option_arguments(arg, value, &config_path));
+        }
+    }
+    // Options outside of the sections may belong to other commands, the section ones must be of this command.
+    let is_option =
+        |options: &[&Arg], key: &str| options.iter().any(|arg| arg.get_long() == Some(key));
+    for key in configuration.global.keys() {
+        if !command
+            .get_subcommands()
+            .any(|subcommand| is_option(&command_options(&command, subcommand.get_name()), key))
+        {
+            panic!(
+                "Unknown option '{}' in the configuration file {}!",
+                key, config_path
+            );
+        }
+    }
+    for key in configuration
+        .sections
+        .get(command_name)
+        .into_iter()
+        .flat_map(|values| values.keys())
+    {
+        if !is_option(&options, key) {
+            panic!(
+                "Unknown option '{}' for the {} command in the configuration file {}!",
+                key, command_name, config_path
+            );
+        }
+    }
+    command.get_matches_from(args.into_iter().chain(extra_args))
+}
+
+pub fn write_config_dump(command: &Command, matches: &ArgMatches) {
+    /*
+    Writes the options of the running command with their final values to the path of
+    '--dump-config', in a format that can be used with '--config'.
+     */
+    let (command_name, command_matches) = match matches.subcommand() {
+        Some(subcommand) => subcommand,
+        None => return,
+    };
+    let dump_path = match command_matches.get_one::<String>("arg_dump_config") {
+        Some(dump_path) if !dump_path.is_empty() => dump_path,
+        _ => return,
+    };
+    let mut options = Table::new();
+    for arg in command_options(command, command_name) {
+        let values: Vec<String> = match command_matches.get_raw(arg.get_id().as_str()) {
+            Some(values) => values
+                .map(|value| value.to_string_lossy().to_string())
+                .collect(),
+            None => continue,
+        };
+        let long = arg.get_long().unwrap().to_string();
+        match arg.get_action() {
+            ArgAction::Append => {
+                options.insert(
+                    long,
+                    Value::Array(values.iter().map(|value| toml_value(value)).collect()),
+                );
+            }
+            ArgAction::SetTrue => {
+                options.insert(long, Value::Boolean(values.first().unwrap() == "true"));
+            }
+            _ => {
+                if let Some(value) = values.first() {
+                    options.insert(long, toml_value(value));
+                }
+            }
+        }
+    }
+    let mut config = Table::new();
+    config.insert(command_name.to_string(), Value::Table(options));
+    fs::write(dump_path, config.to_string())
+        .unwrap_or_else(|err| panic!("Can not write the configuration to {}: {}", dump_path, err));
+}
+
+pub fn append_resolved_config(command_matches: &ArgMatches, resolved: &[(&str, String)]) {
+    /*
+    Adds the values that are detected while running (lane, flowcell, threads...) to the dumped
+    configuration, in a section that is ignored when the file is used with '--config'.
+     */
+    let dump_path = match command_matches.get_one::<String>("arg_dump_config") {
+        Some(dump_path) if !dump_path.is_empty() => dump_path,
+        _ => return,
+    };
+    let mut config = Table::new();
+    config.insert(
+        String::from("resolved"),
+        Value::Table(
+            resolved
+                .iter()
+                .map(|(key, value)| (key.to_string(), toml_value(value)))
+                .collect(),
+        ),
+    );
+    let config_str = format!("\n{}", config);
+    let mut outfile = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(dump_path)
+        .unwrap();
+    outfile.write_all(config_str.as_bytes()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_configuration() {
+        let configuration = Configuration::parse(
+            "# shared options\nlog_level = \"warn\"\nmismatches = 1\n\n[demultiplex]\nmismatches = 2 # per index\nsample-sheet = 'sheet.tsv'\nforce = true\n\n[report]\nqc-report = [\"a.json\", \"b # c.json\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            configuration.get("demultiplex", "mismatches"),
+            Some(&ConfigValue::Text(String::from("2")))
+        );
+        assert_eq!(
+            configuration.get("template", "mismatches"),
+            Some(&ConfigValue::Text(String::from("1")))
+        );
+        assert_eq!(
+            configuration.get("demultiplex", "log-level"),
+            Some(&ConfigValue::Text(String::from("warn")))
+        );
+        assert_eq!(
+            configuration.get("demultiplex", "force"),
+            Some(&ConfigValue::Boolean(true))
+        );
+        assert_eq!(
+            configuration.get("report", "qc-report"),
+            Some(&ConfigValue::List(vec![
+                ConfigValue::Text(String::from("a.json")),
+                ConfigValue::Text(String::from("b # c.json"))
+            ]))
+        );
+        assert!(Configuration::parse("sample-sheet = sheet.tsv").is_err());
+        assert!(Configuration::parse("mismatches = 1\nmismatches = 2").is_err());
+        assert!(Configuration::parse("[demultiplex\n").is_err());
+        assert!(Configuration::parse("sample_sheet = 'a.tsv'\nsample-sheet = 'b.tsv'").is_err());
+        assert!(Configuration::parse("[demultiplex]\nthreads = [[1], [2]]").is_err());
+    }
+
+    #[test]
+    fn test_toml_value() {
+        assert_eq!(toml_value("3"), Value::Integer(3));
+        assert_eq!(toml_value("0.75"), Value::Float(0.75));
+        // Values that would not be written back the same way are kept as text.
+        assert_eq!(toml_value("007"), Value::String(String::from("007")));
+        assert_eq!(toml_value("1e3"), Value::String(String::from("1e3")));
+        assert_eq!(toml_value("L01"), Value::String(String::from("L01")));
+    }
+}
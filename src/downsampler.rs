@@ -0,0 +1,115 @@
+use crate::next_random;
+use log::info;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Clone, Debug, Default)]
+pub struct Downsampler {
+    fraction: f64,
+    seed: u64,
+    // Reads are kept when the hash of their name is below this value.
+    hash_threshold: u64,
+    max_sample_reads: u64,
+    // The reads written to every output so far, shared by the processing threads.
+    written_reads: Arc<Vec<AtomicU64>>,
+}
+
+impl Downsampler {
+    pub fn new(fraction: f64, seed: u64, max_sample_reads: u64, outputs: usize) -> Self {
+        if fraction <= 0.0 || fraction > 1.0 {
+            panic!("Downsampling fraction must be greater than 0 and at most 1!");
+        }
+        if fraction < 1.0 {
+            info!(
+                "A fraction of {} of the reads will be written to the output files (seed: {}).",
+                fraction, seed
+            );
+        }
+        if max_sample_reads > 0 {
+            info!(
+                "At most {} reads will be written to the output files of each sample.",
+                max_sample_reads
+            );
+        }
+        Self {
+            fraction,
+            seed,
+            hash_threshold: if fraction < 1.0 {
+                (fraction * 2_f64.powi(64)) as u64
+            } else {
+                u64::MAX
+            },
+            max_sample_reads,
+            written_reads: Arc::new((0..outputs).map(|_| AtomicU64::new(0)).collect()),
+        }
+    }
+
+    pub fn fraction(&self) -> f64 {
+        self.fraction
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn max_sample_reads(&self) -> u64 {
+        self.max_sample_reads
+    }
+
+    fn read_hash(&self, read_name: &[u8]) -> u64 {
+        let mut hash = self.seed;
+        for chunk in read_name.chunks(8) {
+            let mut word = [0_u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            hash ^= u64::from_le_bytes(word);
+            hash = next_random(&mut hash);
+        }
+        hash
+    }
+
+    pub fn keep_read(&self, read_name: &[u8], output_id: usize) -> bool {
+        /*
+        Whether the read (and its pair, as both have the same name) is written to the output.
+        The sampled reads only depend on the read names and the seed, so they are the same for
+        any number of threads, while the reads under the cap are the first ones processed.
+         */
+        if self.hash_threshold < u64::MAX && self.read_hash(read_name) >= self.hash_threshold {
+            return false;
+        }
+        self.max_sample_reads == 0
+            || self.written_reads[output_id].fetch_add(1, Ordering::Relaxed) < self.max_sample_reads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keep_read() {
+        let read_names: Vec<String> = (0..10000)
+            .map(|read_index| format!("V300012345L1C001R00100{:06}", read_index))
+            .collect();
+        let downsampler = Downsampler::new(0.25, 7, 0, 1);
+        let kept: Vec<bool> = read_names
+            .iter()
+            .map(|read_name| downsampler.keep_read(read_name.as_bytes(), 0))
+            .collect();
+        let kept_reads = kept.iter().filter(|&&keep| keep).count();
+        assert!(kept_reads > 2300 && kept_reads < 2700);
+        // The same seed keeps the same reads.
+        let downsampler = Downsampler::new(0.25, 7, 0, 1);
+        assert!(read_names
+            .iter()
+            .zip(&kept)
+            .all(|(read_name, &keep)| downsampler.keep_read(read_name.as_bytes(), 0) == keep));
+
+        let downsampler = Downsampler::new(1.0, 0, 3, 2);
+        let kept_reads = read_names
+            .iter()
+            .filter(|read_name| downsampler.keep_read(read_name.as_bytes(), 1))
+            .count();
+        assert_eq!(kept_reads, 3);
+        assert!(downsampler.keep_read(read_names[0].as_bytes(), 0));
+    }
+}
@@ -1,638 +1,970 @@
-use std::fs;
-use std::path::{ Path, PathBuf };
-use log::info;
-use core::panic;
-use flate2::read::MultiGzDecoder;
-use std::io::{ self, BufReader, BufWriter, Read, Write };
-use std::fs::File;
-use std::thread::{ self, JoinHandle };
-use crossbeam_channel::{ Receiver, Sender };
-use memchr::memchr_iter;
-use log::debug;
-use std::mem;
-
-pub struct RawReader {
-    sender: Sender<(usize, Vec<u8>)>,
-    receiver: Receiver<(usize, Vec<u8>)>,
-    buffer: Vec<u8>,
-    offset: usize,
-    end: usize,
-    done: bool,
-}
-
-impl RawReader {
-    pub fn new(
-        receiver: Receiver<(usize, Vec<u8>)>,
-        sender: Sender<(usize, Vec<u8>)>,
-        buffer_size: usize
-    ) -> Self {
-        Self {
-            sender,
-            receiver,
-            buffer: vec![0_u8; buffer_size],
-            offset: 0,
-            end: 0,
-            done: false,
-        }
-    }
-}
-
-impl Read for RawReader {
-    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
-        if self.offset == self.end && !self.done {
-            match self.receiver.recv() {
-                Ok((bytes, mut chunk)) => {
-                    mem::swap(&mut self.buffer, &mut chunk);
-                    match self.sender.send((0, chunk)) {
-                        Ok(_) => {
-                            //debug!("Sending: {} bytes", last_sent_endline);
-                            //debug!("kept: {}",extra_len);
-                        }
-                        Err(e) => println!("error: {e:?}"),
-                    }
-                    self.offset = 0;
-                    self.end = bytes;
-                }
-                Err(_) => {
-                    panic!("Error receiving!");
-                }
-            }
-        }
-
-        if self.offset < self.end {
-            let n = (self.end - self.offset).min(out.len());
-            out[..n].copy_from_slice(&self.buffer[self.offset..self.offset + n]);
-            self.offset += n;
-            Ok(n)
-        } else {
-            Ok(0) // EOF
-        }
-    }
-}
-
-fn read_bytes_in_reads<R: Read>(
-    reader: &mut R,
-    buffer: &mut [u8],
-    _minimum: usize,
-    last_byte: &mut usize
-) -> (usize, Vec<usize>) {
-    let mut curr_bytes: usize = 0;
-    loop {
-        debug!(
-            "buffer length: {}, starting from {}, current read {}",
-            buffer.len(),
-            last_byte,
-            curr_bytes
-        );
-        curr_bytes = reader.read(&mut buffer[*last_byte..]).unwrap();
-        if curr_bytes == 0 {
-            //debug!("total lines: {} - no more", line_cnt);
-            break;
-        }
-        *last_byte += curr_bytes;
-    }
-    //debug!("total lines: {} - still more", line_cnt);
-    return (curr_bytes, memchr_iter(b'\n', &buffer[..*last_byte]).collect::<Vec<usize>>());
-}
-
-
-pub fn fill_send_buffers<R: Read>(
-    full_sender: &Sender<(usize, Vec<u8>, Vec<usize>)>,
-    empty_receiver: &Receiver<(usize, Vec<u8>)>,
-    empty_sender: &Sender<(usize, Vec<u8>)>,
-    reader: &mut R,
-    read_cnt: usize,
-    extra: &mut Vec<u8>,
-    extra_len: &mut usize,
-    force: bool
-) -> (usize, usize) {
-    let mut total_bytes = 0;
-    match empty_receiver.recv() {
-        Ok((_, mut buffer)) => {
-            if *extra_len > 0 {
-                buffer[..*extra_len].copy_from_slice(&extra[..*extra_len]);
-                total_bytes = *extra_len;
-                *extra_len = 0;
-            }
-
-            let (last_read_bytes, mut lines) = read_bytes_in_reads(
-                reader,
-                &mut buffer[..],
-                read_cnt,
-                &mut total_bytes
-            );
-            //let mut lines = memchr_iter(b'\n', &buffer[..total_bytes]);
-            //debug!("total read bytes: {}  -  lines: {}  - needed lines: {}", total_bytes, lines.count(), read_cnt * 4);
-            //let mut lines = memchr_iter(b'\n', &buffer[..total_bytes]);
-            if lines.len() >= read_cnt * 4 || force {
-                if lines.len() >= read_cnt * 4 {
-                    lines.truncate(read_cnt * 4);
-                    *extra_len = total_bytes - lines.last().unwrap() - 1;
-                    extra[..*extra_len].copy_from_slice(
-                        &buffer[lines.last().unwrap() + 1..total_bytes]
-                    );
-                    total_bytes = lines.last().unwrap() + 1;
-                }
-                //println!("reader lines: {}", lines.count());
-                match full_sender.send((total_bytes, buffer, lines)) {
-                    Ok(_) => {
-                        //debug!("Sending: {} bytes", last_sent_endline);
-                        //debug!("kept: {}",extra_len);
-                    }
-                    Err(e) => println!("error: {e:?}"),
-                }
-                return (total_bytes, last_read_bytes);
-            } else {
-                *extra_len = total_bytes;
-                extra[..*extra_len].copy_from_slice(&buffer[..total_bytes]);
-                match empty_sender.send((0, buffer)) {
-                    Ok(_) => {
-                        //debug!("Sending: {} bytes", last_sent_endline);
-                        //debug!("kept: {}",extra_len);
-
-                    }
-                    Err(e) => println!("error: {e:?}"),
-                }
-                return (0, last_read_bytes);
-            }
-        }
-        Err(_) => {
-            debug!("Error while sending!");
-            panic!("error!")
-        }
-    }
-}
-
-pub fn send_raw_data_buffers<R: Read>(
-    full_sender: &Sender<(usize, Vec<u8>)>,
-    empty_receiver: &Receiver<(usize, Vec<u8>)>,
-    reader: &mut R
-) -> bool {
-    let mut total_bytes = 0;
-    match empty_receiver.recv() {
-        Ok((_, mut buffer)) => {
-            let _ = read_bytes(reader, &mut buffer[..], &mut total_bytes);
-            match full_sender.send((total_bytes, buffer)) {
-                Ok(_) => {
-                    //debug!("Sending: {} bytes", last_sent_endline);
-                    //debug!("kept: {}",extra_len);
-
-                }
-                Err(e) => println!("error: {e:?}"),
-            }
-            if total_bytes == 0 {
-                return false;
-            }
-        }
-        Err(_) => {
-            debug!("Error while sending!");
-        }
-    }
-    return true;
-}
-
-pub fn check_file<P: AsRef<Path>>(path: &P) {
-    if !path.as_ref().is_file() {
-        panic!("File is not accessible: {}", path.as_ref().display());
-    }
-}
-
-pub fn create_folder<P: AsRef<Path>>(path: &P) {
-    info!("A directory is created: {}", path.as_ref().display());
-    fs::create_dir_all(path.as_ref()).unwrap();
-}
-
-pub fn get_buf_reader(input_file: &PathBuf) -> BufReader<MultiGzDecoder<File>> {
-    BufReader::new(MultiGzDecoder::new(File::open(input_file).expect("Could not open the file")))
-}
-
-/*
-pub fn get_reader(input_file: &PathBuf) -> Box<dyn Read> {
-    let (reader, _) = niffler
-        ::get_reader(Box::new(std::fs::File::open(input_file).unwrap()))
-        .unwrap();
-    reader
-}
-*/
-
-pub fn delete_file<P: AsRef<Path>>(path: &P) {
-    if path.as_ref().exists() {
-        fs::remove_file(path).unwrap();
-    }
-}
-
-pub fn create_output_file<P: AsRef<Path>>(file_path: &P) -> File {
-    File::create(file_path.as_ref()).expect("couldn't create output")
-}
-pub fn get_buf_writer<P: AsRef<Path>>(file_path: &P) -> BufWriter<File> {
-    BufWriter::new(File::create(file_path.as_ref()).expect("couldn't create output"))
-}
-
-pub fn get_gzip_reader<P: AsRef<Path>>(file_path: &P) -> MultiGzDecoder<File> {
-    flate2::read::MultiGzDecoder::new(std::fs::File::open(file_path).unwrap())
-}
-
-pub fn get_reader<P: AsRef<Path>>(file_path: &P) -> File {
-    std::fs::File::open(file_path).unwrap()
-}
-
-pub fn parallel_reader_thread(
-    paired_reads: PathBuf,
-    barcode_reads: PathBuf,
-    batch_size: usize,
-    read_rb: bool,
-    read_rp: bool,
-    full_sender_rb: Sender<(usize, Vec<u8>, Vec<usize>)>,
-    full_sender_rp: Sender<(usize, Vec<u8>, Vec<usize>)>,
-    empty_sender_rb: Sender<(usize, Vec<u8>)>,
-    empty_sender_rp: Sender<(usize, Vec<u8>)>,
-    full_receiver_rb: Receiver<(usize, Vec<u8>, Vec<usize>)>,
-    full_receiver_rp: Receiver<(usize, Vec<u8>, Vec<usize>)>,
-    empty_receiver_rb: Receiver<(usize, Vec<u8>)>,
-    empty_receiver_rp: Receiver<(usize, Vec<u8>)>,
-    full_sender: Sender<(usize, Vec<u8>, Vec<usize>, usize, Vec<u8>, Vec<usize>)>,
-    processing_threads: usize,
-    buffer_size: usize,
-    paired_input: bool,
-    main_sender: bool
-) -> JoinHandle<()> {
-    thread::spawn(move || {
-        info!("Reader thread has started!");
-        let mut reader_barcode_read = if read_rb {
-            Some(get_gzip_reader(&barcode_reads))
-        } else {
-            None
-        };
-        let mut reader_paired_read = if read_rp {
-            Some(get_gzip_reader(&paired_reads))
-        } else {
-            None
-        };
-
-        let mut extra_rb: Vec<u8> = if read_rb { vec![b'0'; buffer_size] } else { Vec::new() };
-        let mut extra_len_rb: usize = 0;
-
-        let mut extra_rp: Vec<u8> = if read_rp { vec![b'0'; buffer_size] } else { Vec::new() };
-        let mut extra_len_rp: usize = 0;
-
-        let mut readers_finished = false;
-        loop {
-            //debug!("--------------------------------");
-            if read_rb {
-                match reader_barcode_read {
-                    Some(ref mut reader_barcode) => {
-                        let (sent_bytes, _) = fill_send_buffers(
-                            &full_sender_rb,
-                            &empty_receiver_rb,
-                            &empty_sender_rb,
-                            reader_barcode,
-                            batch_size,
-                            &mut extra_rb,
-                            &mut extra_len_rb,
-                            true
-                        );
-                        if !main_sender && sent_bytes == 0 {
-                            readers_finished = true;
-                        }
-                        debug!("Sent rb full buffer!");
-                    }
-                    None => {}
-                }
-            }
-
-            if read_rp {
-                match reader_paired_read {
-                    Some(ref mut reader_paired) => {
-                        let (sent_bytes, _) = fill_send_buffers(
-                            &full_sender_rp,
-                            &empty_receiver_rp,
-                            &empty_sender_rp,
-                            reader_paired,
-                            batch_size,
-                            &mut extra_rp,
-                            &mut extra_len_rp,
-                            true
-                        );
-                        if !main_sender && sent_bytes == 0 {
-                            readers_finished = true;
-                        }
-                        debug!("Sent rp full buffer!");
-                    }
-                    None => {}
-                }
-            }
-
-            if main_sender {
-                let (read_bytes2, buffer2, lines_rb) = full_receiver_rb.recv().unwrap();
-                let (read_bytes1, buffer1, lines_rp) = if paired_input {
-                    full_receiver_rp.recv().unwrap()
-                } else {
-                    (0, Vec::new(), Vec::new())
-                };
-                match
-                    full_sender.send((
-                        read_bytes2,
-                        buffer2,
-                        lines_rb,
-                        read_bytes1,
-                        buffer1,
-                        lines_rp,
-                    ))
-                {
-                    Ok(_) => {
-                        debug!("Sending full {} - {}", read_bytes1, read_bytes2);
-                    } //println!("All good: {v:?}"),
-                    Err(e) => println!("error: {e:?}"),
-                }
-                if (paired_input && read_bytes1 == 0) || read_bytes2 == 0 {
-                    readers_finished = true;
-                }
-            }
-            //println!("reading: {}   ---  {}", read_rb, read_rp);
-            //panic!(" ------------");
-            if readers_finished {
-                if main_sender {
-                    for i in 1..processing_threads {
-                        match
-                            full_sender.send((0, Vec::new(), Vec::new(), 0, Vec::new(), Vec::new()))
-                        {
-                            Ok(_) => {
-                                debug!("Sending finish signal {}", i);
-                            } //println!("All good: {v:?}"),
-                            Err(e) => println!("error: {e:?}"),
-                        };
-                    }
-                    info!("Readers threads are done!");
-                } else {
-                    info!("Secondary reader thread is done!");
-                }
-                break;
-            }
-        }
-    })
-}
-
-pub fn read_bytes<R: Read>(reader: &mut R, buffer: &mut [u8], last_byte: &mut usize) -> bool {
-    let mut curr_bytes: usize;
-    loop {
-        curr_bytes = reader.read(&mut buffer[*last_byte..]).unwrap();
-        *last_byte += curr_bytes;
-
-        if *last_byte == buffer.len() || curr_bytes == 0 {
-            if curr_bytes == 0 {
-                return false;
-            }
-            return true;
-        }
-    }
-}
-
-pub fn read_buffers<R: Read>(
-    mut bytes: usize,
-    buffer: &mut Vec<u8>,
-    reader_op: &mut Option<R>
-) -> usize {
-    if bytes >= 10000 {
-        return bytes;
-    }
-    match reader_op {
-        Some(ref mut reader) => {
-            read_bytes(reader, buffer, &mut bytes);
-        }
-        None => {
-            panic!("There should be a reader here!");
-        }
-    }
-
-    //let lines_itr = memchr_iter(b'\n', &buffer[..bytes]);
-    bytes
-}
-
-pub fn write_file<P: AsRef<Path>>(file_path: &P, content: &String) {
-    File::create(file_path.as_ref())
-        .expect("couldn't create output")
-        .write_all(content.as_bytes())
-        .unwrap();
-}
-/*
-pub fn parallel_reader_decompressor_thread(
-    reads_path: PathBuf,
-    batch_size: usize,
-    empty_raw_sender: Sender<(usize, Vec<u8>)>,
-    empty_raw_receiver: Receiver<(usize, Vec<u8>)>,
-    full_raw_sender: Sender<(usize, Vec<u8>)>,
-    full_raw_receiver: Receiver<(usize, Vec<u8>)>,
-    full_receiver_rb: Receiver<(usize, Vec<u8>, Vec<usize>)>,
-    full_receiver_rp: Receiver<(usize, Vec<u8>, Vec<usize>)>,
-    full_sender: Sender<(usize, Vec<u8>, Vec<usize>)>,
-    empty_receiver: Receiver<(usize, Vec<u8>)>,
-    empty_sender: Sender<(usize, Vec<u8>)>,
-    full_sender_paired: Sender<(usize, Vec<u8>, Vec<usize>, usize, Vec<u8>, Vec<usize>)>,
-    processing_threads: usize,
-    buffer_size: usize,
-    paired_input: bool,
-    main_sender: bool
-) -> JoinHandle<()> {
-    thread::spawn(move || {
-        info!("Reader thread has started!");
-        let decoder_thread = thread::spawn(move || {
-            let mut decoder = Decompress::new(true);
-            //let mut read_bytes ;
-            //let mut buffer = Vec::new();
-            let mut extra_len = 0;
-            let mut extra = vec![0_u8; buffer_size];
-            let mut raw_start;
-            let mut decompressed_bytes;
-            let mut sent_buffer;
-            let mut reset_decompressor;
-            loop{
-                let (read_bytes, buffer) = full_raw_receiver.recv().unwrap();
-                raw_start = 0;
-                if read_bytes == 0{
-                    send_buffer(
-                        &full_sender,
-                        &empty_receiver,
-                        &extra[..extra_len]
-                    );
-                    break;
-                }
-                loop{
-                    (decompressed_bytes, sent_buffer, reset_decompressor) = decode_send_buffer(
-                        &full_sender,
-                        &empty_receiver,
-                        &empty_sender,
-                        &mut decoder,
-                        &buffer[raw_start..read_bytes],
-                        batch_size,
-                        &mut extra,
-                        &mut extra_len,
-                        read_bytes == 0
-                    );
-                    debug!("decompressed_bytes: {} - sent_buffer: {} - reset_decompressor: {}", decompressed_bytes, sent_buffer, reset_decompressor);
-                    if reset_decompressor{
-                        decoder = Decompress::new(true);
-                    }
-                    raw_start += decompressed_bytes;
-                    
-                    if main_sender && sent_buffer {
-                        let (read_bytes2, buffer2, lines_rb) = full_receiver_rb.recv().unwrap();
-                        let (read_bytes1, buffer1, lines_rp) = if paired_input {
-                            full_receiver_rp.recv().unwrap()
-                        } else {
-                            (0, Vec::new(), Vec::new())
-                        };
-                        match
-                            full_sender_paired.send((
-                                read_bytes2,
-                                buffer2,
-                                lines_rb,
-                                read_bytes1,
-                                buffer1,
-                                lines_rp,
-                            ))
-                        {
-                            Ok(_) => {
-                                debug!("Sending full {} - {}", read_bytes1, read_bytes2);
-                            } //println!("All good: {v:?}"),
-                            Err(e) => println!("error: {e:?}"),
-                        }
-                    }
-                    if raw_start == read_bytes
-                    {
-                        break;
-                    }
-                }
-                match empty_raw_sender.send((0, buffer)) {
-                    Ok(_) => {
-                        //debug!("Sending: {} bytes", last_sent_endline);
-                        //debug!("kept: {}",extra_len);
-
-                    }
-                    Err(e) => println!("error: {e:?}"),
-                }
-            }
-            if main_sender {
-                for i in 1..processing_threads {
-                    match
-                        full_sender_paired.send((0, Vec::new(), Vec::new(), 0, Vec::new(), Vec::new()))
-                    {
-                        Ok(_) => {
-                            debug!("Sending finish signal {}", i);
-                        } //println!("All good: {v:?}"),
-                        Err(e) => println!("error: {e:?}"),
-                    };
-                }
-                info!("Readers threads are done!");
-            } else {
-                info!("Secondary reader thread is done!");
-            }
-        });
-        let mut data_reader = get_reader(&reads_path);
-        loop {
-            if ! send_raw_data_buffers(
-                &full_raw_sender,
-                &empty_raw_receiver,
-                &mut data_reader,
-            ){break;}
-        }
-        decoder_thread.join().unwrap();
-    })
-}
-*/
-pub fn parallel_reader_decompressor_thread(
-    reads_path: PathBuf,
-    batch_size: usize,
-    empty_raw_sender: Sender<(usize, Vec<u8>)>,
-    empty_raw_receiver: Receiver<(usize, Vec<u8>)>,
-    full_raw_sender: Sender<(usize, Vec<u8>)>,
-    full_raw_receiver: Receiver<(usize, Vec<u8>)>,
-    full_receiver_rb: Receiver<(usize, Vec<u8>, Vec<usize>)>,
-    full_receiver_rp: Receiver<(usize, Vec<u8>, Vec<usize>)>,
-    full_sender: Sender<(usize, Vec<u8>, Vec<usize>)>,
-    empty_receiver: Receiver<(usize, Vec<u8>)>,
-    empty_sender: Sender<(usize, Vec<u8>)>,
-    full_sender_paired: Sender<(usize, Vec<u8>, Vec<usize>, usize, Vec<u8>, Vec<usize>)>,
-    processing_threads: usize,
-    buffer_size: usize,
-    paired_input: bool,
-    main_sender: bool
-) -> JoinHandle<()> {
-    thread::spawn(move || {
-        info!("Reader thread has started!");
-        let decoder_thread = thread::spawn(move || {
-            let mut extra_len = 0;
-            let mut extra = vec![0_u8; buffer_size];
-            let mut decoder = MultiGzDecoder::new(
-                RawReader::new(full_raw_receiver, empty_raw_sender, buffer_size)
-            );
-            loop {
-                let (sent_bytes, _) = fill_send_buffers(
-                    &full_sender,
-                    &empty_receiver,
-                    &empty_sender,
-                    &mut decoder,
-                    batch_size,
-                    &mut extra,
-                    &mut extra_len,
-                    true
-                );
-
-                if main_sender {
-                    let (read_bytes2, buffer2, lines_rb) = full_receiver_rb.recv().unwrap();
-                    let (read_bytes1, buffer1, lines_rp) = if paired_input {
-                        full_receiver_rp.recv().unwrap()
-                    } else {
-                        (0, Vec::new(), Vec::new())
-                    };
-                    match
-                        full_sender_paired.send((
-                            read_bytes2,
-                            buffer2,
-                            lines_rb,
-                            read_bytes1,
-                            buffer1,
-                            lines_rp,
-                        ))
-                    {
-                        Ok(_) => {
-                            debug!("Sending full {} - {}", read_bytes1, read_bytes2);
-                        } //println!("All good: {v:?}"),
-                        Err(e) => println!("error: {e:?}"),
-                    }
-                }
-                if sent_bytes == 0 {
-                    break;
-                }
-            }
-            if main_sender {
-                for i in 1..processing_threads {
-                    match
-                        full_sender_paired.send((
-                            0,
-                            Vec::new(),
-                            Vec::new(),
-                            0,
-                            Vec::new(),
-                            Vec::new(),
-                        ))
-                    {
-                        Ok(_) => {
-                            debug!("Sending finish signal {}", i);
-                        } //println!("All good: {v:?}"),
-                        Err(e) => println!("error: {e:?}"),
-                    };
-                }
-                info!("Readers threads are done!");
-            } else {
-                info!("Secondary reader thread is done!");
-            }
-        });
-        let mut data_reader = get_reader(&reads_path);
-        loop {
-            if !send_raw_data_buffers(&full_raw_sender, &empty_raw_receiver, &mut data_reader) {
-                break;
-            }
-        }
-        decoder_thread.join().unwrap();
-    })
-}
+use std::fs;
+use std::path::{ Path, PathBuf };
+use log::info;
+use core::panic;
+use flate2::read::MultiGzDecoder;
+use std::io::{ self, BufRead, BufReader, BufWriter, Read, Write };
+use std::fs::File;
+use std::thread::{ self, JoinHandle };
+use std::sync::Arc;
+use std::time::Duration;
+use crossbeam_channel::{ Receiver, RecvTimeoutError, Sender };
+use memchr::memchr_iter;
+use log::debug;
+use std::mem;
+use crate::progress::RunProgress;
+use crate::provenance::InputChecksums;
+
+// (barcode read bytes, barcode read buffer, barcode read line ends,
+//  paired read bytes, paired read buffer, paired read line ends, records)
+pub type PairedReadsBatch = (usize, Vec<u8>, Vec<usize>, usize, Vec<u8>, Vec<usize>, usize);
+
+pub struct RawReader {
+    sender: Sender<(usize, Vec<u8>)>,
+    receiver: Receiver<(usize, Vec<u8>)>,
+    buffer: Vec<u8>,
+    offset: usize,
+    end: usize,
+    done: bool,
+}
+
+impl RawReader {
+    pub fn new(
+        receiver: Receiver<(usize, Vec<u8>)>,
+        sender: Sender<(usize, Vec<u8>)>,
+        buffer_size: usize
+    ) -> Self {
+        Self {
+            sender,
+            receiver,
+            buffer: vec![0_u8; buffer_size],
+            offset: 0,
+            end: 0,
+            done: false,
+        }
+    }
+}
+
+impl Read for RawReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.offset == self.end && !self.done {
+            match self.receiver.recv() {
+                Ok((bytes, mut chunk)) => {
+                    mem::swap(&mut self.buffer, &mut chunk);
+                    match self.sender.send((0, chunk)) {
+                        Ok(_) => {
+                            //debug!("Sending: {} bytes", last_sent_endline);
+                            //debug!("kept: {}",extra_len);
+                        }
+                        Err(e) => println!("error: {e:?}"),
+                    }
+                    self.offset = 0;
+                    self.end = bytes;
+                }
+                Err(_) => {
+                    panic!("Error receiving!");
+                }
+            }
+        }
+
+        if self.offset < self.end {
+            let n = (self.end - self.offset).min(out.len());
+            out[..n].copy_from_slice(&self.buffer[self.offset..self.offset + n]);
+            self.offset += n;
+            Ok(n)
+        } else {
+            Ok(0) // EOF
+        }
+    }
+}
+
+pub struct FastqReader<R: Read> {
+    inner: R,
+    flexible: bool,
+    line_index: usize,
+    line_position: usize,
+}
+
+impl<R: Read> FastqReader<R> {
+    pub fn new(inner: R, flexible: bool) -> Self {
+        Self {
+            inner,
+            flexible,
+            line_index: 0,
+            line_position: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for FastqReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        /*
+        In flexible mode, the reads are normalised while reading so that the records have bare `+`
+        lines and `\n` line endings. Carriage returns are dropped and anything after the `+` of the
+        third line of the record is ignored.
+         */
+        if !self.flexible {
+            return self.inner.read(out);
+        }
+        loop {
+            let bytes = self.inner.read(out)?;
+            if bytes == 0 {
+                return Ok(0);
+            }
+            let mut kept = 0;
+            for i in 0..bytes {
+                let byte = out[i];
+                if byte == b'\r' || (self.line_index == 2 && self.line_position > 0 && byte != b'\n') {
+                    continue;
+                }
+                out[kept] = byte;
+                kept += 1;
+                if byte == b'\n' {
+                    self.line_index = (self.line_index + 1) % 4;
+                    self.line_position = 0;
+                } else {
+                    self.line_position += 1;
+                }
+            }
+            if kept > 0 {
+                return Ok(kept);
+            }
+        }
+    }
+}
+
+fn read_bytes_in_reads<R: Read>(
+    reader: &mut R,
+    buffer: &mut [u8],
+    _minimum: usize,
+    last_byte: &mut usize
+) -> (usize, Vec<usize>) {
+    let mut curr_bytes: usize = 0;
+    loop {
+        debug!(
+            "buffer length: {}, starting from {}, current read {}",
+            buffer.len(),
+            last_byte,
+            curr_bytes
+        );
+        curr_bytes = reader.read(&mut buffer[*last_byte..]).unwrap();
+        if curr_bytes == 0 {
+            //debug!("total lines: {} - no more", line_cnt);
+            break;
+        }
+        *last_byte += curr_bytes;
+    }
+    //debug!("total lines: {} - still more", line_cnt);
+    return (curr_bytes, memchr_iter(b'\n', &buffer[..*last_byte]).collect::<Vec<usize>>());
+}
+
+
+pub fn fill_send_buffers<R: Read>(
+    full_sender: &Sender<(usize, Vec<u8>, Vec<usize>)>,
+    empty_receiver: &Receiver<(usize, Vec<u8>)>,
+    empty_sender: &Sender<(usize, Vec<u8>)>,
+    reader: &mut R,
+    read_cnt: usize,
+    extra: &mut Vec<u8>,
+    extra_len: &mut usize,
+    force: bool
+) -> (usize, usize) {
+    let mut total_bytes = 0;
+    match empty_receiver.recv() {
+        Ok((_, mut buffer)) => {
+            if *extra_len > 0 {
+                buffer[..*extra_len].copy_from_slice(&extra[..*extra_len]);
+                total_bytes = *extra_len;
+                *extra_len = 0;
+            }
+
+            let (last_read_bytes, mut lines) = read_bytes_in_reads(
+                reader,
+                &mut buffer[..],
+                read_cnt,
+                &mut total_bytes
+            );
+            //let mut lines = memchr_iter(b'\n', &buffer[..total_bytes]);
+            //debug!("total read bytes: {}  -  lines: {}  - needed lines: {}", total_bytes, lines.count(), read_cnt * 4);
+            //let mut lines = memchr_iter(b'\n', &buffer[..total_bytes]);
+            if lines.len() >= read_cnt * 4 || force {
+                if lines.len() >= read_cnt * 4 {
+                    lines.truncate(read_cnt * 4);
+                    *extra_len = total_bytes - lines.last().unwrap() - 1;
+                    extra[..*extra_len].copy_from_slice(
+                        &buffer[lines.last().unwrap() + 1..total_bytes]
+                    );
+                    total_bytes = lines.last().unwrap() + 1;
+                }
+                //println!("reader lines: {}", lines.count());
+                match full_sender.send((total_bytes, buffer, lines)) {
+                    Ok(_) => {
+                        //debug!("Sending: {} bytes", last_sent_endline);
+                        //debug!("kept: {}",extra_len);
+                    }
+                    Err(e) => println!("error: {e:?}"),
+                }
+                return (total_bytes, last_read_bytes);
+            } else {
+                *extra_len = total_bytes;
+                extra[..*extra_len].copy_from_slice(&buffer[..total_bytes]);
+                match empty_sender.send((0, buffer)) {
+                    Ok(_) => {
+                        //debug!("Sending: {} bytes", last_sent_endline);
+                        //debug!("kept: {}",extra_len);
+
+                    }
+                    Err(e) => println!("error: {e:?}"),
+                }
+                return (0, last_read_bytes);
+            }
+        }
+        Err(_) => {
+            debug!("Error while sending!");
+            panic!("error!")
+        }
+    }
+}
+
+pub fn send_raw_data_buffers<R: Read>(
+    full_sender: &Sender<(usize, Vec<u8>)>,
+    empty_receiver: &Receiver<(usize, Vec<u8>)>,
+    reader: &mut R
+) -> bool {
+    let mut total_bytes = 0;
+    match empty_receiver.recv() {
+        Ok((_, mut buffer)) => {
+            let _ = read_bytes(reader, &mut buffer[..], &mut total_bytes);
+            match full_sender.send((total_bytes, buffer)) {
+                Ok(_) => {
+                    //debug!("Sending: {} bytes", last_sent_endline);
+                    //debug!("kept: {}",extra_len);
+
+                }
+                Err(_) => {
+                    // The decoder has stopped as the run is aborted.
+                    debug!("The decoder is not receiving anymore!");
+                    return false;
+                }
+            }
+            if total_bytes == 0 {
+                return false;
+            }
+        }
+        Err(_) => {
+            debug!("The decoder is not returning buffers anymore!");
+            return false;
+        }
+    }
+    return true;
+}
+
+fn receive_reads_batch(
+    receiver: &Receiver<(usize, Vec<u8>, Vec<usize>)>,
+    progress: &RunProgress
+) -> Option<(usize, Vec<u8>, Vec<usize>)> {
+    // The other reader stops when the run is aborted, so its batches are not awaited then.
+    loop {
+        match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(batch) => {
+                return Some(batch);
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if progress.run_aborted() {
+                    return None;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => panic!("The reads channel is closed!"),
+        }
+    }
+}
+
+fn send_finish_signals(
+    full_sender: &Sender<PairedReadsBatch>,
+    processing_threads: usize
+) {
+    // The processing threads that are still waiting for a batch stop at the finish signal.
+    for i in 0..processing_threads {
+        match full_sender.send((0, Vec::new(), Vec::new(), 0, Vec::new(), Vec::new(), 0)) {
+            Ok(_) => {
+                debug!("Sending finish signal {}", i);
+            }
+            Err(e) => println!("error: {e:?}"),
+        };
+    }
+}
+
+pub fn check_file<P: AsRef<Path>>(path: &P) {
+    if !path.as_ref().is_file() {
+        panic!("File is not accessible: {}", path.as_ref().display());
+    }
+}
+
+pub fn create_folder<P: AsRef<Path>>(path: &P) {
+    info!("A directory is created: {}", path.as_ref().display());
+    fs::create_dir_all(path.as_ref()).unwrap();
+}
+
+pub fn get_buf_reader(input_file: &PathBuf) -> BufReader<MultiGzDecoder<File>> {
+    BufReader::new(MultiGzDecoder::new(File::open(input_file).expect("Could not open the file")))
+}
+
+pub fn fastq_records_reader_thread(
+    input_file: PathBuf,
+    batch_size: usize,
+    records_sender: Sender<Vec<(Vec<u8>, Vec<u8>)>>
+) -> JoinHandle<()> {
+    /*
+    Decompresses the file and sends the header and the sequence of its records in batches. The
+    thread stops when the file ends or the receiver is dropped.
+    */
+    thread::spawn(move || {
+        let mut reader = get_buf_reader(&input_file);
+        let mut batch: Vec<(Vec<u8>, Vec<u8>)> = Vec::with_capacity(batch_size);
+        let mut skipped_line: Vec<u8> = Vec::new();
+        loop {
+            let mut header: Vec<u8> = Vec::new();
+            if reader.read_until(b'\n', &mut header).unwrap() == 0 {
+                break;
+            }
+            let mut sequence: Vec<u8> = Vec::new();
+            reader.read_until(b'\n', &mut sequence).unwrap();
+            for _ in 0..2 {
+                skipped_line.clear();
+                reader.read_until(b'\n', &mut skipped_line).unwrap();
+            }
+            while matches!(header.last(), Some(b'\n') | Some(b'\r')) {
+                header.pop();
+            }
+            while matches!(sequence.last(), Some(b'\n') | Some(b'\r')) {
+                sequence.pop();
+            }
+            batch.push((header, sequence));
+            if
+                batch.len() == batch_size &&
+                records_sender.send(mem::replace(&mut batch, Vec::with_capacity(batch_size))).is_err()
+            {
+                return;
+            }
+        }
+        if !batch.is_empty() {
+            let _ = records_sender.send(batch);
+        }
+    })
+}
+
+/*
+pub fn get_reader(input_file: &PathBuf) -> Box<dyn Read> {
+    let (reader, _) = niffler
+        ::get_reader(Box::new(std::fs::File::open(input_file).unwrap()))
+        .unwrap();
+    reader
+}
+*/
+
+pub fn delete_file<P: AsRef<Path>>(path: &P) {
+    if path.as_ref().exists() {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+pub fn create_output_file<P: AsRef<Path>>(file_path: &P) -> File {
+    File::create(file_path.as_ref()).expect("couldn't create output")
+}
+pub fn get_buf_writer<P: AsRef<Path>>(file_path: &P) -> BufWriter<File> {
+    BufWriter::new(File::create(file_path.as_ref()).expect("couldn't create output"))
+}
+
+pub struct CountingReader<R: Read> {
+    // Adds the bytes read from the input files to the progress counters of the run and, when the
+    // run asks for the checksums, records the md5 of the files that are read to the end.
+    reader: R,
+    progress: Option<Arc<RunProgress>>,
+    input_checksums: Option<Arc<InputChecksums>>,
+    file_path: PathBuf,
+    context: Option<md5::Context>,
+    read_bytes: u64,
+    finished: bool,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new<P: AsRef<Path>>(
+        reader: R,
+        file_path: &P,
+        progress: Option<Arc<RunProgress>>,
+        input_checksums: Option<Arc<InputChecksums>>
+    ) -> Self {
+        let context = match &input_checksums {
+            Some(input_checksums) if input_checksums.enabled() => Some(md5::Context::new()),
+            _ => None,
+        };
+        Self {
+            reader,
+            progress,
+            input_checksums,
+            file_path: file_path.as_ref().to_path_buf(),
+            context,
+            read_bytes: 0,
+            finished: false,
+        }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.reader.read(buf)?;
+        if let Some(progress) = &self.progress {
+            progress.add_input_bytes(bytes);
+        }
+        if let Some(context) = &mut self.context {
+            if bytes > 0 {
+                context.consume(&buf[..bytes]);
+                self.read_bytes += bytes as u64;
+            } else if !buf.is_empty() && !self.finished {
+                self.finished = true;
+                if let Some(input_checksums) = &self.input_checksums {
+                    input_checksums.record(
+                        &self.file_path,
+                        self.read_bytes,
+                        format!("{:x}", context.clone().compute())
+                    );
+                }
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+pub fn get_gzip_reader<P: AsRef<Path>>(
+    file_path: &P,
+    progress: Option<Arc<RunProgress>>,
+    input_checksums: Option<Arc<InputChecksums>>
+) -> MultiGzDecoder<CountingReader<File>> {
+    flate2::read::MultiGzDecoder::new(get_reader(file_path, progress, input_checksums))
+}
+
+pub fn get_fastq_reader<P: AsRef<Path>>(
+    file_path: &P,
+    flexible: bool,
+    progress: Option<Arc<RunProgress>>,
+    input_checksums: Option<Arc<InputChecksums>>
+) -> FastqReader<MultiGzDecoder<CountingReader<File>>> {
+    FastqReader::new(get_gzip_reader(file_path, progress, input_checksums), flexible)
+}
+
+pub fn get_reader<P: AsRef<Path>>(
+    file_path: &P,
+    progress: Option<Arc<RunProgress>>,
+    input_checksums: Option<Arc<InputChecksums>>
+) -> CountingReader<File> {
+    // The progress and the checksums of the run are only updated by the readers of the run, not
+    // by sampling ones.
+    CountingReader::new(std::fs::File::open(file_path).unwrap(), file_path, progress, input_checksums)
+}
+
+pub fn parallel_reader_thread(
+    paired_reads: PathBuf,
+    barcode_reads: PathBuf,
+    batch_size: usize,
+    read_rb: bool,
+    read_rp: bool,
+    full_sender_rb: Sender<(usize, Vec<u8>, Vec<usize>)>,
+    full_sender_rp: Sender<(usize, Vec<u8>, Vec<usize>)>,
+    empty_sender_rb: Sender<(usize, Vec<u8>)>,
+    empty_sender_rp: Sender<(usize, Vec<u8>)>,
+    full_receiver_rb: Receiver<(usize, Vec<u8>, Vec<usize>)>,
+    full_receiver_rp: Receiver<(usize, Vec<u8>, Vec<usize>)>,
+    empty_receiver_rb: Receiver<(usize, Vec<u8>)>,
+    empty_receiver_rp: Receiver<(usize, Vec<u8>)>,
+    full_sender: Sender<PairedReadsBatch>,
+    processing_threads: usize,
+    buffer_size: usize,
+    paired_input: bool,
+    main_sender: bool,
+    flexible: bool,
+    progress: Arc<RunProgress>,
+    input_checksums: Arc<InputChecksums>
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        info!("Reader thread has started!");
+        let mut reader_barcode_read = if read_rb {
+            Some(get_fastq_reader(
+                &barcode_reads,
+                flexible,
+                Some(progress.clone()),
+                Some(input_checksums.clone())
+            ))
+        } else {
+            None
+        };
+        let mut reader_paired_read = if read_rp {
+            Some(get_fastq_reader(
+                &paired_reads,
+                flexible,
+                Some(progress.clone()),
+                Some(input_checksums.clone())
+            ))
+        } else {
+            None
+        };
+
+        let mut extra_rb: Vec<u8> = if read_rb { vec![b'0'; buffer_size] } else { Vec::new() };
+        let mut extra_len_rb: usize = 0;
+
+        let mut extra_rp: Vec<u8> = if read_rp { vec![b'0'; buffer_size] } else { Vec::new() };
+        let mut extra_len_rp: usize = 0;
+
+        let mut readers_finished = false;
+        // The number of records sent before the current batch, used to report record numbers.
+        let mut sent_records: usize = 0;
+        loop {
+            //debug!("--------------------------------");
+            if progress.run_aborted() {
+                if main_sender {
+                    send_finish_signals(&full_sender, processing_threads);
+                }
+                info!("Reader thread has stopped as the run is aborted.");
+                break;
+            }
+            if read_rb {
+                match reader_barcode_read {
+                    Some(ref mut reader_barcode) => {
+                        let (sent_bytes, _) = fill_send_buffers(
+                            &full_sender_rb,
+                            &empty_receiver_rb,
+                            &empty_sender_rb,
+                            reader_barcode,
+                            batch_size,
+                            &mut extra_rb,
+                            &mut extra_len_rb,
+                            true
+                        );
+                        if !main_sender && sent_bytes == 0 {
+                            readers_finished = true;
+                        }
+                        debug!("Sent rb full buffer!");
+                    }
+                    None => {}
+                }
+            }
+
+            if read_rp {
+                match reader_paired_read {
+                    Some(ref mut reader_paired) => {
+                        let (sent_bytes, _) = fill_send_buffers(
+                            &full_sender_rp,
+                            &empty_receiver_rp,
+                            &empty_sender_rp,
+                            reader_paired,
+                            batch_size,
+                            &mut extra_rp,
+                            &mut extra_len_rp,
+                            true
+                        );
+                        if !main_sender && sent_bytes == 0 {
+                            readers_finished = true;
+                        }
+                        debug!("Sent rp full buffer!");
+                    }
+                    None => {}
+                }
+            }
+
+            if main_sender {
+                let (read_bytes2, buffer2, lines_rb) = match
+                    receive_reads_batch(&full_receiver_rb, &progress)
+                {
+                    Some(batch) => batch,
+                    None => {
+                        continue;
+                    }
+                };
+                let (read_bytes1, buffer1, lines_rp) = if paired_input {
+                    match receive_reads_batch(&full_receiver_rp, &progress) {
+                        Some(batch) => batch,
+                        None => {
+                            continue;
+                        }
+                    }
+                } else {
+                    (0, Vec::new(), Vec::new())
+                };
+                let batch_records = lines_rb.len() / 4;
+                match
+                    full_sender.send((
+                        read_bytes2,
+                        buffer2,
+                        lines_rb,
+                        read_bytes1,
+                        buffer1,
+                        lines_rp,
+                        sent_records,
+                    ))
+                {
+                    Ok(_) => {
+                        debug!("Sending full {} - {}", read_bytes1, read_bytes2);
+                    } //println!("All good: {v:?}"),
+                    Err(e) => println!("error: {e:?}"),
+                }
+                sent_records += batch_records;
+                if (paired_input && read_bytes1 == 0) || read_bytes2 == 0 {
+                    readers_finished = true;
+                }
+            }
+            //println!("reading: {}   ---  {}", read_rb, read_rp);
+            //panic!(" ------------");
+            if readers_finished {
+                if main_sender {
+                    for i in 1..processing_threads {
+                        match
+                            full_sender.send((0, Vec::new(), Vec::new(), 0, Vec::new(), Vec::new(), 0))
+                        {
+                            Ok(_) => {
+                                debug!("Sending finish signal {}", i);
+                            } //println!("All good: {v:?}"),
+                            Err(e) => println!("error: {e:?}"),
+                        };
+                    }
+                    info!("Readers threads are done!");
+                } else {
+                    info!("Secondary reader thread is done!");
+                }
+                break;
+            }
+        }
+    })
+}
+
+pub fn read_bytes<R: Read>(reader: &mut R, buffer: &mut [u8], last_byte: &mut usize) -> bool {
+    let mut curr_bytes: usize;
+    loop {
+        curr_bytes = reader.read(&mut buffer[*last_byte..]).unwrap();
+        *last_byte += curr_bytes;
+
+        if *last_byte == buffer.len() || curr_bytes == 0 {
+            if curr_bytes == 0 {
+                return false;
+            }
+            return true;
+        }
+    }
+}
+
+pub fn read_buffers<R: Read>(
+    mut bytes: usize,
+    buffer: &mut Vec<u8>,
+    reader_op: &mut Option<R>,
+    minimum_bytes: usize
+) -> usize {
+    if bytes >= minimum_bytes {
+        return bytes;
+    }
+    match reader_op {
+        Some(ref mut reader) => {
+            read_bytes(reader, buffer, &mut bytes);
+        }
+        None => {
+            panic!("There should be a reader here!");
+        }
+    }
+
+    //let lines_itr = memchr_iter(b'\n', &buffer[..bytes]);
+    bytes
+}
+
+pub fn write_file<P: AsRef<Path>>(file_path: &P, content: &String) {
+    File::create(file_path.as_ref())
+        .expect("couldn't create output")
+        .write_all(content.as_bytes())
+        .unwrap();
+}
+/*
+pub fn parallel_reader_decompressor_thread(
+    reads_path: PathBuf,
+    batch_size: usize,
+    empty_raw_sender: Sender<(usize, Vec<u8>)>,
+    empty_raw_receiver: Receiver<(usize, Vec<u8>)>,
+    full_raw_sender: Sender<(usize, Vec<u8>)>,
+    full_raw_receiver: Receiver<(usize, Vec<u8>)>,
+    full_receiver_rb: Receiver<(usize, Vec<u8>, Vec<usize>)>,
+    full_receiver_rp: Receiver<(usize, Vec<u8>, Vec<usize>)>,
+    full_sender: Sender<(usize, Vec<u8>, Vec<usize>)>,
+    empty_receiver: Receiver<(usize, Vec<u8>)>,
+    empty_sender: Sender<(usize, Vec<u8>)>,
+    full_sender_paired: Sender<(usize, Vec<u8>, Vec<usize>, usize, Vec<u8>, Vec<usize>)>,
+    processing_threads: usize,
+    buffer_size: usize,
+    paired_input: bool,
+    main_sender: bool
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        info!("Reader thread has started!");
+        let decoder_thread = thread::spawn(move || {
+            let mut decoder = Decompress::new(true);
+            //let mut read_bytes ;
+            //let mut buffer = Vec::new();
+            let mut extra_len = 0;
+            let mut extra = vec![0_u8; buffer_size];
+            let mut raw_start;
+            let mut decompressed_bytes;
+            let mut sent_buffer;
+            let mut reset_decompressor;
+            loop{
+                let (read_bytes, buffer) = full_raw_receiver.recv().unwrap();
+                raw_start = 0;
+                if read_bytes == 0{
+                    send_buffer(
+                        &full_sender,
+                        &empty_receiver,
+                        &extra[..extra_len]
+                    );
+                    break;
+                }
+                loop{
+                    (decompressed_bytes, sent_buffer, reset_decompressor) = decode_send_buffer(
+                        &full_sender,
+                        &empty_receiver,
+                        &empty_sender,
+                        &mut decoder,
+                        &buffer[raw_start..read_bytes],
+                        batch_size,
+                        &mut extra,
+                        &mut extra_len,
+                        read_bytes == 0
+                    );
+                    debug!("decompressed_bytes: {} - sent_buffer: {} - reset_decompressor: {}", decompressed_bytes, sent_buffer, reset_decompressor);
+                    if reset_decompressor{
+                        decoder = Decompress::new(true);
+                    }
+                    raw_start += decompressed_bytes;
+                    
+                    if main_sender && sent_buffer {
+                        let (read_bytes2, buffer2, lines_rb) = full_receiver_rb.recv().unwrap();
+                        let (read_bytes1, buffer1, lines_rp) = if paired_input {
+                            full_receiver_rp.recv().unwrap()
+                        } else {
+                            (0, Vec::new(), Vec::new())
+                        };
+                        match
+                            full_sender_paired.send((
+                                read_bytes2,
+                                buffer2,
+                                lines_rb,
+                                read_bytes1,
+                                buffer1,
+                                lines_rp,
+                            ))
+                        {
+                            Ok(_) => {
+                                debug!("Sending full {} - {}", read_bytes1, read_bytes2);
+                            } //println!("All good: {v:?}"),
+                            Err(e) => println!("error: {e:?}"),
+                        }
+                    }
+                    if raw_start == read_bytes
+                    {
+                        break;
+                    }
+                }
+                match empty_raw_sender.send((0, buffer)) {
+                    Ok(_) => {
+                        //debug!("Sending: {} bytes", last_sent_endline);
+                        //debug!("kept: {}",extra_len);
+
+                    }
+                    Err(e) => println!("error: {e:?}"),
+                }
+            }
+            if main_sender {
+                for i in 1..processing_threads {
+                    match
+                        full_sender_paired.send((0, Vec::new(), Vec::new(), 0, Vec::new(), Vec::new()))
+                    {
+                        Ok(_) => {
+                            debug!("Sending finish signal {}", i);
+                        } //println!("All good: {v:?}"),
+                        Err(e) => println!("error: {e:?}"),
+                    };
+                }
+                info!("Readers threads are done!");
+            } else {
+                info!("Secondary reader thread is done!");
+            }
+        });
+        let mut data_reader = get_reader(&reads_path);
+        loop {
+            if ! send_raw_data_buffers(
+                &full_raw_sender,
+                &empty_raw_receiver,
+                &mut data_reader,
+            ){break;}
+        }
+        decoder_thread.join().unwrap();
+    })
+}
+*/
+pub fn parallel_reader_decompressor_thread(
+    reads_path: PathBuf,
+    batch_size: usize,
+    empty_raw_sender: Sender<(usize, Vec<u8>)>,
+    empty_raw_receiver: Receiver<(usize, Vec<u8>)>,
+    full_raw_sender: Sender<(usize, Vec<u8>)>,
+    full_raw_receiver: Receiver<(usize, Vec<u8>)>,
+    full_receiver_rb: Receiver<(usize, Vec<u8>, Vec<usize>)>,
+    full_receiver_rp: Receiver<(usize, Vec<u8>, Vec<usize>)>,
+    full_sender: Sender<(usize, Vec<u8>, Vec<usize>)>,
+    empty_receiver: Receiver<(usize, Vec<u8>)>,
+    empty_sender: Sender<(usize, Vec<u8>)>,
+    full_sender_paired: Sender<PairedReadsBatch>,
+    processing_threads: usize,
+    buffer_size: usize,
+    paired_input: bool,
+    main_sender: bool,
+    flexible: bool,
+    progress: Arc<RunProgress>,
+    input_checksums: Arc<InputChecksums>
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        info!("Reader thread has started!");
+        let decoder_progress = progress.clone();
+        let decoder_thread = thread::spawn(move || {
+            let mut extra_len = 0;
+            let mut extra = vec![0_u8; buffer_size];
+            let mut sent_records: usize = 0;
+            let mut decoder = FastqReader::new(
+                MultiGzDecoder::new(RawReader::new(full_raw_receiver, empty_raw_sender, buffer_size)),
+                flexible
+            );
+            loop {
+                if decoder_progress.run_aborted() {
+                    if main_sender {
+                        send_finish_signals(&full_sender_paired, processing_threads);
+                    }
+                    info!("Reader thread has stopped as the run is aborted.");
+                    return;
+                }
+                let (sent_bytes, _) = fill_send_buffers(
+                    &full_sender,
+                    &empty_receiver,
+                    &empty_sender,
+                    &mut decoder,
+                    batch_size,
+                    &mut extra,
+                    &mut extra_len,
+                    true
+                );
+
+                if main_sender {
+                    let (read_bytes2, buffer2, lines_rb) = match
+                        receive_reads_batch(&full_receiver_rb, &decoder_progress)
+                    {
+                        Some(batch) => batch,
+                        None => {
+                            continue;
+                        }
+                    };
+                    let (read_bytes1, buffer1, lines_rp) = if paired_input {
+                        match receive_reads_batch(&full_receiver_rp, &decoder_progress) {
+                            Some(batch) => batch,
+                            None => {
+                                continue;
+                            }
+                        }
+                    } else {
+                        (0, Vec::new(), Vec::new())
+                    };
+                    let batch_records = lines_rb.len() / 4;
+                    match
+                        full_sender_paired.send((
+                            read_bytes2,
+                            buffer2,
+                            lines_rb,
+                            read_bytes1,
+                            buffer1,
+                            lines_rp,
+                            sent_records,
+                        ))
+                    {
+                        Ok(_) => {
+                            debug!("Sending full {} - {}", read_bytes1, read_bytes2);
+                        } //println!("All good: {v:?}"),
+                        Err(e) => println!("error: {e:?}"),
+                    }
+                    sent_records += batch_records;
+                }
+                if sent_bytes == 0 {
+                    break;
+                }
+            }
+            if main_sender {
+                for i in 1..processing_threads {
+                    match
+                        full_sender_paired.send((
+                            0,
+                            Vec::new(),
+                            Vec::new(),
+                            0,
+                            Vec::new(),
+                            Vec::new(),
+                            0,
+                        ))
+                    {
+                        Ok(_) => {
+                            debug!("Sending finish signal {}", i);
+                        } //println!("All good: {v:?}"),
+                        Err(e) => println!("error: {e:?}"),
+                    };
+                }
+                info!("Readers threads are done!");
+            } else {
+                info!("Secondary reader thread is done!");
+            }
+        });
+        let mut data_reader = get_reader(&reads_path, Some(progress), Some(input_checksums));
+        loop {
+            if !send_raw_data_buffers(&full_raw_sender, &empty_raw_receiver, &mut data_reader) {
+                break;
+            }
+        }
+        decoder_thread.join().unwrap();
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct ChunkedReader<'a> {
+        data: &'a [u8],
+        chunk_size: usize,
+    }
+
+    impl<'a> Read for ChunkedReader<'a> {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            let n = self.chunk_size.min(self.data.len()).min(out.len());
+            out[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_fastq_reader_flexible() {
+        let input = b"@read_1\r\nACGTN\r\n+read_1\r\nIIIII\r\n@read_2\r\nAC\r\n+\r\nII\r\n";
+        let expected = b"@read_1\nACGTN\n+\nIIIII\n@read_2\nAC\n+\nII\n";
+        // the line state has to be kept when the records are split over several reads.
+        for chunk_size in [1, 3, 7, input.len()] {
+            let mut output = Vec::new();
+            FastqReader::new(ChunkedReader { data: input, chunk_size }, true)
+                .read_to_end(&mut output)
+                .unwrap();
+            assert_eq!(output, expected.to_vec(), "chunk size {}", chunk_size);
+        }
+        let mut output = Vec::new();
+        FastqReader::new(ChunkedReader { data: input, chunk_size: 4 }, false)
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, input.to_vec());
+    }
+}
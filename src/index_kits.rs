@@ -0,0 +1,192 @@
+use crate::file_utils::check_file;
+use crate::sample_manager::reverse_complement;
+use log::info;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone, Debug, Default)]
+pub struct IndexKits {
+    // kit -> index_id -> (i7, i5), i5 is '.' for single index kits.
+    kits: HashMap<String, HashMap<String, (String, String)>>,
+    i7_lookup: HashMap<String, Vec<String>>,
+    i5_lookup: HashMap<String, Vec<String>>,
+}
+
+impl IndexKits {
+    pub fn from_file(path: &Path) -> Self {
+        /*
+        Loads index kit definitions from a tab or comma separated file with the columns
+        kit, index_id, i7 and optionally i5. Lines starting with '#' are ignored.
+         */
+        check_file(&path);
+        let file_content = fs::read_to_string(path).unwrap();
+        let mut index_kits = IndexKits::default();
+        let mut header: Vec<String> = Vec::new();
+        let mut delimiter = '\t';
+        let mut columns = [usize::MAX; 4];
+        let mut entries = 0;
+        for line in file_content.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if header.is_empty() {
+                if !line.contains('\t') {
+                    delimiter = ',';
+                }
+                header = line
+                    .to_lowercase()
+                    .split(delimiter)
+                    .map(|x| x.trim().to_string())
+                    .collect();
+                for (column_itr, column) in ["kit", "index_id", "i7", "i5"].iter().enumerate() {
+                    if let Some(pos) = header.iter().position(|x| x == column) {
+                        columns[column_itr] = pos;
+                    }
+                }
+                if columns[0..3].contains(&usize::MAX) {
+                    panic!(
+                        "Index kit file ({}) must have the columns kit, index_id and i7!",
+                        path.display()
+                    );
+                }
+                continue;
+            }
+            let vals: Vec<String> = line
+                .split(delimiter)
+                .map(|x| x.trim().to_string())
+                .collect();
+            if vals.len() < header.len() {
+                panic!("Index kit line has missing columns: '{}'", line);
+            }
+            let i7 = vals[columns[2]].to_uppercase();
+            let i5 = if columns[3] == usize::MAX || vals[columns[3]].is_empty() {
+                String::from(".")
+            } else {
+                vals[columns[3]].to_uppercase()
+            };
+            for index in [&i7, &i5] {
+                if index != "." && !index.chars().all(|c| ['A', 'C', 'G', 'T'].contains(&c)) {
+                    panic!(
+                        "Index kit sequences must only contain A, C, G and T letters! found '{}'",
+                        index
+                    );
+                }
+            }
+            let kit_entries = index_kits
+                .kits
+                .entry(vals[columns[0]].to_uppercase())
+                .or_default();
+            if kit_entries.contains_key(&vals[columns[1]].to_uppercase()) {
+                panic!(
+                    "Index id {} is defined more than once in kit {}!",
+                    vals[columns[1]], vals[columns[0]]
+                );
+            }
+            kit_entries.insert(
+                vals[columns[1]].to_uppercase(),
+                (i7.to_string(), i5.to_string()),
+            );
+            let label = format!("{}:{}", vals[columns[0]], vals[columns[1]]);
+            index_kits
+                .i7_lookup
+                .entry(i7)
+                .or_default()
+                .push(label.to_string());
+            if i5 != "." {
+                index_kits
+                    .i5_lookup
+                    .entry(i5)
+                    .or_default()
+                    .push(label);
+            }
+            entries += 1;
+        }
+        if entries == 0 {
+            panic!("No index kit definitions were found in {}!", path.display());
+        }
+        info!(
+            "{} indexes from {} index kits were loaded.",
+            entries,
+            index_kits.kits.len()
+        );
+        index_kits
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kits.is_empty()
+    }
+
+    pub fn get_indexes(&self, kit: &str, index_id: &str) -> Option<&(String, String)> {
+        // Kit names and index ids are matched case insensitively.
+        self.kits
+            .get(&kit.to_uppercase())
+            .and_then(|kit_entries| kit_entries.get(&index_id.to_uppercase()))
+    }
+
+    fn annotate_index(&self, index: &str, lookup: &HashMap<String, Vec<String>>) -> String {
+        let mut matches: Vec<String> = Vec::new();
+        if let Some(labels) = lookup.get(index) {
+            matches.extend(labels.iter().cloned());
+        }
+        if let Some(labels) =
+            lookup.get(&reverse_complement(&index.to_string()).unwrap_or_default())
+        {
+            matches.extend(labels.iter().map(|label| format!("{}(rc)", label)));
+        }
+        if matches.is_empty() {
+            String::from(".")
+        } else {
+            matches.join(",")
+        }
+    }
+
+    pub fn annotate(&self, i7: &str, i5: &str) -> (String, String) {
+        /*
+        Returns the kit entries (kit:index_id) that have the i7 and the i5, matched as they are
+        or reverse complemented (marked with '(rc)'). '.' is used when nothing matches.
+         */
+        (
+            self.annotate_index(i7, &self.i7_lookup),
+            if i5.is_empty() || i5 == "." {
+                String::from(".")
+            } else {
+                self.annotate_index(i5, &self.i5_lookup)
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_kits() {
+        let path = std::env::temp_dir()
+            .join(format!("mgikit_test_index_kits_{}.csv", std::process::id()));
+        fs::write(
+            &path,
+            "kit,index_id,i7,i5\nplate_a,A01,ACGGGCAT,GGCTAGAG\nplate_a,B01,ACGCTAAT,GGCTAGAG\n",
+        )
+        .unwrap();
+        let index_kits = IndexKits::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            index_kits.get_indexes("PLATE_A", "b01"),
+            Some(&(String::from("ACGCTAAT"), String::from("GGCTAGAG")))
+        );
+        assert_eq!(index_kits.get_indexes("plate_a", "C01"), None);
+        assert_eq!(
+            index_kits.annotate("ACGGGCAT", "CTCTAGCC"),
+            (
+                String::from("plate_a:A01"),
+                String::from("plate_a:A01(rc),plate_a:B01(rc)")
+            )
+        );
+        assert_eq!(
+            index_kits.annotate("TTTTTTTT", "."),
+            (String::from("."), String::from("."))
+        );
+    }
+}
@@ -1,40 +1,63 @@
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+use chrono::prelude::Local;
 use clap::ArgMatches;
+use config::append_resolved_config;
 use crossbeam_channel::{bounded, Receiver, Sender};
 use file_utils::{
-    get_buf_reader, get_gzip_reader, parallel_reader_decompressor_thread, parallel_reader_thread,
-    read_buffers, write_file,
+    fastq_records_reader_thread, get_fastq_reader, parallel_reader_decompressor_thread,
+    parallel_reader_thread, read_buffers, write_file, PairedReadsBatch,
 };
-use log::{debug, info, warn};
-use memchr::{memchr, memchr_iter};
+use itertools::Itertools;
+use log::{debug, error, info, warn};
+use memchr::{memchr, memchr2, memchr_iter};
+use progress::ProgressReporter;
+use report_manager::{extra_quality_thresholds, LEGACY_QUALITY_THRESHOLD};
+use sample_manager::sheet_location;
+use sample_manager::TemplateData;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fmt::Write as _;
 use std::fs;
-use std::io::BufRead;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Instant;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use variables::{I5_COLUMN, I7_COLUMN, PROJECT_ID_COLUMN, SAMPLE_COLUMN};
 
 // my modules
+mod adapter_trimmer;
+mod barcode_lookup;
+mod barcode_posterior;
+mod config;
+mod downsampler;
 mod file_utils;
 mod formater;
 mod hardware_resources;
+mod index_kits;
+mod progress;
+mod provenance;
 mod report_manager;
 mod run_manager;
 mod sample_data;
 mod sample_manager;
+mod sheet_validator;
 mod variables;
 
-pub use crate::hardware_resources::{get_available_memory, get_cpus};
+pub use crate::adapter_trimmer::AdapterTrimmer;
+pub use crate::barcode_lookup::BarcodeLookup;
+pub use crate::barcode_posterior::{PosteriorBuffers, PosteriorModel};
+pub use crate::config::{get_matches_with_config, option_environment, write_config_dump};
+pub use crate::downsampler::Downsampler;
+pub use crate::hardware_resources::{get_available_memory, get_cpus, get_writer_threads};
+pub use crate::index_kits::IndexKits;
 pub use crate::sample_data::*;
 pub use formater::{parse_sb_file_name, ReformatedSample};
 pub use report_manager::ReportManager;
 pub use run_manager::RunManager;
 pub use sample_manager::{get_all_mismatches, reverse_complement, SampleManager};
+pub use sheet_validator::{validate_sample_sheet, SheetValidation};
 
 const BUFFER_SIZE: usize = 1 << 22;
 const RAW_BUFFER_SIZE: usize = 1 << 23;
@@ -69,16 +92,48 @@ pub fn add_match(
     };
 }
 
+/// Returns the positions of the index in the read barcode with the number of mismatches at each of them.
+fn find_index_positions(
+    read_barcode_seq: &String,
+    index: &String,
+    allowed_mismatches: usize,
+) -> Vec<(usize, usize)> {
+    if allowed_mismatches == 0 {
+        return read_barcode_seq
+            .match_indices(index.as_str())
+            .map(|(i, _)| (i, 0))
+            .collect();
+    }
+    let barcode = read_barcode_seq.as_bytes();
+    let index = index.as_bytes();
+    if index.len() > barcode.len() {
+        return Vec::new();
+    }
+    (0..barcode.len() - index.len() + 1)
+        .filter_map(|position| {
+            let mismatches =
+                hamming_distance(&barcode[position..position + index.len()], index).unwrap();
+            if mismatches <= allowed_mismatches {
+                Some((position, mismatches))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
 fn find_matches(
     sample_itr: usize,
     matches_stat: &mut HashMap<String, Vec<usize>>,
+    exact_matches_stat: &mut HashMap<String, Vec<usize>>,
     sample_list_ln: usize,
     read_barcode_seq: &String,
     sample_first_indx: &String,
     sample_first_indx_rc: &String,
     sample_second_indx: &String,
     sample_second_indx_rc: &String,
-) {
+    allowed_mismatches: usize,
+) -> bool {
     //println!("{} - {} - {} - {}  -> {}", sample_first_indx, sample_first_indx_rc, sample_second_indx, sample_second_indx_rc, read_barcode_seq);
     let indexes = match sample_second_indx.len() < 2 {
         true => vec![sample_first_indx, sample_first_indx_rc],
@@ -90,51 +145,245 @@ fn find_matches(
         ],
     };
     //println!("{:?}", indexes);
+    /*
+    With mismatches, an index can also match at the positions next to its real one, so each read
+    is counted once for the sample, at the template with the fewest mismatches.
+    */
+    let mut best_match: Option<(String, usize)> = None;
     for i in 0..2 {
-        let first_matches: Vec<usize> = read_barcode_seq
-            .match_indices(indexes[i])
-            .map(|(i, _)| i)
-            .collect();
+        let first_matches: Vec<(usize, usize)> =
+            find_index_positions(read_barcode_seq, indexes[i], allowed_mismatches);
         //println!("---------------------");
         //println!("{} -> {}, matches first: {:?}", i, indexes[i], first_matches);
 
         if first_matches.len() > 0 {
             for j in 2..indexes.len() {
-                let second_matches: Vec<usize> = read_barcode_seq
-                    .match_indices(indexes[j])
-                    .map(|(i, _)| i)
-                    .collect();
+                let second_matches: Vec<(usize, usize)> =
+                    find_index_positions(read_barcode_seq, indexes[j], allowed_mismatches);
                 //println!("{} -> {}, matches second: {:?}", j, indexes[j], second_matches);
-                for second_match in &second_matches {
-                    for first_match in &first_matches {
+                for (second_match, second_mismatches) in &second_matches {
+                    for (first_match, first_mismatches) in &first_matches {
                         //println!("sample - {}: comparing  {}  ->  {}    {}:{}",sample_itr, first_match, second_match, i, j);
 
-                        if *second_match >= first_match + sample_first_indx.len()
-                            || *first_match >= second_match + sample_second_indx.len()
+                        if (*second_match >= first_match + sample_first_indx.len()
+                            || *first_match >= second_match + sample_second_indx.len())
+                            && best_match.as_ref().is_none_or(|(_, best_mismatches)| {
+                                first_mismatches + second_mismatches < *best_mismatches
+                            })
                         {
-                            add_match(
+                            best_match = Some((
                                 format!("{}:{}_{}:{}", first_match, second_match, i, j - 2),
-                                sample_itr,
-                                matches_stat,
-                                sample_list_ln,
-                            );
+                                first_mismatches + second_mismatches,
+                            ));
                         }
                     }
                 }
             }
 
             if indexes.len() == 2 {
-                for first_match in first_matches {
-                    add_match(
-                        format!("{}_{}:.", first_match, i),
-                        sample_itr,
-                        matches_stat,
-                        sample_list_ln,
+                for (first_match, first_mismatches) in first_matches {
+                    if best_match
+                        .as_ref()
+                        .is_none_or(|(_, best_mismatches)| first_mismatches < *best_mismatches)
+                    {
+                        best_match = Some((format!("{}_{}:.", first_match, i), first_mismatches));
+                    }
+                }
+            }
+        }
+    }
+    if let Some((curr_template, mismatches)) = best_match {
+        if mismatches == 0 {
+            add_match(
+                curr_template.clone(),
+                sample_itr,
+                exact_matches_stat,
+                sample_list_ln,
+            );
+        }
+        add_match(curr_template, sample_itr, matches_stat, sample_list_ln);
+        return true;
+    }
+    false
+}
+
+/// splitmix64 step, used to make the read sampling reproducible for a given seed.
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+fn sample_reads<T>(
+    records: impl Iterator<Item = T>,
+    testing_reads: usize,
+    sampling: &str,
+    seed: u64,
+) -> Vec<T> {
+    /*
+    first: the first reads of the input.
+    stride: reads evenly spaced across the input, starting from a random offset. Every stride-th
+    read is kept and the stride is doubled whenever twice the requested reads are kept, so the
+    input is read once without knowing its size.
+    reservoir: a uniform random sample of the reads of the input.
+    */
+    let mut random_state = seed;
+    let mut sampled: Vec<T> = Vec::with_capacity(testing_reads);
+    if testing_reads == 0 {
+        return sampled;
+    }
+    let mut read_count: usize = 0;
+    match sampling {
+        "first" => {
+            sampled.extend(records.take(testing_reads));
+            read_count = sampled.len();
+        }
+        "stride" => {
+            let mut stride: usize = 1;
+            for record in records {
+                if read_count.is_multiple_of(stride) {
+                    sampled.push(record);
+                    if sampled.len() == 2 * testing_reads {
+                        sampled = sampled.into_iter().step_by(2).collect();
+                        stride *= 2;
+                    }
+                }
+                read_count += 1;
+            }
+            if sampled.len() > testing_reads {
+                let step = sampled.len() as f64 / testing_reads as f64;
+                let offset =
+                    (next_random(&mut random_state) >> 11) as f64 / (1u64 << 53) as f64 * step;
+                let mut positions = (0..testing_reads)
+                    .map(|itr| (offset + itr as f64 * step) as usize)
+                    .peekable();
+                sampled = sampled
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(position, _)| {
+                        if positions.peek() == Some(position) {
+                            positions.next();
+                            true
+                        } else {
+                            false
+                        }
+                    })
+                    .map(|(_, record)| record)
+                    .collect();
+            }
+        }
+        "reservoir" => {
+            for record in records {
+                if read_count < testing_reads {
+                    sampled.push(record);
+                } else {
+                    let position =
+                        (next_random(&mut random_state) % (read_count as u64 + 1)) as usize;
+                    if position < testing_reads {
+                        sampled[position] = record;
+                    }
+                }
+                read_count += 1;
+            }
+        }
+        _ => panic!(
+            "Sampling must be either first, stride or reservoir! Found {}!",
+            sampling
+        ),
+    }
+    info!(
+        "{} reads were sampled of {} reads.",
+        sampled.len(),
+        read_count
+    );
+    sampled
+}
+
+fn sample_template_barcodes(
+    run_manager: &RunManager,
+    barcode_length: usize,
+    check_read_lengths: bool,
+    testing_reads: usize,
+    sampling: &str,
+    seed: u64,
+) -> Vec<String> {
+    /*
+    The reads are decompressed in their own threads. For paired input, R1 is read along R2 so
+    that mismatched or truncated pairs are caught, and when the barcode length is the difference
+    of the read lengths, pairs that do not show that difference are not sampled.
+    */
+    let batch_size: usize = 1000;
+    let (barcode_sender, barcode_receiver) = bounded(4);
+    let barcode_reader_handler = fastq_records_reader_thread(
+        run_manager.barcode_reads().clone(),
+        batch_size,
+        barcode_sender,
+    );
+    let mut barcode_records = barcode_receiver.into_iter().flatten();
+    let paired_read_input = run_manager.paired_read_input();
+    let (paired_sender, paired_receiver) = bounded(4);
+    let paired_reader_handler = if paired_read_input {
+        Some(fastq_records_reader_thread(
+            run_manager.paired_reads().clone(),
+            batch_size,
+            paired_sender,
+        ))
+    } else {
+        drop(paired_sender);
+        None
+    };
+    let mut paired_records = paired_receiver.into_iter().flatten();
+
+    let mut skipped_reads: usize = 0;
+    let records = std::iter::from_fn(|| {
+        let barcode_record = barcode_records.next();
+        if !paired_read_input {
+            return barcode_record.map(|record| (record, None));
+        }
+        match (barcode_record, paired_records.next()) {
+            (Some(barcode_record), Some(paired_record)) => {
+                if get_read_name(&barcode_record.0) != get_read_name(&paired_record.0) {
+                    panic!(
+                        "Read names do not match between R2 and R1: {} and {}!",
+                        String::from_utf8_lossy(&barcode_record.0),
+                        String::from_utf8_lossy(&paired_record.0)
                     );
                 }
+                Some((barcode_record, Some(paired_record)))
             }
+            (None, None) => None,
+            _ => panic!("R1 and R2 files do not have the same number of reads!"),
+        }
+    })
+    .filter_map(|((_, barcode_sequence), paired_record)| {
+        let wrong_length = barcode_sequence.len() < barcode_length
+            || (check_read_lengths
+                && paired_record.is_some_and(|(_, paired_sequence)| {
+                    barcode_sequence.len() != paired_sequence.len() + barcode_length
+                }));
+        if wrong_length {
+            skipped_reads += 1;
+            return None;
         }
+        Some(
+            String::from_utf8_lossy(&barcode_sequence[barcode_sequence.len() - barcode_length..])
+                .to_string(),
+        )
+    });
+    let barcodes = sample_reads(records, testing_reads, sampling, seed);
+    barcode_reader_handler.join().unwrap();
+    if let Some(paired_reader_handler) = paired_reader_handler {
+        paired_reader_handler.join().unwrap();
+    }
+    if skipped_reads > 0 {
+        warn!(
+            "{} reads were not sampled as their length does not fit the barcode length {}.",
+            skipped_reads, barcode_length
+        );
     }
+    barcodes
 }
 
 pub fn get_mgikit_template(
@@ -221,227 +470,294 @@ pub fn get_mgikit_template(
 }
 
 pub fn find_matching_sample(
-    all_template_data: &Vec<(
-        u32,
-        HashSet<String>,
-        HashSet<String>,
-        String,
-        HashMap<String, (usize, HashMap<String, usize>)>,
-        bool,
-        [usize; 10],
-    )>,
-    mismatches_dic_i7: &Vec<HashMap<Vec<u8>, (Vec<&String>, usize)>>,
-    mismatches_dic_i5: &Vec<HashMap<Vec<u8>, (Vec<&String>, usize)>>,
+    all_template_data: &[TemplateData],
+    barcode_lookup: &BarcodeLookup,
     read_barcode: &[u8],
     allowed_mismatches: usize,
     all_index_allowed_mismatches: usize,
     total_samples: usize,
     comprehensive_scan: bool,
     i7_rc: bool,
+    nested_index_lengths: bool,
 ) -> (usize, usize, String, String) {
-    let mut template_itr = 0;
     let undetermined_label_id = total_samples - 2;
     let ambiguous_label_id = total_samples - 1;
     let mut sample_id: usize = undetermined_label_id;
-    let mut curr_umi: String = String::new();
-    let mut curr_barcode = String::new();
     let mut latest_mismatch = usize::MAX;
     let mut curr_mismatch = usize::MAX;
+    // The barcode and UMI are only extracted once, from the template of the best match.
+    let mut matched_template = usize::MAX;
 
-    for template_details in all_template_data {
-        let sample_info = &template_details.4;
+    for (template_itr, template_details) in all_template_data.iter().enumerate() {
         let indexes_info = template_details.6;
+        // Matches from templates of different index lengths are compared by their mismatches,
+        // so a shorter nested index is only rejected when the longer index matches better.
+        let template_lookup = barcode_lookup.template(template_itr);
 
-        let all_i7_mismatches = &mismatches_dic_i7[template_itr];
-        let all_i5_mismatches = &mismatches_dic_i5[template_itr];
-
-        //println!("{:?}", indexes_info);
-        //println!("{}", template_details.5);
-        let extract_umi = if indexes_info[6] == 1 { true } else { false };
-
-        match all_i7_mismatches.get(
+        if let Some((i7_ids, i7_mismatches)) = template_lookup.get_i7(
             &read_barcode[read_barcode.len() - indexes_info[2]
                 ..read_barcode.len() - indexes_info[2] + indexes_info[1]],
         ) {
-            Some(i7_matches) => {
-                //info!("{:?}", i7_matches);
-                if i7_matches.1 <= allowed_mismatches {
-                    if template_details.5 {
-                        match all_i5_mismatches.get(
-                            &read_barcode[read_barcode.len() - indexes_info[5]
-                                ..read_barcode.len() - indexes_info[5] + indexes_info[4]],
-                        ) {
-                            Some(i5_matches) => {
-                                //info!("{:?} and {:?}", i7_matches, i5_matches);
-                                for i7_match_itr in 0..i7_matches.0.len() {
-                                    for i5_match_itr in 0..i5_matches.0.len() {
-                                        if i5_matches.1 > allowed_mismatches
-                                            || latest_mismatch < i7_matches.1 + i5_matches.1
+            if i7_mismatches <= allowed_mismatches {
+                if template_details.5 {
+                    if let Some((i5_ids, i5_mismatches)) = template_lookup.get_i5(
+                        &read_barcode[read_barcode.len() - indexes_info[5]
+                            ..read_barcode.len() - indexes_info[5] + indexes_info[4]],
+                    ) {
+                        for &i7_id in i7_ids.iter() {
+                            for &i5_id in i5_ids.iter() {
+                                if i5_mismatches > allowed_mismatches
+                                    || latest_mismatch < i7_mismatches + i5_mismatches
+                                {
+                                    continue;
+                                }
+                                curr_mismatch = i7_mismatches + i5_mismatches;
+                                if curr_mismatch <= all_index_allowed_mismatches {
+                                    if let Some(i5_info) = template_lookup.dual_sample(i7_id, i5_id)
+                                    {
+                                        if sample_id >= total_samples
+                                            || latest_mismatch > curr_mismatch
                                         {
-                                            continue;
-                                        }
-                                        curr_mismatch = i7_matches.1 + i5_matches.1;
-                                        if curr_mismatch <= all_index_allowed_mismatches {
-                                            //println!("{}", i7_matches.0[i7_match_itr]);
-                                            match sample_info
-                                                .get(i7_matches.0[i7_match_itr])
-                                                .unwrap()
-                                                .1
-                                                .get(i5_matches.0[i5_match_itr])
-                                            {
-                                                Some(i5_info) => {
-                                                    if sample_id >= total_samples
-                                                        || latest_mismatch > curr_mismatch
-                                                    {
-                                                        sample_id = *i5_info;
-                                                        latest_mismatch = curr_mismatch;
-                                                        curr_barcode = unsafe {
-                                                            String::from_utf8_unchecked(
-                                                                read_barcode[read_barcode.len()
-                                                                    - indexes_info[2]
-                                                                    ..read_barcode.len()
-                                                                        - indexes_info[2]
-                                                                        + indexes_info[1]]
-                                                                    .to_vec(),
-                                                            )
-                                                        };
-                                                        curr_barcode.push('+');
-                                                        curr_barcode.push_str(
-                                                            &(unsafe {
-                                                                String::from_utf8_unchecked(
-                                                                    read_barcode[read_barcode.len()
-                                                                        - indexes_info[5]
-                                                                        ..read_barcode.len()
-                                                                            - indexes_info[5]
-                                                                            + indexes_info[4]]
-                                                                        .to_vec(),
-                                                                )
-                                                            }),
-                                                        );
-
-                                                        if extract_umi {
-                                                            curr_umi = String::from(":");
-                                                            if i7_rc {
-                                                                curr_umi.push_str(
-                                                                    &reverse_complement(
-                                                                        &(unsafe {
-                                                                            String::from_utf8_unchecked(
-                                                                                read_barcode[
-                                                                                    read_barcode.len() -
-                                                                                        indexes_info
-                                                                                            [8]..read_barcode.len() -
-                                                                                        indexes_info
-                                                                                            [8] +
-                                                                                        indexes_info
-                                                                                            [7]
-                                                                                ].to_vec()
-                                                                            )
-                                                                        })
-                                                                    ).unwrap()
-                                                                );
-                                                            } else {
-                                                                curr_umi.push_str(
-                                                                    &(unsafe {
-                                                                        String::from_utf8_unchecked(
-                                                                            read_barcode[
-                                                                                read_barcode.len() -
-                                                                                    indexes_info
-                                                                                        [8]..read_barcode.len() -
-                                                                                    indexes_info
-                                                                                        [8] +
-                                                                                    indexes_info[7]
-                                                                            ].to_vec()
-                                                                        )
-                                                                    })
-                                                                );
-                                                            }
-                                                        }
-                                                    } else {
-                                                        sample_id = ambiguous_label_id;
-                                                        break;
-                                                    }
-                                                }
-                                                None => {
-                                                    //break
-                                                }
-                                            };
+                                            sample_id = i5_info;
+                                            latest_mismatch = curr_mismatch;
+                                            matched_template = template_itr;
+                                        } else {
+                                            sample_id = ambiguous_label_id;
+                                            break;
                                         }
                                     }
                                 }
                             }
-                            None => {}
-                        }
-                    } else {
-                        if latest_mismatch < i7_matches.1 {
-                            continue;
-                        } else if latest_mismatch > i7_matches.1 && i7_matches.0.len() < 2 {
-                            curr_mismatch = i7_matches.1;
-                            latest_mismatch = curr_mismatch;
-                            sample_id = match template_details.4.get(i7_matches.0[0]) {
-                                Some(tmp) => tmp.0,
-                                None => {
-                                    panic!("There should be a value here");
-                                }
-                            };
-                            //barcode_length = indexes_info[9];
-                            curr_barcode = unsafe {
-                                String::from_utf8_unchecked(
-                                    read_barcode[read_barcode.len() - indexes_info[2]
-                                        ..read_barcode.len() - indexes_info[2] + indexes_info[1]]
-                                        .to_vec(),
-                                )
-                            };
-                            if extract_umi {
-                                curr_umi = String::from(":");
-                                curr_umi.push_str(
-                                    &(unsafe {
-                                        String::from_utf8_unchecked(
-                                            read_barcode[read_barcode.len() - indexes_info[8]
-                                                ..read_barcode.len() - indexes_info[8]
-                                                    + indexes_info[7]]
-                                                .to_vec(),
-                                        )
-                                    }),
-                                );
-                                /*if i7_rc {
-                                    curr_umi.push_str(
-                                        &reverse_complement(
-                                            &(unsafe {
-                                                String::from_utf8_unchecked(
-                                                    read_barcode[
-                                                        read_barcode.len() -
-                                                            indexes_info
-                                                                [8]..read_barcode.len() -
-                                                            indexes_info[8] +
-                                                            indexes_info[7]
-                                                    ].to_vec()
-                                                )
-                                            })
-                                        ).unwrap()
-                                    );
-                                }*/
-                            }
-                        } else {
-                            sample_id = ambiguous_label_id;
                         }
                     }
+                } else if latest_mismatch < i7_mismatches {
+                    continue;
+                } else if latest_mismatch > i7_mismatches && i7_ids.len() < 2 {
+                    curr_mismatch = i7_mismatches;
+                    latest_mismatch = curr_mismatch;
+                    matched_template = template_itr;
+                    sample_id = template_lookup.i7_sample(i7_ids[0]);
+                } else {
+                    sample_id = ambiguous_label_id;
                 }
             }
-            None => {}
         }
 
-        if sample_id != undetermined_label_id && sample_id < total_samples && !comprehensive_scan {
+        // With nested index lengths, a shorter template can match the same read equally well or
+        // better, so all templates are checked.
+        if sample_id != undetermined_label_id
+            && sample_id < total_samples
+            && !comprehensive_scan
+            && !nested_index_lengths
+        {
             break;
         }
-
-        template_itr += 1;
     }
     if sample_id >= undetermined_label_id {
         curr_mismatch = 0;
     }
+    let (curr_barcode, curr_umi) = if matched_template == usize::MAX {
+        (String::new(), String::new())
+    } else {
+        extract_barcode_and_umi(&all_template_data[matched_template], read_barcode, i7_rc)
+    };
     (sample_id, curr_mismatch, curr_barcode, curr_umi)
 }
 
+fn extract_barcode_and_umi(
+    template_details: &TemplateData,
+    read_barcode: &[u8],
+    i7_rc: bool,
+) -> (String, String) {
+    let indexes_info = template_details.6;
+    let read_part = |shift: usize, length: usize| {
+        String::from_utf8_lossy(
+            &read_barcode[read_barcode.len() - shift..read_barcode.len() - shift + length],
+        )
+        .to_string()
+    };
+    let mut curr_barcode = read_part(indexes_info[2], indexes_info[1]);
+    if template_details.5 {
+        curr_barcode.push('+');
+        curr_barcode.push_str(&read_part(indexes_info[5], indexes_info[4]));
+    }
+    let mut curr_umi = String::new();
+    if indexes_info[6] == 1 {
+        curr_umi.push(':');
+        // Only dual index templates reverse complement the UMI.
+        if i7_rc && template_details.5 {
+            curr_umi.push_str(
+                &reverse_complement(&read_part(indexes_info[8], indexes_info[7])).unwrap(),
+            );
+        } else {
+            curr_umi.push_str(&read_part(indexes_info[8], indexes_info[7]));
+        }
+    }
+    (curr_barcode, curr_umi)
+}
+
+/// The settings of the posterior matching, the same for all the reads of a run.
+pub struct PosteriorMatching<'a> {
+    pub all_template_data: &'a [TemplateData],
+    pub barcode_lookup: &'a BarcodeLookup,
+    pub allowed_mismatches: usize,
+    pub all_index_allowed_mismatches: usize,
+    pub total_samples: usize,
+    pub posterior_model: &'a PosteriorModel,
+}
+
+pub fn find_matching_sample_posterior(
+    posterior_matching: &PosteriorMatching,
+    read_barcode: &[u8],
+    read_qualities: &[u8],
+    i7_rc: bool,
+    posterior_buffers: &mut PosteriorBuffers,
+) -> (usize, usize, String, String, f64) {
+    /*
+    Every sample within the allowed mismatches is a candidate and the read is assigned to the
+    most likely one if its posterior probability reaches the threshold. When the candidates
+    reach the threshold only together, the read is ambiguous, otherwise it is undetermined.
+     */
+    let PosteriorMatching {
+        all_template_data,
+        barcode_lookup,
+        allowed_mismatches,
+        all_index_allowed_mismatches,
+        total_samples,
+        posterior_model,
+    } = *posterior_matching;
+    let undetermined_label_id = total_samples - 2;
+    let ambiguous_label_id = total_samples - 1;
+    let PosteriorBuffers {
+        candidates,
+        log_likelihoods,
+        posteriors,
+        i7_matches,
+        i5_matches,
+        i5_log_likelihoods,
+    } = posterior_buffers;
+    candidates.clear();
+    log_likelihoods.clear();
+    for (template_itr, template_details) in all_template_data.iter().enumerate() {
+        let indexes_info = template_details.6;
+        let template_lookup = barcode_lookup.template(template_itr);
+        let i7_start = read_barcode.len() - indexes_info[2];
+        let i7_end = i7_start + indexes_info[1];
+        template_lookup.get_all_i7(
+            &read_barcode[i7_start..i7_end],
+            allowed_mismatches,
+            i7_matches,
+        );
+        // Bases outside the indexes of the template are random for its samples.
+        let mut covered_length = indexes_info[1];
+        if template_details.5 {
+            let i5_start = read_barcode.len() - indexes_info[5];
+            let i5_end = i5_start + indexes_info[4];
+            covered_length += indexes_info[4];
+            template_lookup.get_all_i5(
+                &read_barcode[i5_start..i5_end],
+                allowed_mismatches,
+                i5_matches,
+            );
+            i5_log_likelihoods.clear();
+            i5_log_likelihoods.extend(i5_matches.iter().map(|&(i5_id, _)| {
+                posterior_model.index_log_likelihood(
+                    &read_barcode[i5_start..i5_end],
+                    &read_qualities[i5_start..i5_end],
+                    template_lookup.i5_index(i5_id),
+                )
+            }));
+            for &(i7_id, i7_mismatches) in i7_matches.iter() {
+                let i7_log_likelihood = posterior_model.index_log_likelihood(
+                    &read_barcode[i7_start..i7_end],
+                    &read_qualities[i7_start..i7_end],
+                    template_lookup.i7_index(i7_id),
+                );
+                for (i5_itr, &(i5_id, i5_mismatches)) in i5_matches.iter().enumerate() {
+                    if i7_mismatches + i5_mismatches > all_index_allowed_mismatches {
+                        continue;
+                    }
+                    if let Some(sample_id) = template_lookup.dual_sample(i7_id, i5_id) {
+                        candidates.push((sample_id, template_itr, i7_mismatches + i5_mismatches));
+                        log_likelihoods.push(
+                            i7_log_likelihood
+                                + i5_log_likelihoods[i5_itr]
+                                + posterior_model
+                                    .random_log_likelihood(read_barcode.len() - covered_length),
+                        );
+                    }
+                }
+            }
+        } else {
+            for &(i7_id, i7_mismatches) in i7_matches.iter() {
+                candidates.push((
+                    template_lookup.i7_sample(i7_id),
+                    template_itr,
+                    i7_mismatches,
+                ));
+                log_likelihoods.push(
+                    posterior_model.index_log_likelihood(
+                        &read_barcode[i7_start..i7_end],
+                        &read_qualities[i7_start..i7_end],
+                        template_lookup.i7_index(i7_id),
+                    ) + posterior_model.random_log_likelihood(read_barcode.len() - covered_length),
+                );
+            }
+        }
+    }
+
+    posterior_model.posteriors(
+        log_likelihoods,
+        read_barcode.len(),
+        undetermined_label_id,
+        posteriors,
+    );
+    let mut best_itr = usize::MAX;
+    let mut second_posterior = 0.0;
+    for (candidate_itr, &posterior) in posteriors.iter().enumerate() {
+        if best_itr == usize::MAX || posterior > posteriors[best_itr] {
+            if best_itr < usize::MAX {
+                second_posterior = posteriors[best_itr];
+            }
+            best_itr = candidate_itr;
+        } else if posterior > second_posterior {
+            second_posterior = posterior;
+        }
+    }
+    if best_itr == usize::MAX {
+        return (undetermined_label_id, 0, String::new(), String::new(), 0.0);
+    }
+    let best_posterior = posteriors[best_itr];
+    if best_posterior >= posterior_model.threshold() {
+        let (sample_id, template_itr, mismatches) = candidates[best_itr];
+        let (curr_barcode, curr_umi) =
+            extract_barcode_and_umi(&all_template_data[template_itr], read_barcode, i7_rc);
+        (
+            sample_id,
+            mismatches,
+            curr_barcode,
+            curr_umi,
+            best_posterior,
+        )
+    } else if best_posterior + second_posterior >= posterior_model.threshold() {
+        (
+            ambiguous_label_id,
+            0,
+            String::new(),
+            String::new(),
+            best_posterior,
+        )
+    } else {
+        (
+            undetermined_label_id,
+            0,
+            String::new(),
+            String::new(),
+            best_posterior,
+        )
+    }
+}
+
 fn copy_within_a_slice<T: Clone>(v: &mut [T], from: usize, to: usize, len: usize) {
     if len == 0 {
         return;
@@ -455,25 +771,69 @@ fn copy_within_a_slice<T: Clone>(v: &mut [T], from: usize, to: usize, len: usize
     }
 }
 
-fn sum_qc(qc_seq: &[u8], check_content: bool) -> (u64, u64) {
+fn sum_qc(
+    qc_seq: &[u8],
+    check_content: bool,
+    quality_range: (u8, u8),
+    quality_thresholds: &[u8],
+    high_qc: &mut [u64],
+) -> u64 {
+    /*
+    Returns the sum of the quality scores (as encoded) and fills high_qc with the count of
+    bases at or above each of the quality thresholds (as encoded).
+     */
     let mut qc_total: u64 = 0;
-    let mut high_qc: u64 = 0;
+    high_qc.fill(0);
     for &qs in qc_seq.iter() {
         if check_content {
-            if qs < 33 || qs > 73 {
-                panic!("Reverse read quality scores should be between 33 and 73. the detected quality score is {}", qs);
+            if qs < quality_range.0 || qs > quality_range.1 {
+                panic!(
+                    "Reverse read quality scores should be between {} and {}. the detected quality score is {}",
+                    quality_range.0, quality_range.1, qs
+                );
             }
         }
-        if qs >= 63 {
-            high_qc += 1;
+        for (threshold_index, &threshold) in quality_thresholds.iter().enumerate() {
+            if qs >= threshold {
+                high_qc[threshold_index] += 1;
+            }
         }
         qc_total += qs as u64;
     }
     //debug!("quality res: {} - {} - {}", qc_seq.len(), qc_total, high_qc);
-    (qc_total, high_qc)
+    qc_total
+}
+
+fn mean_quality(qc_seq: &[u8], quality_offset: u8) -> usize {
+    // Reads without sequence are never filtered for quality.
+    if qc_seq.is_empty() {
+        return usize::MAX;
+    }
+    let qc_total: usize = qc_seq
+        .iter()
+        .map(|&qs| qs.saturating_sub(quality_offset) as usize)
+        .sum();
+    qc_total / qc_seq.len()
+}
+
+/// Returns the read name of a fastq header line, without the comment and the `/1` or `/2` suffix.
+pub fn get_read_name(header: &[u8]) -> &[u8] {
+    let name_end = memchr2(b' ', b'\t', header).unwrap_or(header.len());
+    let name = &header[..name_end];
+    if name.len() > 2 && (name.ends_with(b"/1") || name.ends_with(b"/2")) {
+        &name[..name.len() - 2]
+    } else {
+        name
+    }
 }
 
-pub fn check_read_content(read_seq: &[u8], seq_start: usize, plus_start: usize, qc_start: usize) {
+pub fn check_read_content(
+    read_seq: &[u8],
+    seq_start: usize,
+    plus_start: usize,
+    qc_start: usize,
+    quality_range: (u8, u8),
+) {
     if read_seq[0] != b'@' {
         panic!(
             "Read header must starts with '@'! The detected header is : {}",
@@ -499,10 +859,11 @@ pub fn check_read_content(read_seq: &[u8], seq_start: usize, plus_start: usize,
         }
     }
     for &qs in read_seq[qc_start..read_seq.len()].iter() {
-        if qs > 73 || qs < 33 {
+        if qs > quality_range.1 || qs < quality_range.0 {
             panic!(
-                "Quality score must be between [0 and 40]! Found {}!",
-                qs - 33
+                "Quality score must be between [0 and {}]! Found {}!",
+                quality_range.1 - quality_range.0,
+                qs as i16 - quality_range.0 as i16
             );
         }
     }
@@ -517,6 +878,7 @@ fn demultiplex(
     all_index_error: bool,
     reader_threads: usize,
     processing_threads: usize,
+    writer_threads: usize,
 ) -> Result<ReportManager, Box<dyn Error>> {
     let start = Instant::now();
     //let dur;
@@ -548,6 +910,23 @@ fn demultiplex(
 
     let all_template_data = sample_manager.all_template_data();
     let total_samples = sample_manager.get_sample_count();
+    let barcode_lookup = Arc::new(BarcodeLookup::new(all_template_data, allowed_mismatches));
+    run_manager.progress().set_input_size(
+        fs::metadata(run_manager.barcode_reads())?.len()
+            + if run_manager.paired_read_input() {
+                fs::metadata(run_manager.paired_reads())?.len()
+            } else {
+                0
+            },
+    );
+    let progress_reporter = if run_manager.progress_interval() > 0 {
+        Some(ProgressReporter::start(
+            run_manager.progress().clone(),
+            Duration::from_secs(run_manager.progress_interval()),
+        ))
+    } else {
+        None
+    };
 
     if run_manager.force() {
         clean_output_directory(
@@ -652,6 +1031,9 @@ fn demultiplex(
                 BUFFER_SIZE,
                 run_manager.paired_read_input(),
                 barcode_process_master,
+                run_manager.flexible(),
+                run_manager.progress().clone(),
+                run_manager.input_checksums().clone(),
             ))
         } else {
             Some(parallel_reader_thread(
@@ -673,6 +1055,9 @@ fn demultiplex(
                 BUFFER_SIZE,
                 run_manager.paired_read_input(),
                 barcode_process_master,
+                run_manager.flexible(),
+                run_manager.progress().clone(),
+                run_manager.input_checksums().clone(),
             ))
         }
     } else {
@@ -698,6 +1083,9 @@ fn demultiplex(
                 BUFFER_SIZE,
                 true,
                 !barcode_process_master,
+                run_manager.flexible(),
+                run_manager.progress().clone(),
+                run_manager.input_checksums().clone(),
             ))
         } else {
             Some(parallel_reader_thread(
@@ -719,22 +1107,35 @@ fn demultiplex(
                 BUFFER_SIZE,
                 true,
                 !barcode_process_master,
+                run_manager.flexible(),
+                run_manager.progress().clone(),
+                run_manager.input_checksums().clone(),
             ))
         }
     } else {
         None
     };
 
-    let report_manager_arc = Arc::new(Mutex::new(ReportManager::new(
-        total_samples,
-        allowed_mismatches,
-    )));
-    let mut processor_pool = Vec::new();
-    let mut sample_lockes = Vec::new();
-    for _ in 0..total_samples {
-        sample_lockes.push(Mutex::new(false));
+    let mut report_manager = ReportManager::new(total_samples, allowed_mismatches);
+    report_manager
+        .set_quality_thresholds(run_manager.quality_offset(), run_manager.yield_thresholds());
+    if run_manager.cycle_stats() && reporting_level > 1 {
+        report_manager.enable_cycle_statistics();
     }
-    let samples_locks_arc = Arc::new(sample_lockes);
+    let report_manager_arc = Arc::new(Mutex::new(report_manager));
+    let mut processor_pool = Vec::new();
+    let (output_writers, writer_handlers) = start_output_writers(
+        create_sample_data_list(
+            sample_manager,
+            run_manager,
+            buffer_info,
+            run_manager.read2_has_sequence(),
+            run_manager.illumina_format(),
+        ),
+        buffer_info.compression_level(),
+        writer_threads,
+        run_manager.progress().clone(),
+    );
 
     for th_id in 0..processing_threads - 1 {
         let thread_name = format!("worker-{}", th_id);
@@ -745,8 +1146,9 @@ fn demultiplex(
         let run_manager = run_manager.clone();
         let buffer_info = buffer_info.clone();
         let all_template_data = all_template_data.clone();
+        let barcode_lookup = barcode_lookup.clone();
         let report_manager_arc = report_manager_arc.clone();
-        let samples_locks_arc: Arc<Vec<Mutex<bool>>> = samples_locks_arc.clone();
+        let output_writers = output_writers.clone();
         processor_pool.push(
             thread::Builder::new()
                 .name(thread_name.clone())
@@ -756,6 +1158,7 @@ fn demultiplex(
                         &run_manager,
                         &buffer_info,
                         all_template_data.clone(),
+                        barcode_lookup,
                         reporting_level,
                         allowed_mismatches,
                         all_index_error,
@@ -764,7 +1167,7 @@ fn demultiplex(
                         empty_sender_rb,
                         empty_sender_rp,
                         reader_threads > 0,
-                        samples_locks_arc,
+                        output_writers,
                         true,
                         &ReformatedSample::default(),
                         vec![false; 10],
@@ -781,30 +1184,63 @@ fn demultiplex(
         &run_manager,
         &buffer_info,
         all_template_data.clone(),
+        barcode_lookup,
         reporting_level,
         allowed_mismatches,
         all_index_error,
         BUFFER_SIZE,
-        full_receiver,
-        empty_sender_rb,
-        empty_sender_rp,
+        full_receiver.clone(),
+        empty_sender_rb.clone(),
+        empty_sender_rp.clone(),
         reader_threads > 0,
-        samples_locks_arc,
+        output_writers,
         true,
         &ReformatedSample::default(),
         vec![true; 10],
     );
 
+    let run_aborted = run_manager.progress().run_aborted();
+    if run_aborted {
+        // The readers of an aborted run stop at their next batch. The processing threads stop first,
+        // then the batches left in the channels are returned to the readers until they are done.
+        for handler in processor_pool.drain(..) {
+            handler.unwrap().join().unwrap();
+        }
+    }
     if reader_threads > 0 {
         let _ = match reader_handler {
-            Some(handler) => handler.join().unwrap(),
+            Some(handler) => {
+                if run_aborted {
+                    drain_reads_batches(
+                        &handler,
+                        &full_receiver,
+                        &full_receiver_rb,
+                        &full_receiver_rp,
+                        &empty_sender_rb,
+                        &empty_sender_rp,
+                    );
+                }
+                handler.join().unwrap()
+            }
             None => {
                 panic!("there must be a reader thread here!")
             }
         };
         if reader_threads > 2 || (reader_threads == 2 && run_manager.paired_read_input()) {
             let _ = match reader_handler_secondary {
-                Some(handler) => handler.join().unwrap(),
+                Some(handler) => {
+                    if run_aborted {
+                        drain_reads_batches(
+                            &handler,
+                            &full_receiver,
+                            &full_receiver_rb,
+                            &full_receiver_rp,
+                            &empty_sender_rb,
+                            &empty_sender_rp,
+                        );
+                    }
+                    handler.join().unwrap()
+                }
                 None => {
                     panic!("there must be a secondary reader thread here!")
                 }
@@ -815,6 +1251,12 @@ fn demultiplex(
             handler.unwrap().join().unwrap();
         }
     }
+    for handler in writer_handlers {
+        handler.join().unwrap();
+    }
+    if let Some(progress_reporter) = progress_reporter {
+        progress_reporter.stop();
+    }
 
     let mut report_manager = match Arc::try_unwrap(report_manager_arc) {
         Ok(mutex) => match mutex.into_inner() {
@@ -826,6 +1268,19 @@ fn demultiplex(
 
     report_manager.update(&curr_report_manager);
 
+    if run_manager.progress().run_aborted() {
+        let diagnostics_path = report_manager.write_abort_diagnostics(
+            run_manager,
+            sample_manager,
+            run_manager.progress().processed_reads(),
+        );
+        return Err(format!(
+            "The run was aborted as most of the reads are undetermined, the partial outputs were deleted! Check the sample sheet using the diagnostics in {}",
+            diagnostics_path.display()
+        )
+        .into());
+    }
+
     let dur = start.elapsed();
 
     info!(
@@ -857,15 +1312,16 @@ fn process_buffer(
     allowed_mismatches: usize,
     buffer_1: &[u8],
     buffer_2: &[u8],
-    mismatches_dic_i7: &Vec<HashMap<Vec<u8>, (Vec<&String>, usize)>>,
-    mismatches_dic_i5: &Vec<HashMap<Vec<u8>, (Vec<&String>, usize)>>,
-    samples_locks: &Arc<Vec<Mutex<bool>>>,
+    barcode_lookup: &BarcodeLookup,
+    output_writers: &OutputWriters,
     lines_rb: Vec<usize>,
     lines_rp: Vec<usize>,
     demultiplex: bool,
     reformated_sample: &ReformatedSample,
     warning_ls: &mut Vec<bool>,
-) -> (usize, usize) {
+    posterior_buffers: &mut PosteriorBuffers,
+    first_record: usize,
+) -> (usize, usize, usize) {
     let l_position: usize = run_manager.l_position();
     let total_samples: usize = sample_manager.get_sample_count();
     let undetermined_label_id = total_samples - 2;
@@ -885,6 +1341,39 @@ fn process_buffer(
         true => 0,
     };
     let writing_samples: &Vec<usize> = sample_manager.writing_samples();
+    let (barcode_read_trimmer, paired_read_trimmer) = if run_manager.paired_read_input() {
+        (
+            run_manager.r2_adapter_trimmer().as_ref(),
+            run_manager.r1_adapter_trimmer().as_ref(),
+        )
+    } else {
+        (run_manager.r1_adapter_trimmer().as_ref(), None)
+    };
+    // trimming stats are r1 then r2, the barcode read is r2 for paired-end input.
+    let barcode_read_trimming_index = if run_manager.paired_read_input() {
+        2
+    } else {
+        0
+    };
+    let mut barcode_read_trimmed: usize;
+    let mut paired_read_trimmed: usize;
+    let filter_reads = demultiplex && run_manager.filter_reads();
+    let min_insert_length = run_manager.min_insert_length();
+    let min_mean_quality = run_manager.min_mean_quality();
+    let max_n_bases = run_manager.max_n_bases();
+    let cycle_stats = run_manager.cycle_stats() && reporting_level > 1;
+    let flexible = run_manager.flexible();
+    let validate_pairs = run_manager.validate_pairs() && run_manager.paired_read_input();
+    let filtered_reads_output = run_manager.filtered_reads_output().as_str();
+    let mut filtered_read: bool;
+    let downsampler = run_manager.downsampler().as_ref();
+    let nested_index_lengths = demultiplex
+        && all_template_data
+            .iter()
+            .map(|template_details| template_details.6[1] + template_details.6[4])
+            .unique()
+            .count()
+            > 1;
     //let mut curr_template;
     let mut header_start = 0;
     let mut sample_id;
@@ -903,11 +1392,19 @@ fn process_buffer(
     let mut qual_start_pr: usize = 0;
 
     let mut curr_writing_sample: usize;
-    let undertmined_threshold_check = 5000;
     let mut reached_an_end = false;
     let mut i7_rc;
     let mut read_cntr: u64 = 0;
     let check_content = run_manager.check_content();
+    let quality_offset = run_manager.quality_offset();
+    let quality_range = (quality_offset, quality_offset + run_manager.max_quality());
+    // The legacy Q30 yield is always counted first, followed by the extra thresholds.
+    let quality_thresholds: Vec<u8> = [LEGACY_QUALITY_THRESHOLD]
+        .iter()
+        .chain(extra_quality_thresholds(run_manager.yield_thresholds()).iter())
+        .map(|threshold| quality_offset + threshold)
+        .collect();
+    let mut high_qc = vec![0_u64; quality_thresholds.len()];
 
     //let mut lines_rb: Memchr = memchr_iter(b'\n', &buffer_2);
     //let mut lines_rp: Memchr = memchr_iter(b'\n', &buffer_1);
@@ -915,6 +1412,25 @@ fn process_buffer(
 
     let illumina_format = run_manager.illumina_format();
     let comprehensive_scan = run_manager.comprehensive_scan();
+    let posterior_model = if demultiplex && run_manager.posterior_threshold() > 0.0 {
+        Some(PosteriorModel::new(
+            run_manager.posterior_threshold(),
+            run_manager.unknown_prior(),
+            quality_offset,
+        ))
+    } else {
+        None
+    };
+    let posterior_matching = posterior_model
+        .as_ref()
+        .map(|posterior_model| PosteriorMatching {
+            all_template_data,
+            barcode_lookup,
+            allowed_mismatches,
+            all_index_allowed_mismatches,
+            total_samples,
+            posterior_model,
+        });
     let mut lines_rp = lines_rp.into_iter();
     let mut lines_rb = lines_rb.into_iter();
     let mut sep_position: usize;
@@ -1003,48 +1519,64 @@ fn process_buffer(
         } else {
             i7_rc = false;
 
+            if validate_pairs {
+                let read_name = get_read_name(&buffer_2[header_start..seq_start - 1]);
+                let paired_read_name = get_read_name(&buffer_1[header_start_pr..seq_start_pr - 1]);
+                if read_name != paired_read_name {
+                    panic!(
+                        "Read names do not match at record {}: {} - {}! The paired read files must contain the paired reads in the same order.",
+                        first_record + read_cntr as usize + 1,
+                        String::from_utf8_lossy(paired_read_name),
+                        String::from_utf8_lossy(read_name)
+                    );
+                }
+            }
+
             if demultiplex {
+                if flexible && plus_start - seq_start - 1 < barcode_length {
+                    panic!(
+                        "The read {} is shorter than the barcode length ({})!",
+                        String::from_utf8_lossy(&buffer_2[header_start..seq_start - 1]),
+                        barcode_length
+                    );
+                }
                 sep_position = seq_start - header_start - 3;
-                (sample_id, curr_mismatch, curr_barcode, curr_umi) = find_matching_sample(
-                    &all_template_data,
-                    &mismatches_dic_i7,
-                    &mismatches_dic_i5,
-                    &buffer_2[plus_start - 1 - barcode_length..plus_start - 1],
-                    allowed_mismatches,
-                    all_index_allowed_mismatches,
-                    total_samples,
-                    comprehensive_scan,
-                    i7_rc,
-                );
+                match &posterior_matching {
+                    Some(posterior_matching) => {
+                        let posterior;
+                        (sample_id, curr_mismatch, curr_barcode, curr_umi, posterior) =
+                            find_matching_sample_posterior(
+                                posterior_matching,
+                                &buffer_2[plus_start - 1 - barcode_length..plus_start - 1],
+                                &buffer_2[read_end - barcode_length..read_end],
+                                i7_rc,
+                                posterior_buffers,
+                            );
+                        if sample_id < undetermined_label_id {
+                            // The posterior is written as a tag after the barcode in the header.
+                            write!(curr_barcode, " XP:f:{:.4}", posterior).unwrap();
+                        }
+                    }
+                    None => {
+                        (sample_id, curr_mismatch, curr_barcode, curr_umi) = find_matching_sample(
+                            all_template_data,
+                            barcode_lookup,
+                            &buffer_2[plus_start - 1 - barcode_length..plus_start - 1],
+                            allowed_mismatches,
+                            all_index_allowed_mismatches,
+                            total_samples,
+                            comprehensive_scan,
+                            i7_rc,
+                            nested_index_lengths,
+                        );
+                    }
+                }
                 tail_offset = curr_barcode.len() + 6;
                 if sample_id >= total_samples {
                     sample_id = undetermined_label_id;
                 }
 
                 if sample_id == undetermined_label_id || sample_id == ambiguous_label_id {
-                    if warning_ls[0]
-                        && read_cntr > undertmined_threshold_check
-                        && report_manager.get_sample_reads(sample_id)
-                            > ((0.75 * (read_cntr as f64)) as u64)
-                    {
-                        if run_manager.ignore_undetermined() {
-                            warn!(
-                                "{}\nAll reads: {}, Undetermined reads: {}",
-                                "Seems that there is an issue with the input. Most of the reads are undetermined!",
-                                read_cntr,
-                                report_manager.get_sample_reads(sample_id)
-                            );
-                            warning_ls[0] = false;
-                        } else {
-                            panic!(
-                                "{}\nAll reads: {}, Undetermined reads: {}",
-                                "Seems that there is an issue with the input. Most of the reads are undetermined!",
-                                read_cntr,
-                                report_manager.get_sample_reads(sample_id)
-                            );
-                        }
-                    }
-
                     if all_template_data.len() == 1 {
                         let indexes_info = all_template_data[0].6;
                         curr_barcode = unsafe {
@@ -1158,6 +1690,7 @@ fn process_buffer(
                         seq_start_pr - header_start_pr,
                         plus_start_pr - header_start_pr,
                         qual_start_pr - header_start_pr,
+                        quality_range,
                     );
 
                     if run_manager.mgi_data()
@@ -1182,49 +1715,212 @@ fn process_buffer(
                     seq_start - header_start,
                     plus_start - header_start,
                     qual_start - header_start,
+                    quality_range,
                 );
             }
 
-            if reporting_level > 0 {
+            // Filtered reads are only counted in the filtering columns, not in the sample yields.
+            filtered_read = false;
+            if sample_id < undetermined_label_id {
+                barcode_read_trimmed = match barcode_read_trimmer {
+                    Some(adapter_trimmer) => {
+                        let insert_end = plus_start - 1 - barcode_length;
+                        insert_end
+                            - seq_start
+                            - adapter_trimmer.find_adapter(&buffer_2[seq_start..insert_end])
+                    }
+                    None => 0,
+                };
+                paired_read_trimmed = match paired_read_trimmer {
+                    Some(adapter_trimmer) => {
+                        plus_start_pr
+                            - 1
+                            - seq_start_pr
+                            - adapter_trimmer
+                                .find_adapter(&buffer_1[seq_start_pr..plus_start_pr - 1])
+                    }
+                    None => 0,
+                };
+                if filter_reads {
+                    filtered_read = true;
+                    let mut insert_quality: &[u8] = &[];
+                    let mut insert_sequence: &[u8] = &[];
+                    let mut paired_insert_quality: &[u8] = &[];
+                    let mut paired_insert_sequence: &[u8] = &[];
+                    if run_manager.read2_has_sequence() {
+                        insert_sequence = &buffer_2
+                            [seq_start..plus_start - 1 - barcode_length - barcode_read_trimmed];
+                        insert_quality =
+                            &buffer_2[qual_start..read_end - barcode_length - barcode_read_trimmed];
+                    }
+                    if run_manager.paired_read_input() {
+                        paired_insert_sequence =
+                            &buffer_1[seq_start_pr..plus_start_pr - 1 - paired_read_trimmed];
+                        paired_insert_quality =
+                            &buffer_1[qual_start_pr..read_end_pr - paired_read_trimmed];
+                    }
+                    if min_insert_length > 0
+                        && ((run_manager.read2_has_sequence()
+                            && insert_sequence.len() < min_insert_length)
+                            || (run_manager.paired_read_input()
+                                && paired_insert_sequence.len() < min_insert_length))
+                    {
+                        report_manager.update_filtering(sample_id, 0, 1);
+                    } else if min_mean_quality > 0
+                        && (mean_quality(insert_quality, quality_offset) < min_mean_quality
+                            || mean_quality(paired_insert_quality, quality_offset)
+                                < min_mean_quality)
+                    {
+                        report_manager.update_filtering(sample_id, 1, 1);
+                    } else if max_n_bases.is_some_and(|max_n_bases| {
+                        memchr_iter(b'N', insert_sequence).count() > max_n_bases
+                            || memchr_iter(b'N', paired_insert_sequence).count() > max_n_bases
+                    }) {
+                        report_manager.update_filtering(sample_id, 2, 1);
+                    } else {
+                        filtered_read = false;
+                    }
+                }
+                if !filtered_read && barcode_read_trimmed > 0 {
+                    report_manager.update_trimming(sample_id, barcode_read_trimming_index, 1);
+                    report_manager.update_trimming(
+                        sample_id,
+                        barcode_read_trimming_index + 1,
+                        barcode_read_trimmed as u64,
+                    );
+                }
+                if !filtered_read && paired_read_trimmed > 0 {
+                    report_manager.update_trimming(sample_id, 0, 1);
+                    report_manager.update_trimming(sample_id, 1, paired_read_trimmed as u64);
+                }
+            } else {
+                barcode_read_trimmed = 0;
+                paired_read_trimmed = 0;
+            }
+
+            // The statistics are calculated on the reads as they are written, after adapter trimming.
+            if reporting_level > 0 && !filtered_read {
                 if run_manager.paired_read_input() {
                     // this is for r1 only if paired end
-                    let (qc_total, high_qc) =
-                        sum_qc(&buffer_1[qual_start_pr..read_end_pr], check_content);
-                    report_manager.update_stats(sample_id, 0, high_qc);
+                    let qc_total = sum_qc(
+                        &buffer_1[qual_start_pr..read_end_pr - paired_read_trimmed],
+                        check_content,
+                        quality_range,
+                        &quality_thresholds,
+                        &mut high_qc,
+                    );
+                    report_manager.update_stats(sample_id, 0, high_qc[0]);
+                    report_manager.update_stats(
+                        sample_id,
+                        3,
+                        (read_end_pr - paired_read_trimmed - qual_start_pr) as u64,
+                    );
                     report_manager.update_stats(sample_id, 6, qc_total);
+                    report_manager.update_quality_yields(sample_id, 0, &high_qc[1..]);
                 }
 
-                let (qc_total, high_qc) = sum_qc(
+                let qc_total = sum_qc(
                     &buffer_2[read_end - barcode_length..read_end],
                     check_content,
+                    quality_range,
+                    &quality_thresholds,
+                    &mut high_qc,
                 );
-                report_manager.update_stats(sample_id, 2, high_qc);
+                report_manager.update_stats(sample_id, 2, high_qc[0]);
+                report_manager.update_stats(sample_id, 5, barcode_length as u64);
                 report_manager.update_stats(sample_id, 8, qc_total);
+                report_manager.update_quality_yields(sample_id, 2, &high_qc[1..]);
 
-                let (qc_total, high_qc) = sum_qc(
-                    &buffer_2[qual_start..read_end - barcode_length],
+                let qc_total = sum_qc(
+                    &buffer_2[qual_start..read_end - barcode_length - barcode_read_trimmed],
                     check_content,
+                    quality_range,
+                    &quality_thresholds,
+                    &mut high_qc,
+                );
+                report_manager.update_stats(sample_id, shift, high_qc[0]);
+                report_manager.update_stats(
+                    sample_id,
+                    3 + shift,
+                    (read_end - barcode_length - barcode_read_trimmed - qual_start) as u64,
                 );
-                report_manager.update_stats(sample_id, shift, high_qc);
                 report_manager.update_stats(sample_id, 6 + shift, qc_total);
+                report_manager.update_quality_yields(sample_id, shift, &high_qc[1..]);
             }
-            //report_manager.update_mismatches(sample_id, 0, 1);
-            report_manager.update_mismatches(sample_id, curr_mismatch + 1, 1_u64);
 
-            curr_writing_sample = writing_samples[sample_id];
-            // writing preperation
-            // this works for mgi format and unde and ambig and ilumina with a bit of extr
-            match samples_reads.get_mut(curr_writing_sample) {
-                Some(curr_sample) => {
-                    curr_sample.compress_and_write(false, &samples_locks[curr_writing_sample]);
-                }
-                None => {}
+            if cycle_stats && !filtered_read {
+                if run_manager.paired_read_input() {
+                    report_manager.update_cycle_statistics(
+                        sample_id,
+                        0,
+                        &buffer_1[seq_start_pr..plus_start_pr - 1 - paired_read_trimmed],
+                        &buffer_1[qual_start_pr..read_end_pr - paired_read_trimmed],
+                    );
+                }
+                report_manager.update_cycle_statistics(
+                    sample_id,
+                    shift,
+                    &buffer_2[seq_start..plus_start - 1 - barcode_length - barcode_read_trimmed],
+                    &buffer_2[qual_start..read_end - barcode_length - barcode_read_trimmed],
+                );
+                report_manager.update_cycle_statistics(
+                    sample_id,
+                    2,
+                    &buffer_2[plus_start - 1 - barcode_length..plus_start - 1],
+                    &buffer_2[read_end - barcode_length..read_end],
+                );
+            }
+            //report_manager.update_mismatches(sample_id, 0, 1);
+            if !filtered_read {
+                report_manager.update_mismatches(sample_id, curr_mismatch + 1, 1_u64);
+            }
+
+            curr_writing_sample = if !filtered_read {
+                writing_samples[sample_id]
+            } else if filtered_reads_output == "sample" {
+                total_samples + writing_samples[sample_id]
+            } else if filtered_reads_output == "global" {
+                total_samples
+            } else {
+                // filtered reads are dropped
+                if run_manager.paired_read_input() {
+                    header_start_pr = read_end_pr + 1;
+                }
+                header_start = read_end + 1;
+                read_cntr += 1;
+                continue;
+            };
+            if downsampler.is_some_and(|downsampler| {
+                !downsampler.keep_read(
+                    get_read_name(&buffer_2[header_start..seq_start - 1]),
+                    curr_writing_sample,
+                )
+            }) {
+                // the read is counted in the reports but not written
+                if run_manager.paired_read_input() {
+                    header_start_pr = read_end_pr + 1;
+                }
+                header_start = read_end + 1;
+                read_cntr += 1;
+                continue;
+            }
+            // writing preperation
+            // this works for mgi format and unde and ambig and ilumina with a bit of extr
+            match samples_reads.get_mut(curr_writing_sample) {
+                Some(curr_sample) => {
+                    curr_sample.send_reads(false, curr_writing_sample, output_writers);
+                }
+                None => {}
             }
 
             if demultiplex || illumina_format {
                 if sample_id >= undetermined_label_id {
                     match samples_reads.get_mut(sample_id) {
                         Some(curr_sample) => {
+                            curr_sample.reserve_reads(
+                                read_end + 1 - header_start,
+                                read_end_pr + 1 - header_start_pr,
+                            );
                             curr_sample.add_barcode_reads(&buffer_2[header_start..read_end + 1]);
                             if run_manager.paired_read_input() {
                                 curr_sample
@@ -1237,6 +1933,13 @@ fn process_buffer(
                     match samples_reads.get_mut(curr_writing_sample) {
                         Some(curr_sample) => {
                             //curr_sample.add_barcode_reads(&buffer_2[header_start..read_end + 1]);
+                            // room for the read plus the rewritten header, umi and barcode.
+                            let header_extra =
+                                seq_start - header_start + curr_umi.len() + curr_barcode.len() + 32;
+                            curr_sample.reserve_reads(
+                                read_end + 1 - header_start + header_extra,
+                                read_end_pr + 1 - header_start_pr + header_extra,
+                            );
                             if illumina_format {
                                 // Illumina format write the heder and skip the header for mgi.
                                 barcode_read_illumina_header_start =
@@ -1318,16 +2021,47 @@ fn process_buffer(
                                 }
                             }
 
-                            curr_sample.add_barcode_reads(
-                                &buffer_2[header_start..plus_start - writen_barcode_length - 1],
-                            );
-                            curr_sample.add_barcode_reads(
-                                &buffer_2[plus_start - 1..read_end - writen_barcode_length],
-                            );
+                            if barcode_read_trimmed > 0 {
+                                // the adapter is trimmed from the insert, the barcode is kept if requested.
+                                curr_sample.add_barcode_reads(
+                                    &buffer_2[header_start
+                                        ..plus_start - barcode_length - barcode_read_trimmed - 1],
+                                );
+                                curr_sample.add_barcode_reads(
+                                    &buffer_2[plus_start - barcode_length - 1
+                                        ..plus_start - writen_barcode_length - 1],
+                                );
+                                curr_sample.add_barcode_reads(
+                                    &buffer_2[plus_start - 1
+                                        ..read_end - barcode_length - barcode_read_trimmed],
+                                );
+                                curr_sample.add_barcode_reads(
+                                    &buffer_2
+                                        [read_end - barcode_length..read_end - writen_barcode_length],
+                                );
+                            } else {
+                                curr_sample.add_barcode_reads(
+                                    &buffer_2[header_start..plus_start - writen_barcode_length - 1],
+                                );
+                                curr_sample.add_barcode_reads(
+                                    &buffer_2[plus_start - 1..read_end - writen_barcode_length],
+                                );
+                            }
                             curr_sample.add_barcode_reads(&[b'\n']);
                             if run_manager.paired_read_input() {
-                                curr_sample
-                                    .add_paired_reads(&buffer_1[header_start_pr..read_end_pr + 1]);
+                                if paired_read_trimmed > 0 {
+                                    curr_sample.add_paired_reads(
+                                        &buffer_1
+                                            [header_start_pr..plus_start_pr - paired_read_trimmed - 1],
+                                    );
+                                    curr_sample.add_paired_reads(
+                                        &buffer_1[plus_start_pr - 1..read_end_pr - paired_read_trimmed],
+                                    );
+                                    curr_sample.add_paired_reads(b"\n");
+                                } else {
+                                    curr_sample
+                                        .add_paired_reads(&buffer_1[header_start_pr..read_end_pr + 1]);
+                                }
                             }
                         }
                         None => {
@@ -1344,7 +2078,81 @@ fn process_buffer(
             read_cntr += 1;
         }
     }
-    return (header_start, header_start_pr);
+    (header_start, header_start_pr, read_cntr as usize)
+}
+
+fn drain_reads_batches(
+    reader_handler: &JoinHandle<()>,
+    full_receiver: &Receiver<PairedReadsBatch>,
+    full_receiver_rb: &Receiver<(usize, Vec<u8>, Vec<usize>)>,
+    full_receiver_rp: &Receiver<(usize, Vec<u8>, Vec<usize>)>,
+    empty_sender_rb: &Sender<(usize, Vec<u8>)>,
+    empty_sender_rp: &Sender<(usize, Vec<u8>)>,
+) {
+    /*
+    Returns the buffers of the batches that were read but not processed to the readers of an
+    aborted run, so they are not blocked waiting for an empty buffer or a free slot in the channel.
+    The finish signals have no buffer and are dropped.
+     */
+    let return_buffer = |empty_sender: &Sender<(usize, Vec<u8>)>, buffer: Vec<u8>| {
+        if !buffer.is_empty() {
+            let _ = empty_sender.try_send((0, buffer));
+        }
+    };
+    while !reader_handler.is_finished() {
+        if let Ok((_, buffer_rb, _, _, buffer_rp, _, _)) =
+            full_receiver.recv_timeout(Duration::from_millis(10))
+        {
+            return_buffer(empty_sender_rb, buffer_rb);
+            return_buffer(empty_sender_rp, buffer_rp);
+        }
+        while let Ok((_, buffer_rb, _)) = full_receiver_rb.try_recv() {
+            return_buffer(empty_sender_rb, buffer_rb);
+        }
+        while let Ok((_, buffer_rp, _)) = full_receiver_rp.try_recv() {
+            return_buffer(empty_sender_rp, buffer_rp);
+        }
+    }
+}
+
+fn check_unassigned_reads(run_manager: &RunManager) {
+    /*
+    Checks once, when the run has processed abort_check_reads reads, the fraction of the undetermined
+    and ambiguous reads. The run is aborted when it is above abort_unassigned_fraction, which is
+    mostly the result of a wrong sample sheet.
+    The processed reads are counted per batch, so the check is done at the end of the first batch
+    that reaches abort_check_reads, and the batches that are being processed in the other threads
+    are still completed before the run stops.
+     */
+    let progress = run_manager.progress();
+    let (reads, undetermined_reads, ambiguous_reads) = progress.processed_reads();
+    if run_manager.abort_check_reads() == 0
+        || reads < run_manager.abort_check_reads()
+        || !progress.start_unassigned_check()
+    {
+        return;
+    }
+    let unassigned_fraction = (undetermined_reads + ambiguous_reads) as f64 / reads as f64;
+    if unassigned_fraction <= run_manager.abort_unassigned_fraction() {
+        return;
+    }
+    if run_manager.ignore_undetermined() {
+        warn!(
+            "{}\nAll reads: {}, Undetermined reads: {}, Ambiguous reads: {}",
+            "Seems that there is an issue with the input. Most of the reads are undetermined!",
+            reads,
+            undetermined_reads,
+            ambiguous_reads
+        );
+    } else {
+        error!(
+            "{:.2}% of the first {} reads are undetermined or ambiguous, which is above {:.2}%! The run is aborted.",
+            unassigned_fraction * 100.0,
+            reads,
+            run_manager.abort_unassigned_fraction() * 100.0
+        );
+        progress.abort_run();
+    }
 }
 
 fn analyse_fastq(
@@ -1360,15 +2168,16 @@ fn analyse_fastq(
         bool,
         [usize; 10],
     )>,
+    barcode_lookup: Arc<BarcodeLookup>,
     reporting_level: usize,
     allowed_mismatches: usize,
     all_index_error: bool,
     buffer_size: usize,
-    full_receiver: Receiver<(usize, Vec<u8>, Vec<usize>, usize, Vec<u8>, Vec<usize>)>,
+    full_receiver: Receiver<PairedReadsBatch>,
     empty_sender_rb: Sender<(usize, Vec<u8>)>,
     empty_sender_rp: Sender<(usize, Vec<u8>)>,
     parallel_reader: bool,
-    samples_locks: Arc<Vec<Mutex<bool>>>,
+    output_writers: OutputWriters,
     demultiplex: bool,
     reformated_sample: &ReformatedSample,
     mut warnings_ls: Vec<bool>,
@@ -1404,28 +2213,36 @@ fn analyse_fastq(
             SampleData::minimal_sample(),
         ]
     };
-
     //let project_samples = get_project_samples(sample_information).unwrap();
-    let mut mismatches_dic_i7: Vec<HashMap<Vec<u8>, (Vec<&String>, usize)>> = Vec::new();
-    let mut mismatches_dic_i5: Vec<HashMap<Vec<u8>, (Vec<&String>, usize)>> = Vec::new();
-
-    for template_details in &all_template_data {
-        mismatches_dic_i7.push(get_all_mismatches(&template_details.1, allowed_mismatches));
-        mismatches_dic_i5.push(get_all_mismatches(&template_details.2, allowed_mismatches));
-    }
 
     let total_samples: usize = sample_manager.get_sample_count();
     let mut report_manager = ReportManager::new(total_samples, allowed_mismatches);
+    report_manager
+        .set_quality_thresholds(run_manager.quality_offset(), run_manager.yield_thresholds());
+    if run_manager.cycle_stats() && reporting_level > 1 {
+        report_manager.enable_cycle_statistics();
+    }
     let paired_input = run_manager.paired_read_input();
+    let mut posterior_buffers = PosteriorBuffers::default();
 
     let mut reader_barcode_read = if !parallel_reader {
-        Some(get_gzip_reader(run_manager.barcode_reads()))
+        Some(get_fastq_reader(
+            run_manager.barcode_reads(),
+            run_manager.flexible(),
+            Some(run_manager.progress().clone()),
+            Some(run_manager.input_checksums().clone()),
+        ))
     } else {
         None
     };
 
     let mut reader_paired_read = if !parallel_reader && paired_input {
-        let r1 = get_gzip_reader(run_manager.paired_reads());
+        let r1 = get_fastq_reader(
+            run_manager.paired_reads(),
+            run_manager.flexible(),
+            Some(run_manager.progress().clone()),
+            Some(run_manager.input_checksums().clone()),
+        );
         Some(r1)
     } else {
         None
@@ -1444,11 +2261,16 @@ fn analyse_fastq(
 
     let mut read_bytes_1: usize = 0;
     let mut read_bytes_2: usize = 0;
+    let mut minimum_read_bytes: usize = 10000;
 
     let mut header_start_pr: usize = 0;
     let mut header_start: usize = 0;
     let mut header_start_pr_tmp: usize;
     let mut header_start_tmp: usize;
+    let mut processed_records: usize = 0;
+    let mut batch_records: usize;
+    let mut undetermined_reads: u64;
+    let mut ambiguous_reads: u64;
     //debug!("{}, {}", run_manager.paired_reads().display(), run_manager.barcode_reads().display());
     //debug!("{}, {}  - {} - {}  - {}", buffer_1.len(), buffer_2.len(), minimum_read_bytes, paired_input, parallel_reader);
 
@@ -1461,10 +2283,31 @@ fn analyse_fastq(
                 header_start = 0;
                 header_start_pr = 0;
                 //let start = Instant::now();
-                let (read_bytes_2_tmp, buffer_2, lines_rb, read_bytes_1_tmp, buffer_1, lines_rp) =
-                    full_receiver.recv().unwrap();
+                let (
+                    read_bytes_2_tmp,
+                    buffer_2,
+                    lines_rb,
+                    read_bytes_1_tmp,
+                    buffer_1,
+                    lines_rp,
+                    first_record,
+                ) = full_receiver.recv().unwrap();
                 read_bytes_2 = read_bytes_2_tmp;
                 read_bytes_1 = read_bytes_1_tmp;
+                processed_records = first_record;
+                if run_manager.validate_pairs()
+                    && paired_input
+                    && lines_rb.len() / 4 != lines_rp.len() / 4
+                {
+                    panic!(
+                        "The input files have different number of reads in the batch starting at record {}! {} has {} reads and {} has {} reads in this batch.",
+                        first_record + 1,
+                        run_manager.barcode_reads().display(),
+                        lines_rb.len() / 4,
+                        run_manager.paired_reads().display(),
+                        lines_rp.len() / 4
+                    );
+                }
                 //debug!("Barcode read: Received {} bytes", read_bytes_2);
                 //debug!("Paired read: Received {} bytes", read_bytes_1);
                 //reading_time += start.elapsed();
@@ -1472,11 +2315,19 @@ fn analyse_fastq(
             }
             false => {
                 //let start = Instant::now();
-                read_bytes_2 =
-                    read_buffers(read_bytes_2, &mut main_buffer_2, &mut reader_barcode_read);
+                read_bytes_2 = read_buffers(
+                    read_bytes_2,
+                    &mut main_buffer_2,
+                    &mut reader_barcode_read,
+                    minimum_read_bytes,
+                );
                 if paired_input {
-                    read_bytes_1 =
-                        read_buffers(read_bytes_1, &mut main_buffer_1, &mut reader_paired_read);
+                    read_bytes_1 = read_buffers(
+                        read_bytes_1,
+                        &mut main_buffer_1,
+                        &mut reader_paired_read,
+                        minimum_read_bytes,
+                    );
                 }
                 //reading_time += start.elapsed();
                 (Vec::new(), Vec::new(), Vec::new(), Vec::new())
@@ -1490,10 +2341,28 @@ fn analyse_fastq(
         } else if (read_bytes_2 == 0 && read_bytes_1 != 0)
             || (read_bytes_2 != 0 && read_bytes_1 == 0 && run_manager.paired_read_input())
         {
+            if run_manager.validate_pairs() {
+                panic!(
+                    "{} ends before {} after {} records! One of the input files seems to be truncated.",
+                    if read_bytes_2 == 0 {
+                        run_manager.barcode_reads().display()
+                    } else {
+                        run_manager.paired_reads().display()
+                    },
+                    if read_bytes_2 == 0 {
+                        run_manager.paired_reads().display()
+                    } else {
+                        run_manager.barcode_reads().display()
+                    },
+                    processed_records
+                );
+            }
             panic!("Something wrong in the input files!");
         }
 
-        (header_start_tmp, header_start_pr_tmp) = process_buffer(
+        undetermined_reads = report_manager.get_sample_reads(total_samples - 2);
+        ambiguous_reads = report_manager.get_sample_reads(total_samples - 1);
+        (header_start_tmp, header_start_pr_tmp, batch_records) = process_buffer(
             run_manager,
             buffer_info,
             &mut report_manager,
@@ -1517,9 +2386,8 @@ fn analyse_fastq(
             } else {
                 &main_buffer_2[header_start..read_bytes_2]
             },
-            &mismatches_dic_i7,
-            &mismatches_dic_i5,
-            &samples_locks,
+            &barcode_lookup,
+            &output_writers,
             if parallel_reader {
                 lines_rb
             } else {
@@ -1533,7 +2401,22 @@ fn analyse_fastq(
             demultiplex,
             reformated_sample,
             &mut warnings_ls,
+            &mut posterior_buffers,
+            processed_records,
         );
+        processed_records += batch_records;
+        // The reads of the batch are handed over, so the thread does not keep buffers per sample.
+        for (sample_index, sample) in samples_reads.iter_mut().enumerate() {
+            sample.send_reads(true, sample_index, &output_writers);
+        }
+        if demultiplex {
+            run_manager.progress().add_processed_reads(
+                batch_records,
+                report_manager.get_sample_reads(total_samples - 2) - undetermined_reads,
+                report_manager.get_sample_reads(total_samples - 1) - ambiguous_reads,
+            );
+            check_unassigned_reads(run_manager);
+        }
 
         //read_leftover_leng_rp
         //debug!("Z1 - 1- {}  -  {}", header_start_pr_tmp, header_start_tmp);
@@ -1556,9 +2439,19 @@ fn analyse_fastq(
                 }
             }
         } else {
+            if header_start_tmp == 0 && run_manager.flexible() {
+                // No complete read in the buffer, the reads are longer than the sampled ones.
+                minimum_read_bytes *= 2;
+                if 2 * minimum_read_bytes > main_buffer_2.len() {
+                    main_buffer_2.resize(2 * minimum_read_bytes, 0);
+                    if paired_input {
+                        main_buffer_1.resize(2 * minimum_read_bytes, 0);
+                    }
+                }
+            }
             header_start += header_start_tmp;
             header_start_pr += header_start_pr_tmp;
-            if read_bytes_2 - header_start < 10000 {
+            if read_bytes_2 - header_start < minimum_read_bytes {
                 //info!("ZB0: {} - {} - *{}*", header_start, read_bytes_2 - header_start, String::from_utf8(buffer_2[header_start..read_bytes_2].to_vec()).unwrap());
                 copy_within_a_slice(
                     &mut main_buffer_2,
@@ -1574,7 +2467,7 @@ fn analyse_fastq(
             }
 
             if paired_input {
-                if read_bytes_1 - header_start_pr < 10000 {
+                if read_bytes_1 - header_start_pr < minimum_read_bytes {
                     copy_within_a_slice(
                         &mut main_buffer_1,
                         header_start_pr,
@@ -1587,23 +2480,153 @@ fn analyse_fastq(
             }
         }
         //debug!("Z1 - 3- {}  -  {}  -  {}  -  {}", read_bytes_1, header_start_pr, read_bytes_2, header_start);
+        if demultiplex && run_manager.progress().run_aborted() {
+            info!(
+                "Thread ({}) has stopped as the run is aborted.",
+                curr_thread
+            );
+            break;
+        }
     }
 
     //debug!("Spent {:?} for reading", reading_time);
     //debug!("Spent {:?} for evertyihng else", start_full.elapsed() - reading_time);
 
-    for curr_writing_sample in 0..total_samples {
-        if let Some(curr_sample) = samples_reads.get_mut(curr_writing_sample) {
-            curr_sample.compress_and_write(true, &samples_locks[curr_writing_sample]);
-        };
-    }
-
     // should update run reports on here
     report_manager.set_sample_total_reads();
     report_manager
 }
 
-pub fn initiate_demultiplexing(demultiplex_command: &ArgMatches) {
+fn report_dry_run(
+    sample_manager: &SampleManager,
+    run_manager: &RunManager,
+    buffer_info: &BufferInfo,
+    available_memory: f64,
+    threads: (usize, usize, usize),
+) -> bool {
+    /*
+    Reports what the demultiplexing run would do without processing any read: the templates, the
+    threads, the memory budget and the output files, with the ones that already exist.
+    The same information is printed as JSON at the end for job schedulers.
+    Returns false when the run would stop with an error.
+     */
+    let (reader_threads, processing_threads, writer_threads) = threads;
+    let total_samples = sample_manager.get_sample_count();
+    let input_size = fs::metadata(run_manager.barcode_reads()).unwrap().len()
+        + if run_manager.paired_read_input() {
+            fs::metadata(run_manager.paired_reads()).unwrap().len()
+        } else {
+            0
+        };
+    let required_memory = calculate_reqiured_memory(
+        total_samples,
+        buffer_info.writing_threshold(),
+        buffer_info.compression_buffer_size(),
+        !run_manager.paired_read_input(),
+    );
+    let output_files = list_output_files(sample_manager, run_manager, buffer_info);
+    let existing_files: Vec<&PathBuf> = output_files.iter().filter(|path| path.exists()).collect();
+
+    // The same checks as when the output and report directories are prepared.
+    let mut errors = Vec::new();
+    if !run_manager.force() {
+        if run_manager.output_dir().is_dir() {
+            errors.push(format!(
+                "Output directory exists, use --force to overwrite its data: {}",
+                run_manager.output_dir().display()
+            ));
+        }
+        if run_manager.report_dir() != run_manager.output_dir() && run_manager.report_dir().is_dir()
+        {
+            errors.push(format!(
+                "Report directory exists, use --force to overwrite its data: {}",
+                run_manager.report_dir().display()
+            ));
+        }
+    }
+
+    info!("Dry run, no reads will be processed.");
+    info!("Input size: {} bytes.", input_size);
+    let mut templates_json = Vec::new();
+    for template_details in sample_manager.all_template_data() {
+        info!(
+            "Template {} is used for {} samples.",
+            template_details.3, template_details.0
+        );
+        templates_json.push(serde_json::json!({
+            "template": template_details.3,
+            "samples": template_details.0,
+            "i7_length": template_details.6[1],
+            "i5_length": template_details.6[4],
+            "umi_length": template_details.6[7],
+            "dual_index": template_details.5,
+        }));
+    }
+    info!(
+        "Threads: {} reader, {} processing and {} writer threads.",
+        reader_threads, processing_threads, writer_threads
+    );
+    info!(
+        "Memory: {:.0} bytes are required for the buffers of {} samples, {:.0} bytes are available.",
+        required_memory, total_samples, available_memory
+    );
+    info!(
+        "{} output files would be created in {}, {} of them exist already.",
+        output_files.len(),
+        run_manager.output_dir().display(),
+        existing_files.len()
+    );
+    for path in &existing_files {
+        warn!(
+            "The output file exists and would be overwritten: {}",
+            path.display()
+        );
+    }
+    for error in &errors {
+        error!("{}", error);
+    }
+
+    let dry_run_json = serde_json::json!({
+        "input": {
+            "barcode_reads": run_manager.barcode_reads(),
+            "paired_reads": if run_manager.paired_read_input() {
+                Some(run_manager.paired_reads())
+            } else {
+                None
+            },
+            "input_size": input_size,
+            "barcode_read_length": run_manager.barcode_read_info().read_length(),
+            "paired_read_length": run_manager.paired_read_info().read_length(),
+        },
+        "flowcell": run_manager.flowcell(),
+        "lane": run_manager.lane(),
+        "samples": total_samples - 2,
+        "templates": templates_json,
+        "threads": {
+            "reader": reader_threads,
+            "processing": processing_threads,
+            "writer": writer_threads,
+        },
+        "memory": {
+            "required": required_memory as u64,
+            "available": available_memory as u64,
+            "writing_buffer_size": buffer_info.writing_threshold(),
+            "compression_buffer_size": buffer_info.compression_buffer_size(),
+        },
+        "output_dir": run_manager.output_dir(),
+        "report_dir": run_manager.report_dir(),
+        "output_files": output_files,
+        "existing_files": existing_files,
+        "errors": errors,
+    });
+    println!("{}", serde_json::to_string_pretty(&dry_run_json).unwrap());
+    errors.is_empty()
+}
+
+pub fn initiate_demultiplexing(
+    demultiplex_command: &ArgMatches,
+    environment: Vec<(String, String)>,
+) {
     //let arg_input_folder_path: &String = demultiplex_command.get_one::<String>("arg_input_folder_path").unwrap();
     //let mut arg_read1_file_path: String = demultiplex_command.get_one::<String>("arg_read1_file_path").unwrap().to_string();
     //let mut arg_read2_file_path: String = demultiplex_command.get_one::<String>("arg_read2_file_path").unwrap().to_string();
@@ -1642,6 +2665,11 @@ pub fn initiate_demultiplexing(demultiplex_command: &ArgMatches) {
             .get_one::<String>("arg_ambiguous_label")
             .unwrap()
             .to_string(),
+        &load_index_kits(
+            demultiplex_command
+                .get_one::<String>("arg_index_kits")
+                .unwrap(),
+        ),
     );
 
     let mut run_manager = RunManager::new(
@@ -1709,7 +2737,60 @@ pub fn initiate_demultiplexing(demultiplex_command: &ArgMatches) {
         *demultiplex_command
             .get_one::<bool>("arg_mgi_full_header")
             .unwrap(),
+        *demultiplex_command.get_one::<bool>("arg_dry_run").unwrap(),
+    );
+    run_manager.set_flexible(*demultiplex_command.get_one::<bool>("arg_flexible").unwrap());
+    run_manager.set_validate_pairs(
+        *demultiplex_command
+            .get_one::<bool>("arg_validate_pairs")
+            .unwrap(),
+    );
+    let arg_quality_offset: u8 = *demultiplex_command
+        .get_one::<u8>("arg_quality_offset")
+        .unwrap();
+    if arg_quality_offset != 33 && arg_quality_offset != 64 {
+        panic!(
+            "Quality offset must be either 33 or 64! Found {}!",
+            arg_quality_offset
+        );
+    }
+    let arg_max_quality: u8 = *demultiplex_command
+        .get_one::<u8>("arg_max_quality")
+        .unwrap();
+    if arg_max_quality as usize + arg_quality_offset as usize > 126 {
+        panic!(
+            "Maximum quality {} can not be encoded with offset {}!",
+            arg_max_quality, arg_quality_offset
+        );
+    }
+    let arg_yield_thresholds: Vec<u8> = demultiplex_command
+        .get_one::<String>("arg_yield_thresholds")
+        .unwrap()
+        .split(',')
+        .map(|threshold| {
+            threshold.trim().parse::<u8>().unwrap_or_else(|_| {
+                panic!(
+                    "Yield thresholds must be comma separated integers! Found {}!",
+                    threshold
+                )
+            })
+        })
+        .collect();
+    for threshold in &arg_yield_thresholds {
+        if *threshold > arg_max_quality {
+            panic!(
+                "Yield threshold {} is higher than the maximum quality {}!",
+                threshold, arg_max_quality
+            );
+        }
+    }
+    info!(
+        "Quality offset: {}, maximum quality: {}, yield thresholds: {:?}",
+        arg_quality_offset, arg_max_quality, arg_yield_thresholds
     );
+    run_manager.set_quality_offset(arg_quality_offset);
+    run_manager.set_max_quality(arg_max_quality);
+    run_manager.set_yield_thresholds(arg_yield_thresholds);
     let (barcode_read_info, paired_read_info) = run_manager.get_read_information();
     if run_manager.lane().len() == 0 {
         info!("lane detected in the read header will be used for this run!");
@@ -1726,6 +2807,129 @@ pub fn initiate_demultiplexing(demultiplex_command: &ArgMatches) {
         run_manager.set_read2_has_sequence(false);
     }
 
+    let adapter_min_overlap = *demultiplex_command
+        .get_one::<usize>("arg_adapter_min_overlap")
+        .unwrap();
+    let adapter_error_rate = *demultiplex_command
+        .get_one::<f64>("arg_adapter_error_rate")
+        .unwrap();
+    for (arg_adapter, read_number) in [("arg_adapter_r1", 1), ("arg_adapter_r2", 2)] {
+        let adapter = demultiplex_command.get_one::<String>(arg_adapter).unwrap();
+        if adapter.is_empty() {
+            continue;
+        }
+        if read_number == 2 && !run_manager.paired_read_input() {
+            panic!("`--adapter-r2` can only be used with paired-end input!");
+        }
+        let adapter_trimmer = Some(AdapterTrimmer::new(
+            adapter,
+            adapter_min_overlap,
+            adapter_error_rate,
+        ));
+        if read_number == 1 {
+            run_manager.set_r1_adapter_trimmer(adapter_trimmer);
+        } else {
+            run_manager.set_r2_adapter_trimmer(adapter_trimmer);
+        }
+    }
+
+    run_manager.set_min_insert_length(
+        *demultiplex_command
+            .get_one::<usize>("arg_min_length")
+            .unwrap(),
+    );
+    run_manager.set_min_mean_quality(
+        *demultiplex_command
+            .get_one::<usize>("arg_min_mean_quality")
+            .unwrap(),
+    );
+    run_manager.set_max_n_bases(
+        demultiplex_command
+            .get_one::<usize>("arg_max_n_bases")
+            .copied(),
+    );
+    run_manager.set_filtered_reads_output(
+        demultiplex_command
+            .get_one::<String>("arg_filtered_output")
+            .unwrap()
+            .to_string(),
+    );
+    run_manager.set_filtered_label(
+        demultiplex_command
+            .get_one::<String>("arg_filtered_label")
+            .unwrap()
+            .to_string(),
+    );
+    if run_manager.filter_reads() {
+        info!(
+            "Filtered reads of the samples will be handled using the mode: {}",
+            run_manager.filtered_reads_output()
+        );
+    }
+    run_manager.set_posterior_threshold(
+        *demultiplex_command
+            .get_one::<f64>("arg_posterior_threshold")
+            .unwrap(),
+    );
+    run_manager.set_unknown_prior(
+        *demultiplex_command
+            .get_one::<f64>("arg_unknown_prior")
+            .unwrap(),
+    );
+    run_manager.set_environment(environment);
+    if *demultiplex_command
+        .get_one::<bool>("arg_provenance_checksums")
+        .unwrap()
+    {
+        run_manager.enable_input_checksums();
+    }
+    run_manager.set_abort_check_reads(
+        *demultiplex_command
+            .get_one::<u64>("arg_abort_check_reads")
+            .unwrap(),
+    );
+    run_manager.set_abort_unassigned_fraction(
+        *demultiplex_command
+            .get_one::<f64>("arg_abort_unassigned_fraction")
+            .unwrap(),
+    );
+    run_manager.set_progress_interval(
+        *demultiplex_command
+            .get_one::<u64>("arg_progress_interval")
+            .unwrap(),
+    );
+    let downsample_fraction = *demultiplex_command
+        .get_one::<f64>("arg_downsample_fraction")
+        .unwrap();
+    let max_sample_reads = *demultiplex_command
+        .get_one::<u64>("arg_max_reads_per_sample")
+        .unwrap();
+    if downsample_fraction != 1.0 || max_sample_reads > 0 {
+        // Filtered reads can be written to extra outputs after the samples.
+        let total_samples = sample_manager.get_sample_count();
+        run_manager.set_downsampler(Some(Downsampler::new(
+            downsample_fraction,
+            *demultiplex_command
+                .get_one::<u64>("arg_downsample_seed")
+                .unwrap(),
+            max_sample_reads,
+            total_samples + run_manager.filtered_samples_count(total_samples),
+        )));
+    }
+    if run_manager.posterior_threshold() > 0.0 {
+        // Validates the parameters before reading any input.
+        PosteriorModel::new(
+            run_manager.posterior_threshold(),
+            run_manager.unknown_prior(),
+            run_manager.quality_offset(),
+        );
+        info!(
+            "Reads are assigned to samples with a posterior probability of at least {} (unknown prior: {}).",
+            run_manager.posterior_threshold(),
+            run_manager.unknown_prior()
+        );
+    }
+
     run_manager.confirm_format();
     let compression_buffer_size = *demultiplex_command
         .get_one::<usize>("arg_compression_buffer_size")
@@ -1756,8 +2960,15 @@ pub fn initiate_demultiplexing(demultiplex_command: &ArgMatches) {
         .unwrap();
 
     let mut tmp_processing_threads = *demultiplex_command
+        .get_one::<usize>("arg_threads_p")
+        .unwrap();
+
+    let requested_writer_threads = *demultiplex_command
         .get_one::<usize>("arg_threads_w")
         .unwrap();
+    if requested_writer_threads > 0 && tmp_reader_threads > 0 && tmp_processing_threads == 0 {
+        warn!("'--writer-threads' sets the output writer threads only, use '--processing-threads' to set the processing threads!");
+    }
 
     let (reader_threads, processing_threads) = if tmp_processing_threads > 0
         && tmp_reader_threads > 0
@@ -1790,13 +3001,26 @@ pub fn initiate_demultiplexing(demultiplex_command: &ArgMatches) {
             run_manager.paired_read_input(),
         )
     };
+    let writer_threads = get_writer_threads(requested_writer_threads, processing_threads);
+    append_resolved_config(
+        demultiplex_command,
+        &[
+            ("lane", run_manager.lane().clone()),
+            ("flowcell", run_manager.flowcell()),
+            ("instrument", run_manager.instrument().clone()),
+            ("run", run_manager.run().clone()),
+            ("reader-threads", reader_threads.to_string()),
+            ("processing-threads", processing_threads.to_string()),
+            ("writer-threads", writer_threads.to_string()),
+        ],
+    );
 
+    let available_memory = get_available_memory(arg_memory);
     let max_buffer_size = calculate_largest_buffer_size(
-        get_available_memory(arg_memory),
+        available_memory,
         sample_manager.get_sample_count(),
         buffer_info.compression_buffer_size(),
         !run_manager.paired_read_input(),
-        processing_threads,
     );
     buffer_info.calculate_final_writing_buffer_size(max_buffer_size);
 
@@ -1808,12 +3032,71 @@ pub fn initiate_demultiplexing(demultiplex_command: &ArgMatches) {
         .get_one::<usize>("arg_report_level")
         .unwrap();
     info!("Reporting level is: {}", arg_report_level);
+    run_manager.set_cycle_stats(
+        *demultiplex_command
+            .get_one::<bool>("arg_cycle_stats")
+            .unwrap(),
+    );
+    if run_manager.cycle_stats() && *arg_report_level < 2 {
+        warn!("Per cycle statistics are only reported with reporting level 2!");
+    }
+    run_manager.set_index_hopping(
+        *demultiplex_command
+            .get_one::<bool>("arg_index_hopping")
+            .unwrap(),
+    );
+    if run_manager.index_hopping() && *arg_report_level < 2 {
+        warn!("Index hopping is only reported with reporting level 2!");
+    }
     //let arg_compression_level: &u32 = demultiplex_command.get_one::<u32>("arg_compression_level").unwrap();
     //let arg_compression_buffer_size:  &usize = demultiplex_command.get_one::<usize>("arg_compression_buffer_size").unwrap();
     let arg_all_index_error: &bool = & ! demultiplex_command
         .get_one::<bool>("arg_per_index_error")
         .unwrap();
 
+    let cross_length_conflicts = sample_manager.check_cross_length_conflicts(
+        if *arg_all_index_error {
+            *arg_allowed_mismatches
+        } else {
+            *arg_allowed_mismatches * 2
+        },
+    );
+    if !cross_length_conflicts.is_empty() {
+        warn!(
+            "{} cross-length index conflicts were found! Reads are assigned to the sample with fewer mismatches and reads matching both samples equally well are ambiguous.",
+            cross_length_conflicts.len()
+        );
+        for conflict in &cross_length_conflicts {
+            warn!("{}", conflict);
+        }
+    }
+
+    run_manager.set_allowed_mismatches(*arg_allowed_mismatches);
+    run_manager.set_all_index_error(*arg_all_index_error);
+    run_manager.set_reader_threads(reader_threads);
+    run_manager.set_processing_threads(processing_threads);
+    run_manager.set_writer_threads(writer_threads);
+    run_manager.set_available_memory(available_memory as u64);
+    run_manager.set_required_memory(calculate_reqiured_memory(
+        sample_manager.get_sample_count(),
+        buffer_info.writing_threshold(),
+        buffer_info.compression_buffer_size(),
+        !run_manager.paired_read_input(),
+    ) as u64);
+
+    if *demultiplex_command.get_one::<bool>("arg_dry_run").unwrap() {
+        if !report_dry_run(
+            &sample_manager,
+            &run_manager,
+            &buffer_info,
+            available_memory,
+            (reader_threads, processing_threads, writer_threads),
+        ) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
     match demultiplex(
         &sample_manager,
         &run_manager,
@@ -1823,6 +3106,7 @@ pub fn initiate_demultiplexing(demultiplex_command: &ArgMatches) {
         *arg_all_index_error,
         reader_threads,
         processing_threads,
+        writer_threads,
     ) {
         Ok(mut report_manager) => {
             let max_mismatches = if *arg_all_index_error {
@@ -1830,18 +3114,8 @@ pub fn initiate_demultiplexing(demultiplex_command: &ArgMatches) {
             } else {
                 *arg_allowed_mismatches * 2 + 1
             };
-            let shift = if run_manager.paired_read_input() {
-                1
-            } else {
-                0
-            };
-            report_manager.prepare_final_data(
-                max_mismatches,
-                shift,
-                &barcode_read_info,
-                &paired_read_info,
-                barcode_length,
-            );
+            report_manager.set_report_filtering(run_manager.filter_reads());
+            report_manager.prepare_final_data(max_mismatches);
             report_manager.write_reports(
                 &run_manager,
                 &sample_manager,
@@ -1851,7 +3125,10 @@ pub fn initiate_demultiplexing(demultiplex_command: &ArgMatches) {
                 usize::MAX,
             );
         }
-        Err(err) => eprintln!("Error: {}", err),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
     };
 }
 
@@ -1877,7 +3154,8 @@ pub fn merge_qc_reports(
     let mut project_id: String;
     let mut sample_id: String;
     let mut vals: Vec<String>;
-    let mut report_manager = ReportManager::new(1000, 10);
+    // The samples are added as they are found in the reports.
+    let mut report_manager = ReportManager::new(0, 10);
     let mut sample_manager = SampleManager::default();
     let flowcell_id = if project.len() == 0 {
         Path::new(&qc_report_paths[0])
@@ -1892,12 +3170,15 @@ pub fn merge_qc_reports(
         project.to_string()
     };
     let mut run_manager = RunManager::default();
+    run_manager.set_start_time(Local::now().to_rfc3339());
     run_manager.set_flowcell(flowcell_id);
     run_manager.set_lane(used_lane);
     run_manager.set_report_dir(PathBuf::from(output_dir));
 
     let mut sample_index;
     let mut max_mismatches = 0;
+    let mut filtering_columns = 0;
+    let mut yield_thresholds: Option<Vec<u8>> = None;
 
     for qc_report_path in qc_report_paths {
         info!("Reading {} ...", qc_report_path);
@@ -1906,32 +3187,99 @@ pub fn merge_qc_reports(
 
         for line in lines {
             if line.starts_with("job_number\tsample_id") {
-                continue;
-            }
-            //println!("{}", line);
-            vals = line.split("\t").map(|x| x.to_string()).collect();
-            project_id = vals.remove(0);
-            sample_id = vals.remove(0);
-            sample_index = sample_manager.get_sample_index(&sample_id);
-            if sample_index == usize::MAX {
+                // reports of runs with read filtering have three extra columns,
+                // followed by three columns for each extra yield threshold.
+                let columns: Vec<&str> = line.split("\t").collect();
+                filtering_columns = if columns.contains(&"filtered_n") {
+                    3
+                } else {
+                    0
+                };
+                if filtering_columns > 0 {
+                    report_manager.set_report_filtering(true);
+                }
+                let mut report_thresholds = vec![LEGACY_QUALITY_THRESHOLD];
+                for column in columns.iter().skip(5) {
+                    if let Some(threshold) = column.strip_prefix("r1_qc_") {
+                        report_thresholds.push(threshold.parse::<u8>().unwrap());
+                    }
+                }
+                match &yield_thresholds {
+                    None => {
+                        report_manager.set_quality_thresholds(
+                            run_manager.quality_offset(),
+                            &report_thresholds,
+                        );
+                        yield_thresholds = Some(report_thresholds);
+                    }
+                    Some(yield_thresholds) => {
+                        if *yield_thresholds != report_thresholds {
+                            panic!(
+                                "{} has yield thresholds {:?} while the previous reports have {:?}!",
+                                qc_report_path, report_thresholds, yield_thresholds
+                            );
+                        }
+                    }
+                }
+                continue;
+            }
+            //println!("{}", line);
+            vals = line.split("\t").map(|x| x.to_string()).collect();
+            project_id = vals.remove(0);
+            sample_id = vals.remove(0);
+            sample_index = sample_manager.get_sample_index(&sample_id);
+            if sample_index == usize::MAX {
                 sample_manager.add_sample(&sample_id, &project_id);
-                sample_index = sample_manager.get_sample_count();
+                sample_index = sample_manager.get_sample_count() - 1;
                 report_manager.add_stats_entry();
                 report_manager.add_mismatches_entry(20);
             }
             sample_manager.add_project_sample(&project_id, sample_index);
+            let yield_columns = yield_thresholds
+                .as_ref()
+                .map_or(0, |thresholds| 3 * (thresholds.len() - 1));
+            let yields: Vec<u64> = vals
+                .split_off(vals.len() - yield_columns)
+                .iter()
+                .map(|val| val.parse::<u64>().unwrap())
+                .collect();
+            for read_index in 0..3 {
+                report_manager.update_quality_yields(
+                    sample_index,
+                    read_index,
+                    &yields
+                        .iter()
+                        .skip(read_index)
+                        .step_by(3)
+                        .copied()
+                        .collect::<Vec<u64>>(),
+                );
+            }
+            for i in (0..filtering_columns).rev() {
+                report_manager.update_filtering(
+                    sample_index,
+                    i,
+                    vals.pop().unwrap().parse::<u64>().unwrap(),
+                );
+            }
             if vals.len() - 10 > max_mismatches {
                 max_mismatches = vals.len() - 10;
             }
             for i in 0..10 {
                 report_manager.update_stats(sample_index, i, vals[i].parse::<u64>().unwrap());
             }
+            // all_reads followed by the read counts per mismatch.
             for i in 9..vals.len() {
-                report_manager.update_mismatches(sample_index, i, vals[i].parse::<u64>().unwrap());
+                report_manager.update_mismatches(
+                    sample_index,
+                    i - 9,
+                    vals[i].parse::<u64>().unwrap(),
+                );
             }
         }
     }
-    report_manager.truncate_mismatches(max_mismatches);
+    report_manager.truncate_mismatches(max_mismatches + 1);
+    report_manager.append_mismatches_to_statistics(max_mismatches);
 
     report_manager.write_reports(
         &run_manager,
@@ -1945,6 +3293,80 @@ pub fn merge_qc_reports(
     //for (sample_id, val) in map.iter_mut() {  }
 }
 
+pub fn validate_sheet(validate_command: &ArgMatches) -> bool {
+    let sample_sheet_path = validate_command
+        .get_one::<String>("arg_sample_sheet_file_path")
+        .unwrap();
+    let template = validate_command.get_one::<String>("arg_template").unwrap();
+    let i7_rc = *validate_command.get_one::<bool>("arg_i7_rc").unwrap();
+    let i5_rc = *validate_command.get_one::<bool>("arg_i5_rc").unwrap();
+    let allowed_mismatches = *validate_command
+        .get_one::<usize>("arg_allowed_mismatches")
+        .unwrap();
+    let all_index_error = !*validate_command
+        .get_one::<bool>("arg_per_index_error")
+        .unwrap();
+    let index_kits = load_index_kits(
+        validate_command
+            .get_one::<String>("arg_index_kits")
+            .unwrap(),
+    );
+
+    let mut validation = validate_sample_sheet(
+        Path::new(sample_sheet_path),
+        template,
+        i7_rc,
+        i5_rc,
+        &index_kits,
+        allowed_mismatches,
+        all_index_error,
+    );
+    if validation.errors.is_empty() {
+        // The sheet is also loaded as for demultiplexing to make sure it is accepted.
+        let sample_manager = SampleManager::new(
+            sample_sheet_path.to_string(),
+            template.to_string(),
+            i7_rc,
+            i5_rc,
+            String::from("Undetermined"),
+            String::from("Ambiguous"),
+            &index_kits,
+        );
+        for conflict in sample_manager.check_cross_length_conflicts(if all_index_error {
+            allowed_mismatches
+        } else {
+            allowed_mismatches * 2
+        }) {
+            validation.warnings.push((0, conflict));
+        }
+    }
+
+    let mut out_str = String::from("level\tline\tmessage\n");
+    for (line, message) in &validation.errors {
+        error!("{}: {}.", sheet_location(*line), message);
+        out_str.push_str(&format!("error\t{}\t{}\n", line, message));
+    }
+    for (line, message) in &validation.warnings {
+        warn!("{}: {}.", sheet_location(*line), message);
+        out_str.push_str(&format!("warning\t{}\t{}\n", line, message));
+    }
+    for distance in &validation.distances {
+        info!("{}", distance);
+    }
+    let output_path = validate_command
+        .get_one::<String>("arg_output_file")
+        .unwrap();
+    if !output_path.is_empty() {
+        write_file(output_path, &out_str);
+    }
+    info!(
+        "{} errors and {} warnings were found in the sample sheet.",
+        validation.errors.len(),
+        validation.warnings.len()
+    );
+    validation.errors.is_empty()
+}
+
 pub fn detect_template(template_command: &ArgMatches) {
     let start = Instant::now();
     let sample_manager = SampleManager::from_simple_sheet(
@@ -1952,6 +3374,11 @@ pub fn detect_template(template_command: &ArgMatches) {
             .get_one::<String>("arg_sample_sheet_file_path")
             .unwrap()
             .to_string(),
+        &load_index_kits(
+            template_command
+                .get_one::<String>("arg_index_kits")
+                .unwrap(),
+        ),
     );
 
     let path = Path::new(template_command.get_one::<String>("arg_ouput_dir").unwrap());
@@ -2002,6 +3429,7 @@ pub fn detect_template(template_command: &ArgMatches) {
         true,
         true,
         false,
+        false,
     );
 
     let input_barcode_length: usize = *template_command
@@ -2017,8 +3445,26 @@ pub fn detect_template(template_command: &ArgMatches) {
         .get_one::<bool>("arg_popular_template")
         .unwrap();
     let add_umi: bool = !template_command.get_one::<bool>("arg_no_umi").unwrap();
+    let sampling: &String = template_command.get_one::<String>("arg_sampling").unwrap();
+    let seed: u64 = *template_command.get_one::<u64>("arg_seed").unwrap();
+    let allowed_mismatches: usize = *template_command
+        .get_one::<usize>("arg_allowed_mismatches")
+        .unwrap();
+    let min_margin: f64 = *template_command.get_one::<f64>("arg_min_margin").unwrap();
+    let write_sample_sheet: bool = *template_command
+        .get_one::<bool>("arg_write_sample_sheet")
+        .unwrap();
 
     let (barcode_read_info, paired_read_info) = run_manager.get_read_information();
+    append_resolved_config(
+        template_command,
+        &[
+            ("lane", run_manager.lane().clone()),
+            ("flowcell", run_manager.flowcell()),
+            ("instrument", run_manager.instrument().clone()),
+            ("run", run_manager.run().clone()),
+        ],
+    );
     let sample_indexes = sample_manager.get_samples_indices().unwrap();
 
     let barcode_length: usize;
@@ -2047,50 +3493,45 @@ pub fn detect_template(template_command: &ArgMatches) {
 
     info!("Barcode length: {}", barcode_length);
 
-    let mut read_barcode_seq;
-    let mut read_bytes;
-
     let mut read_cntr: usize = 0;
     let mut matches_stat: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut exact_matches_stat: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut undetermined_barcodes: HashMap<&String, usize> = HashMap::new();
     let sample_information = sample_manager.sample_information();
-    let mut barcode_reader = get_buf_reader(run_manager.barcode_reads());
+    let sampled_barcodes = sample_template_barcodes(
+        &run_manager,
+        barcode_length,
+        input_barcode_length == 0,
+        testing_reads,
+        sampling,
+        seed,
+    );
 
-    loop {
-        if read_cntr >= testing_reads {
-            break;
-        }
-        read_barcode_seq = String::new();
-        read_bytes = barcode_reader.read_line(&mut read_barcode_seq).unwrap();
-        if read_bytes == 0 {
-            break;
-        }
-        read_barcode_seq = String::new();
-        barcode_reader.read_line(&mut read_barcode_seq).unwrap();
-        read_barcode_seq = read_barcode_seq
-            [read_barcode_seq.len() - barcode_length - 1..read_barcode_seq.len() - 1]
-            .to_string();
+    for read_barcode_seq in &sampled_barcodes {
         //println!(" {} Barcode seq: {}", read_cntr, read_barcode_seq);
-
+        let mut matched = false;
         for sample_itr in 0..sample_indexes.len() {
             let sample_i7 = &sample_indexes[sample_itr][0];
             let sample_i7_rc = &sample_indexes[sample_itr][1];
             let sample_i5 = &sample_indexes[sample_itr][2];
             let sample_i5_rc = &sample_indexes[sample_itr][3];
 
-            find_matches(
+            matched |= find_matches(
                 sample_itr,
                 &mut matches_stat,
+                &mut exact_matches_stat,
                 sample_indexes.len(),
-                &read_barcode_seq,
+                read_barcode_seq,
                 &sample_i7,
                 &sample_i7_rc,
                 &sample_i5,
                 &sample_i5_rc,
+                allowed_mismatches,
             );
         }
-
-        barcode_reader.read_line(&mut read_barcode_seq).unwrap();
-        barcode_reader.read_line(&mut read_barcode_seq).unwrap();
+        if !matched {
+            *undetermined_barcodes.entry(read_barcode_seq).or_default() += 1;
+        }
         read_cntr += 1;
     }
 
@@ -2146,23 +3587,11 @@ pub fn detect_template(template_command: &ArgMatches) {
         );
     }
 
-    let mut out_str = String::from("sample_id\ti7\ti5\tjob_number\ttemplate\ti7_rc\ti5_rc\n");
+    let mut sample_templates: Vec<(String, String, String)> = Vec::new();
 
     let mut out_str_full = String::from("sample_id\ti7\ti5\tall-matches\n");
 
     for sample_itr in 0..sample_information.len() {
-        out_str.push_str(&sample_information[sample_itr][SAMPLE_COLUMN]);
-        out_str.push('\t');
-
-        out_str.push_str(&sample_information[sample_itr][I7_COLUMN]);
-        out_str.push('\t');
-
-        out_str.push_str(&sample_information[sample_itr][I5_COLUMN]);
-        out_str.push('\t');
-
-        out_str.push_str(&sample_information[sample_itr][PROJECT_ID_COLUMN]);
-        out_str.push('\t');
-
         if use_popular_template {
             if sample_reads_final[sample_itr].len() > 0
                 && popular_template != sample_reads_final[sample_itr][0].0
@@ -2179,20 +3608,15 @@ pub fn detect_template(template_command: &ArgMatches) {
                 );
             }
 
-            let template_info = get_mgikit_template(
+            sample_templates.push(get_mgikit_template(
                 &popular_template,
                 add_umi,
                 barcode_length,
                 sample_information[sample_itr][I7_COLUMN].len(),
                 sample_information[sample_itr][I5_COLUMN].len(),
-            );
-            out_str.push_str(&template_info.0);
-            out_str.push('\t');
-            out_str.push_str(&template_info.1);
-            out_str.push('\t');
-            out_str.push_str(&template_info.2);
+            ));
         } else {
-            let template_info = match sample_reads_final[sample_itr].len() > 0 {
+            sample_templates.push(match !sample_reads_final[sample_itr].is_empty() {
                 true => get_mgikit_template(
                     &sample_reads_final[sample_itr][0].0,
                     add_umi,
@@ -2205,16 +3629,9 @@ pub fn detect_template(template_command: &ArgMatches) {
                     String::from("."),
                     String::from("."),
                 ),
-            };
-            out_str.push_str(&template_info.0);
-            out_str.push('\t');
-            out_str.push_str(&template_info.1);
-            out_str.push('\t');
-            out_str.push_str(&template_info.2);
+            });
         }
 
-        out_str.push('\n');
-
         out_str_full.push_str(&sample_information[sample_itr][SAMPLE_COLUMN]);
         out_str_full.push('\t');
 
@@ -2227,7 +3644,22 @@ pub fn detect_template(template_command: &ArgMatches) {
         if sample_reads_final[sample_itr].len() > 0 {
             let tmp: Vec<String> = sample_reads_final[sample_itr]
                 .iter()
-                .map(|tmp_det| format!("{} ({})", tmp_det.0, tmp_det.1))
+                .map(|tmp_det| {
+                    if allowed_mismatches > 0 {
+                        // tolerant matches then the exact ones.
+                        format!(
+                            "{} ({}, {} exact)",
+                            tmp_det.0,
+                            tmp_det.1,
+                            match exact_matches_stat.get(&tmp_det.0) {
+                                Some(sample_reads) => sample_reads[sample_itr],
+                                None => 0,
+                            }
+                        )
+                    } else {
+                        format!("{} ({})", tmp_det.0, tmp_det.1)
+                    }
+                })
                 .collect();
             out_str_full.push_str(&tmp.join("\t"));
         } else {
@@ -2237,13 +3669,14 @@ pub fn detect_template(template_command: &ArgMatches) {
         out_str_full.push('\n');
     }
 
-    write_file(
+    write_template_sheet(
         &format!(
             "{}/{}_template.tsv",
             run_manager.output_dir().display(),
             output_file
         ),
-        &out_str,
+        sample_information,
+        &sample_templates,
     );
     write_file(
         &format!(
@@ -2254,6 +3687,218 @@ pub fn detect_template(template_command: &ArgMatches) {
         &out_str_full,
     );
 
+    /*
+    The confidence of a sample template is the fraction of the sample matches that support it,
+    and the margin is how far the second best template is behind it, relative to the best one.
+    Samples without matches or with a margin below the minimum are flagged and get the most
+    frequent template in the sample sheet.
+    */
+    let mut out_str_confidence = String::from(
+        "sample_id\ti7\ti5\ttemplate\ti7_rc\ti5_rc\tmatches\tall_matches\tconfidence\tsecond_template\tsecond_i7_rc\tsecond_i5_rc\tsecond_matches\tmargin\tstatus\n",
+    );
+    let mut sheet_templates: Vec<(String, String, String)> = Vec::new();
+    // The most frequent template for single and dual index samples, used in the sample sheet
+    // for the samples without a confident template.
+    let mut popular_templates: HashMap<usize, (&String, usize)> = HashMap::new();
+    for (template_str, sample_reads) in &matches_stat {
+        let index_count = template_str.split('_').next().unwrap().split(':').count();
+        let cnt: usize = sample_reads.iter().sum();
+        match popular_templates.get(&index_count) {
+            Some((popular, popular_cnt))
+                if *popular_cnt > cnt || (*popular_cnt == cnt && *popular < template_str) => {}
+            _ => {
+                popular_templates.insert(index_count, (template_str, cnt));
+            }
+        }
+    }
+    for sample_itr in 0..sample_information.len() {
+        let sample_matches = &sample_reads_final[sample_itr];
+        let all_matches: usize = sample_matches
+            .iter()
+            .map(|sample_match| sample_match.1)
+            .sum();
+        let i7_len = sample_indexes[sample_itr][0].len();
+        let i5_len = sample_indexes[sample_itr][2].len();
+
+        out_str_confidence.push_str(&format!(
+            "{}\t{}\t{}\t",
+            sample_information[sample_itr][SAMPLE_COLUMN],
+            sample_information[sample_itr][I7_COLUMN],
+            sample_information[sample_itr][I5_COLUMN]
+        ));
+        let status = if sample_matches.is_empty() {
+            out_str_confidence.push_str(".\t.\t.\t0\t0\t0.000\t.\t.\t.\t0\t0.000\t");
+            "no_matches"
+        } else {
+            let best_template = get_mgikit_template(
+                &sample_matches[0].0,
+                add_umi,
+                barcode_length,
+                i7_len,
+                i5_len,
+            );
+            let (second_template, second_matches) = match sample_matches.len() > 1 {
+                true => (
+                    get_mgikit_template(
+                        &sample_matches[1].0,
+                        add_umi,
+                        barcode_length,
+                        i7_len,
+                        i5_len,
+                    ),
+                    sample_matches[1].1,
+                ),
+                false => ((String::from("."), String::from("."), String::from(".")), 0),
+            };
+            let margin = (sample_matches[0].1 - second_matches) as f64 / sample_matches[0].1 as f64;
+            out_str_confidence.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{:.3}\t{}\t{}\t{}\t{}\t{:.3}\t",
+                best_template.0,
+                best_template.1,
+                best_template.2,
+                sample_matches[0].1,
+                all_matches,
+                sample_matches[0].1 as f64 / all_matches as f64,
+                second_template.0,
+                second_template.1,
+                second_template.2,
+                second_matches,
+                margin
+            ));
+            if margin < min_margin {
+                warn!(
+                    "Sample ({}) has conflicting templates: {} ({}) and {} ({})!",
+                    sample_information[sample_itr][SAMPLE_COLUMN],
+                    best_template.0,
+                    sample_matches[0].1,
+                    second_template.0,
+                    second_matches
+                );
+                "conflicting"
+            } else {
+                "confident"
+            }
+        };
+        out_str_confidence.push_str(status);
+        out_str_confidence.push('\n');
+
+        if write_sample_sheet {
+            let index_count = if i5_len > 0 { 2 } else { 1 };
+            sheet_templates.push(if use_popular_template {
+                sample_templates[sample_itr].clone()
+            } else if status != "confident" {
+                warn!(
+                    "The most frequent template is used for sample ({}) in the sample sheet as it is {}!",
+                    sample_information[sample_itr][SAMPLE_COLUMN],
+                    status
+                );
+                get_mgikit_template(
+                    match popular_templates.get(&index_count) {
+                        Some((template_str, _)) => template_str,
+                        None => &popular_template,
+                    },
+                    add_umi,
+                    barcode_length,
+                    i7_len,
+                    i5_len,
+                )
+            } else {
+                get_mgikit_template(
+                    &sample_matches[0].0,
+                    add_umi,
+                    barcode_length,
+                    i7_len,
+                    i5_len,
+                )
+            });
+        }
+    }
+
+    write_file(
+        &format!(
+            "{}/{}_confidence.tsv",
+            run_manager.output_dir().display(),
+            output_file
+        ),
+        &out_str_confidence,
+    );
+    if write_sample_sheet {
+        write_template_sheet(
+            &format!(
+                "{}/{}_sample_sheet.tsv",
+                run_manager.output_dir().display(),
+                output_file
+            ),
+            sample_information,
+            &sheet_templates,
+        );
+    }
+
+    if !sample_manager.index_kits().is_empty() {
+        /*
+        The testing reads that match no sample are split with the best template of each sample,
+        the first split that matches the kits is reported, otherwise the split of the first template.
+        */
+        let mut templates_info: Vec<(&String, usize, usize)> = Vec::new();
+        for sample_itr in 0..sample_information.len() {
+            if let Some(sample_match) = sample_reads_final[sample_itr].first() {
+                let template_info = (
+                    &sample_match.0,
+                    sample_indexes[sample_itr][0].len(),
+                    sample_indexes[sample_itr][2].len(),
+                );
+                if !templates_info.contains(&template_info) {
+                    templates_info.push(template_info);
+                }
+            }
+        }
+        let mut undetermined_barcodes_out: Vec<_> = undetermined_barcodes.iter().collect();
+        undetermined_barcodes_out.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let mut out_str_kits = String::from("barcode\treads\ti7\ti5\ti7_kit_match\ti5_kit_match\n");
+        for (barcode, count) in undetermined_barcodes_out {
+            let mut annotation = None;
+            for (template_str, i7_len, i5_len) in &templates_info {
+                let positions: Vec<usize> = template_str
+                    .split('_')
+                    .next()
+                    .unwrap()
+                    .split(':')
+                    .map(|position| position.parse().unwrap())
+                    .collect();
+                let i7 = barcode.get(positions[0]..positions[0] + i7_len);
+                let i5 = match positions.len() > 1 {
+                    true => barcode.get(positions[1]..positions[1] + i5_len),
+                    false => Some("."),
+                };
+                let (Some(i7), Some(i5)) = (i7, i5) else {
+                    continue;
+                };
+                let (i7_match, i5_match) = sample_manager.index_kits().annotate(i7, i5);
+                let found = i7_match != "." || i5_match != ".";
+                if annotation.is_none() || found {
+                    annotation = Some((i7, i5, i7_match, i5_match));
+                }
+                if found {
+                    break;
+                }
+            }
+            if let Some((i7, i5, i7_match, i5_match)) = annotation {
+                out_str_kits.push_str(&format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\n",
+                    barcode, count, i7, i5, i7_match, i5_match
+                ));
+            }
+        }
+        write_file(
+            &format!(
+                "{}/{}_undetermined.tsv",
+                run_manager.output_dir().display(),
+                output_file
+            ),
+            &out_str_kits,
+        );
+    }
+
     let dur = start.elapsed();
 
     info!(
@@ -2263,6 +3908,405 @@ pub fn detect_template(template_command: &ArgMatches) {
     );
 }
 
+fn write_template_sheet(
+    output_file: &String,
+    sample_information: &[Vec<String>],
+    sample_templates: &[(String, String, String)],
+) {
+    // A sample sheet with the template, i7_rc and i5_rc of every sample.
+    let mut out_str = String::from("sample_id\ti7\ti5\tjob_number\ttemplate\ti7_rc\ti5_rc\n");
+    for (sample_info, template_info) in sample_information.iter().zip(sample_templates.iter()) {
+        out_str.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            sample_info[SAMPLE_COLUMN],
+            sample_info[I7_COLUMN],
+            sample_info[I5_COLUMN],
+            sample_info[PROJECT_ID_COLUMN],
+            template_info.0,
+            template_info.1,
+            template_info.2
+        ));
+    }
+    write_file(output_file, &out_str);
+}
+
+/// Returns the start of the barcode window with the least diverse k-mers (lowest Shannon entropy)
+/// and its entropy in bits. Windows overlapping the excluded range are skipped.
+fn find_index_position(
+    barcodes: &Vec<String>,
+    index_length: usize,
+    excluded: Option<(usize, usize)>,
+    min_fraction: f64,
+    cluster_distance: usize,
+) -> (usize, f64) {
+    /*
+    Constant regions have the lowest entropy, so a window is taken as an index only when its
+    frequent sequences make at least two clusters (one per sample). Between windows of the same
+    entropy, like an index shifted into a constant region, the one that varies at more positions
+    is taken. Without such windows (a single sample), the lowest entropy is used.
+    */
+    let min_reads = min_fraction * barcodes.len() as f64;
+    // (position, entropy, distinct indexes, varying positions)
+    let mut windows: Vec<(usize, f64, usize, usize)> = Vec::new();
+    for position in 0..barcodes[0].len() - index_length + 1 {
+        if let Some((excluded_start, excluded_end)) = excluded {
+            if position < excluded_end && position + index_length > excluded_start {
+                continue;
+            }
+        }
+        let mut kmer_counts: HashMap<&str, usize> = HashMap::new();
+        for barcode in barcodes {
+            *kmer_counts
+                .entry(&barcode[position..position + index_length])
+                .or_insert(0) += 1;
+        }
+        let entropy: f64 = kmer_counts
+            .values()
+            .map(|&cnt| {
+                let frequency = cnt as f64 / barcodes.len() as f64;
+                -frequency * frequency.log2()
+            })
+            .sum();
+        let mut indexes: Vec<&str> = Vec::new();
+        for (kmer, _) in kmer_counts
+            .into_iter()
+            .filter(|(_, cnt)| *cnt as f64 >= min_reads)
+            .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)))
+        {
+            if !indexes.iter().any(|index| {
+                hamming_distance(index.as_bytes(), kmer.as_bytes()).unwrap() <= cluster_distance
+            }) {
+                indexes.push(kmer);
+            }
+        }
+        let varying_positions = (0..index_length)
+            .filter(|itr| {
+                indexes
+                    .iter()
+                    .any(|index| index.as_bytes()[*itr] != indexes[0].as_bytes()[*itr])
+            })
+            .count();
+        debug!(
+            "Index window at {}: {:.3} bits, {} distinct indexes varying at {} positions",
+            position,
+            entropy,
+            indexes.len(),
+            varying_positions
+        );
+        windows.push((position, entropy, indexes.len(), varying_positions));
+    }
+    if windows.is_empty() {
+        panic!(
+            "No position in the barcode can hold an index of length {}!",
+            index_length
+        );
+    }
+    let is_better = |window: &(usize, f64, usize, usize), best: &(usize, f64, usize, usize)| {
+        if (window.1 - best.1).abs() > 1e-9 {
+            window.1 < best.1
+        } else {
+            window.3 > best.3
+        }
+    };
+    let (best_position, best_entropy, _, _) = match windows
+        .iter()
+        .filter(|window| window.2 >= 2)
+        .copied()
+        .reduce(|best, window| {
+            if is_better(&window, &best) {
+                window
+            } else {
+                best
+            }
+        }) {
+        Some(window) => window,
+        None => {
+            warn!(
+                "No position of the barcode has at least two frequent index sequences of length {}. The position with the lowest entropy is used and it might be a constant region of the barcode!",
+                index_length
+            );
+            windows
+                .iter()
+                .copied()
+                .reduce(|best, window| {
+                    if is_better(&window, &best) {
+                        window
+                    } else {
+                        best
+                    }
+                })
+                .unwrap()
+        }
+    };
+    (best_position, best_entropy)
+}
+
+fn load_index_kits(index_kits_path: &String) -> IndexKits {
+    if index_kits_path.is_empty() {
+        IndexKits::default()
+    } else {
+        IndexKits::from_file(Path::new(index_kits_path))
+    }
+}
+
+pub fn discover_barcodes(discover_command: &ArgMatches) {
+    let start = Instant::now();
+    let index_kits = load_index_kits(
+        discover_command
+            .get_one::<String>("arg_index_kits")
+            .unwrap(),
+    );
+    let path = Path::new(discover_command.get_one::<String>("arg_ouput_dir").unwrap());
+
+    let output_file = path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("")
+        .to_string();
+
+    let parent_path = path.parent().and_then(|p| p.to_str()).unwrap_or("");
+
+    let mut run_manager = RunManager::new(
+        discover_command
+            .get_one::<String>("arg_input_folder_path")
+            .unwrap()
+            .to_string(),
+        discover_command
+            .get_one::<String>("arg_read1_file_path")
+            .unwrap()
+            .to_string(),
+        discover_command
+            .get_one::<String>("arg_read2_file_path")
+            .unwrap()
+            .to_string(),
+        parent_path.to_string(),
+        String::new(),
+        String::new(),
+        String::new(),
+        String::new(),
+        true,
+        true,
+        discover_command
+            .get_one::<String>("arg_read1_file_name_suf")
+            .unwrap()
+            .to_string(),
+        discover_command
+            .get_one::<String>("arg_read2_file_name_suf")
+            .unwrap()
+            .to_string(),
+        discover_command
+            .get_one::<String>("arg_info_file")
+            .unwrap()
+            .to_string(),
+        true,
+        false,
+        true,
+        true,
+        true,
+        false,
+        false,
+    );
+
+    let input_barcode_length: usize = *discover_command
+        .get_one::<usize>("arg_barcode_length")
+        .unwrap();
+    let testing_reads: usize = *discover_command
+        .get_one::<usize>("arg_testing_reads")
+        .unwrap();
+    let sampling: &String = discover_command.get_one::<String>("arg_sampling").unwrap();
+    let seed: u64 = *discover_command.get_one::<u64>("arg_seed").unwrap();
+    let i7_length: usize = *discover_command.get_one::<usize>("arg_i7_length").unwrap();
+    let i5_length: usize = *discover_command.get_one::<usize>("arg_i5_length").unwrap();
+    let max_samples: usize = *discover_command
+        .get_one::<usize>("arg_max_samples")
+        .unwrap();
+    let min_fraction: f64 = *discover_command.get_one::<f64>("arg_min_fraction").unwrap();
+    let cluster_distance: usize = *discover_command
+        .get_one::<usize>("arg_cluster_distance")
+        .unwrap();
+
+    let (barcode_read_info, paired_read_info) = run_manager.get_read_information();
+    let barcode_length: usize = if input_barcode_length > 0 {
+        input_barcode_length
+    } else {
+        info!("Barcode length is calculated as the difference between R2 length and R1.");
+        barcode_read_info.sequence_length() - paired_read_info.sequence_length()
+    };
+    info!("Barcode length: {}", barcode_length);
+    if i7_length == 0 || i7_length + i5_length > barcode_length {
+        panic!(
+            "The indexes lengths ({} and {}) do not fit in the barcode length {}!",
+            i7_length, i5_length, barcode_length
+        );
+    }
+
+    let barcodes = sample_template_barcodes(
+        &run_manager,
+        barcode_length,
+        input_barcode_length == 0,
+        testing_reads,
+        sampling,
+        seed,
+    );
+    if barcodes.is_empty() {
+        panic!("No reads were found in the input file!");
+    }
+
+    /*
+    Index positions have a few frequent sequences (one per sample) while UMIs and other positions
+    are diverse, so the index is placed where the k-mers have the lowest entropy among the
+    positions with more than one frequent sequence.
+    */
+    let (mut i7_position, i7_entropy) =
+        find_index_position(&barcodes, i7_length, None, min_fraction, cluster_distance);
+    let (mut i5_position, i5_entropy) = if i5_length > 0 {
+        find_index_position(
+            &barcodes,
+            i5_length,
+            Some((i7_position, i7_position + i7_length)),
+            min_fraction,
+            cluster_distance,
+        )
+    } else {
+        (0, 0.0)
+    };
+    if i5_length == i7_length && i5_position < i7_position {
+        // With equal lengths, the first index in the barcode is reported as i7.
+        (i7_position, i5_position) = (i5_position, i7_position);
+    }
+    info!(
+        "i7 detected at position {} of the barcode (entropy {:.3} bits).",
+        i7_position, i7_entropy
+    );
+    if i5_length > 0 {
+        info!(
+            "i5 detected at position {} of the barcode (entropy {:.3} bits).",
+            i5_position, i5_entropy
+        );
+    }
+
+    let mut barcode_counts: HashMap<(&str, &str), usize> = HashMap::new();
+    for barcode in &barcodes {
+        *barcode_counts
+            .entry((
+                &barcode[i7_position..i7_position + i7_length],
+                if i5_length > 0 {
+                    &barcode[i5_position..i5_position + i5_length]
+                } else {
+                    ""
+                },
+            ))
+            .or_insert(0) += 1;
+    }
+
+    // Greedy clustering, each barcode joins the first more frequent barcode within the distance of both indexes.
+    let mut clusters: Vec<(&str, &str, usize, usize)> = Vec::new();
+    for ((i7, i5), reads) in barcode_counts
+        .into_iter()
+        .sorted_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+    {
+        match clusters.iter_mut().find(|cluster| {
+            hamming_distance(cluster.0.as_bytes(), i7.as_bytes()).unwrap() <= cluster_distance
+                && hamming_distance(cluster.1.as_bytes(), i5.as_bytes()).unwrap()
+                    <= cluster_distance
+        }) {
+            Some(cluster) => {
+                cluster.2 += reads;
+                cluster.3 += 1;
+            }
+            None => clusters.push((i7, i5, reads, 1)),
+        }
+    }
+    clusters.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(b.0)));
+
+    let template = get_mgikit_template(
+        &match i5_length > 0 {
+            true => format!("{}:{}_0:0", i7_position, i5_position),
+            false => format!("{}_0:.", i7_position),
+        },
+        false,
+        barcode_length,
+        i7_length,
+        i5_length,
+    );
+
+    let mut out_str = String::from("i7\ti5\treads\tfraction\tmerged_barcodes");
+    if index_kits.is_empty() {
+        out_str.push('\n');
+    } else {
+        out_str.push_str("\ti7_kit_match\ti5_kit_match\n");
+    }
+    let mut out_str_sheet = match i5_length > 0 {
+        true => String::from("sample_id\ti7\ti5\tjob_number\ttemplate\ti7_rc\ti5_rc\n"),
+        false => String::from("sample_id\ti7\ttemplate\ti7_rc\n"),
+    };
+    let mut sample_cnt: usize = 0;
+    let mut sample_reads: usize = 0;
+    for (i7, i5, reads, merged_barcodes) in &clusters {
+        let fraction = *reads as f64 / barcodes.len() as f64;
+        if fraction < min_fraction {
+            break;
+        }
+        out_str.push_str(&format!(
+            "{}\t{}\t{}\t{:.5}\t{}",
+            i7,
+            if i5_length > 0 { i5 } else { "." },
+            reads,
+            fraction,
+            merged_barcodes
+        ));
+        if index_kits.is_empty() {
+            out_str.push('\n');
+        } else {
+            let (i7_match, i5_match) = index_kits.annotate(i7, i5);
+            out_str.push_str(&format!("\t{}\t{}\n", i7_match, i5_match));
+        }
+        if sample_cnt < max_samples {
+            sample_cnt += 1;
+            sample_reads += reads;
+            if i5_length > 0 {
+                out_str_sheet.push_str(&format!(
+                    "sample_{}\t{}\t{}\t.\t{}\t{}\t{}\n",
+                    sample_cnt, i7, i5, template.0, template.1, template.2
+                ));
+            } else {
+                out_str_sheet.push_str(&format!(
+                    "sample_{}\t{}\t{}\t{}\n",
+                    sample_cnt, i7, template.0, template.1
+                ));
+            }
+        }
+    }
+    info!(
+        "{} barcodes were added to the draft sample sheet covering {:.3} of the reads.",
+        sample_cnt,
+        sample_reads as f64 / barcodes.len() as f64
+    );
+
+    write_file(
+        &format!(
+            "{}/{}_discovered.tsv",
+            run_manager.output_dir().display(),
+            output_file
+        ),
+        &out_str,
+    );
+    write_file(
+        &format!(
+            "{}/{}_sample_sheet.tsv",
+            run_manager.output_dir().display(),
+            output_file
+        ),
+        &out_str_sheet,
+    );
+
+    info!(
+        "{} reads were processed in {} secs.",
+        barcodes.len(),
+        start.elapsed().as_secs()
+    );
+}
+
 pub fn reformat(reformat_command: &ArgMatches) -> Result<(), Box<dyn Error>> {
     let reporting_level: usize = *reformat_command
         .get_one::<usize>("arg_report_level")
@@ -2319,7 +4363,14 @@ pub fn reformat(reformat_command: &ArgMatches) -> Result<(), Box<dyn Error>> {
             .get_one::<bool>("arg_check_content")
             .unwrap(),
         false,
+        false,
     );
+    if *reformat_command
+        .get_one::<bool>("arg_provenance_checksums")
+        .unwrap()
+    {
+        run_manager.enable_input_checksums();
+    }
     let (barcode_read_info, paired_read_info) = run_manager.get_read_information();
     if run_manager.lane().len() == 0 {
         info!("lane detected in the read header will be used for this run!");
@@ -2327,6 +4378,15 @@ pub fn reformat(reformat_command: &ArgMatches) -> Result<(), Box<dyn Error>> {
     }
 
     run_manager.confirm_format();
+    append_resolved_config(
+        reformat_command,
+        &[
+            ("lane", run_manager.lane().clone()),
+            ("flowcell", run_manager.flowcell()),
+            ("instrument", run_manager.instrument().clone()),
+            ("run", run_manager.run().clone()),
+        ],
+    );
     let compression_buffer_size = *reformat_command
         .get_one::<usize>("arg_compression_buffer_size")
         .unwrap();
@@ -2388,35 +4448,52 @@ pub fn reformat(reformat_command: &ArgMatches) -> Result<(), Box<dyn Error>> {
     if 0.0 < arg_memory && arg_memory <= 0.5 {
         panic!("Requested memory should be greater than 0.5 GB!");
     }
+    let available_memory = get_available_memory(arg_memory);
     let max_buffer_size = calculate_largest_buffer_size(
-        get_available_memory(arg_memory),
+        available_memory,
         1,
         buffer_info.compression_buffer_size(),
         !run_manager.paired_read_input(),
-        1,
     );
     buffer_info.calculate_final_writing_buffer_size(max_buffer_size);
+    // The reads are processed by the main thread and written by a single writer.
+    run_manager.set_processing_threads(1);
+    run_manager.set_writer_threads(1);
+    run_manager.set_available_memory(available_memory as u64);
+    run_manager.set_required_memory(calculate_reqiured_memory(
+        1,
+        buffer_info.writing_threshold(),
+        buffer_info.compression_buffer_size(),
+        !run_manager.paired_read_input(),
+    ) as u64);
 
+    let mut sample = SampleData::new(
+        sample_label.clone(),
+        run_manager.paired_read_input(),
+        run_manager.read2_has_sequence(),
+        buffer_info.clone(),
+        run_manager.create_illumina_header_prefix(),
+    );
+    sample.create_files(
+        run_manager.lane(),
+        reformated_sample.sample_index(),
+        run_manager.illumina_format(),
+        run_manager.output_dir(),
+        run_manager.paired_read_input(),
+    );
     if run_manager.force() {
-        let mut sample = SampleData::new(
-            sample_label.clone(),
-            run_manager.paired_read_input(),
-            run_manager.read2_has_sequence(),
-            buffer_info.clone(),
-            run_manager.create_illumina_header_prefix(),
-        );
-        sample.create_files(
-            run_manager.lane(),
-            reformated_sample.sample_index(),
-            run_manager.illumina_format(),
-            run_manager.output_dir(),
-            run_manager.paired_read_input(),
-        );
         sample.delete_sample_files();
     }
-
-    let sample_lockes = vec![Mutex::new(false), Mutex::new(false), Mutex::new(false)];
-    let samples_locks_arc = Arc::new(sample_lockes);
+    let (output_writers, writer_handlers) = start_output_writers(
+        vec![
+            sample,
+            SampleData::minimal_sample(),
+            SampleData::minimal_sample(),
+        ],
+        buffer_info.compression_level(),
+        1,
+        run_manager.progress().clone(),
+    );
     let (empty_sender_dummy, _) = bounded(1);
     let (_, full_receiver_dummy) = bounded(1);
     let sample_manager = SampleManager::dummy_sample(sample_label.clone());
@@ -2426,6 +4503,7 @@ pub fn reformat(reformat_command: &ArgMatches) -> Result<(), Box<dyn Error>> {
         &run_manager,
         &buffer_info,
         Vec::new(),
+        Arc::new(BarcodeLookup::default()),
         reporting_level,
         5,
         false,
@@ -2434,24 +4512,17 @@ pub fn reformat(reformat_command: &ArgMatches) -> Result<(), Box<dyn Error>> {
         empty_sender_dummy.clone(),
         empty_sender_dummy,
         false,
-        samples_locks_arc,
+        output_writers,
         false,
         &reformated_sample,
         vec![true; 10],
     );
+    for handler in writer_handlers {
+        handler.join().unwrap();
+    }
 
     if reporting_level > 0 {
-        report_manager.prepare_final_data(
-            5,
-            if run_manager.paired_read_input() {
-                1
-            } else {
-                0
-            },
-            &barcode_read_info,
-            &paired_read_info,
-            reformated_sample.umi_length(),
-        );
+        report_manager.prepare_final_data(5);
     }
     report_manager.write_reports(&run_manager, &sample_manager, reporting_level, 0, 5, 0);
     dur = start.elapsed();
@@ -2462,3 +4533,241 @@ pub fn reformat(reformat_command: &ArgMatches) -> Result<(), Box<dyn Error>> {
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sample_manager::{extract_templates_information, SheetProblems};
+
+    fn get_sample_information(samples: &[(&str, &str, &str, &str)]) -> Vec<Vec<String>> {
+        samples
+            .iter()
+            .map(|(sample_id, i7, i5, template)| {
+                [*sample_id, *i7, *i5, *template, "0", "0", "."]
+                    .iter()
+                    .map(|item| item.to_string())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_find_matching_sample_nested_lengths() {
+        // The 8bp indexes of S1 are nested within the 10bp barcode of S0, while the 8bp indexes
+        // of S2 differ by one base from the end of the barcode of S3.
+        let single_index = get_sample_information(&[
+            ("S0", "AAACGTACGT", ".", "i710"),
+            ("S1", "ACGTACGT", ".", "--2:i78"),
+            ("S2", "CCTTGGCT", ".", "--2:i78"),
+            ("S3", "GGCCTTGGCA", ".", "i710"),
+        ]);
+        let dual_index = get_sample_information(&[
+            ("S0", "AAACGTACGT", "CCTTGGAATT", "i710:i510"),
+            ("S1", "ACGTACGT", "TTGGAATT", "--2:i78:--2:i58"),
+            ("S2", "CCTTGGCT", "AACCGGTA", "--2:i78:--2:i58"),
+            ("S3", "GGCCTTGGCA", "TTAACCGGTT", "i710:i510"),
+        ]);
+        // Undetermined and ambiguous reads follow the samples.
+        let (undetermined, ambiguous) = (4, 5);
+        for (sample_information, expected_samples, all_index_allowed_mismatches) in [
+            (
+                single_index,
+                vec![
+                    // Both samples match without mismatches.
+                    ("AAACGTACGT", ambiguous, 0),
+                    // The nested index matches with fewer mismatches than the long index.
+                    ("TAACGTACGT", 1, 0),
+                    ("AAACGTACGA", ambiguous, 0),
+                    ("TTACGTACGT", 1, 0),
+                    // The long index matches with fewer mismatches than the nested index.
+                    ("GGCCTTGGCA", 3, 0),
+                    ("GGCCTTGGCT", 2, 0),
+                    ("TTCCTTGGCT", 2, 0),
+                    ("TTTTTTTTTT", undetermined, 0),
+                ],
+                1,
+            ),
+            (
+                dual_index,
+                vec![
+                    ("AAACGTACGTCCTTGGAATT", ambiguous, 0),
+                    ("TAACGTACGTCCTTGGAATT", 1, 0),
+                    ("TAACGTACGTGCTTGGAATT", 1, 0),
+                    ("TTACGTACGTCCTTGGAATT", 1, 0),
+                    ("TTACGTACGTGGTTGGAATT", 1, 0),
+                    ("GGCCTTGGCATTAACCGGTT", 3, 0),
+                    ("GGCCTTGGCATTAACCGGTA", ambiguous, 0),
+                    ("GGCCTTGGCTTTAACCGGTA", 2, 0),
+                    ("TTTTTTTTTTTTTTTTTTTT", undetermined, 0),
+                ],
+                2,
+            ),
+        ] {
+            let total_samples = sample_information.len() + 2;
+            let all_template_data = extract_templates_information(
+                &sample_information,
+                &String::new(),
+                false,
+                false,
+                &mut SheetProblems::default(),
+            )
+            .unwrap();
+            let barcode_lookup = BarcodeLookup::new(&all_template_data, 1);
+            for comprehensive_scan in [false, true] {
+                for (read_barcode, expected_sample, expected_mismatches) in &expected_samples {
+                    let (sample_id, mismatches, _, _) = find_matching_sample(
+                        &all_template_data,
+                        &barcode_lookup,
+                        read_barcode.as_bytes(),
+                        1,
+                        all_index_allowed_mismatches,
+                        total_samples,
+                        comprehensive_scan,
+                        false,
+                        true,
+                    );
+                    assert_eq!(
+                        (sample_id, mismatches),
+                        (*expected_sample, *expected_mismatches),
+                        "{}",
+                        read_barcode
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sample_reads() {
+        let reads: Vec<usize> = (0..1000).collect();
+        assert_eq!(
+            sample_reads(reads.clone().into_iter(), 10, "first", 7),
+            (0..10).collect::<Vec<usize>>()
+        );
+        for sampling in ["first", "stride", "reservoir"] {
+            // Fewer reads than requested, all the reads are kept.
+            let mut sampled = sample_reads(reads[..5].iter().copied(), 10, sampling, 7);
+            sampled.sort();
+            assert_eq!(sampled, (0..5).collect::<Vec<usize>>());
+            assert_eq!(
+                sample_reads(reads.clone().into_iter(), 0, sampling, 7).len(),
+                0
+            );
+        }
+
+        // The same seed gives the same reads.
+        for sampling in ["stride", "reservoir"] {
+            let sampled = sample_reads(reads.clone().into_iter(), 10, sampling, 7);
+            assert_eq!(sampled.len(), 10);
+            assert_eq!(
+                sampled,
+                sample_reads(reads.clone().into_iter(), 10, sampling, 7)
+            );
+            assert!(sampled.iter().all(|read| *read < 1000));
+            assert_eq!(sampled.iter().unique().count(), 10);
+        }
+        assert_ne!(
+            sample_reads(reads.clone().into_iter(), 10, "reservoir", 7),
+            sample_reads(reads.clone().into_iter(), 10, "reservoir", 8)
+        );
+
+        // Stride reads are spread over the whole input.
+        for seed in 0..20 {
+            let sampled = sample_reads(reads.clone().into_iter(), 10, "stride", seed);
+            assert!(sampled[0] < 100);
+            assert!(sampled[9] >= 800);
+            for pair in sampled.windows(2) {
+                assert!(pair[1] - pair[0] >= 64 && pair[1] - pair[0] <= 128);
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_matches_shifted_index() {
+        // The index is found at position 1, and with one mismatch at positions 0 and 2.
+        let read_barcode_seq = String::from("CAAAAAAAAGTTGCATGCAT");
+        let index = String::from("AAAAAAAA");
+        let index_rc = String::from("TTTTTTTT");
+        let no_index = String::from(".");
+        assert_eq!(
+            find_index_positions(&read_barcode_seq, &index, 1),
+            vec![(0, 1), (1, 0), (2, 1)]
+        );
+        for allowed_mismatches in [0, 1] {
+            let mut matches_stat: HashMap<String, Vec<usize>> = HashMap::new();
+            let mut exact_matches_stat: HashMap<String, Vec<usize>> = HashMap::new();
+            find_matches(
+                0,
+                &mut matches_stat,
+                &mut exact_matches_stat,
+                1,
+                &read_barcode_seq,
+                &index,
+                &index_rc,
+                &no_index,
+                &no_index,
+                allowed_mismatches,
+            );
+            assert_eq!(
+                matches_stat,
+                HashMap::from([("1_0:.".to_string(), vec![1])])
+            );
+            assert_eq!(exact_matches_stat, matches_stat);
+        }
+
+        // Dual indexes, the i5 matches at 12 with one mismatch and its reverse complement at 10.
+        // The read is counted once at the pair with the fewest mismatches.
+        let read_barcode_seq = String::from("CAAAAAAAAGTTGCATGCAT");
+        let i5 = String::from("GCATGCAA");
+        let i5_rc = String::from("TTGCATGC");
+        let mut matches_stat: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut exact_matches_stat: HashMap<String, Vec<usize>> = HashMap::new();
+        find_matches(
+            0,
+            &mut matches_stat,
+            &mut exact_matches_stat,
+            1,
+            &read_barcode_seq,
+            &index,
+            &index_rc,
+            &i5,
+            &i5_rc,
+            1,
+        );
+        assert_eq!(
+            matches_stat,
+            HashMap::from([("1:10_0:1".to_string(), vec![1])])
+        );
+        assert_eq!(exact_matches_stat, matches_stat);
+    }
+
+    #[test]
+    fn test_find_index_position_constant_region() {
+        // constant region (8) + i7 (8) + UMI (8) + i5 (8), with errors in the constant region.
+        let samples = [
+            ("AACCGGTT", "TTGGCCAA"),
+            ("CCGGTTAA", "GGCCAATT"),
+            ("GGTTAACC", "CCAATTGG"),
+        ];
+        let mut random_state: u64 = 7;
+        let barcodes: Vec<String> = (0..300)
+            .map(|read_itr| {
+                let constant = if read_itr % 20 == 0 {
+                    "ACGAACGT"
+                } else {
+                    "ACGTACGT"
+                };
+                let umi: String = (0..8)
+                    .map(|_| ['A', 'C', 'G', 'T'][(next_random(&mut random_state) % 4) as usize])
+                    .collect();
+                let (i7, i5) = samples[read_itr % samples.len()];
+                format!("{}{}{}{}", constant, i7, umi, i5)
+            })
+            .collect();
+        let (i7_position, i7_entropy) = find_index_position(&barcodes, 8, None, 0.001, 1);
+        assert_eq!(i7_position, 8);
+        assert!((i7_entropy - 3f64.log2()).abs() < 1e-9);
+        let (i5_position, _) = find_index_position(&barcodes, 8, Some((8, 16)), 0.001, 1);
+        assert_eq!(i5_position, 24);
+    }
+}
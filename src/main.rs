@@ -70,7 +70,7 @@ fn main() {
     print_logo();
 
     {
-        let matches = Command::new("MGIKIT - MGI data demultipexing kit.")
+        let command = Command::new("MGIKIT - MGI data demultipexing kit.")
             .about("mgikit is a multiple commands to support MGI fastq demultiplexing.")
             .author("Ziad Al-Bkhetan, ziad.albkhetan@gmail.com")
             .version(VERSION)
@@ -89,6 +89,24 @@ fn main() {
                         "log level for output messages. Expected values: [error, warn, info, debug, trace]. Default is info."
                     )
             )
+            .arg(
+                Arg::new("arg_config")
+                    .long("config")
+                    .global(true)
+                    .default_value("")
+                    .help(
+                        "Path to a TOML configuration file with the options of the command (long option names as keys, optionally under a [command] section). Command line options and MGIKIT_* environment variables override the file."
+                    )
+            )
+            .arg(
+                Arg::new("arg_dump_config")
+                    .long("dump-config")
+                    .global(true)
+                    .default_value("")
+                    .help(
+                        "Write the resolved options of the command, with the detected lane, flowcell, instrument, run and threads, to this TOML file."
+                    )
+            )
             .subcommand(
                 Command::new("demultiplex")
                     .about("Demultipex fastq files.")
@@ -128,6 +146,14 @@ fn main() {
                             .required(true)
                             .help("The path to the sample/index map.")
                     )
+                    .arg(
+                        Arg::new("arg_index_kits")
+                            .long("index-kits")
+                            .default_value("")
+                            .help(
+                                "The path to an index kit file (kit, index_id, i7 and i5 columns). It allows using index_kit and index_id columns in the sample sheet and annotates undetermined barcodes with the kit entries they match."
+                            )
+                    )
                     .arg(
                         Arg::new("arg_ouput_dir")
                             .short('o')
@@ -254,6 +280,15 @@ fn main() {
                                 "Force running the tool and overwrite existed output/report files."
                             )
                     )
+                    .arg(
+                        Arg::new("arg_dry_run")
+                            .long("dry-run")
+                            .action(ArgAction::SetTrue)
+                            .default_value("false")
+                            .help(
+                                "Check the input, sample sheet and output directory, then report the templates, threads, memory and output files of the run as JSON without processing any read."
+                            )
+                    )
                     .arg(
                         Arg::new("arg_report_limit")
                             .long("report-limit")
@@ -318,7 +353,50 @@ fn main() {
                             .action(ArgAction::SetTrue)
                             .default_value("false")
                             .help(
-                                "Do not stop if there are many undetermined reads in the dataset."
+                                "Do not stop if there are many undetermined reads in the dataset, only warn about them."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_abort_check_reads")
+                            .long("abort-check-reads")
+                            .default_value("5000")
+                            .value_parser(clap::value_parser!(u64))
+                            .help(
+                                "Check the undetermined and ambiguous reads after this many reads and abort the run when they are too many. 0 disables the check."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_abort_unassigned_fraction")
+                            .long("abort-unassigned-fraction")
+                            .default_value("0.75")
+                            .value_parser(clap::value_parser!(f64))
+                            .help(
+                                "Abort the run when the fraction of undetermined and ambiguous reads is above this value at the check of '--abort-check-reads'."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_downsample_fraction")
+                            .long("downsample-fraction")
+                            .default_value("1.0")
+                            .value_parser(clap::value_parser!(f64))
+                            .help(
+                                "Write only this fraction of the reads (and their pairs) to the output files, selected from the read names with '--downsample-seed'. The reports still include all the reads. 1 writes all the reads."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_downsample_seed")
+                            .long("downsample-seed")
+                            .default_value("0")
+                            .value_parser(clap::value_parser!(u64))
+                            .help("The seed of '--downsample-fraction'. The same seed and input write the same reads.")
+                    )
+                    .arg(
+                        Arg::new("arg_max_reads_per_sample")
+                            .long("max-reads-per-sample")
+                            .default_value("0")
+                            .value_parser(clap::value_parser!(u64))
+                            .help(
+                                "Write at most this many reads (or read pairs) to the output files of each sample. The reports still include all the reads. The written reads are the first ones processed, so they are only reproducible with one processing thread. 0 means no limit."
                             )
                     )
                     .arg(
@@ -336,6 +414,41 @@ fn main() {
                                 "Add sample barcode and UMI to MGI header when MGI format is enabled."
                             )
                     )
+                    .arg(
+                        Arg::new("arg_flexible")
+                            .long("flexible")
+                            .action(ArgAction::SetTrue)
+                            .default_value("false")
+                            .help(
+                                "Accept reads with variable lengths, `+` lines that repeat the header and CRLF line endings."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_quality_offset")
+                            .long("quality-offset")
+                            .default_value("33")
+                            .value_parser(clap::value_parser!(u8))
+                            .help(
+                                "The ASCII offset of the quality scores. Either 33 (Phred+33) or 64 (Phred+64)."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_max_quality")
+                            .long("max-quality")
+                            .default_value("40")
+                            .value_parser(clap::value_parser!(u8))
+                            .help(
+                                "The maximum quality score expected in the reads. Used when checking the read content."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_yield_thresholds")
+                            .long("yield-thresholds")
+                            .default_value("30")
+                            .help(
+                                "Comma separated quality thresholds to report the yield for. Q30 is always kept in the sample statistics."
+                            )
+                    )
                     .arg(
                         Arg::new("arg_per_index_error")
                             .long("per-index-error")
@@ -379,6 +492,15 @@ fn main() {
                             .action(ArgAction::SetTrue)
                             .default_value("false")
                             .help("Validate the content of the fastq files.")
+                    )
+                    .arg(
+                        Arg::new("arg_validate_pairs")
+                            .long("validate-pairs")
+                            .action(ArgAction::SetTrue)
+                            .default_value("false")
+                            .help(
+                                "Check that the forward and reverse reads have the same read names and that both files have the same number of reads."
+                            )
                     ).arg(
                         Arg::new("arg_threads_r")
                             .long("reader-threads")
@@ -387,13 +509,155 @@ fn main() {
                             .help(
                                 "The requested threads to be used for input reading. Default is 0 which means auto configuration."
                             )
+                    ).arg(
+                        Arg::new("arg_threads_p")
+                            .long("processing-threads")
+                            .default_value("0")
+                            .value_parser(clap::value_parser!(usize))
+                            .help(
+                                "The requested threads to be used for processing the reads. Default is 0 which means auto configuration."
+                            )
                     ).arg(
                         Arg::new("arg_threads_w")
                             .long("writer-threads")
                             .default_value("0")
                             .value_parser(clap::value_parser!(usize))
                             .help(
-                                "The requested threads to be used for processing and writing outputs. Default is 0 which means auto configuration."
+                                "The requested threads to be used for compressing and writing the outputs. Default is 0 which means one writer thread for every processing thread."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_adapter_r1")
+                            .long("adapter-r1")
+                            .default_value("")
+                            .help(
+                                "The adapter sequence to be trimmed from the 3' end of read1 of the samples' reads."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_adapter_r2")
+                            .long("adapter-r2")
+                            .default_value("")
+                            .help(
+                                "The adapter sequence to be trimmed from the 3' end of read2 of the samples' reads (before the barcode)."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_adapter_min_overlap")
+                            .long("adapter-min-overlap")
+                            .default_value("3")
+                            .value_parser(clap::value_parser!(usize))
+                            .help(
+                                "The minimum overlap between the read end and the adapter to be trimmed."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_adapter_error_rate")
+                            .long("adapter-error-rate")
+                            .default_value("0.1")
+                            .value_parser(clap::value_parser!(f64))
+                            .help(
+                                "The maximum allowed mismatches as a fraction of the overlap between the read and the adapter."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_min_length")
+                            .long("min-length")
+                            .default_value("0")
+                            .value_parser(clap::value_parser!(usize))
+                            .help(
+                                "Filter read pairs with an insert shorter than this length after barcode and adapter trimming. 0 disables the filter."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_posterior_threshold")
+                            .long("posterior-threshold")
+                            .default_value("0")
+                            .value_parser(clap::value_parser!(f64))
+                            .help(
+                                "Assign reads using the base qualities of their barcode, only when the posterior probability of the sample is at least this value. 0 disables the quality aware assignment."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_unknown_prior")
+                            .long("unknown-prior")
+                            .default_value("0.05")
+                            .value_parser(clap::value_parser!(f64))
+                            .help(
+                                "The prior probability that a read belongs to none of the samples, used with '--posterior-threshold'."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_progress_interval")
+                            .long("progress-interval")
+                            .default_value("60")
+                            .value_parser(clap::value_parser!(u64))
+                            .help(
+                                "Report the progress (reads, reads/s, written bytes, undetermined rate and ETA) every this many seconds. A progress bar refreshed every second is shown instead when stderr is a terminal. 0 disables the progress reporting."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_min_mean_quality")
+                            .long("min-mean-quality")
+                            .default_value("0")
+                            .value_parser(clap::value_parser!(usize))
+                            .help(
+                                "Filter read pairs where the mean quality score of a read is below this threshold. 0 disables the filter."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_max_n_bases")
+                            .long("max-n-bases")
+                            .value_parser(clap::value_parser!(usize))
+                            .help(
+                                "Filter read pairs where a read has more N bases than this value. Disabled by default."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_filtered_output")
+                            .long("filtered-output")
+                            .default_value("drop")
+                            .value_parser(["drop", "sample", "global"])
+                            .help(
+                                "What to do with the filtered read pairs: drop them, write them to a filtered file per sample, or to one global filtered file."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_cycle_stats")
+                            .long("cycle-stats")
+                            .action(ArgAction::SetTrue)
+                            .default_value("false")
+                            .value_parser(clap::value_parser!(bool))
+                            .help(
+                                "Report per sample, per cycle quality and base composition statistics. Only applies with reporting level 2."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_provenance_checksums")
+                            .long("provenance-checksums")
+                            .action(ArgAction::SetTrue)
+                            .default_value("false")
+                            .value_parser(clap::value_parser!(bool))
+                            .help(
+                                "Add the md5 checksums of the input files to the provenance report, the checksum of the sample sheet is always added. The input checksums are calculated while the files are read, which slows down the reading."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_filtered_label")
+                            .long("filtered-label")
+                            .default_value("Filtered")
+                            .help(
+                                "The name of the file that contains filtered reads. Per sample files are named with the sample id followed by this label."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_index_hopping")
+                            .long("index-hopping")
+                            .action(ArgAction::SetTrue)
+                            .default_value("false")
+                            .value_parser(clap::value_parser!(bool))
+                            .help(
+                                "Report undetermined reads where the i7 and the i5 belong to different samples of the sample sheet. Only applies with reporting level 2."
                             )
                     )
             )
@@ -436,6 +700,14 @@ fn main() {
                             .required(true)
                             .help("The path to the sample/index map.")
                     )
+                    .arg(
+                        Arg::new("arg_index_kits")
+                            .long("index-kits")
+                            .default_value("")
+                            .help(
+                                "The path to an index kit file (kit, index_id, i7 and i5 columns). It allows using index_kit and index_id columns in the sample sheet and annotates undetermined barcodes with the kit entries they match."
+                            )
+                    )
                     .arg(
                         Arg::new("arg_info_file")
                             .long("info-file")
@@ -471,6 +743,24 @@ fn main() {
                                 "Use the most frequent template for all samples even if some of them have more matches with other template."
                             )
                     )
+                    .arg(
+                        Arg::new("arg_min_margin")
+                            .long("min-margin")
+                            .default_value("0.5")
+                            .value_parser(clap::value_parser!(f64))
+                            .help(
+                                "The minimum margin of the best template over the second best template of a sample, relative to the matches of the best template. Samples below it are reported as conflicting."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_write_sample_sheet")
+                            .long("write-sample-sheet")
+                            .action(ArgAction::SetTrue)
+                            .default_value("false")
+                            .help(
+                                "Write a sample sheet with the detected templates that can be used directly for demultiplexing."
+                            )
+                    )
                     .arg(
                         Arg::new("arg_no_umi")
                             .long("no-umi")
@@ -485,6 +775,32 @@ fn main() {
                             .value_parser(clap::value_parser!(usize))
                             .help("The number of reads used to detect the barcode.")
                     )
+                    .arg(
+                        Arg::new("arg_allowed_mismatches")
+                            .short('m')
+                            .long("mismatches")
+                            .default_value("0")
+                            .value_parser(clap::value_parser!(usize))
+                            .help(
+                                "The number of allowed mismatches per index when searching for the indexes in the read barcode."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_sampling")
+                            .long("sampling")
+                            .default_value("first")
+                            .value_parser(["first", "stride", "reservoir"])
+                            .help(
+                                "How the testing reads are selected: the first reads of the file, reads evenly spaced across the file (stride) or a random sample of the whole file (reservoir)."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_seed")
+                            .long("seed")
+                            .default_value("0")
+                            .value_parser(clap::value_parser!(u64))
+                            .help("The seed of the random read sampling for reproducible results.")
+                    )
                     .arg(
                         Arg::new("arg_max_umi_length")
                             .long("max-umi-len")
@@ -509,6 +825,224 @@ fn main() {
                             )
                     )
             )
+            .subcommand(
+                Command::new("discover")
+                    .about("Discover the sample barcodes of a run without a sample sheet.")
+                    .arg(
+                        Arg::new("arg_input_folder_path")
+                            .short('i')
+                            .default_value("")
+                            .long("input")
+                            .help(
+                                "The path to the input directory. It should contain one file ending with read1 suffix and another with read2 suffix."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_read2_file_path")
+                            .short('r')
+                            .long("read2")
+                            .alias("2")
+                            .default_value("")
+                            .help(
+                                "The path to read2.fastq.gz See the example for the required format."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_read1_file_path")
+                            .short('f')
+                            .long("read1")
+                            .alias("1")
+                            .default_value("")
+                            .help(
+                                "The path to read1.fastq.gz See the example for the required format."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_info_file")
+                            .long("info-file")
+                            .default_value("")
+                            .help(
+                                "The path to the info file that contains the run information (similar to `BioInfo.csv` generated by MGI machines under the lane directory). Check the documentation for more details."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_index_kits")
+                            .long("index-kits")
+                            .default_value("")
+                            .help(
+                                "The path to an index kit file (kit, index_id, i7 and i5 columns). The discovered barcodes are annotated with the kit entries they match."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_ouput_dir")
+                            .short('o')
+                            .long("output")
+                            .default_value("")
+                            .help(
+                                "Path to the output file prefix. If not provided, the output will be written at mgiKit_ followed by current data and time."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_barcode_length")
+                            .long("barcode-length")
+                            .default_value("0")
+                            .value_parser(clap::value_parser!(usize))
+                            .help(
+                                "The barcode length to scan. When set to 0, the barcode length will be length's difference between R2 and R1."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_i7_length")
+                            .long("i7-len")
+                            .default_value("8")
+                            .value_parser(clap::value_parser!(usize))
+                            .help("The length of the i7 index.")
+                    )
+                    .arg(
+                        Arg::new("arg_i5_length")
+                            .long("i5-len")
+                            .default_value("8")
+                            .value_parser(clap::value_parser!(usize))
+                            .help("The length of the i5 index. Use 0 for single index runs.")
+                    )
+                    .arg(
+                        Arg::new("arg_testing_reads")
+                            .long("testing-reads")
+                            .default_value("100000")
+                            .value_parser(clap::value_parser!(usize))
+                            .help("The number of reads used to discover the barcodes.")
+                    )
+                    .arg(
+                        Arg::new("arg_sampling")
+                            .long("sampling")
+                            .default_value("first")
+                            .value_parser(["first", "stride", "reservoir"])
+                            .help(
+                                "How the testing reads are selected: the first reads of the file, reads evenly spaced across the file (stride) or a random sample of the whole file (reservoir)."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_seed")
+                            .long("seed")
+                            .default_value("0")
+                            .value_parser(clap::value_parser!(u64))
+                            .help("The seed of the random read sampling for reproducible results.")
+                    )
+                    .arg(
+                        Arg::new("arg_max_samples")
+                            .long("max-samples")
+                            .default_value("96")
+                            .value_parser(clap::value_parser!(usize))
+                            .help(
+                                "The maximum number of barcodes written to the draft sample sheet."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_min_fraction")
+                            .long("min-fraction")
+                            .default_value("0.001")
+                            .value_parser(clap::value_parser!(f64))
+                            .help("The minimum fraction of the reads for a barcode to be reported.")
+                    )
+                    .arg(
+                        Arg::new("arg_cluster_distance")
+                            .long("cluster-distance")
+                            .default_value("1")
+                            .value_parser(clap::value_parser!(usize))
+                            .help(
+                                "The maximum Hamming distance of each index for a barcode to be merged with a more frequent barcode."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_read1_file_name_suf")
+                            .long("in-r1-file-suf")
+                            .default_value("_read_1.fq.gz")
+                            .help(
+                                "The suffix to read1 file name. When using the --input parameter, the tool looks for the file that ends with this suffix and use it as read1 file. There should be one file with this suffix in the input directory."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_read2_file_name_suf")
+                            .long("in-r2-file-suf")
+                            .default_value("_read_2.fq.gz")
+                            .help(
+                                "The suffix to read2 file name. When using the --input parameter, the tool looks for the file that ends with this suffix and use it as read2 file. There should be one file with this suffix in the input directory."
+                            )
+                    )
+            )
+            .subcommand(
+                Command::new("validate-sheet")
+                    .about("Check a sample sheet and report all its problems without any fastq input.")
+                    .arg(
+                        Arg::new("arg_sample_sheet_file_path")
+                            .short('s')
+                            .long("sample-sheet")
+                            .required(true)
+                            .help("The path to the sample/index map.")
+                    )
+                    .arg(
+                        Arg::new("arg_template")
+                            .long("template")
+                            .default_value("")
+                            .help(
+                                "The general template of the indexes to be used for demultiplexing."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_i7_rc")
+                            .long("i7-rc")
+                            .action(ArgAction::SetTrue)
+                            .default_value("false")
+                            .help(
+                                "Convert i7 to reveres complement. Only valid when using general template."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_i5_rc")
+                            .long("i5-rc")
+                            .action(ArgAction::SetTrue)
+                            .default_value("false")
+                            .help(
+                                "Convert i5 to reveres complement. Only valid when using general template."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_allowed_mismatches")
+                            .short('m')
+                            .long("mismatches")
+                            .default_value("1")
+                            .value_parser(clap::value_parser!(usize))
+                            .help(
+                                "The number of allowed mismatches to be used for demultiplexing. The distances between samples are reported for up to this number of mismatches."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_per_index_error")
+                            .long("per-index-error")
+                            .action(ArgAction::SetTrue)
+                            .default_value("false")
+                            .help(
+                                "By default, the allowed mismatches `-m or --mismatches` are considered for both indexes. This flag will allow the mismatches per each index."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_index_kits")
+                            .long("index-kits")
+                            .default_value("")
+                            .help(
+                                "The path to an index kit file (kit, index_id, i7 and i5 columns), needed when the sample sheet uses index_kit and index_id columns."
+                            )
+                    )
+                    .arg(
+                        Arg::new("arg_output_file")
+                            .short('o')
+                            .long("output")
+                            .default_value("")
+                            .help(
+                                "The path of a file to write the problems found in a tab separated format. If not provided, the problems are only reported in the log."
+                            )
+                    )
+            )
             .subcommand(
                 Command::new("report")
                     .about("Merge demultipexing reports.")
@@ -705,9 +1239,19 @@ fn main() {
                                 "Sample label to be used for file naming. if not provided, it will be taken from the name of the input file"
                             )
                     )
+                    .arg(
+                        Arg::new("arg_provenance_checksums")
+                            .long("provenance-checksums")
+                            .action(ArgAction::SetTrue)
+                            .default_value("false")
+                            .value_parser(clap::value_parser!(bool))
+                            .help(
+                                "Add the md5 checksums of the input files to the provenance report. The input checksums are calculated while the files are read, which slows down the reading."
+                            )
+                    )
             )
-            .arg_required_else_help(true)
-            .get_matches();
+            .arg_required_else_help(true);
+        let matches = get_matches_with_config(command.clone());
 
         let start = Instant::now();
         let mut log_level = matches
@@ -731,10 +1275,14 @@ fn main() {
         info!("Complete Command: {}", args.join(" "));
         info!("Exection start time: {:?}", chrono::offset::Local::now());
         info!("Log level is: {}.", log_level);
+        write_config_dump(&command, &matches);
 
         match matches.subcommand() {
             Some(("demultiplex", demultiplex_command)) => {
-                initiate_demultiplexing(demultiplex_command);
+                initiate_demultiplexing(
+                    demultiplex_command,
+                    option_environment(&command, &matches),
+                );
             }
             Some(("report", report_command)) => {
                 let arg_ouput_dir: &String =
@@ -752,6 +1300,15 @@ fn main() {
             Some(("template", template_command)) => {
                 detect_template(template_command);
             }
+            Some(("discover", discover_command)) => {
+                discover_barcodes(discover_command);
+            }
+            Some(("validate-sheet", validate_command)) => {
+                if !validate_sheet(validate_command) {
+                    error!("The sample sheet is not valid!");
+                    std::process::exit(1);
+                }
+            }
             Some(("reformat", reformat_command)) => {
                 //let arg_input_folder_path: &String = reformat_command.get_one::<String>("arg_input_folder_path").unwrap();
                 match reformat(reformat_command) {
@@ -760,11 +1317,11 @@ fn main() {
                 };
             }
             Some((command_nm, _)) => {
-                error!("Unknown command `{}`. Please enter a command to perform from (demultiplex, report, template, or reformat)!", command_nm);
+                error!("Unknown command `{}`. Please enter a command to perform from (demultiplex, report, template, discover, validate-sheet, or reformat)!", command_nm);
             }
             None => {
                 panic!(
-                    "Please enter a command to perform from (demultiplex, report, template, or reformat)!"
+                    "Please enter a command to perform from (demultiplex, report, template, discover, validate-sheet, or reformat)!"
                 );
             }
         }
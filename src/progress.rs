@@ -0,0 +1,241 @@
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use log::info;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use termion::{is_tty, terminal_size};
+
+// How often the progress bar is redrawn when stderr is a terminal.
+const TTY_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_BAR_WIDTH: usize = 30;
+
+#[derive(Default)]
+pub struct RunProgress {
+    // Counters and state shared by the reader, processing and writer threads of a run.
+    input_bytes: AtomicU64,
+    input_size: AtomicU64,
+    processed_reads: AtomicU64,
+    undetermined_reads: AtomicU64,
+    ambiguous_reads: AtomicU64,
+    written_bytes: AtomicU64,
+    unassigned_checked: AtomicBool,
+    run_aborted: AtomicBool,
+}
+
+impl RunProgress {
+    pub fn set_input_size(&self, input_size: u64) {
+        self.input_size.store(input_size, Ordering::Relaxed);
+    }
+
+    pub fn add_input_bytes(&self, bytes: usize) {
+        self.input_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_processed_reads(&self, reads: usize, undetermined_reads: u64, ambiguous_reads: u64) {
+        self.processed_reads
+            .fetch_add(reads as u64, Ordering::Relaxed);
+        self.undetermined_reads
+            .fetch_add(undetermined_reads, Ordering::Relaxed);
+        self.ambiguous_reads
+            .fetch_add(ambiguous_reads, Ordering::Relaxed);
+    }
+
+    pub fn processed_reads(&self) -> (u64, u64, u64) {
+        // The processed, undetermined and ambiguous reads so far.
+        (
+            self.processed_reads.load(Ordering::Relaxed),
+            self.undetermined_reads.load(Ordering::Relaxed),
+            self.ambiguous_reads.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn start_unassigned_check(&self) -> bool {
+        // Only the first caller gets to check the unassigned reads of the run.
+        !self.unassigned_checked.swap(true, Ordering::Relaxed)
+    }
+
+    pub fn abort_run(&self) {
+        self.run_aborted.store(true, Ordering::Relaxed);
+    }
+
+    pub fn run_aborted(&self) -> bool {
+        self.run_aborted.load(Ordering::Relaxed)
+    }
+
+    pub fn add_written_bytes(&self, bytes: usize) {
+        self.written_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+struct ProgressSnapshot {
+    reads: u64,
+    reads_per_second: f64,
+    written_bytes: u64,
+    undetermined_rate: f64,
+    // Fraction of the compressed input that was read, between 0 and 1.
+    input_fraction: f64,
+    eta: Option<Duration>,
+}
+
+impl ProgressSnapshot {
+    fn take(progress: &RunProgress, elapsed: Duration) -> Self {
+        let (reads, undetermined_reads, _) = progress.processed_reads();
+        let input_size = progress.input_size.load(Ordering::Relaxed);
+        let input_fraction = if input_size == 0 {
+            0.0
+        } else {
+            (progress.input_bytes.load(Ordering::Relaxed) as f64 / input_size as f64).min(1.0)
+        };
+        let seconds = elapsed.as_secs_f64();
+        Self {
+            reads,
+            reads_per_second: if seconds > 0.0 {
+                reads as f64 / seconds
+            } else {
+                0.0
+            },
+            written_bytes: progress.written_bytes.load(Ordering::Relaxed),
+            undetermined_rate: if reads == 0 {
+                0.0
+            } else {
+                undetermined_reads as f64 / reads as f64
+            },
+            input_fraction,
+            eta: if input_fraction > 0.0 {
+                Some(Duration::from_secs_f64(
+                    seconds * (1.0 - input_fraction) / input_fraction,
+                ))
+            } else {
+                None
+            },
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} reads, {:.0} reads/s, {} written, {:.2}% undetermined, ETA {}",
+            self.reads,
+            self.reads_per_second,
+            format_bytes(self.written_bytes),
+            self.undetermined_rate * 100.0,
+            match self.eta {
+                Some(eta) => format_duration(eta),
+                None => String::from("--:--:--"),
+            }
+        )
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, units[unit])
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60
+    )
+}
+
+fn draw_progress_bar(snapshot: &ProgressSnapshot) {
+    let summary = snapshot.summary();
+    // Leave room for the brackets, the percentage and the summary.
+    let bar_width = match terminal_size() {
+        Ok((columns, _)) => (columns as usize)
+            .saturating_sub(summary.len() + 10)
+            .min(DEFAULT_BAR_WIDTH),
+        Err(_) => DEFAULT_BAR_WIDTH,
+    };
+    let filled = (snapshot.input_fraction * bar_width as f64) as usize;
+    let mut stderr = std::io::stderr().lock();
+    let _ = write!(
+        stderr,
+        "\r[{}{}] {:>3.0}% {}",
+        "#".repeat(filled),
+        " ".repeat(bar_width - filled),
+        snapshot.input_fraction * 100.0,
+        summary
+    );
+    let _ = stderr.flush();
+}
+
+fn log_progress(snapshot: &ProgressSnapshot) {
+    info!(
+        "Progress: {:.1}% of the input, {}",
+        snapshot.input_fraction * 100.0,
+        snapshot.summary()
+    );
+}
+
+pub struct ProgressReporter {
+    stop_sender: Sender<()>,
+    handler: JoinHandle<()>,
+}
+
+impl ProgressReporter {
+    pub fn start(progress: Arc<RunProgress>, log_interval: Duration) -> Self {
+        /*
+        Reports the progress of the run counters until it is stopped. A progress bar is drawn on
+        stderr when it is a terminal, otherwise a log line is written every log_interval.
+         */
+        let (stop_sender, stop_receiver) = bounded::<()>(1);
+        let tty = is_tty(&std::io::stderr());
+        let interval = if tty {
+            TTY_REFRESH_INTERVAL
+        } else {
+            log_interval
+        };
+        let handler = thread::Builder::new()
+            .name(String::from("progress"))
+            .spawn(move || {
+                let start = Instant::now();
+                while let Err(RecvTimeoutError::Timeout) = stop_receiver.recv_timeout(interval) {
+                    let snapshot = ProgressSnapshot::take(&progress, start.elapsed());
+                    if tty {
+                        draw_progress_bar(&snapshot);
+                    } else {
+                        log_progress(&snapshot);
+                    }
+                }
+                if tty {
+                    draw_progress_bar(&ProgressSnapshot::take(&progress, start.elapsed()));
+                    eprintln!();
+                }
+            })
+            .unwrap();
+        Self {
+            stop_sender,
+            handler,
+        }
+    }
+
+    pub fn stop(self) {
+        let _ = self.stop_sender.send(());
+        self.handler.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(format_bytes(512), "512.0 B");
+        assert_eq!(format_bytes(3 * 1024 * 1024 / 2), "1.5 MB");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "01:02:05");
+    }
+}
@@ -0,0 +1,212 @@
+use crate::run_manager::RunManager;
+use crate::sample_manager::SampleManager;
+use crate::variables::{I5_COLUMN, I7_COLUMN, PROJECT_ID_COLUMN, SAMPLE_COLUMN};
+use chrono::prelude::Local;
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use sysinfo::{System, SystemExt};
+
+const CHECKSUM_BUFFER_SIZE: usize = 1 << 20;
+
+#[derive(Default)]
+pub struct InputChecksums {
+    // The size and md5 of the input files that were read to the end, calculated by the readers of
+    // the run when it asks for the checksums of the input files.
+    enabled: bool,
+    checksums: Mutex<Vec<(PathBuf, u64, String)>>,
+}
+
+impl InputChecksums {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            checksums: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn record(&self, file_path: &Path, size: u64, checksum: String) {
+        self.checksums
+            .lock()
+            .unwrap()
+            .push((file_path.to_path_buf(), size, checksum));
+    }
+
+    pub fn get(&self, file_path: &Path, size: u64) -> Option<String> {
+        // The checksum of the last complete read of the file, if it still has the same size.
+        self.checksums
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(path, recorded_size, _)| path == file_path && *recorded_size == size)
+            .map(|(_, _, checksum)| checksum.clone())
+    }
+}
+
+fn file_checksum(
+    input_checksums: &InputChecksums,
+    file_path: &Path,
+    size: u64,
+) -> io::Result<String> {
+    /*
+    The md5 of the file. The checksum calculated while reading the input is used when the whole
+    file was read, otherwise the file is read again.
+     */
+    if let Some(checksum) = input_checksums.get(file_path, size) {
+        return Ok(checksum);
+    }
+    debug!("Reading {} to calculate its checksum.", file_path.display());
+    let mut file = File::open(file_path)?;
+    let mut context = md5::Context::new();
+    let mut buffer = vec![0; CHECKSUM_BUFFER_SIZE];
+    loop {
+        let bytes = file.read(&mut buffer)?;
+        if bytes == 0 {
+            break;
+        }
+        context.consume(&buffer[..bytes]);
+    }
+    Ok(format!("{:x}", context.compute()))
+}
+
+fn file_entry(input_checksums: &InputChecksums, file_path: &Path, with_checksum: bool) -> Value {
+    let entry = fs::metadata(file_path).and_then(|metadata| {
+        let size = metadata.len();
+        if with_checksum {
+            Ok((size, Some(file_checksum(input_checksums, file_path, size)?)))
+        } else {
+            Ok((size, None))
+        }
+    });
+    match entry {
+        Ok((size, Some(checksum))) => json!({
+            "path": file_path,
+            "size": size,
+            "md5": checksum,
+        }),
+        Ok((size, None)) => json!({
+            "path": file_path,
+            "size": size,
+        }),
+        Err(err) => {
+            warn!(
+                "Can not read the size or the checksum of {}: {}",
+                file_path.display(),
+                err
+            );
+            json!({
+                "path": file_path,
+                "error": err.to_string(),
+            })
+        }
+    }
+}
+
+pub fn write_provenance(run_manager: &RunManager, sample_manager: &SampleManager, out_file: &Path) {
+    /*
+    Writes how the reports were produced: the mgikit version and command line, the size and checksum
+    of the sample sheet, the sizes and, when asked for, the checksums of the input files, the template
+    of every sample, the mismatch settings, the threads and memory budget, the host and the start and
+    end time of the run.
+     */
+    // The same file is used as barcode and paired read for single end input.
+    let mut input_files = Vec::new();
+    for file_path in [run_manager.barcode_reads(), run_manager.paired_reads()] {
+        if !file_path.as_os_str().is_empty() && !input_files.contains(&file_path) {
+            input_files.push(file_path);
+        }
+    }
+    // Reading the input files again is slow, so their md5 is only calculated when the run asks
+    // for the checksums. The sample sheet is small and its md5 is always recorded.
+    let input_checksums = run_manager.input_checksums();
+    let input_files: Vec<Value> = input_files
+        .iter()
+        .map(|file_path| file_entry(input_checksums, file_path, input_checksums.enabled()))
+        .collect();
+
+    let sample_information = sample_manager.sample_information();
+    let samples: Vec<Value> = sample_manager
+        .get_sample_templates()
+        .iter()
+        .enumerate()
+        .filter_map(|(sample_index, template)| {
+            template.as_ref().map(|(template, i7, i5)| {
+                json!({
+                    "sample_id": sample_information[sample_index][SAMPLE_COLUMN],
+                    "job_number": sample_information[sample_index][PROJECT_ID_COLUMN],
+                    "i7": sample_information[sample_index][I7_COLUMN],
+                    "i5": sample_information[sample_index][I5_COLUMN],
+                    "template": template,
+                    "read_i7": i7,
+                    "read_i5": i5,
+                })
+            })
+        })
+        .collect();
+
+    let environment: serde_json::Map<String, Value> = run_manager
+        .environment()
+        .iter()
+        .map(|(name, value)| (name.clone(), Value::String(value.clone())))
+        .collect();
+
+    let provenance = json!({
+        "mgikit_version": env!("CARGO_PKG_VERSION"),
+        "command_line": env::args().collect::<Vec<String>>(),
+        "environment": environment,
+        "start_time": run_manager.start_time(),
+        "end_time": Local::now().to_rfc3339(),
+        "host": System::new().host_name().unwrap_or_default(),
+        "flowcell": run_manager.flowcell(),
+        "lane": run_manager.lane(),
+        "instrument": run_manager.instrument(),
+        "run": run_manager.run(),
+        "input_files": input_files,
+        "sample_sheet": if sample_manager.sample_sheet().as_os_str().is_empty() {
+            Value::Null
+        } else {
+            file_entry(input_checksums, sample_manager.sample_sheet(), true)
+        },
+        "samples": samples,
+        "mismatches": {
+            "allowed_mismatches": run_manager.allowed_mismatches(),
+            "all_index_error": run_manager.all_index_error(),
+            "posterior_threshold": run_manager.posterior_threshold(),
+            "unknown_prior": run_manager.unknown_prior(),
+        },
+        "downsampling": match run_manager.downsampler() {
+            Some(downsampler) => json!({
+                "fraction": downsampler.fraction(),
+                "seed": downsampler.seed(),
+                "max_reads_per_sample": downsampler.max_sample_reads(),
+            }),
+            None => Value::Null,
+        },
+        "threads": {
+            "reader": run_manager.reader_threads(),
+            "processing": run_manager.processing_threads(),
+            "writer": run_manager.writer_threads(),
+        },
+        "memory": {
+            "required": run_manager.required_memory(),
+            "available": run_manager.available_memory(),
+        },
+        "output_dir": run_manager.output_dir(),
+        "report_dir": run_manager.report_dir(),
+    });
+    fs::write(
+        out_file,
+        serde_json::to_string_pretty(&provenance).unwrap() + "\n",
+    )
+    .unwrap();
+    info!("Provenance of the run is written to {}", out_file.display());
+}
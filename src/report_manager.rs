@@ -1,677 +1,1451 @@
-use crate::file_utils::{create_output_file, get_buf_writer};
-use crate::{run_manager::ReadInfo, variables::*, RunManager, SampleManager};
-use log::{error, info};
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::BufWriter;
-use std::io::Write;
-use std::path::PathBuf;
-use std::time::Instant;
-
-pub fn write_general_info_report(
-    sample_information: &Vec<Vec<String>>,
-    sample_statistics: &Vec<Vec<u64>>,
-    kept_samples: &Vec<usize>,
-    run: &String,
-    lane: &String,
-    output_file: &PathBuf,
-    execluded_samples: &Vec<usize>,
-) {
-    //Mb Total Yield: total bases as cnt of r1 + r2
-    //M Total Clusters: number of reads
-    //% bases ≥ Q30	r1 and r2 bases that are greater than 30
-    //Mean Quality:	r1qc + r2qc / cnt or bases in r1 and r2
-    //% Perfect Index: index with 0 mismatches / all reads.
-
-    /*
-        sample_statistics[sample_id]:
-        0: r1 count of qs > 30
-        1: r2 count os qs > 30
-        2: r3 count of (barcode) with qs > 30
-
-        3: r1 count of bases
-        4: r2 count of bases
-        5: r3 count of bases
-
-        6: qs of r1
-        7: qs of r2
-        8:  qs of r3
-        9: read count
-        from 10 to whatever mismatches allowed: is the number oif reads with the iteration mismatch
-
-    */
-    let mut out_str = String::from(
-        "#sample general info\nSample ID\tM Clusters\tMb Yield ≥ Q30\t% R1 Yield ≥ Q30\t% R2 Yield ≥ Q30\t% R3 Yield ≥ Q30\t% Perfect Index\n"
-    );
-    let non_sample = vec!["undetermined".to_string(), "ambiguous".to_string()];
-    let mut all_q_30;
-    let exclude_non_sample = true;
-    let mut outfile;
-    let mut curr_sample_stat: Vec<f64>;
-    let mut tmp_val;
-    let mut sample_id;
-    let mut lane_statistics: Vec<f64> = vec![0 as f64, 0 as f64, 0 as f64, 0 as f64, 0 as f64];
-    let mut sample_statistics_copy = sample_statistics.clone();
-    for sample_id_itr in 0..sample_statistics_copy.len() {
-        sample_statistics_copy[sample_id_itr].push(sample_id_itr as u64);
-    }
-    let mut execluded_reads: f64 = 0.0;
-
-    let mut unique_sample_ids = HashSet::new();
-    for sample_inf in sample_information {
-        unique_sample_ids.insert(sample_inf[SAMPLE_COLUMN].clone());
-    }
-
-    if unique_sample_ids.len() == sample_information.len() {
-        sample_statistics_copy.sort_by(|a, b| {
-            b[9].cmp(&a[9])
-                .then_with(|| a.last().unwrap().cmp(&b.last().unwrap()))
-        });
-    }
-
-    for sample_id_itr in 0..sample_statistics_copy.len() {
-        sample_id = sample_statistics_copy[sample_id_itr]
-            .last()
-            .unwrap()
-            .clone() as usize;
-
-        if kept_samples.len() > 0 && !kept_samples.contains(&sample_id) {
-            continue;
-        }
-        if execluded_samples.contains(&sample_id) {
-            continue;
-        }
-
-        curr_sample_stat = sample_statistics_copy[sample_id_itr]
-            .iter()
-            .map(|x| x.clone() as f64)
-            .collect();
-
-        let mut curr_sample_id: String = sample_information[sample_id][SAMPLE_COLUMN].to_string(); //.split("_").map(|x| x.to_string()).collect::<Vec<String>>()[0].to_lowercase()
-
-        out_str.push_str(&curr_sample_id);
-        out_str.push('\t');
-
-        // M clusters
-        out_str.push_str(&format!("{}", curr_sample_stat[9] / 1000000.0));
-        out_str.push('\t');
-        all_q_30 = curr_sample_stat[0] + curr_sample_stat[1];
-        lane_statistics[0] += curr_sample_stat[3] + curr_sample_stat[4];
-        lane_statistics[1] += curr_sample_stat[9];
-        lane_statistics[2] += all_q_30;
-
-        // Mb Yiled > 30
-        out_str.push_str(&format!("{}", all_q_30 / 1000000.0));
-        out_str.push('\t');
-
-        // % R1 > 30
-        if curr_sample_stat[3] == 0.0 {
-            tmp_val = 0.0;
-        } else {
-            tmp_val = curr_sample_stat[0] / curr_sample_stat[3];
-        }
-        out_str.push_str(&format!("{:.3?}", tmp_val * 100.0));
-        out_str.push('\t');
-
-        // % R2 > 30
-        if curr_sample_stat[4] == 0.0 {
-            tmp_val = 0.0;
-        } else {
-            tmp_val = curr_sample_stat[1] / curr_sample_stat[4];
-        }
-
-        out_str.push_str(&format!("{:.3?}", tmp_val * 100.0));
-
-        // % R3 > 30
-        out_str.push('\t');
-
-        if curr_sample_stat[5] == 0.0 {
-            tmp_val = 0.0;
-        } else {
-            tmp_val = curr_sample_stat[2] / curr_sample_stat[5];
-        }
-        out_str.push_str(&format!("{:.3?}", tmp_val * 100.0));
-        out_str.push('\t');
-
-        // Perfect index
-        if curr_sample_stat[9] == 0.0 {
-            tmp_val = 0.0;
-        } else {
-            tmp_val = curr_sample_stat[10] / curr_sample_stat[9];
-        }
-
-        out_str.push_str(&format!("{:.3?}", tmp_val * 100.0));
-
-        lane_statistics[3] += curr_sample_stat[6] + curr_sample_stat[7];
-
-        curr_sample_id = curr_sample_id.to_lowercase();
-        if non_sample.contains(&curr_sample_id) {
-            execluded_reads += curr_sample_stat[9] as f64;
-        }
-
-        if !exclude_non_sample || !non_sample.contains(&curr_sample_id) {
-            lane_statistics[4] += curr_sample_stat[10];
-            //println!("{}  ->  {}   {}    {}    {}", sample_information[sample_id][SAMPLE_COLUMN], lane_statistics[4], curr_sample_stat[10], lane_statistics[1], execluded_reads);
-        }
-
-        out_str.push('\n');
-    }
-
-    //output_file_path = Path::new(&output_file);
-    let mut final_out_str = String::from(
-        "#Lane statistics\nRun ID-Lane\tMb Total Yield\tM Total Clusters\t% bases ≥ Q30\tMean Quality\t% Perfect Index\n"
-    );
-    final_out_str.push_str(&run);
-    final_out_str.push('-');
-    final_out_str.push_str(&lane);
-    final_out_str.push('\t');
-
-    final_out_str.push_str(&format!("{}", lane_statistics[0] / 1000000.0));
-    final_out_str.push('\t');
-
-    final_out_str.push_str(&format!("{}", lane_statistics[1] / 1000000.0));
-    final_out_str.push('\t');
-
-    final_out_str.push_str(&format!(
-        "{:.3?}",
-        (lane_statistics[2] / lane_statistics[0]) * 100.0
-    ));
-    final_out_str.push('\t');
-    final_out_str.push_str(&format!("{:.3?}", lane_statistics[3] / lane_statistics[0]));
-    final_out_str.push('\t');
-    final_out_str.push_str(&format!(
-        "{:.3?}",
-        (lane_statistics[4] / (lane_statistics[1] - execluded_reads)) * 100.0
-    ));
-    final_out_str.push('\n');
-
-    final_out_str.push_str(&out_str);
-
-    outfile = create_output_file(output_file);
-    outfile.write_all(&final_out_str.as_bytes()).unwrap();
-}
-
-pub fn write_index_info_report(
-    sample_information: &Vec<Vec<String>>,
-    sample_mismatches: &Vec<Vec<u64>>,
-    kept_samples: &Vec<usize>,
-    max_mismatches: usize,
-    output_file: &PathBuf,
-    execluded_samples: &Vec<usize>,
-) {
-    let mut report_str = String::from("sample");
-
-    for i in 0..max_mismatches {
-        report_str.push('\t');
-        report_str.push_str(&i.to_string());
-        report_str.push_str(&"-mismatches");
-    }
-    report_str.push('\n');
-    let mut sample_mismatches_copy = sample_mismatches.clone();
-    for sample_id_itr in 0..sample_mismatches_copy.len() {
-        sample_mismatches_copy[sample_id_itr].push(sample_id_itr as u64);
-    }
-
-    //sample_mismatches_copy.sort_by(|a, b| b[0].cmp(&a[0]));
-    let mut unique_sample_ids = HashSet::new();
-    for sample_inf in sample_information {
-        unique_sample_ids.insert(sample_inf[SAMPLE_COLUMN].clone());
-    }
-
-    if unique_sample_ids.len() == sample_information.len() {
-        sample_mismatches_copy.sort_by(|a, b| {
-            b[0].cmp(&a[0])
-                .then_with(|| a.last().unwrap().cmp(&b.last().unwrap()))
-        });
-    }
-    //sample_mismatches_copy.sort_by(|a, b| b[0].cmp(&a[0]));
-    for sample_id_itr in 0..sample_mismatches_copy.len() {
-        let sample_index = *sample_mismatches_copy[sample_id_itr].last().unwrap() as usize;
-        if kept_samples.len() > 0 && !kept_samples.contains(&sample_index) {
-            continue;
-        }
-        if execluded_samples.contains(&sample_id_itr) {
-            continue;
-        }
-        report_str.push_str(&sample_information[sample_index][SAMPLE_COLUMN]);
-        for cnt in 1..max_mismatches + 1 {
-            if cnt < sample_mismatches_copy[sample_id_itr].len() {
-                report_str.push('\t');
-                report_str.push_str(&sample_mismatches_copy[sample_id_itr][cnt].to_string());
-            } else {
-                report_str.push_str("\t0");
-            }
-        }
-        report_str.push('\n');
-    }
-
-    //println!("report: {}", output_file_path.display());
-    let mut outfile = create_output_file(&output_file);
-    outfile.write_all(&report_str.as_bytes()).unwrap();
-}
-
-#[derive(Default)]
-pub struct ReportManager {
-    total_samples: usize,
-    sample_mismatches: Vec<Vec<u64>>,
-    sample_statistics: Vec<Vec<u64>>,
-    undetermined_barcodes: HashMap<String, u64>,
-    ambiguous_barcodes: HashMap<String, u64>,
-}
-
-impl ReportManager {
-    pub fn new(total_samples: usize, allowed_mismatches: usize) -> Self {
-        let mut sample_mismatches: Vec<Vec<u64>> = Vec::new();
-        let mut sample_statistics: Vec<Vec<u64>> = Vec::new();
-        for _ in 0..total_samples {
-            sample_mismatches.push(vec![0; 2 * allowed_mismatches + 2]);
-            sample_statistics.push(vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
-            /*
-                sample_statistics[sample_id]:
-                0: r1 count of qs > 30
-                1: r2 count os qs > 30
-                2: r3 count of (barcode) with qs > 30
-
-                3: r1 count of bases
-                4: r2 count of bases
-                5: r3 count of bases
-
-                6: qs of r1
-                7: qs of r2
-                8:  qs of r3
-                9: read count
-                from 10 to whatever mismatches allowed: is the number oif reads with the iteration mismatch
-
-            */
-        }
-        Self {
-            total_samples,
-            sample_mismatches,
-            sample_statistics,
-            undetermined_barcodes: HashMap::new(),
-            ambiguous_barcodes: HashMap::new(),
-        }
-    }
-
-    pub fn update_stats(&mut self, sample_id: usize, index: usize, increment: u64) {
-        /*debug!(
-            "update stats: sample_id: {}, stat_id: {}, increment: {}",
-            sample_id,
-            index,
-            increment
-        );*/
-        self.sample_statistics[sample_id][index] += increment;
-    }
-
-    pub fn add_stats_entry(&mut self) {
-        self.sample_statistics.push(vec![0; 10]);
-    }
-    pub fn add_mismatches_entry(&mut self, size: usize) {
-        self.sample_mismatches.push(vec![0; size]);
-    }
-    pub fn truncate_mismatches(&mut self, size: usize) {
-        for i in 0..self.sample_mismatches.len() {
-            self.sample_mismatches[i].truncate(size);
-        }
-    }
-
-    pub fn update_mismatches(&mut self, sample_id: usize, index: usize, increment: u64) {
-        /*debug!(
-            "update mismatches: sample_id: {}, mismatches: {}, increment: {}",
-            sample_id,
-            index,
-            increment
-        );
-        debug!("update mismatches: {:?}", &self.sample_mismatches[sample_id]);
-        */
-        self.sample_mismatches[sample_id][index] += increment;
-    }
-
-    pub fn update_undetermined(&mut self, curr_barcode: String, increment: u64) {
-        match self.undetermined_barcodes.get_mut(&curr_barcode) {
-            Some(barcode_info) => {
-                *barcode_info += increment;
-            }
-            None => {
-                self.undetermined_barcodes.insert(curr_barcode, increment);
-            }
-        };
-    }
-
-    pub fn update_ambiguous(&mut self, curr_barcode: String, increment: u64) {
-        match self.ambiguous_barcodes.get_mut(&curr_barcode) {
-            Some(barcode_info) => {
-                *barcode_info += increment;
-            }
-            None => {
-                self.ambiguous_barcodes.insert(curr_barcode, increment);
-            }
-        };
-    }
-
-    pub fn get_sample_reads(&self, sample_id: usize) -> u64 {
-        self.sample_mismatches[sample_id][0]
-    }
-
-    pub fn get_total_reads(&self) -> u64 {
-        let mut reads: u64 = 0;
-        for i in 0..self.total_samples {
-            for j in 1..self.sample_mismatches[i].len() {
-                reads += self.sample_mismatches[i][j];
-            }
-        }
-        reads
-    }
-
-    pub fn set_sample_total_reads(&mut self) {
-        for i in 0..self.total_samples {
-            let mut reads: u64 = 0;
-            for j in 1..self.sample_mismatches[i].len() {
-                reads += self.sample_mismatches[i][j];
-            }
-            self.sample_mismatches[i][0] = reads;
-        }
-    }
-
-    pub fn update(&mut self, report_manager: &ReportManager) {
-        if self.sample_mismatches.len() != report_manager.sample_mismatches.len() {
-            error!(
-                "update ReportManager expects source and destination to have same dimentions. found {} vs {}!",
-                self.sample_mismatches.len(),
-                report_manager.sample_mismatches.len()
-            );
-        }
-        for i in 0..self.sample_mismatches.len() {
-            if self.sample_mismatches[i].len() != report_manager.sample_mismatches[i].len() {
-                error!(
-                    "update ReportManager expects source and destination to have same dimentions. found {} vs {}!",
-                    self.sample_mismatches.len(),
-                    report_manager.sample_mismatches.len()
-                );
-            }
-            for j in 0..self.sample_mismatches[i].len() {
-                self.sample_mismatches[i][j] += report_manager.sample_mismatches[i][j];
-            }
-        }
-
-        if self.sample_statistics.len() != report_manager.sample_statistics.len() {
-            error!(
-                "update ReportManager expects source and destination to have same dimentions. found {} vs {}!",
-                self.sample_statistics.len(),
-                report_manager.sample_statistics.len()
-            );
-        }
-        for i in 0..self.sample_statistics.len() {
-            if self.sample_statistics[i].len() != report_manager.sample_statistics[i].len() {
-                error!(
-                    "update ReportManager expects source and destination to have same dimentions. found {} vs {}!",
-                    self.sample_statistics.len(),
-                    report_manager.sample_statistics.len()
-                );
-            }
-            for j in 0..self.sample_statistics[i].len() {
-                self.sample_statistics[i][j] += report_manager.sample_statistics[i][j];
-            }
-        }
-
-        for (key, value) in &report_manager.ambiguous_barcodes {
-            self.ambiguous_barcodes
-                .entry(key.clone())
-                .and_modify(|v| {
-                    *v += value;
-                })
-                .or_insert(*value);
-        }
-
-        for (key, value) in &report_manager.undetermined_barcodes {
-            self.undetermined_barcodes
-                .entry(key.clone())
-                .and_modify(|v| {
-                    *v += value;
-                })
-                .or_insert(*value);
-        }
-    }
-
-    pub fn prepare_final_data(
-        &mut self,
-        max_mismatches: usize,
-        shift: usize,
-        barcode_read_info: &ReadInfo,
-        paired_read_info: &ReadInfo,
-        barcode_length: usize,
-    ) {
-        //let max_mismatches = if all_index_error {allowed_mismatches + 1} else {allowed_mismatches * 2 + 1};
-        for sample_id in 0..self.total_samples {
-            self.sample_statistics[sample_id][3] =
-                (*paired_read_info.sequence_length() as u64) * self.sample_mismatches[sample_id][0];
-            self.sample_statistics[sample_id][6] -= self.sample_statistics[sample_id][3] * 33;
-            self.sample_statistics[sample_id][shift + 3] =
-                ((barcode_read_info.sequence_length() - barcode_length) as u64)
-                    * self.sample_mismatches[sample_id][0];
-            self.sample_statistics[sample_id][5] =
-                (barcode_length as u64) * self.sample_mismatches[sample_id][0];
-            self.sample_statistics[sample_id][6 + shift] -=
-                self.sample_statistics[sample_id][shift + 3] * 33;
-            self.sample_statistics[sample_id][8] -= self.sample_statistics[sample_id][5] * 33;
-            self.sample_statistics[sample_id][9] = self.sample_mismatches[sample_id][0];
-            for cnt in 1..max_mismatches + 1 {
-                self.sample_statistics[sample_id].push(self.sample_mismatches[sample_id][cnt]);
-            }
-        }
-    }
-
-    pub fn write_reports(
-        &self,
-        run_manager: &RunManager,
-        sample_manager: &SampleManager,
-        reporting_level: usize,
-        report_limit: usize,
-        mut max_mismatches: usize,
-        individual_sample: usize,
-    ) {
-        let start_logs = Instant::now();
-        let mut sample_stats_width: usize = self.sample_statistics[0].len();
-        let mut execluded_samples = if individual_sample > self.total_samples {
-            Vec::new()
-        } else {
-            let mut execluded_samples: Vec<usize> = (0..self.total_samples).collect();
-            execluded_samples.remove(individual_sample);
-            execluded_samples
-        };
-
-        //println!("{:?}", execluded_samples);
-        //println!("Mism: {:?}", self.sample_mismatches);
-        //println!("Stat: {:?}", self.sample_statistics);
-
-        if self.sample_mismatches[self.total_samples - 2][0] == 0 {
-            execluded_samples.push(self.total_samples - 2);
-        }
-        if self.sample_mismatches[self.total_samples - 1][0] == 0 {
-            execluded_samples.push(self.total_samples - 1);
-        }
-
-        let mut report_path_main = if individual_sample == usize::MAX {
-            let mut report_path_main = String::from(run_manager.flowcell());
-            report_path_main.push('.');
-            report_path_main.push_str(run_manager.lane());
-            report_path_main
-        } else {
-            for i in (11..self.sample_statistics[0].len()).rev() {
-                if self.sample_statistics[0][i] == 0 {
-                    max_mismatches = i - 10;
-                    sample_stats_width = i;
-                } else {
-                    break;
-                }
-            }
-            String::from(sample_manager.sample_information()[0][SAMPLE_COLUMN].clone())
-        };
-        report_path_main.push_str(&".mgikit.");
-        let mut outfile;
-
-        let mut file_name_extra: String;
-        let project_samples = sample_manager.project_samples();
-        let sample_information = sample_manager.sample_information();
-        if individual_sample == usize::MAX {
-            for (project_id, samples) in project_samples.iter() {
-                if project_id != "." {
-                    info!(
-                        "generating report for job_number: {} with {} samples.",
-                        project_id,
-                        samples.len()
-                    );
-                    file_name_extra = project_id.clone();
-                    file_name_extra.push('_');
-                } else {
-                    info!(
-                        "generating report for the whole run with {} samples.",
-                        self.sample_mismatches.len()
-                    );
-                    file_name_extra = String::new();
-                }
-                let out_file = &run_manager
-                    .report_dir()
-                    .clone()
-                    .join(format!("{}{}info", &file_name_extra, &report_path_main));
-                write_index_info_report(
-                    sample_information,
-                    &self.sample_mismatches,
-                    samples,
-                    max_mismatches,
-                    &out_file,
-                    &execluded_samples,
-                );
-                //Finish writing info report
-
-                if reporting_level > 0 {
-                    let out_file = &run_manager
-                        .report_dir()
-                        .clone()
-                        .join(format!("{}{}general", &file_name_extra, &report_path_main));
-
-                    write_general_info_report(
-                        sample_information,
-                        &self.sample_statistics,
-                        samples,
-                        &run_manager.flowcell(),
-                        &run_manager.lane(),
-                        &out_file,
-                        &execluded_samples,
-                    );
-                }
-                //start writing general report
-            }
-        }
-        if reporting_level > 0 {
-            let mut out_str = String::from(
-                format!(
-                    "job_number\tsample_id\tr1_qc_30\tr2_qc_30\tr3_qc_30\tr1_bases\tr2_bases\tr3_bases\tr1_qc\tr2_qc\tr3_qc\tall_reads"
-                )
-            );
-            for cnt in 0..max_mismatches {
-                out_str.push('\t');
-                out_str.push_str(&cnt.to_string());
-                out_str.push_str(&"-mismatches");
-            }
-            out_str.push('\n');
-            for sample_index in 0..self.sample_statistics.len() {
-                if !execluded_samples.contains(&sample_index) {
-                    out_str.push_str(&sample_information[sample_index][PROJECT_ID_COLUMN]);
-                    out_str.push('\t');
-                    out_str.push_str(&sample_information[sample_index][SAMPLE_COLUMN]);
-                    for cnt in 0..sample_stats_width {
-                        out_str.push('\t');
-                        out_str.push_str(&self.sample_statistics[sample_index][cnt].to_string());
-                    }
-                    out_str.push('\n');
-                }
-            }
-            outfile = create_output_file(
-                &run_manager
-                    .report_dir()
-                    .clone()
-                    .join(format!("{}sample_stats", &report_path_main)),
-            );
-            outfile.write_all(&out_str.as_bytes()).unwrap();
-        }
-
-        if reporting_level > 1 {
-            let mut outfile: BufWriter<File>;
-            let mut rep_itr = 0;
-            let mut ambiguous_barcodes_out: Vec<_> = self.ambiguous_barcodes.iter().collect();
-            if ambiguous_barcodes_out.len() > 0 {
-                outfile = get_buf_writer(
-                    &run_manager
-                        .report_dir()
-                        .clone()
-                        .join(format!("{}ambiguous_barcode", &report_path_main)),
-                );
-                ambiguous_barcodes_out.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)).reverse());
-                rep_itr = 0;
-                for barcode in &ambiguous_barcodes_out {
-                    outfile
-                        .write_all(&format!("{}\t{}\n", barcode.0, barcode.1).as_bytes())
-                        .unwrap();
-                    rep_itr += 1;
-                    if rep_itr == report_limit {
-                        break;
-                    }
-                }
-
-                outfile = get_buf_writer(
-                    &run_manager
-                        .report_dir()
-                        .clone()
-                        .join(format!("{}ambiguous_barcode.complete", &report_path_main)),
-                );
-                for barcode in &ambiguous_barcodes_out {
-                    outfile
-                        .write_all(&format!("{}\t{}\n", barcode.0, barcode.1).as_bytes())
-                        .unwrap();
-                }
-            }
-
-            //return;
-
-            let mut undetermined_barcodes_out: Vec<_> = self.undetermined_barcodes.iter().collect();
-            if undetermined_barcodes_out.len() > 0 {
-                outfile = get_buf_writer(
-                    &run_manager
-                        .report_dir()
-                        .clone()
-                        .join(&format!("{}undetermined_barcode", report_path_main)),
-                );
-                undetermined_barcodes_out.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)).reverse());
-                for barcode in &undetermined_barcodes_out {
-                    outfile
-                        .write_all(&format!("{}\t{}\n", barcode.0, barcode.1).as_bytes())
-                        .unwrap();
-                    rep_itr += 1;
-                    if rep_itr == report_limit {
-                        break;
-                    }
-                }
-
-                outfile = get_buf_writer(&run_manager.report_dir().clone().join(&format!(
-                    "{}undetermined_barcode.complete",
-                    report_path_main
-                )));
-                undetermined_barcodes_out.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)).reverse());
-                for barcode in &undetermined_barcodes_out {
-                    outfile
-                        .write_all(&format!("{}\t{}\n", barcode.0, barcode.1).as_bytes())
-                        .unwrap();
-                }
-            }
-        }
-
-        let log_dur = start_logs.elapsed();
-
-        info!(
-            "Writing all logs and reports took {} secs.",
-            log_dur.as_secs()
-        );
-    }
-}
+use crate::file_utils::{create_output_file, get_buf_writer};
+use crate::provenance::write_provenance;
+use crate::sample_manager::split_barcode_indexes;
+use crate::{variables::*, RunManager, SampleManager};
+use itertools::Itertools;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+// The yield at this quality is always kept in the sample statistics (r*_qc_30 columns).
+pub const LEGACY_QUALITY_THRESHOLD: u8 = 30;
+
+pub fn extra_quality_thresholds(quality_thresholds: &[u8]) -> Vec<u8> {
+    // The thresholds that are counted in addition to the legacy one, in the given order.
+    quality_thresholds
+        .iter()
+        .filter(|threshold| **threshold != LEGACY_QUALITY_THRESHOLD)
+        .copied()
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn write_general_info_report(
+    sample_information: &Vec<Vec<String>>,
+    sample_statistics: &Vec<Vec<u64>>,
+    kept_samples: &Vec<usize>,
+    run: &String,
+    lane: &String,
+    output_file: &PathBuf,
+    execluded_samples: &Vec<usize>,
+    quality_thresholds: &[u8],
+    quality_yields: &[Vec<u64>],
+) {
+    //Mb Total Yield: total bases as cnt of r1 + r2
+    //M Total Clusters: number of reads
+    //% bases ≥ Q30	r1 and r2 bases that are greater than 30
+    //Mean Quality:	r1qc + r2qc / cnt or bases in r1 and r2
+    //% Perfect Index: index with 0 mismatches / all reads.
+
+    /*
+        sample_statistics[sample_id]:
+        0: r1 count of qs > 30
+        1: r2 count os qs > 30
+        2: r3 count of (barcode) with qs > 30
+
+        3: r1 count of bases
+        4: r2 count of bases
+        5: r3 count of bases
+
+        6: qs of r1
+        7: qs of r2
+        8:  qs of r3
+        9: read count
+        from 10 to whatever mismatches allowed: is the number oif reads with the iteration mismatch
+
+    */
+    // quality_yields has the counts of the extra thresholds (see ReportManager).
+    let extra_thresholds = extra_quality_thresholds(quality_thresholds);
+    let mut out_str = String::from("#sample general info\nSample ID\tM Clusters");
+    for threshold in quality_thresholds {
+        out_str.push_str(&format!(
+            "\tMb Yield ≥ Q{0}\t% R1 Yield ≥ Q{0}\t% R2 Yield ≥ Q{0}\t% R3 Yield ≥ Q{0}",
+            threshold
+        ));
+    }
+    out_str.push_str("\t% Perfect Index\n");
+    let non_sample = vec!["undetermined".to_string(), "ambiguous".to_string()];
+    let mut all_high_qc;
+    let mut lane_yields: Vec<f64> = vec![0.0; quality_thresholds.len()];
+    let exclude_non_sample = true;
+    let mut outfile;
+    let mut curr_sample_stat: Vec<f64>;
+    let mut tmp_val;
+    let mut sample_id;
+    let mut lane_statistics: Vec<f64> = vec![0 as f64, 0 as f64, 0 as f64, 0 as f64, 0 as f64];
+    let mut sample_statistics_copy = sample_statistics.clone();
+    for sample_id_itr in 0..sample_statistics_copy.len() {
+        sample_statistics_copy[sample_id_itr].push(sample_id_itr as u64);
+    }
+    let mut execluded_reads: f64 = 0.0;
+
+    let mut unique_sample_ids = HashSet::new();
+    for sample_inf in sample_information {
+        unique_sample_ids.insert(sample_inf[SAMPLE_COLUMN].clone());
+    }
+
+    if unique_sample_ids.len() == sample_information.len() {
+        sample_statistics_copy.sort_by(|a, b| {
+            b[9].cmp(&a[9])
+                .then_with(|| a.last().unwrap().cmp(&b.last().unwrap()))
+        });
+    }
+
+    for sample_id_itr in 0..sample_statistics_copy.len() {
+        sample_id = sample_statistics_copy[sample_id_itr]
+            .last()
+            .unwrap()
+            .clone() as usize;
+
+        if kept_samples.len() > 0 && !kept_samples.contains(&sample_id) {
+            continue;
+        }
+        if execluded_samples.contains(&sample_id) {
+            continue;
+        }
+
+        curr_sample_stat = sample_statistics_copy[sample_id_itr]
+            .iter()
+            .map(|x| x.clone() as f64)
+            .collect();
+
+        let mut curr_sample_id: String = sample_information[sample_id][SAMPLE_COLUMN].to_string(); //.split("_").map(|x| x.to_string()).collect::<Vec<String>>()[0].to_lowercase()
+
+        out_str.push_str(&curr_sample_id);
+        out_str.push('\t');
+
+        // M clusters
+        out_str.push_str(&format!("{}", curr_sample_stat[9] / 1000000.0));
+        out_str.push('\t');
+        lane_statistics[0] += curr_sample_stat[3] + curr_sample_stat[4];
+        lane_statistics[1] += curr_sample_stat[9];
+
+        for (threshold_index, threshold) in quality_thresholds.iter().enumerate() {
+            let high_qc = match extra_thresholds.iter().position(|extra| extra == threshold) {
+                None => [
+                    curr_sample_stat[0],
+                    curr_sample_stat[1],
+                    curr_sample_stat[2],
+                ],
+                Some(extra_index) => {
+                    let yields = &quality_yields[sample_id][3 * extra_index..];
+                    [yields[0] as f64, yields[1] as f64, yields[2] as f64]
+                }
+            };
+            all_high_qc = high_qc[0] + high_qc[1];
+            lane_yields[threshold_index] += all_high_qc;
+
+            // Mb Yield > threshold
+            out_str.push_str(&format!("{}", all_high_qc / 1000000.0));
+
+            // % R1, R2 and R3 > threshold
+            for read_index in 0..3 {
+                out_str.push('\t');
+                if curr_sample_stat[3 + read_index] == 0.0 {
+                    tmp_val = 0.0;
+                } else {
+                    tmp_val = high_qc[read_index] / curr_sample_stat[3 + read_index];
+                }
+                out_str.push_str(&format!("{:.3?}", tmp_val * 100.0));
+            }
+            out_str.push('\t');
+        }
+
+        // Perfect index
+        if curr_sample_stat[9] == 0.0 {
+            tmp_val = 0.0;
+        } else {
+            tmp_val = curr_sample_stat[10] / curr_sample_stat[9];
+        }
+
+        out_str.push_str(&format!("{:.3?}", tmp_val * 100.0));
+
+        lane_statistics[3] += curr_sample_stat[6] + curr_sample_stat[7];
+
+        curr_sample_id = curr_sample_id.to_lowercase();
+        if non_sample.contains(&curr_sample_id) {
+            execluded_reads += curr_sample_stat[9] as f64;
+        }
+
+        if !exclude_non_sample || !non_sample.contains(&curr_sample_id) {
+            lane_statistics[4] += curr_sample_stat[10];
+            //println!("{}  ->  {}   {}    {}    {}", sample_information[sample_id][SAMPLE_COLUMN], lane_statistics[4], curr_sample_stat[10], lane_statistics[1], execluded_reads);
+        }
+
+        out_str.push('\n');
+    }
+
+    //output_file_path = Path::new(&output_file);
+    let mut final_out_str =
+        String::from("#Lane statistics\nRun ID-Lane\tMb Total Yield\tM Total Clusters");
+    for threshold in quality_thresholds {
+        final_out_str.push_str(&format!("\t% bases ≥ Q{}", threshold));
+    }
+    final_out_str.push_str("\tMean Quality\t% Perfect Index\n");
+    final_out_str.push_str(&run);
+    final_out_str.push('-');
+    final_out_str.push_str(&lane);
+    final_out_str.push('\t');
+
+    final_out_str.push_str(&format!("{}", lane_statistics[0] / 1000000.0));
+    final_out_str.push('\t');
+
+    final_out_str.push_str(&format!("{}", lane_statistics[1] / 1000000.0));
+    final_out_str.push('\t');
+
+    for lane_yield in &lane_yields {
+        final_out_str.push_str(&format!(
+            "{:.3?}",
+            (lane_yield / lane_statistics[0]) * 100.0
+        ));
+        final_out_str.push('\t');
+    }
+    final_out_str.push_str(&format!("{:.3?}", lane_statistics[3] / lane_statistics[0]));
+    final_out_str.push('\t');
+    final_out_str.push_str(&format!(
+        "{:.3?}",
+        (lane_statistics[4] / (lane_statistics[1] - execluded_reads)) * 100.0
+    ));
+    final_out_str.push('\n');
+
+    final_out_str.push_str(&out_str);
+
+    outfile = create_output_file(output_file);
+    outfile.write_all(&final_out_str.as_bytes()).unwrap();
+}
+
+fn describe_orientation(is_i7_rc: bool, is_i5_rc: bool, swapped: bool) -> String {
+    let mut parts = Vec::new();
+    if swapped {
+        parts.push("i7 and i5 swapped");
+    }
+    match (is_i7_rc, is_i5_rc) {
+        (true, true) => parts.push("i7 and i5 reverse-complemented"),
+        (true, false) => parts.push("i7 reverse-complemented"),
+        (false, true) => parts.push("i5 reverse-complemented"),
+        (false, false) => {}
+    }
+    parts.join(", ")
+}
+
+pub fn write_index_info_report(
+    sample_information: &Vec<Vec<String>>,
+    sample_mismatches: &Vec<Vec<u64>>,
+    kept_samples: &Vec<usize>,
+    max_mismatches: usize,
+    output_file: &PathBuf,
+    execluded_samples: &Vec<usize>,
+) {
+    let mut report_str = String::from("sample");
+
+    for i in 0..max_mismatches {
+        report_str.push('\t');
+        report_str.push_str(&i.to_string());
+        report_str.push_str(&"-mismatches");
+    }
+    report_str.push('\n');
+    let mut sample_mismatches_copy = sample_mismatches.clone();
+    for sample_id_itr in 0..sample_mismatches_copy.len() {
+        sample_mismatches_copy[sample_id_itr].push(sample_id_itr as u64);
+    }
+
+    //sample_mismatches_copy.sort_by(|a, b| b[0].cmp(&a[0]));
+    let mut unique_sample_ids = HashSet::new();
+    for sample_inf in sample_information {
+        unique_sample_ids.insert(sample_inf[SAMPLE_COLUMN].clone());
+    }
+
+    if unique_sample_ids.len() == sample_information.len() {
+        sample_mismatches_copy.sort_by(|a, b| {
+            b[0].cmp(&a[0])
+                .then_with(|| a.last().unwrap().cmp(&b.last().unwrap()))
+        });
+    }
+    //sample_mismatches_copy.sort_by(|a, b| b[0].cmp(&a[0]));
+    for sample_id_itr in 0..sample_mismatches_copy.len() {
+        let sample_index = *sample_mismatches_copy[sample_id_itr].last().unwrap() as usize;
+        if kept_samples.len() > 0 && !kept_samples.contains(&sample_index) {
+            continue;
+        }
+        if execluded_samples.contains(&sample_id_itr) {
+            continue;
+        }
+        report_str.push_str(&sample_information[sample_index][SAMPLE_COLUMN]);
+        for cnt in 1..max_mismatches + 1 {
+            if cnt < sample_mismatches_copy[sample_id_itr].len() {
+                report_str.push('\t');
+                report_str.push_str(&sample_mismatches_copy[sample_id_itr][cnt].to_string());
+            } else {
+                report_str.push_str("\t0");
+            }
+        }
+        report_str.push('\n');
+    }
+
+    //println!("report: {}", output_file_path.display());
+    let mut outfile = create_output_file(&output_file);
+    outfile.write_all(&report_str.as_bytes()).unwrap();
+}
+
+const BASES: [u8; 5] = [b'A', b'C', b'G', b'T', b'N'];
+
+#[derive(Clone, Default)]
+pub struct CycleStatistics {
+    quality_sums: Vec<u64>,
+    base_counts: Vec<[u64; 5]>,
+    quality_histogram: Vec<u64>,
+}
+
+impl CycleStatistics {
+    pub fn update(&mut self, sequence: &[u8], quality: &[u8], quality_offset: u8) {
+        if sequence.len() > self.quality_sums.len() {
+            self.quality_sums.resize(sequence.len(), 0);
+            self.base_counts.resize(sequence.len(), [0; 5]);
+        }
+        for (cycle, (&base, &qs)) in sequence.iter().zip(quality.iter()).enumerate() {
+            let qs = qs.saturating_sub(quality_offset) as usize;
+            self.quality_sums[cycle] += qs as u64;
+            if qs >= self.quality_histogram.len() {
+                self.quality_histogram.resize(qs + 1, 0);
+            }
+            self.quality_histogram[qs] += 1;
+            self.base_counts[cycle][match base {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                _ => 4,
+            }] += 1;
+        }
+    }
+
+    pub fn update_from(&mut self, cycle_statistics: &CycleStatistics) {
+        if cycle_statistics.quality_sums.len() > self.quality_sums.len() {
+            self.quality_sums
+                .resize(cycle_statistics.quality_sums.len(), 0);
+            self.base_counts
+                .resize(cycle_statistics.quality_sums.len(), [0; 5]);
+        }
+        if cycle_statistics.quality_histogram.len() > self.quality_histogram.len() {
+            self.quality_histogram
+                .resize(cycle_statistics.quality_histogram.len(), 0);
+        }
+        for cycle in 0..cycle_statistics.quality_sums.len() {
+            self.quality_sums[cycle] += cycle_statistics.quality_sums[cycle];
+            for base in 0..5 {
+                self.base_counts[cycle][base] += cycle_statistics.base_counts[cycle][base];
+            }
+        }
+        for qs in 0..cycle_statistics.quality_histogram.len() {
+            self.quality_histogram[qs] += cycle_statistics.quality_histogram[qs];
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.quality_sums.len() == 0
+    }
+
+    fn cycle_bases(&self, cycle: usize) -> u64 {
+        self.base_counts[cycle].iter().sum()
+    }
+
+    fn mean_quality(&self, cycle: usize) -> f64 {
+        let bases = self.cycle_bases(cycle);
+        if bases == 0 {
+            0.0
+        } else {
+            self.quality_sums[cycle] as f64 / bases as f64
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ReportManager {
+    total_samples: usize,
+    sample_mismatches: Vec<Vec<u64>>,
+    sample_statistics: Vec<Vec<u64>>,
+    undetermined_barcodes: HashMap<String, u64>,
+    ambiguous_barcodes: HashMap<String, u64>,
+    sample_trimming: Vec<Vec<u64>>,
+    sample_filtering: Vec<Vec<u64>>,
+    report_filtering: bool,
+    cycle_statistics: Vec<[CycleStatistics; 3]>,
+    quality_offset: u8,
+    quality_thresholds: Vec<u8>,
+    quality_yields: Vec<Vec<u64>>,
+}
+
+impl ReportManager {
+    pub fn new(total_samples: usize, allowed_mismatches: usize) -> Self {
+        let mut sample_mismatches: Vec<Vec<u64>> = Vec::new();
+        let mut sample_statistics: Vec<Vec<u64>> = Vec::new();
+        let mut sample_trimming: Vec<Vec<u64>> = Vec::new();
+        let mut sample_filtering: Vec<Vec<u64>> = Vec::new();
+        for _ in 0..total_samples {
+            /*
+                sample_trimming[sample_id]:
+                0: r1 count of reads with trimmed adapter
+                1: r1 count of trimmed bases
+                2: r2 count of reads with trimmed adapter
+                3: r2 count of trimmed bases
+            */
+            sample_trimming.push(vec![0, 0, 0, 0]);
+            /*
+                sample_filtering[sample_id]:
+                0: reads filtered for short insert
+                1: reads filtered for low mean quality
+                2: reads filtered for too many N bases
+            */
+            sample_filtering.push(vec![0, 0, 0]);
+            sample_mismatches.push(vec![0; 2 * allowed_mismatches + 2]);
+            sample_statistics.push(vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            /*
+                sample_statistics[sample_id]:
+                0: r1 count of qs > 30
+                1: r2 count os qs > 30
+                2: r3 count of (barcode) with qs > 30
+
+                3: r1 count of bases
+                4: r2 count of bases
+                5: r3 count of bases
+
+                6: qs of r1
+                7: qs of r2
+                8:  qs of r3
+                9: read count
+                from 10 to whatever mismatches allowed: is the number oif reads with the iteration mismatch
+
+            */
+        }
+        Self {
+            total_samples,
+            sample_mismatches,
+            sample_statistics,
+            undetermined_barcodes: HashMap::new(),
+            ambiguous_barcodes: HashMap::new(),
+            sample_trimming,
+            sample_filtering,
+            report_filtering: false,
+            cycle_statistics: Vec::new(),
+            quality_offset: 33,
+            quality_thresholds: vec![30],
+            quality_yields: Vec::new(),
+        }
+    }
+
+    pub fn set_quality_thresholds(&mut self, quality_offset: u8, quality_thresholds: &[u8]) {
+        /*
+        The legacy threshold (Q30) is always reported in sample_statistics (0, 1 and 2).
+        quality_yields[sample_id] has r1, r2 and r3 counts for each of the extra thresholds.
+        */
+        self.quality_offset = quality_offset;
+        self.quality_thresholds = quality_thresholds.to_vec();
+        let extra_thresholds = extra_quality_thresholds(quality_thresholds).len();
+        if extra_thresholds > 0 {
+            self.quality_yields = vec![vec![0; 3 * extra_thresholds]; self.sample_statistics.len()];
+        }
+    }
+
+    pub fn update_quality_yields(&mut self, sample_id: usize, read_index: usize, counts: &[u64]) {
+        for (threshold_index, count) in counts.iter().enumerate() {
+            self.quality_yields[sample_id][3 * threshold_index + read_index] += count;
+        }
+    }
+
+    pub fn enable_cycle_statistics(&mut self) {
+        // per cycle statistics for r1, r2 and r3 (barcode) of each sample.
+        self.cycle_statistics = vec![Default::default(); self.total_samples];
+    }
+
+    pub fn update_cycle_statistics(
+        &mut self,
+        sample_id: usize,
+        read_index: usize,
+        sequence: &[u8],
+        quality: &[u8],
+    ) {
+        self.cycle_statistics[sample_id][read_index].update(sequence, quality, self.quality_offset);
+    }
+
+    pub fn set_report_filtering(&mut self, report_filtering: bool) {
+        self.report_filtering = report_filtering;
+    }
+
+    pub fn update_stats(&mut self, sample_id: usize, index: usize, increment: u64) {
+        /*debug!(
+            "update stats: sample_id: {}, stat_id: {}, increment: {}",
+            sample_id,
+            index,
+            increment
+        );*/
+        self.sample_statistics[sample_id][index] += increment;
+    }
+
+    pub fn update_trimming(&mut self, sample_id: usize, index: usize, increment: u64) {
+        self.sample_trimming[sample_id][index] += increment;
+    }
+
+    pub fn update_filtering(&mut self, sample_id: usize, index: usize, increment: u64) {
+        self.sample_filtering[sample_id][index] += increment;
+    }
+
+    pub fn add_stats_entry(&mut self) {
+        self.total_samples += 1;
+        self.sample_statistics.push(vec![0; 10]);
+        self.sample_filtering.push(vec![0; 3]);
+        let extra_thresholds = extra_quality_thresholds(&self.quality_thresholds).len();
+        if extra_thresholds > 0 {
+            self.quality_yields.push(vec![0; 3 * extra_thresholds]);
+        }
+    }
+    pub fn add_mismatches_entry(&mut self, size: usize) {
+        self.sample_mismatches.push(vec![0; size]);
+    }
+    pub fn truncate_mismatches(&mut self, size: usize) {
+        for i in 0..self.sample_mismatches.len() {
+            self.sample_mismatches[i].truncate(size);
+        }
+    }
+
+    pub fn update_mismatches(&mut self, sample_id: usize, index: usize, increment: u64) {
+        /*debug!(
+            "update mismatches: sample_id: {}, mismatches: {}, increment: {}",
+            sample_id,
+            index,
+            increment
+        );
+        debug!("update mismatches: {:?}", &self.sample_mismatches[sample_id]);
+        */
+        self.sample_mismatches[sample_id][index] += increment;
+    }
+
+    pub fn update_undetermined(&mut self, curr_barcode: String, increment: u64) {
+        match self.undetermined_barcodes.get_mut(&curr_barcode) {
+            Some(barcode_info) => {
+                *barcode_info += increment;
+            }
+            None => {
+                self.undetermined_barcodes.insert(curr_barcode, increment);
+            }
+        };
+    }
+
+    pub fn update_ambiguous(&mut self, curr_barcode: String, increment: u64) {
+        match self.ambiguous_barcodes.get_mut(&curr_barcode) {
+            Some(barcode_info) => {
+                *barcode_info += increment;
+            }
+            None => {
+                self.ambiguous_barcodes.insert(curr_barcode, increment);
+            }
+        };
+    }
+
+    pub fn get_sample_reads(&self, sample_id: usize) -> u64 {
+        // The total at index 0 is only set at the end of the run.
+        self.sample_mismatches[sample_id][1..].iter().sum()
+    }
+
+    pub fn get_total_reads(&self) -> u64 {
+        let mut reads: u64 = 0;
+        for i in 0..self.total_samples {
+            for j in 1..self.sample_mismatches[i].len() {
+                reads += self.sample_mismatches[i][j];
+            }
+            // filtered reads are not part of the sample counts.
+            reads += self.sample_filtering[i].iter().sum::<u64>();
+        }
+        reads
+    }
+
+    pub fn set_sample_total_reads(&mut self) {
+        for i in 0..self.total_samples {
+            let mut reads: u64 = 0;
+            for j in 1..self.sample_mismatches[i].len() {
+                reads += self.sample_mismatches[i][j];
+            }
+            self.sample_mismatches[i][0] = reads;
+        }
+    }
+
+    pub fn update(&mut self, report_manager: &ReportManager) {
+        if self.sample_mismatches.len() != report_manager.sample_mismatches.len() {
+            error!(
+                "update ReportManager expects source and destination to have same dimentions. found {} vs {}!",
+                self.sample_mismatches.len(),
+                report_manager.sample_mismatches.len()
+            );
+        }
+        for i in 0..self.sample_mismatches.len() {
+            if self.sample_mismatches[i].len() != report_manager.sample_mismatches[i].len() {
+                error!(
+                    "update ReportManager expects source and destination to have same dimentions. found {} vs {}!",
+                    self.sample_mismatches.len(),
+                    report_manager.sample_mismatches.len()
+                );
+            }
+            for j in 0..self.sample_mismatches[i].len() {
+                self.sample_mismatches[i][j] += report_manager.sample_mismatches[i][j];
+            }
+        }
+
+        if self.sample_statistics.len() != report_manager.sample_statistics.len() {
+            error!(
+                "update ReportManager expects source and destination to have same dimentions. found {} vs {}!",
+                self.sample_statistics.len(),
+                report_manager.sample_statistics.len()
+            );
+        }
+        for i in 0..self.sample_statistics.len() {
+            if self.sample_statistics[i].len() != report_manager.sample_statistics[i].len() {
+                error!(
+                    "update ReportManager expects source and destination to have same dimentions. found {} vs {}!",
+                    self.sample_statistics.len(),
+                    report_manager.sample_statistics.len()
+                );
+            }
+            for j in 0..self.sample_statistics[i].len() {
+                self.sample_statistics[i][j] += report_manager.sample_statistics[i][j];
+            }
+        }
+
+        for i in 0..self.sample_trimming.len() {
+            for j in 0..self.sample_trimming[i].len() {
+                self.sample_trimming[i][j] += report_manager.sample_trimming[i][j];
+            }
+        }
+
+        for i in 0..self.sample_filtering.len() {
+            for j in 0..self.sample_filtering[i].len() {
+                self.sample_filtering[i][j] += report_manager.sample_filtering[i][j];
+            }
+        }
+
+        for i in 0..self
+            .quality_yields
+            .len()
+            .min(report_manager.quality_yields.len())
+        {
+            for j in 0..self.quality_yields[i].len() {
+                self.quality_yields[i][j] += report_manager.quality_yields[i][j];
+            }
+        }
+
+        for i in 0..self
+            .cycle_statistics
+            .len()
+            .min(report_manager.cycle_statistics.len())
+        {
+            for read_index in 0..3 {
+                self.cycle_statistics[i][read_index]
+                    .update_from(&report_manager.cycle_statistics[i][read_index]);
+            }
+        }
+
+        for (key, value) in &report_manager.ambiguous_barcodes {
+            self.ambiguous_barcodes
+                .entry(key.clone())
+                .and_modify(|v| {
+                    *v += value;
+                })
+                .or_insert(*value);
+        }
+
+        for (key, value) in &report_manager.undetermined_barcodes {
+            self.undetermined_barcodes
+                .entry(key.clone())
+                .and_modify(|v| {
+                    *v += value;
+                })
+                .or_insert(*value);
+        }
+    }
+
+    pub fn prepare_final_data(&mut self, max_mismatches: usize) {
+        //let max_mismatches = if all_index_error {allowed_mismatches + 1} else {allowed_mismatches * 2 + 1};
+        for sample_id in 0..self.total_samples {
+            // The bases are counted per read, the quality sums are converted to phred scores.
+            for read_index in 0..3 {
+                self.sample_statistics[sample_id][6 + read_index] -=
+                    self.sample_statistics[sample_id][3 + read_index] * self.quality_offset as u64;
+            }
+        }
+        self.append_mismatches_to_statistics(max_mismatches);
+    }
+
+    pub fn append_mismatches_to_statistics(&mut self, max_mismatches: usize) {
+        // The read counts per mismatch follow the statistics in the sample_stats report.
+        for sample_id in 0..self.total_samples {
+            self.sample_statistics[sample_id][9] = self.sample_mismatches[sample_id][0];
+            for cnt in 1..max_mismatches + 1 {
+                self.sample_statistics[sample_id].push(self.sample_mismatches[sample_id][cnt]);
+            }
+        }
+    }
+
+    pub fn write_reports(
+        &self,
+        run_manager: &RunManager,
+        sample_manager: &SampleManager,
+        reporting_level: usize,
+        report_limit: usize,
+        mut max_mismatches: usize,
+        individual_sample: usize,
+    ) {
+        let start_logs = Instant::now();
+        let mut sample_stats_width: usize = self.sample_statistics[0].len();
+        let mut execluded_samples = if individual_sample > self.total_samples {
+            Vec::new()
+        } else {
+            let mut execluded_samples: Vec<usize> = (0..self.total_samples).collect();
+            execluded_samples.remove(individual_sample);
+            execluded_samples
+        };
+
+        //println!("{:?}", execluded_samples);
+        //println!("Mism: {:?}", self.sample_mismatches);
+        //println!("Stat: {:?}", self.sample_statistics);
+
+        if self.sample_mismatches[self.total_samples - 2][0] == 0 {
+            execluded_samples.push(self.total_samples - 2);
+        }
+        if self.sample_mismatches[self.total_samples - 1][0] == 0 {
+            execluded_samples.push(self.total_samples - 1);
+        }
+
+        let mut report_path_main = if individual_sample == usize::MAX {
+            let mut report_path_main = String::from(run_manager.flowcell());
+            report_path_main.push('.');
+            report_path_main.push_str(run_manager.lane());
+            report_path_main
+        } else {
+            for i in (11..self.sample_statistics[0].len()).rev() {
+                if self.sample_statistics[0][i] == 0 {
+                    max_mismatches = i - 10;
+                    sample_stats_width = i;
+                } else {
+                    break;
+                }
+            }
+            String::from(sample_manager.sample_information()[0][SAMPLE_COLUMN].clone())
+        };
+        report_path_main.push_str(&".mgikit.");
+        let mut outfile;
+
+        let mut file_name_extra: String;
+        let project_samples = sample_manager.project_samples();
+        let sample_information = sample_manager.sample_information();
+        if individual_sample == usize::MAX {
+            for (project_id, samples) in project_samples.iter() {
+                if project_id != "." {
+                    info!(
+                        "generating report for job_number: {} with {} samples.",
+                        project_id,
+                        samples.len()
+                    );
+                    file_name_extra = project_id.clone();
+                    file_name_extra.push('_');
+                } else {
+                    info!(
+                        "generating report for the whole run with {} samples.",
+                        self.sample_mismatches.len()
+                    );
+                    file_name_extra = String::new();
+                }
+                let out_file = &run_manager
+                    .report_dir()
+                    .clone()
+                    .join(format!("{}{}info", &file_name_extra, &report_path_main));
+                write_index_info_report(
+                    sample_information,
+                    &self.sample_mismatches,
+                    samples,
+                    max_mismatches,
+                    &out_file,
+                    &execluded_samples,
+                );
+                //Finish writing info report
+
+                if reporting_level > 0 {
+                    let out_file = &run_manager
+                        .report_dir()
+                        .clone()
+                        .join(format!("{}{}general", &file_name_extra, &report_path_main));
+
+                    write_general_info_report(
+                        sample_information,
+                        &self.sample_statistics,
+                        samples,
+                        &run_manager.flowcell(),
+                        &run_manager.lane(),
+                        &out_file,
+                        &execluded_samples,
+                        &self.quality_thresholds,
+                        &self.quality_yields,
+                    );
+                }
+                //start writing general report
+            }
+        }
+        if reporting_level > 0 {
+            let mut out_str = String::from(
+                format!(
+                    "job_number\tsample_id\tr1_qc_30\tr2_qc_30\tr3_qc_30\tr1_bases\tr2_bases\tr3_bases\tr1_qc\tr2_qc\tr3_qc\tall_reads"
+                )
+            );
+            for cnt in 0..max_mismatches {
+                out_str.push('\t');
+                out_str.push_str(&cnt.to_string());
+                out_str.push_str(&"-mismatches");
+            }
+            if self.report_filtering {
+                out_str.push_str("\tfiltered_short\tfiltered_low_quality\tfiltered_n");
+            }
+            // The extra yield thresholds are appended so the legacy columns keep their positions.
+            for threshold in extra_quality_thresholds(&self.quality_thresholds) {
+                out_str.push_str(&format!(
+                    "\tr1_qc_{0}\tr2_qc_{0}\tr3_qc_{0}",
+                    threshold
+                ));
+            }
+            out_str.push('\n');
+            for sample_index in 0..self.sample_statistics.len() {
+                if !execluded_samples.contains(&sample_index) {
+                    out_str.push_str(&sample_information[sample_index][PROJECT_ID_COLUMN]);
+                    out_str.push('\t');
+                    out_str.push_str(&sample_information[sample_index][SAMPLE_COLUMN]);
+                    for cnt in 0..sample_stats_width {
+                        out_str.push('\t');
+                        out_str.push_str(&self.sample_statistics[sample_index][cnt].to_string());
+                    }
+                    if self.report_filtering {
+                        for cnt in 0..self.sample_filtering[sample_index].len() {
+                            out_str.push('\t');
+                            out_str.push_str(&self.sample_filtering[sample_index][cnt].to_string());
+                        }
+                    }
+                    if !self.quality_yields.is_empty() {
+                        for cnt in 0..self.quality_yields[sample_index].len() {
+                            out_str.push('\t');
+                            out_str.push_str(&self.quality_yields[sample_index][cnt].to_string());
+                        }
+                    }
+                    out_str.push('\n');
+                }
+            }
+            outfile = create_output_file(
+                &run_manager
+                    .report_dir()
+                    .clone()
+                    .join(format!("{}sample_stats", &report_path_main)),
+            );
+            outfile.write_all(&out_str.as_bytes()).unwrap();
+
+            if run_manager.trim_adapters() {
+                let mut out_str = String::from(
+                    "job_number\tsample_id\tr1_trimmed_reads\tr1_trimmed_bases\tr2_trimmed_reads\tr2_trimmed_bases\n",
+                );
+                for (sample_index, sample_trimming) in self.sample_trimming.iter().enumerate() {
+                    if !execluded_samples.contains(&sample_index) {
+                        out_str.push_str(&sample_information[sample_index][PROJECT_ID_COLUMN]);
+                        out_str.push('\t');
+                        out_str.push_str(&sample_information[sample_index][SAMPLE_COLUMN]);
+                        for cnt in sample_trimming {
+                            out_str.push('\t');
+                            out_str.push_str(&cnt.to_string());
+                        }
+                        out_str.push('\n');
+                    }
+                }
+                outfile = create_output_file(
+                    &run_manager
+                        .report_dir()
+                        .clone()
+                        .join(format!("{}trimming", &report_path_main)),
+                );
+                outfile.write_all(out_str.as_bytes()).unwrap();
+            }
+        }
+
+        if reporting_level > 1 && !self.cycle_statistics.is_empty() {
+            self.write_cycle_statistics(
+                sample_information,
+                &report_path_main,
+                run_manager.report_dir(),
+                &execluded_samples,
+            );
+        }
+
+        if reporting_level > 1 && run_manager.index_hopping() {
+            self.write_index_hopping(
+                sample_manager,
+                &report_path_main,
+                run_manager.report_dir(),
+                run_manager.lane(),
+                run_manager.allowed_mismatches(),
+            );
+        }
+
+        if reporting_level > 1 {
+            self.write_undetermined_diagnostics(
+                sample_manager,
+                &report_path_main,
+                run_manager.report_dir(),
+            );
+        }
+
+        if reporting_level > 1 && !sample_manager.index_kits().is_empty() {
+            self.write_index_kit_matches(
+                sample_manager,
+                &report_path_main,
+                run_manager.report_dir(),
+                report_limit,
+            );
+        }
+
+        if reporting_level > 1 {
+            let mut outfile: BufWriter<File>;
+            let mut rep_itr = 0;
+            let mut ambiguous_barcodes_out: Vec<_> = self.ambiguous_barcodes.iter().collect();
+            if ambiguous_barcodes_out.len() > 0 {
+                outfile = get_buf_writer(
+                    &run_manager
+                        .report_dir()
+                        .clone()
+                        .join(format!("{}ambiguous_barcode", &report_path_main)),
+                );
+                ambiguous_barcodes_out.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)).reverse());
+                rep_itr = 0;
+                for barcode in &ambiguous_barcodes_out {
+                    outfile
+                        .write_all(&format!("{}\t{}\n", barcode.0, barcode.1).as_bytes())
+                        .unwrap();
+                    rep_itr += 1;
+                    if rep_itr == report_limit {
+                        break;
+                    }
+                }
+
+                outfile = get_buf_writer(
+                    &run_manager
+                        .report_dir()
+                        .clone()
+                        .join(format!("{}ambiguous_barcode.complete", &report_path_main)),
+                );
+                for barcode in &ambiguous_barcodes_out {
+                    outfile
+                        .write_all(&format!("{}\t{}\n", barcode.0, barcode.1).as_bytes())
+                        .unwrap();
+                }
+            }
+
+            //return;
+
+            let mut undetermined_barcodes_out: Vec<_> = self.undetermined_barcodes.iter().collect();
+            if undetermined_barcodes_out.len() > 0 {
+                outfile = get_buf_writer(
+                    &run_manager
+                        .report_dir()
+                        .clone()
+                        .join(&format!("{}undetermined_barcode", report_path_main)),
+                );
+                undetermined_barcodes_out.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)).reverse());
+                for barcode in &undetermined_barcodes_out {
+                    outfile
+                        .write_all(&format!("{}\t{}\n", barcode.0, barcode.1).as_bytes())
+                        .unwrap();
+                    rep_itr += 1;
+                    if rep_itr == report_limit {
+                        break;
+                    }
+                }
+
+                outfile = get_buf_writer(&run_manager.report_dir().clone().join(&format!(
+                    "{}undetermined_barcode.complete",
+                    report_path_main
+                )));
+                undetermined_barcodes_out.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)).reverse());
+                for barcode in &undetermined_barcodes_out {
+                    outfile
+                        .write_all(&format!("{}\t{}\n", barcode.0, barcode.1).as_bytes())
+                        .unwrap();
+                }
+            }
+        }
+
+        write_provenance(
+            run_manager,
+            sample_manager,
+            &run_manager
+                .report_dir()
+                .join(format!("{}provenance.json", &report_path_main)),
+        );
+
+        let log_dur = start_logs.elapsed();
+
+        info!(
+            "Writing all logs and reports took {} secs.",
+            log_dur.as_secs()
+        );
+    }
+
+    fn write_cycle_statistics(
+        &self,
+        sample_information: &[Vec<String>],
+        report_path_main: &String,
+        report_dir: &Path,
+        execluded_samples: &[usize],
+    ) {
+        let read_labels = ["r1", "r2", "r3"];
+        let mut cycle_str = String::from("sample_id\tread\tcycle\tmean_quality\tA\tC\tG\tT\tN\n");
+        let mut histogram_str = String::from("sample_id\tread\tquality\tcount\n");
+        let mut json_samples = serde_json::Map::new();
+        for (sample_index, sample_cycle_statistics) in self.cycle_statistics.iter().enumerate() {
+            if execluded_samples.contains(&sample_index) {
+                continue;
+            }
+            let sample_id = &sample_information[sample_index][SAMPLE_COLUMN];
+            let mut json_reads = serde_json::Map::new();
+            for (read_index, cycle_statistics) in sample_cycle_statistics.iter().enumerate() {
+                if cycle_statistics.is_empty() {
+                    continue;
+                }
+                let mut mean_quality = Vec::new();
+                let mut base_composition: Vec<Vec<u64>> = vec![Vec::new(); 5];
+                for cycle in 0..cycle_statistics.quality_sums.len() {
+                    mean_quality
+                        .push((cycle_statistics.mean_quality(cycle) * 100.0).round() / 100.0);
+                    cycle_str.push_str(&format!(
+                        "{}\t{}\t{}\t{:.2}",
+                        sample_id,
+                        read_labels[read_index],
+                        cycle + 1,
+                        cycle_statistics.mean_quality(cycle)
+                    ));
+                    for (base_counts, count) in base_composition
+                        .iter_mut()
+                        .zip(cycle_statistics.base_counts[cycle].iter())
+                    {
+                        cycle_str.push('\t');
+                        cycle_str.push_str(&count.to_string());
+                        base_counts.push(*count);
+                    }
+                    cycle_str.push('\n');
+                }
+                let mut json_histogram = serde_json::Map::new();
+                for (qs, count) in cycle_statistics.quality_histogram.iter().enumerate() {
+                    if *count > 0 {
+                        histogram_str.push_str(&format!(
+                            "{}\t{}\t{}\t{}\n",
+                            sample_id, read_labels[read_index], qs, count
+                        ));
+                        json_histogram.insert(qs.to_string(), serde_json::json!(count));
+                    }
+                }
+                let mut json_bases = serde_json::Map::new();
+                for base in 0..5 {
+                    json_bases.insert(
+                        (BASES[base] as char).to_string(),
+                        serde_json::json!(base_composition[base]),
+                    );
+                }
+                json_reads.insert(
+                    read_labels[read_index].to_string(),
+                    serde_json::json!({
+                        "mean_quality": mean_quality,
+                        "base_composition": json_bases,
+                        "quality_histogram": json_histogram,
+                    }),
+                );
+            }
+            json_samples.insert(sample_id.clone(), serde_json::Value::Object(json_reads));
+        }
+
+        let mut outfile =
+            create_output_file(&report_dir.join(format!("{}cycle_stats", report_path_main)));
+        outfile.write_all(cycle_str.as_bytes()).unwrap();
+        outfile =
+            create_output_file(&report_dir.join(format!("{}quality_histogram", report_path_main)));
+        outfile.write_all(histogram_str.as_bytes()).unwrap();
+        outfile =
+            create_output_file(&report_dir.join(format!("{}cycle_stats.json", report_path_main)));
+        outfile
+            .write_all(
+                serde_json::to_string_pretty(&serde_json::json!({ "samples": json_samples }))
+                    .unwrap()
+                    .as_bytes(),
+            )
+            .unwrap();
+    }
+
+    fn write_index_hopping(
+        &self,
+        sample_manager: &SampleManager,
+        report_path_main: &String,
+        report_dir: &Path,
+        lane: &String,
+        allowed_mismatches: usize,
+    ) {
+        /*
+        Undetermined reads where both the i7 and the i5 belong to samples of the sheet,
+        but not to the same sample, are considered as index hopping.
+        Each index is matched with up to the allowed mismatches, as when demultiplexing.
+        The hopping rate is the hopped reads out of the assigned and the hopped reads of the lane.
+         */
+        let sample_information = sample_manager.sample_information();
+        let mut index_combinations: Vec<_> = sample_manager
+            .get_index_combinations(&self.undetermined_barcodes, allowed_mismatches)
+            .into_iter()
+            .collect();
+        index_combinations.sort_by(|a, b| (b.1 .2, &a.0).cmp(&(a.1 .2, &b.0)));
+
+        let mut assigned_reads: u64 = 0;
+        for sample_index in 0..self.total_samples - 2 {
+            assigned_reads += self.sample_mismatches[sample_index][0];
+        }
+        let hopped_reads: u64 = index_combinations
+            .iter()
+            .map(|combination| combination.1 .2)
+            .sum();
+        let lane_reads = assigned_reads + hopped_reads;
+        let get_rate = |reads: u64| {
+            if lane_reads == 0 {
+                0.0
+            } else {
+                reads as f64 / lane_reads as f64 * 100.0
+            }
+        };
+        let get_sample_ids = |sample_indexes: &Vec<usize>| {
+            sample_indexes
+                .iter()
+                .map(|sample_index| sample_information[*sample_index][SAMPLE_COLUMN].clone())
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+
+        let mut hopping_str =
+            String::from("lane\ti7\ti5\ti7_samples\ti5_samples\treads\thopping_rate\n");
+        for ((i7, i5), (i7_samples, i5_samples, reads)) in &index_combinations {
+            hopping_str.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{:.3?}\n",
+                lane,
+                i7,
+                i5,
+                get_sample_ids(i7_samples),
+                get_sample_ids(i5_samples),
+                reads,
+                get_rate(*reads)
+            ));
+        }
+        let mut outfile =
+            create_output_file(&report_dir.join(format!("{}index_hopping", report_path_main)));
+        outfile.write_all(hopping_str.as_bytes()).unwrap();
+
+        outfile = create_output_file(
+            &report_dir.join(format!("{}index_hopping_summary", report_path_main)),
+        );
+        outfile
+            .write_all(
+                format!(
+                    "lane\tassigned_reads\thopped_reads\tcombinations\thopping_rate\n{}\t{}\t{}\t{}\t{:.3?}\n",
+                    lane,
+                    assigned_reads,
+                    hopped_reads,
+                    index_combinations.len(),
+                    get_rate(hopped_reads)
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        info!(
+            "{} reads ({:.3?}%) were found with index hopping in {} index combinations.",
+            hopped_reads,
+            get_rate(hopped_reads),
+            index_combinations.len()
+        );
+    }
+
+    fn write_undetermined_diagnostics(
+        &self,
+        sample_manager: &SampleManager,
+        report_path_main: &String,
+        report_dir: &Path,
+    ) {
+        /*
+        Checks the undetermined barcodes against the indexes of each sample reverse complemented
+        and swapped, which is the result of wrong i7_rc/i5_rc in the sample sheet.
+        The file is only written when such barcodes are found.
+         */
+        let undetermined_reads: u64 = self.undetermined_barcodes.values().sum();
+        if undetermined_reads == 0 {
+            return;
+        }
+        let sample_information = sample_manager.sample_information();
+        let mut orientations: Vec<_> = sample_manager
+            .get_index_orientations(&self.undetermined_barcodes)
+            .into_iter()
+            .collect();
+        if orientations.is_empty() {
+            return;
+        }
+        orientations.sort_by(|a, b| (b.1, &a.0).cmp(&(a.1, &b.0)));
+        let get_percentage = |reads: u64| reads as f64 / undetermined_reads as f64 * 100.0;
+
+        let mut diagnostics_str = String::from(
+            "sample_id\ti7_rc\ti5_rc\tswapped\treads\tundetermined_percentage\tdescription\n",
+        );
+        let mut orientation_summary: HashMap<(bool, bool, bool), (usize, u64)> = HashMap::new();
+        let mut warnings = 0;
+        for ((sample_index, is_i7_rc, is_i5_rc, swapped), reads) in &orientations {
+            let sample_id = &sample_information[*sample_index][SAMPLE_COLUMN];
+            let description = describe_orientation(*is_i7_rc, *is_i5_rc, *swapped);
+            diagnostics_str.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{:.3}\t{}\n",
+                sample_id,
+                *is_i7_rc as u8,
+                *is_i5_rc as u8,
+                *swapped as u8,
+                reads,
+                get_percentage(*reads),
+                description
+            ));
+            let summary = orientation_summary
+                .entry((*is_i7_rc, *is_i5_rc, *swapped))
+                .or_insert((0, 0));
+            summary.0 += 1;
+            summary.1 += reads;
+            if get_percentage(*reads) >= MIN_DIAGNOSTIC_PERCENTAGE {
+                if warnings < MAX_DIAGNOSTIC_WARNINGS {
+                    warn!(
+                        "{:.2}% of undetermined reads match sample {} with {}.",
+                        get_percentage(*reads),
+                        sample_id,
+                        description
+                    );
+                }
+                warnings += 1;
+            }
+        }
+        if warnings > MAX_DIAGNOSTIC_WARNINGS {
+            warn!(
+                "{} more samples match undetermined reads with a different index orientation.",
+                warnings - MAX_DIAGNOSTIC_WARNINGS
+            );
+        }
+        for ((is_i7_rc, is_i5_rc, swapped), (samples, reads)) in orientation_summary
+            .iter()
+            .sorted_by(|a, b| (b.1 .1, a.0).cmp(&(a.1 .1, b.0)))
+        {
+            if *samples > 1 && get_percentage(*reads) >= MIN_DIAGNOSTIC_PERCENTAGE {
+                warn!(
+                    "{:.2}% of undetermined reads match {} samples with {}, check the i7_rc/i5_rc settings of the sample sheet!",
+                    get_percentage(*reads),
+                    samples,
+                    describe_orientation(*is_i7_rc, *is_i5_rc, *swapped)
+                );
+            }
+        }
+        let mut outfile = create_output_file(
+            &report_dir.join(format!("{}undetermined_diagnostics", report_path_main)),
+        );
+        outfile.write_all(diagnostics_str.as_bytes()).unwrap();
+    }
+
+    pub fn write_abort_diagnostics(
+        &self,
+        run_manager: &RunManager,
+        sample_manager: &SampleManager,
+        processed_reads: (u64, u64, u64),
+    ) -> PathBuf {
+        /*
+        Writes the reads checked before aborting the run and the top undetermined barcodes, with the
+        samples they match when their indexes are reverse complemented and/or swapped.
+        Returns the path of the diagnostics file.
+         */
+        let (reads, undetermined_reads, ambiguous_reads) = processed_reads;
+        let get_percentage = |count: u64| count as f64 / reads.max(1) as f64 * 100.0;
+        let sample_information = sample_manager.sample_information();
+        let mut diagnostics_str = format!(
+            "# The run was aborted after {} reads with {} ({:.2}%) undetermined and {} ({:.2}%) ambiguous reads, the threshold is {:.2}%.\n",
+            reads,
+            undetermined_reads,
+            get_percentage(undetermined_reads),
+            ambiguous_reads,
+            get_percentage(ambiguous_reads),
+            run_manager.abort_unassigned_fraction() * 100.0
+        );
+        diagnostics_str.push_str("barcode\treads\tpercentage\tsuggestion\n");
+        let mut undetermined_barcodes_out: Vec<_> = self.undetermined_barcodes.iter().collect();
+        undetermined_barcodes_out.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)).reverse());
+        for (barcode, count) in undetermined_barcodes_out
+            .into_iter()
+            .take(MAX_ABORT_DIAGNOSTIC_BARCODES)
+        {
+            let orientations =
+                sample_manager.get_index_orientations(&HashMap::from([(barcode.clone(), *count)]));
+            let suggestion = orientations
+                .keys()
+                .sorted()
+                .map(|(sample_index, is_i7_rc, is_i5_rc, swapped)| {
+                    format!(
+                        "{} with {}",
+                        sample_information[*sample_index][SAMPLE_COLUMN],
+                        describe_orientation(*is_i7_rc, *is_i5_rc, *swapped)
+                    )
+                })
+                .join("; ");
+            diagnostics_str.push_str(&format!(
+                "{}\t{}\t{:.3}\t{}\n",
+                barcode,
+                count,
+                get_percentage(*count),
+                if suggestion.is_empty() {
+                    "."
+                } else {
+                    &suggestion
+                }
+            ));
+        }
+        let diagnostics_path = run_manager.report_dir().join(format!(
+            "{}.{}.mgikit.abort_diagnostics",
+            run_manager.flowcell(),
+            run_manager.lane()
+        ));
+        let mut outfile = create_output_file(&diagnostics_path);
+        outfile.write_all(diagnostics_str.as_bytes()).unwrap();
+        diagnostics_path
+    }
+
+    fn write_index_kit_matches(
+        &self,
+        sample_manager: &SampleManager,
+        report_path_main: &String,
+        report_dir: &Path,
+        report_limit: usize,
+    ) {
+        /*
+        Annotates the top undetermined barcodes with the index kit entries of their i7 and i5.
+        The barcode is split with each template of the run, the first split that matches the kits is reported.
+         */
+        let index_kits = sample_manager.index_kits();
+        let templates_info: Vec<[usize; 10]> = sample_manager
+            .all_template_data()
+            .iter()
+            .map(|template_details| template_details.6)
+            .unique()
+            .collect();
+        let mut undetermined_barcodes_out: Vec<_> = self.undetermined_barcodes.iter().collect();
+        undetermined_barcodes_out.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)).reverse());
+
+        let mut kits_str = String::from("barcode	reads	i7	i5	i7_kit_match	i5_kit_match\n");
+        let mut matched_reads: u64 = 0;
+        let mut rep_itr = 0;
+        for (barcode, count) in &undetermined_barcodes_out {
+            let mut annotation = None;
+            for indexes_info in &templates_info {
+                if let Some((i7, i5)) = split_barcode_indexes(barcode, indexes_info) {
+                    let (i7_match, i5_match) = index_kits.annotate(&i7, &i5);
+                    let found = i7_match != "." || i5_match != ".";
+                    if annotation.is_none() || found {
+                        annotation = Some((i7, i5, i7_match, i5_match));
+                    }
+                    if found {
+                        matched_reads += *count;
+                        break;
+                    }
+                }
+            }
+            if let Some((i7, i5, i7_match, i5_match)) = annotation {
+                kits_str.push_str(&format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\n",
+                    barcode,
+                    count,
+                    i7,
+                    if i5.is_empty() { "." } else { &i5 },
+                    i7_match,
+                    i5_match
+                ));
+            }
+            rep_itr += 1;
+            if rep_itr == report_limit {
+                break;
+            }
+        }
+        let mut outfile = create_output_file(
+            &report_dir.join(format!("{}undetermined_barcode.kits", report_path_main)),
+        );
+        outfile.write_all(kits_str.as_bytes()).unwrap();
+        info!(
+            "{} undetermined reads of the reported barcodes match indexes of the index kits.",
+            matched_reads
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_cycle_statistics() {
+        // Qualities are phred+33, '+' is 10, '5' is 20 and '?' is 30.
+        let mut report_manager = ReportManager::new(3, 1);
+        report_manager.enable_cycle_statistics();
+        report_manager.update_cycle_statistics(0, 0, b"ACGN", b"?5+?");
+        report_manager.update_cycle_statistics(0, 0, b"AC", b"5+");
+        // The statistics of other threads are merged, also when their reads are longer.
+        let mut thread_report_manager = ReportManager::new(3, 1);
+        thread_report_manager.enable_cycle_statistics();
+        thread_report_manager.update_cycle_statistics(0, 0, b"TCG", b"???");
+        thread_report_manager.update_cycle_statistics(0, 2, b"GG", b"55");
+        report_manager.update(&thread_report_manager);
+
+        let report_dir = std::env::temp_dir().join("mgikit_test_cycle_statistics");
+        fs::create_dir_all(&report_dir).unwrap();
+        let sample_information = vec![
+            vec![String::from("S1")],
+            vec![String::from("Undetermined")],
+            vec![String::from("Ambiguous")],
+        ];
+        report_manager.write_cycle_statistics(
+            &sample_information,
+            &String::from("FC01.L01.mgikit."),
+            &report_dir,
+            &[1, 2],
+        );
+        assert_eq!(
+            fs::read_to_string(report_dir.join("FC01.L01.mgikit.cycle_stats")).unwrap(),
+            "sample_id\tread\tcycle\tmean_quality\tA\tC\tG\tT\tN\n\
+             S1\tr1\t1\t26.67\t2\t0\t0\t1\t0\n\
+             S1\tr1\t2\t20.00\t0\t3\t0\t0\t0\n\
+             S1\tr1\t3\t20.00\t0\t0\t2\t0\t0\n\
+             S1\tr1\t4\t30.00\t0\t0\t0\t0\t1\n\
+             S1\tr3\t1\t20.00\t0\t0\t1\t0\t0\n\
+             S1\tr3\t2\t20.00\t0\t0\t1\t0\t0\n"
+        );
+        assert_eq!(
+            fs::read_to_string(report_dir.join("FC01.L01.mgikit.quality_histogram")).unwrap(),
+            "sample_id\tread\tquality\tcount\n\
+             S1\tr1\t10\t2\n\
+             S1\tr1\t20\t2\n\
+             S1\tr1\t30\t5\n\
+             S1\tr3\t20\t2\n"
+        );
+        let cycle_stats: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(report_dir.join("FC01.L01.mgikit.cycle_stats.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            cycle_stats["samples"]["S1"]["r1"]["mean_quality"],
+            serde_json::json!([26.67, 20.0, 20.0, 30.0])
+        );
+        assert!(cycle_stats["samples"]["S1"]["r2"].is_null());
+        fs::remove_dir_all(&report_dir).unwrap();
+    }
+}
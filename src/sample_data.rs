@@ -1,787 +1,1075 @@
-use crate::file_utils::delete_file;
-use crate::{RunManager, SampleManager};
-use getset::CopyGetters;
-use libdeflater::{CompressionLvl, Compressor};
-use log::{debug, info, warn};
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::{path::PathBuf, sync::Mutex};
-
-use crate::variables::*;
-
-const MIN_BUFFER_SIZE: usize = 65536;
-const MAX_BUFFER_SIZE: usize = 536870912;
-const HEADER_TAIL: [u8; 5] = [b':', b'N', b':', b'0', b':'];
-
-pub struct SampleData {
-    label: String,
-    compressor: Compressor,
-    barcode_reads: Option<SampleReads>,
-    paired_reads: Option<SampleReads>,
-    buffer_info: BufferInfo,
-    illumina_header_prefix: String,
-}
-
-impl SampleData {
-    pub fn new(
-        label: String,
-        paired_end: bool,
-        read2_has_sequence: bool,
-        buffer_info: BufferInfo,
-        illumina_header_prefix: String,
-    ) -> Self {
-        Self {
-            label,
-            barcode_reads: match read2_has_sequence {
-                false => None,
-                true => Some(SampleReads::new(
-                    PathBuf::new(),
-                    vec![0; buffer_info.reqiured_output_buffer_size()],
-                    vec![0; buffer_info.compression_buffer_size()],
-                    0,
-                    0,
-                )),
-            },
-            paired_reads: match paired_end {
-                false => None,
-                true => Some(SampleReads::new(
-                    PathBuf::new(),
-                    vec![0; buffer_info.reqiured_output_buffer_size()],
-                    vec![0; buffer_info.compression_buffer_size()],
-                    0,
-                    0,
-                )),
-            },
-            compressor: Compressor::new(
-                CompressionLvl::new(buffer_info.compression_level() as i32).unwrap(),
-            ),
-            buffer_info,
-            illumina_header_prefix,
-        }
-    }
-
-    pub fn minimal_sample() -> Self {
-        Self {
-            label: String::new(),
-            barcode_reads: None,
-            paired_reads: None,
-            compressor: Compressor::new(CompressionLvl::new(1_i32).unwrap()),
-            buffer_info: BufferInfo::default(),
-            illumina_header_prefix: String::new(),
-        }
-    }
-
-    pub fn is_single_end(&self) -> bool {
-        match self.paired_reads {
-            None => true,
-            Some(_) => false,
-        }
-    }
-
-    pub fn read2_has_sequence(&self) -> bool {
-        match self.barcode_reads {
-            None => true,
-            Some(_) => false,
-        }
-    }
-
-    pub fn create_files(
-        &mut self,
-        lane: &String,
-        sample_indx: usize,
-        illumina_format: bool,
-        output_dir: &PathBuf,
-        paired_read_input: bool,
-    ) {
-        let (output_file_r1, output_file_r2) = create_output_file_name(
-            &self.label,
-            &lane,
-            sample_indx,
-            illumina_format,
-            paired_read_input,
-        );
-        //debug!("create files for sample {}: {}, {} with path {}", sample_indx, lane, illumina_format, output_dir.display());
-        match self.paired_reads {
-            Some(ref mut sr) => {
-                sr.output_file = output_dir.join(output_file_r1);
-                //debug!("paired path: {}", sr.output_file.display());
-            }
-            None => {}
-        }
-        match self.barcode_reads {
-            Some(ref mut sr) => {
-                sr.output_file = output_dir.join(output_file_r2);
-                //debug!("barcode path: {}", sr.output_file.display());
-            }
-            None => {}
-        };
-    }
-
-    pub fn delete_sample_files(&self) {
-        match &self.barcode_reads {
-            Some(sr) => {
-                delete_file(&sr.output_file);
-            }
-            None => {}
-        }
-        match &self.paired_reads {
-            Some(sr) => {
-                delete_file(&sr.output_file);
-            }
-            None => {}
-        };
-    }
-
-    pub fn barcode_read_buffer_end(&self) -> usize {
-        match &self.barcode_reads {
-            Some(sr) => sr.out_buffer_last,
-            None => 0,
-        }
-    }
-    pub fn paired_read_buffer_end(&self) -> usize {
-        match &self.paired_reads {
-            Some(sr) => sr.out_buffer_last,
-            None => 0,
-        }
-    }
-
-    pub fn barcode_read_compression_end(&self) -> usize {
-        match &self.barcode_reads {
-            Some(sr) => sr.compression_buffer_last,
-            None => 0,
-        }
-    }
-    pub fn paired_read_compression_end(&self) -> usize {
-        match &self.paired_reads {
-            Some(sr) => sr.compression_buffer_last,
-            None => 0,
-        }
-    }
-
-    pub fn print_buffers(&self, start: usize, end: usize, read: usize) {
-        if read == 0 || read == 2 {
-            match &self.barcode_reads {
-                Some(sr) => {
-                    sr.print_part(start, end);
-                }
-                None => {}
-            }
-        }
-        if read == 0 || read == 1 {
-            match &self.paired_reads {
-                Some(sr) => {
-                    sr.print_part(start, end);
-                }
-                None => {}
-            }
-        }
-    }
-
-    pub fn compress_and_write(&mut self, force: bool, lock: &Mutex<bool>) {
-        //debug!("br_comp_end: {}, pr_comp_end: {}, br_out_end: {}, pr_out_end: {}", self.barcode_read_compression_end(), self.paired_read_compression_end(), self.barcode_read_buffer_end(), self.paired_read_buffer_end());
-        if self.barcode_read_compression_end() >= self.buffer_info.compression_threshold()
-            || self.paired_read_compression_end() >= self.buffer_info.compression_threshold()
-            || force
-        {
-            match self.barcode_reads {
-                Some(ref mut sr) => {
-                    sr.compress(&mut self.compressor);
-                }
-                None => {}
-            }
-            match self.paired_reads {
-                Some(ref mut sr) => {
-                    sr.compress(&mut self.compressor);
-                }
-                None => {}
-            };
-        }
-
-        if self.barcode_read_buffer_end() >= self.buffer_info.writing_threshold()
-            || self.paired_read_buffer_end() >= self.buffer_info.writing_threshold()
-            || force
-        {
-            let _l = lock.lock().unwrap();
-            self.write();
-        }
-    }
-
-    pub fn write(&mut self) {
-        match self.barcode_reads {
-            Some(ref mut sr) => {
-                sr.write();
-            }
-            None => {}
-        }
-        match self.paired_reads {
-            Some(ref mut sr) => {
-                sr.write();
-            }
-            None => {}
-        };
-    }
-
-    pub fn add_barcode_reads(&mut self, data: &[u8]) {
-        match self.barcode_reads {
-            Some(ref mut sr) => {
-                sr.add_reads(data);
-            }
-            None => {}
-        };
-    }
-
-    pub fn add_paired_reads(&mut self, data: &[u8]) {
-        match self.paired_reads {
-            Some(ref mut sr) => {
-                sr.add_reads(data);
-            }
-            None => {}
-        };
-    }
-
-    pub fn write_illumina_header(
-        &mut self,
-        mgi_read_header: &[u8],
-        umi: &[u8],
-        l_position: usize,
-        sep_position: usize,
-        sb_header: bool,
-        barcode_read: bool,
-        paired_read: bool,
-    ) {
-        if barcode_read {
-            match self.barcode_reads {
-                Some(ref mut sr) => {
-                    //debug!("Barcode reads - compre buffer: exp size {} - last {} - actual size: {}", self.buffer_info.compression_buffer_size, sr.compression_buffer_last, sr.compression_buffer.len());
-                    sr.add_reads(&self.illumina_header_prefix.as_bytes());
-                    sr.compression_buffer_last = write_illumina_header(
-                        &mut sr.compression_buffer,
-                        sr.compression_buffer_last,
-                        mgi_read_header,
-                        umi,
-                        l_position,
-                        sep_position,
-                        sb_header,
-                    );
-                }
-                None => {}
-            };
-        }
-        if paired_read {
-            match self.paired_reads {
-                Some(ref mut sr) => {
-                    //debug!("Paired reads - compre buffer: exp size {} - last {} - actual size: {}", self.buffer_info.compression_buffer_size, sr.compression_buffer_last, sr.compression_buffer.len());
-                    sr.add_reads(&self.illumina_header_prefix.as_bytes());
-                    sr.compression_buffer_last = write_illumina_header(
-                        &mut sr.compression_buffer,
-                        sr.compression_buffer_last,
-                        mgi_read_header,
-                        umi,
-                        l_position,
-                        sep_position,
-                        sb_header,
-                    );
-                }
-                None => {}
-            };
-        }
-    }
-
-    pub fn get_barcode_read_compression_end(&self) -> usize {
-        match &self.barcode_reads {
-            Some(sr) => sr.compression_buffer_last,
-            None => usize::MAX,
-        }
-    }
-    pub fn get_paired_read_compression_end(&self) -> usize {
-        match &self.paired_reads {
-            Some(sr) => sr.compression_buffer_last,
-            None => usize::MAX,
-        }
-    }
-    pub fn fix_paired_read_header(&mut self, tail_offset: usize, val: u8) {
-        match self.paired_reads {
-            Some(ref mut sr) => {
-                sr.compression_buffer[sr.compression_buffer_last - tail_offset] = val;
-            }
-            None => {}
-        };
-    }
-
-    pub fn copy_header_2_paired(&mut self, start: usize) {
-        match self.paired_reads {
-            Some(ref mut pr) => {
-                match self.barcode_reads {
-                    Some(ref mut br) => {
-                        pr.compression_buffer[pr.compression_buffer_last
-                            ..pr.compression_buffer_last + br.compression_buffer_last - start]
-                            .copy_from_slice(
-                                &br.compression_buffer[start..br.compression_buffer_last],
-                            );
-                        pr.compression_buffer_last += br.compression_buffer_last - start;
-                    }
-                    None => {}
-                };
-            }
-            None => {}
-        };
-    }
-}
-
-pub struct SampleReads {
-    pub output_file: PathBuf,
-    pub out_buffer: Vec<u8>,
-    pub compression_buffer: Vec<u8>,
-    pub out_buffer_last: usize,
-    pub compression_buffer_last: usize,
-}
-
-impl SampleReads {
-    pub fn new(
-        output_file: PathBuf,
-        out_buffer: Vec<u8>,
-        compression_buffer: Vec<u8>,
-        out_buffer_last: usize,
-        compression_buffer_last: usize,
-    ) -> Self {
-        Self {
-            output_file,
-            out_buffer,
-            compression_buffer,
-            out_buffer_last,
-            compression_buffer_last,
-        }
-    }
-
-    /*
-    pub fn compress_and_write(&mut self, compressor: &mut Compressor, compression_threshold: usize, writing_threshold: usize, force: bool){
-        if self.compression_buffer_last >= compression_threshold || force {
-            self.out_buffer_last = compress_buffer(&self.compression_buffer, self.compression_buffer_last, &mut self.out_buffer, self.out_buffer_last, compressor);
-            self.compression_buffer_last = 0;
-        }
-        if self.out_buffer_last >= writing_threshold || force {
-            write_data(&self.out_buffer, self.out_buffer_last, &self.output_file);
-            self.out_buffer_last = 0;
-        }
-    }
-    */
-
-    pub fn print_part(&self, start: usize, end: usize) {
-        println!(
-            "compression_buffer: *{}*",
-            String::from_utf8(self.compression_buffer[start..end].to_vec()).unwrap()
-        )
-    }
-
-    pub fn compress(&mut self, compressor: &mut Compressor) {
-        self.out_buffer_last = compress_buffer(
-            &self.compression_buffer,
-            self.compression_buffer_last,
-            &mut self.out_buffer,
-            self.out_buffer_last,
-            compressor,
-        );
-        self.compression_buffer_last = 0;
-    }
-
-    pub fn write(&mut self) {
-        write_data(&self.out_buffer, self.out_buffer_last, &self.output_file);
-        self.out_buffer_last = 0;
-    }
-
-    pub fn add_reads(&mut self, data: &[u8]) {
-        //debug!("adding read with length {}", data.len());
-        self.compression_buffer
-            [self.compression_buffer_last..self.compression_buffer_last + data.len()]
-            .copy_from_slice(data);
-        self.compression_buffer_last += data.len();
-    }
-}
-
-#[derive(CopyGetters, Clone, Default)]
-#[getset(get_copy = "pub")]
-pub struct BufferInfo {
-    writing_buffer_size: usize,
-    compression_buffer_size: usize,
-    compression_level: u32,
-    reqiured_output_buffer_size: usize,
-    compression_threshold: usize,
-    writing_threshold: usize,
-}
-
-impl BufferInfo {
-    pub fn new(
-        writing_buffer_size: usize,
-        compression_buffer_size: usize,
-        compression_level: u32,
-        compression_threshold: usize,
-        writing_threshold: usize,
-    ) -> Self {
-        info!("Output buffer size: {}", writing_buffer_size);
-        info!("Compression buffer size: {}", compression_buffer_size);
-        info!(
-            "Compression level: {}. (0 no compression but fast, 12 best compression but slow.)",
-            compression_level
-        );
-        let mut compressor =
-            Compressor::new(CompressionLvl::new(compression_level as i32).unwrap());
-        if compression_buffer_size > writing_buffer_size {
-            panic!(
-                "Compression buffer size '--compression-buffer-size' should be less than Writing buffer size ('--writing-buffer-size')."
-            );
-        }
-        if writing_buffer_size < MIN_BUFFER_SIZE {
-            panic!("Writing buffer size '--writing-buffer-size' will be increased to minimal allowed value ({}).", MIN_BUFFER_SIZE);
-        } else if writing_buffer_size > MAX_BUFFER_SIZE {
-            panic!("Writing buffer size '--writing-buffer-size' will be reduced to the maximum allowed value ({}).", MAX_BUFFER_SIZE);
-        }
-
-        let reqiured_output_buffer_size =
-            writing_buffer_size + compressor.gzip_compress_bound(compression_buffer_size);
-        debug!(
-            "Compression buffer flush threshold: {}",
-            compression_threshold
-        );
-        debug!("Output buffer flush threshold: {}", writing_threshold);
-
-        Self {
-            writing_buffer_size: writing_buffer_size,
-            compression_buffer_size,
-            compression_level,
-            reqiured_output_buffer_size,
-            compression_threshold,
-            writing_threshold,
-        }
-    }
-
-    pub fn calculate_final_writing_buffer_size(&mut self, max_buffer_size: usize) {
-        if self.writing_buffer_size > max_buffer_size {
-            warn!(
-                "Writing buffer size has been reduced to {} as there is no enough memory!",
-                max_buffer_size
-            );
-            self.reqiured_output_buffer_size = max_buffer_size
-                + Compressor::new(CompressionLvl::new(self.compression_level as i32).unwrap())
-                    .gzip_compress_bound(self.compression_buffer_size);
-
-            self.writing_threshold = max_buffer_size;
-        }
-    }
-}
-
-fn compress_buffer(
-    source: &Vec<u8>,
-    source_size: usize,
-    destination: &mut Vec<u8>,
-    destination_size: usize,
-    compressor: &mut Compressor,
-) -> usize {
-    //debug!("Compressing {} bytes, on {}", source_size, destination_size);
-    if source_size > 0 {
-        return destination_size
-            + compressor
-                .gzip_compress(
-                    &source[0..source_size],
-                    &mut destination[destination_size..],
-                )
-                .unwrap();
-    }
-    return destination_size;
-}
-
-fn write_data(out_buffer: &Vec<u8>, buffer_size: usize, output_file_path: &PathBuf) {
-    //debug!("writing {} bytes into {}", buffer_size, output_file_path.display());
-    if buffer_size > 0 {
-        let mut curr_writer = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(output_file_path)
-            .expect("couldn't create output");
-        curr_writer.write_all(&out_buffer[..buffer_size]).unwrap();
-        curr_writer.flush().expect("couldn't flush output");
-    }
-}
-
-pub fn calculate_reqiured_memory(
-    total_samples: usize,
-    writing_buffer_size: usize,
-    compression_buffer_size: usize,
-    single_read_input: bool,
-) -> f64 {
-    if single_read_input {
-        (total_samples * (writing_buffer_size + 2 * compression_buffer_size)) as f64
-    } else {
-        2_f64 * ((total_samples * (writing_buffer_size + 2 * compression_buffer_size)) as f64)
-    }
-}
-
-pub fn calculate_largest_buffer_size(
-    available_memory: f64,
-    mut total_samples: usize,
-    compression_buffer_size: usize,
-    single_end: bool,
-    processors: usize,
-) -> usize {
-    if single_end {
-        total_samples *= 2;
-    }
-    (2_usize).pow(
-        (available_memory / ((total_samples * processors) as f64)
-            - 2_f64 * (compression_buffer_size as f64))
-            .log2()
-            .floor() as u32,
-    )
-}
-
-fn create_output_file_name(
-    sample_name: &String,
-    lane: &String,
-    sample_index: usize,
-    illumina_format: bool,
-    paired_read_input: bool,
-) -> (String, String) {
-    let br_suff = if paired_read_input { "R2" } else { "R1" };
-
-    if illumina_format {
-        if sample_index == usize::MAX {
-            return (
-                format!("{}_{}_R1_001.fastq.gz", sample_name, lane),
-                format!("{}_{}_{}_001.fastq.gz", sample_name, lane, br_suff),
-            );
-        } else {
-            return (
-                format!("{}_S{}_{}_R1_001.fastq.gz", sample_name, sample_index, lane),
-                format!(
-                    "{}_S{}_{}_{}_001.fastq.gz",
-                    sample_name, sample_index, lane, br_suff
-                ),
-            );
-        }
-    } else {
-        return (
-            format!("{}_{}_R1.fastq.gz", sample_name, lane),
-            format!("{}_{}_{}.fastq.gz", sample_name, lane, br_suff),
-        );
-        //return (format!("{}_R1.fastq.gz", sample_name), format!("{}_R2.fastq.gz", sample_name));
-    }
-}
-
-fn write_illumina_header(
-    output_buffer: &mut [u8],
-    mut buffer_end: usize,
-    mgi_read_header: &[u8],
-    umi: &[u8],
-    l_position: usize,
-    sep_position: usize,
-    sb_header: bool,
-) -> usize {
-    output_buffer[buffer_end] = mgi_read_header[l_position + 1];
-    buffer_end += 1;
-
-    output_buffer[buffer_end] = b':';
-    buffer_end += 1;
-
-    for i in l_position + 10..sep_position {
-        if mgi_read_header[i] != b'0' {
-            output_buffer[buffer_end..buffer_end + sep_position - i]
-                .copy_from_slice(&mgi_read_header[i..sep_position]);
-            buffer_end += sep_position - i;
-            break;
-        }
-    }
-
-    output_buffer[buffer_end] = b':';
-    buffer_end += 1;
-
-    if mgi_read_header[l_position + 3] != b'0' {
-        output_buffer[buffer_end] = mgi_read_header[l_position + 3];
-        output_buffer[buffer_end + 1] = mgi_read_header[l_position + 4];
-        output_buffer[buffer_end + 2] = mgi_read_header[l_position + 5];
-        buffer_end += 3;
-    } else if mgi_read_header[l_position + 4] != b'0' {
-        output_buffer[buffer_end] = mgi_read_header[l_position + 4];
-        output_buffer[buffer_end + 1] = mgi_read_header[l_position + 5];
-        buffer_end += 2;
-    } else {
-        output_buffer[buffer_end] = mgi_read_header[l_position + 5];
-        buffer_end += 1;
-    }
-
-    output_buffer[buffer_end] = b':';
-    buffer_end += 1;
-
-    //debug!("")
-    if mgi_read_header[l_position + 7] != b'0' {
-        output_buffer[buffer_end] = mgi_read_header[l_position + 7];
-        output_buffer[buffer_end + 1] = mgi_read_header[l_position + 8];
-        output_buffer[buffer_end + 2] = mgi_read_header[l_position + 9];
-        buffer_end += 3;
-    } else if mgi_read_header[l_position + 8] != b'0' {
-        output_buffer[buffer_end] = mgi_read_header[l_position + 8];
-        output_buffer[buffer_end + 1] = mgi_read_header[l_position + 9];
-        buffer_end += 2;
-    } else {
-        output_buffer[buffer_end] = mgi_read_header[l_position + 9];
-        buffer_end += 1;
-    }
-
-    if sb_header {
-        if umi.len() > 0 {
-            output_buffer[buffer_end] = b':';
-            buffer_end += 1;
-            output_buffer[buffer_end..buffer_end + umi.len()].copy_from_slice(&umi[..]);
-            buffer_end += umi.len();
-        }
-        output_buffer[buffer_end..buffer_end + mgi_read_header.len() - sep_position - 1]
-            .copy_from_slice(&mgi_read_header[sep_position..mgi_read_header.len() - 1]);
-
-        buffer_end += mgi_read_header.len() - sep_position - 1;
-    } else {
-        if umi.len() > 0 {
-            output_buffer[buffer_end..buffer_end + umi.len()].copy_from_slice(&umi[..]);
-            buffer_end += umi.len();
-        }
-        output_buffer[buffer_end] = b' ';
-        buffer_end += 1;
-
-        output_buffer[buffer_end] = mgi_read_header[mgi_read_header.len() - 2];
-        buffer_end += 1;
-
-        output_buffer[buffer_end..buffer_end + 5].copy_from_slice(&HEADER_TAIL[..]);
-        buffer_end += 5;
-    }
-    buffer_end
-}
-
-pub fn create_sample_data_list(
-    sample_manager: &SampleManager,
-    run_manager: &RunManager,
-    buffer_info: &BufferInfo,
-    read2_has_sequence: bool,
-    illumina_format: bool,
-) -> Vec<SampleData> {
-    let mut sample_data_list: Vec<SampleData> = Vec::new();
-    let writing_samples = sample_manager.writing_samples();
-    let unique_samples_ids = sample_manager.unique_samples_ids();
-    let sample_information: &Vec<Vec<String>> = sample_manager.sample_information();
-    let total_samples = sample_manager.get_sample_count();
-    let undetermined_label_id = total_samples - 2;
-    //debug!("creating samples: {} - {} - {} - {}", total_samples, read2_has_sequence, illumina_format, run_manager.output_dir().display());
-    let illumina_header = if illumina_format {
-        run_manager.create_illumina_header_prefix()
-    } else {
-        String::new()
-    };
-    {
-        let mut sample_data: SampleData;
-        for i in 0..total_samples {
-            if writing_samples[i] == i {
-                //debug!("creating complete samples: {} - {}", writing_samples[i], i);
-                sample_data = SampleData::new(
-                    sample_information[i][SAMPLE_COLUMN].clone(),
-                    run_manager.paired_read_input(),
-                    read2_has_sequence || i >= undetermined_label_id,
-                    buffer_info.clone(),
-                    illumina_header.clone(),
-                );
-                if i < undetermined_label_id && illumina_format {
-                    sample_data.create_files(
-                        run_manager.lane(),
-                        unique_samples_ids[i] + 1,
-                        true,
-                        run_manager.output_dir(),
-                        run_manager.paired_read_input(),
-                    );
-                } else if i >= undetermined_label_id && illumina_format {
-                    sample_data.create_files(
-                        run_manager.lane(),
-                        usize::MAX,
-                        true,
-                        run_manager.output_dir(),
-                        run_manager.paired_read_input(),
-                    );
-                } else {
-                    sample_data.create_files(
-                        run_manager.lane(),
-                        unique_samples_ids[i] + 1,
-                        false,
-                        run_manager.output_dir(),
-                        run_manager.paired_read_input(),
-                    );
-                }
-            } else {
-                //debug!("creating empty samples: {} - {}", writing_samples[i], i);
-                sample_data = SampleData::new(
-                    sample_information[i][SAMPLE_COLUMN].clone(),
-                    false,
-                    false,
-                    buffer_info.clone(),
-                    illumina_header.clone(),
-                );
-            }
-            sample_data_list.push(sample_data);
-        }
-    }
-    sample_data_list
-}
-
-pub fn clean_output_directory(
-    sample_manager: &SampleManager,
-    run_manager: &RunManager,
-    buffer_info: &BufferInfo,
-    read2_has_sequence: bool,
-    illumina_format: bool,
-) {
-    let writing_samples = sample_manager.writing_samples();
-    let unique_samples_ids = sample_manager.unique_samples_ids();
-    let sample_information: &Vec<Vec<String>> = sample_manager.sample_information();
-    let total_samples = sample_manager.get_sample_count();
-    let undetermined_label_id = total_samples - 2;
-    let illumina_header = if illumina_format {
-        run_manager.create_illumina_header_prefix()
-    } else {
-        String::new()
-    };
-    {
-        let mut sample_data: SampleData;
-        for i in 0..total_samples {
-            if writing_samples[i] == i {
-                sample_data = SampleData::new(
-                    sample_information[i][SAMPLE_COLUMN].clone(),
-                    run_manager.paired_read_input(),
-                    read2_has_sequence || i >= undetermined_label_id,
-                    buffer_info.clone(),
-                    illumina_header.clone(),
-                );
-                if i < undetermined_label_id && illumina_format {
-                    sample_data.create_files(
-                        run_manager.lane(),
-                        unique_samples_ids[i] + 1,
-                        true,
-                        run_manager.output_dir(),
-                        run_manager.paired_read_input(),
-                    );
-                    sample_data.delete_sample_files();
-                } else if i >= undetermined_label_id && illumina_format {
-                    sample_data.create_files(
-                        run_manager.lane(),
-                        usize::MAX,
-                        true,
-                        run_manager.output_dir(),
-                        run_manager.paired_read_input(),
-                    );
-                    sample_data.delete_sample_files();
-                } else {
-                    sample_data.create_files(
-                        run_manager.lane(),
-                        unique_samples_ids[i] + 1,
-                        false,
-                        run_manager.output_dir(),
-                        run_manager.paired_read_input(),
-                    );
-                    sample_data.delete_sample_files();
-                }
-            }
-        }
-    }
-}
+use crate::file_utils::delete_file;
+use crate::progress::RunProgress;
+use crate::{RunManager, SampleManager};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use getset::CopyGetters;
+use libdeflater::{CompressionLvl, Compressor};
+use log::{debug, info, warn};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::variables::*;
+
+const MIN_BUFFER_SIZE: usize = 65536;
+const MAX_BUFFER_SIZE: usize = 536870912;
+const HEADER_TAIL: [u8; 5] = [b':', b'N', b':', b'0', b':'];
+// Read blocks that can wait in the queue of each writer thread.
+const WRITER_QUEUE_SIZE: usize = 64;
+
+// (sample index, barcode read block, paired read block)
+pub type ReadsBlock = (usize, Vec<u8>, Vec<u8>);
+
+#[derive(Clone)]
+pub struct OutputWriters {
+    block_senders: Vec<Sender<ReadsBlock>>,
+    recycled_blocks: Receiver<Vec<u8>>,
+}
+
+impl OutputWriters {
+    pub fn send(&self, sample_index: usize, barcode_block: Vec<u8>, paired_block: Vec<u8>) {
+        self.block_senders[sample_index % self.block_senders.len()]
+            .send((sample_index, barcode_block, paired_block))
+            .expect("Output writer thread has stopped unexpectedly!");
+    }
+
+    pub fn empty_block(&self) -> Vec<u8> {
+        // The blocks emptied by the writer threads are reused before allocating new ones.
+        self.recycled_blocks.try_recv().unwrap_or_default()
+    }
+}
+
+pub struct SampleData {
+    label: String,
+    barcode_reads: Option<SampleReads>,
+    paired_reads: Option<SampleReads>,
+    buffer_info: BufferInfo,
+    illumina_header_prefix: String,
+}
+
+impl SampleData {
+    pub fn new(
+        label: String,
+        paired_end: bool,
+        read2_has_sequence: bool,
+        buffer_info: BufferInfo,
+        illumina_header_prefix: String,
+    ) -> Self {
+        Self {
+            label,
+            barcode_reads: match read2_has_sequence {
+                false => None,
+                true => Some(SampleReads::new(
+                    PathBuf::new(),
+                    Vec::new(),
+                    Vec::new(),
+                    0,
+                    0,
+                )),
+            },
+            paired_reads: match paired_end {
+                false => None,
+                true => Some(SampleReads::new(
+                    PathBuf::new(),
+                    Vec::new(),
+                    Vec::new(),
+                    0,
+                    0,
+                )),
+            },
+            buffer_info,
+            illumina_header_prefix,
+        }
+    }
+
+    pub fn minimal_sample() -> Self {
+        Self {
+            label: String::new(),
+            barcode_reads: None,
+            paired_reads: None,
+            buffer_info: BufferInfo::default(),
+            illumina_header_prefix: String::new(),
+        }
+    }
+
+    pub fn is_single_end(&self) -> bool {
+        match self.paired_reads {
+            None => true,
+            Some(_) => false,
+        }
+    }
+
+    pub fn read2_has_sequence(&self) -> bool {
+        match self.barcode_reads {
+            None => true,
+            Some(_) => false,
+        }
+    }
+
+    pub fn create_files(
+        &mut self,
+        lane: &String,
+        sample_indx: usize,
+        illumina_format: bool,
+        output_dir: &PathBuf,
+        paired_read_input: bool,
+    ) {
+        let (output_file_r1, output_file_r2) = create_output_file_name(
+            &self.label,
+            &lane,
+            sample_indx,
+            illumina_format,
+            paired_read_input,
+        );
+        //debug!("create files for sample {}: {}, {} with path {}", sample_indx, lane, illumina_format, output_dir.display());
+        match self.paired_reads {
+            Some(ref mut sr) => {
+                sr.output_file = output_dir.join(output_file_r1);
+                //debug!("paired path: {}", sr.output_file.display());
+            }
+            None => {}
+        }
+        match self.barcode_reads {
+            Some(ref mut sr) => {
+                sr.output_file = output_dir.join(output_file_r2);
+                //debug!("barcode path: {}", sr.output_file.display());
+            }
+            None => {}
+        };
+    }
+
+    pub fn delete_sample_files(&self) {
+        match &self.barcode_reads {
+            Some(sr) => {
+                delete_file(&sr.output_file);
+            }
+            None => {}
+        }
+        match &self.paired_reads {
+            Some(sr) => {
+                delete_file(&sr.output_file);
+            }
+            None => {}
+        };
+    }
+
+    pub fn output_files(&self) -> Vec<PathBuf> {
+        let mut output_files = Vec::new();
+        for sample_reads in [&self.paired_reads, &self.barcode_reads]
+            .into_iter()
+            .flatten()
+        {
+            output_files.push(sample_reads.output_file.clone());
+        }
+        output_files
+    }
+
+    pub fn barcode_read_buffer_end(&self) -> usize {
+        match &self.barcode_reads {
+            Some(sr) => sr.out_buffer_last,
+            None => 0,
+        }
+    }
+    pub fn paired_read_buffer_end(&self) -> usize {
+        match &self.paired_reads {
+            Some(sr) => sr.out_buffer_last,
+            None => 0,
+        }
+    }
+
+    pub fn barcode_read_compression_end(&self) -> usize {
+        match &self.barcode_reads {
+            Some(sr) => sr.compression_buffer_last,
+            None => 0,
+        }
+    }
+    pub fn paired_read_compression_end(&self) -> usize {
+        match &self.paired_reads {
+            Some(sr) => sr.compression_buffer_last,
+            None => 0,
+        }
+    }
+
+    pub fn print_buffers(&self, start: usize, end: usize, read: usize) {
+        if read == 0 || read == 2 {
+            match &self.barcode_reads {
+                Some(sr) => {
+                    sr.print_part(start, end);
+                }
+                None => {}
+            }
+        }
+        if read == 0 || read == 1 {
+            match &self.paired_reads {
+                Some(sr) => {
+                    sr.print_part(start, end);
+                }
+                None => {}
+            }
+        }
+    }
+
+    pub fn send_reads(&mut self, force: bool, sample_index: usize, output_writers: &OutputWriters) {
+        /*
+        Hands the buffered reads over to the writer thread that owns the sample and continues with
+        an empty block. Both reads are sent together to keep them paired.
+         */
+        let barcode_read_end = self.barcode_read_compression_end();
+        let paired_read_end = self.paired_read_compression_end();
+        if barcode_read_end >= self.buffer_info.compression_threshold()
+            || paired_read_end >= self.buffer_info.compression_threshold()
+            || (force && (barcode_read_end > 0 || paired_read_end > 0))
+        {
+            let barcode_block = match self.barcode_reads {
+                Some(ref mut sr) => sr.take_block(output_writers.empty_block()),
+                None => Vec::new(),
+            };
+            let paired_block = match self.paired_reads {
+                Some(ref mut sr) => sr.take_block(output_writers.empty_block()),
+                None => Vec::new(),
+            };
+            output_writers.send(sample_index, barcode_block, paired_block);
+        }
+    }
+
+    pub fn add_reads_block(
+        &mut self,
+        barcode_block: &[u8],
+        paired_block: &[u8],
+        compressor: &mut Compressor,
+    ) -> usize {
+        /*
+        Appends the reads sent by a processing thread and compresses them once the compression
+        buffer is full. Returns the compressed bytes.
+         */
+        if let Some(ref mut sr) = self.barcode_reads {
+            sr.reserve(barcode_block.len());
+            sr.add_reads(barcode_block);
+        }
+        if let Some(ref mut sr) = self.paired_reads {
+            sr.reserve(paired_block.len());
+            sr.add_reads(paired_block);
+        }
+        if self.barcode_read_compression_end() >= self.buffer_info.compression_threshold()
+            || self.paired_read_compression_end() >= self.buffer_info.compression_threshold()
+        {
+            let compressed_bytes = self.compress(compressor);
+            if self.barcode_read_buffer_end() >= self.buffer_info.writing_threshold()
+                || self.paired_read_buffer_end() >= self.buffer_info.writing_threshold()
+            {
+                self.write();
+            }
+            return compressed_bytes;
+        }
+        0
+    }
+
+    pub fn compress(&mut self, compressor: &mut Compressor) -> usize {
+        // Both reads are compressed together, one gzip member per read.
+        let mut compressed_bytes = 0;
+        for sample_reads in [&mut self.barcode_reads, &mut self.paired_reads]
+            .into_iter()
+            .flatten()
+        {
+            compressed_bytes += sample_reads.compress(compressor);
+        }
+        compressed_bytes
+    }
+
+    pub fn write(&mut self) {
+        match self.barcode_reads {
+            Some(ref mut sr) => {
+                sr.write();
+            }
+            None => {}
+        }
+        match self.paired_reads {
+            Some(ref mut sr) => {
+                sr.write();
+            }
+            None => {}
+        };
+    }
+
+    pub fn reserve_reads(&mut self, barcode_read_size: usize, paired_read_size: usize) {
+        let header_size = self.illumina_header_prefix.len();
+        if let Some(ref mut sr) = self.barcode_reads {
+            sr.reserve(header_size + barcode_read_size);
+        }
+        if let Some(ref mut sr) = self.paired_reads {
+            sr.reserve(header_size + paired_read_size);
+        }
+    }
+
+    pub fn add_barcode_reads(&mut self, data: &[u8]) {
+        match self.barcode_reads {
+            Some(ref mut sr) => {
+                sr.add_reads(data);
+            }
+            None => {}
+        };
+    }
+
+    pub fn add_paired_reads(&mut self, data: &[u8]) {
+        match self.paired_reads {
+            Some(ref mut sr) => {
+                sr.add_reads(data);
+            }
+            None => {}
+        };
+    }
+
+    pub fn write_illumina_header(
+        &mut self,
+        mgi_read_header: &[u8],
+        umi: &[u8],
+        l_position: usize,
+        sep_position: usize,
+        sb_header: bool,
+        barcode_read: bool,
+        paired_read: bool,
+    ) {
+        if barcode_read {
+            match self.barcode_reads {
+                Some(ref mut sr) => {
+                    //debug!("Barcode reads - compre buffer: exp size {} - last {} - actual size: {}", self.buffer_info.compression_buffer_size, sr.compression_buffer_last, sr.compression_buffer.len());
+                    sr.add_reads(&self.illumina_header_prefix.as_bytes());
+                    sr.compression_buffer_last = write_illumina_header(
+                        &mut sr.compression_buffer,
+                        sr.compression_buffer_last,
+                        mgi_read_header,
+                        umi,
+                        l_position,
+                        sep_position,
+                        sb_header,
+                    );
+                }
+                None => {}
+            };
+        }
+        if paired_read {
+            match self.paired_reads {
+                Some(ref mut sr) => {
+                    //debug!("Paired reads - compre buffer: exp size {} - last {} - actual size: {}", self.buffer_info.compression_buffer_size, sr.compression_buffer_last, sr.compression_buffer.len());
+                    sr.add_reads(&self.illumina_header_prefix.as_bytes());
+                    sr.compression_buffer_last = write_illumina_header(
+                        &mut sr.compression_buffer,
+                        sr.compression_buffer_last,
+                        mgi_read_header,
+                        umi,
+                        l_position,
+                        sep_position,
+                        sb_header,
+                    );
+                }
+                None => {}
+            };
+        }
+    }
+
+    pub fn get_barcode_read_compression_end(&self) -> usize {
+        match &self.barcode_reads {
+            Some(sr) => sr.compression_buffer_last,
+            None => usize::MAX,
+        }
+    }
+    pub fn get_paired_read_compression_end(&self) -> usize {
+        match &self.paired_reads {
+            Some(sr) => sr.compression_buffer_last,
+            None => usize::MAX,
+        }
+    }
+    pub fn fix_paired_read_header(&mut self, tail_offset: usize, val: u8) {
+        match self.paired_reads {
+            Some(ref mut sr) => {
+                sr.compression_buffer[sr.compression_buffer_last - tail_offset] = val;
+            }
+            None => {}
+        };
+    }
+
+    pub fn copy_header_2_paired(&mut self, start: usize) {
+        match self.paired_reads {
+            Some(ref mut pr) => {
+                match self.barcode_reads {
+                    Some(ref mut br) => {
+                        pr.compression_buffer[pr.compression_buffer_last
+                            ..pr.compression_buffer_last + br.compression_buffer_last - start]
+                            .copy_from_slice(
+                                &br.compression_buffer[start..br.compression_buffer_last],
+                            );
+                        pr.compression_buffer_last += br.compression_buffer_last - start;
+                    }
+                    None => {}
+                };
+            }
+            None => {}
+        };
+    }
+}
+
+pub struct SampleReads {
+    pub output_file: PathBuf,
+    pub out_buffer: Vec<u8>,
+    pub compression_buffer: Vec<u8>,
+    pub out_buffer_last: usize,
+    pub compression_buffer_last: usize,
+}
+
+impl SampleReads {
+    pub fn new(
+        output_file: PathBuf,
+        out_buffer: Vec<u8>,
+        compression_buffer: Vec<u8>,
+        out_buffer_last: usize,
+        compression_buffer_last: usize,
+    ) -> Self {
+        Self {
+            output_file,
+            out_buffer,
+            compression_buffer,
+            out_buffer_last,
+            compression_buffer_last,
+        }
+    }
+
+    pub fn print_part(&self, start: usize, end: usize) {
+        println!(
+            "compression_buffer: *{}*",
+            String::from_utf8(self.compression_buffer[start..end].to_vec()).unwrap()
+        )
+    }
+
+    pub fn take_block(&mut self, empty_block: Vec<u8>) -> Vec<u8> {
+        let mut block = std::mem::replace(&mut self.compression_buffer, empty_block);
+        block.truncate(self.compression_buffer_last);
+        self.compression_buffer_last = 0;
+        block
+    }
+
+    pub fn compress(&mut self, compressor: &mut Compressor) -> usize {
+        let out_buffer_start = self.out_buffer_last;
+        let required_size =
+            self.out_buffer_last + compressor.gzip_compress_bound(self.compression_buffer_last);
+        if required_size > self.out_buffer.len() {
+            self.out_buffer.resize(required_size, 0);
+        }
+        self.out_buffer_last = compress_buffer(
+            &self.compression_buffer,
+            self.compression_buffer_last,
+            &mut self.out_buffer,
+            self.out_buffer_last,
+            compressor,
+        );
+        self.compression_buffer_last = 0;
+        self.out_buffer_last - out_buffer_start
+    }
+
+    pub fn write(&mut self) {
+        write_data(&self.out_buffer, self.out_buffer_last, &self.output_file);
+        self.out_buffer_last = 0;
+    }
+
+    pub fn reserve(&mut self, size: usize) {
+        // The buffer grows with the reads, using the capacity of a recycled block first.
+        let required_size = self.compression_buffer_last + size;
+        if required_size > self.compression_buffer.len() {
+            self.compression_buffer
+                .resize(required_size.max(self.compression_buffer.capacity()), 0);
+        }
+    }
+
+    pub fn add_reads(&mut self, data: &[u8]) {
+        //debug!("adding read with length {}", data.len());
+        self.compression_buffer
+            [self.compression_buffer_last..self.compression_buffer_last + data.len()]
+            .copy_from_slice(data);
+        self.compression_buffer_last += data.len();
+    }
+}
+
+#[derive(CopyGetters, Clone, Default)]
+#[getset(get_copy = "pub")]
+pub struct BufferInfo {
+    writing_buffer_size: usize,
+    compression_buffer_size: usize,
+    compression_level: u32,
+    reqiured_output_buffer_size: usize,
+    compression_threshold: usize,
+    writing_threshold: usize,
+}
+
+impl BufferInfo {
+    pub fn new(
+        writing_buffer_size: usize,
+        compression_buffer_size: usize,
+        compression_level: u32,
+        compression_threshold: usize,
+        writing_threshold: usize,
+    ) -> Self {
+        info!("Output buffer size: {}", writing_buffer_size);
+        info!("Compression buffer size: {}", compression_buffer_size);
+        info!(
+            "Compression level: {}. (0 no compression but fast, 12 best compression but slow.)",
+            compression_level
+        );
+        let mut compressor =
+            Compressor::new(CompressionLvl::new(compression_level as i32).unwrap());
+        if compression_buffer_size > writing_buffer_size {
+            panic!(
+                "Compression buffer size '--compression-buffer-size' should be less than Writing buffer size ('--writing-buffer-size')."
+            );
+        }
+        if writing_buffer_size < MIN_BUFFER_SIZE {
+            panic!("Writing buffer size '--writing-buffer-size' will be increased to minimal allowed value ({}).", MIN_BUFFER_SIZE);
+        } else if writing_buffer_size > MAX_BUFFER_SIZE {
+            panic!("Writing buffer size '--writing-buffer-size' will be reduced to the maximum allowed value ({}).", MAX_BUFFER_SIZE);
+        }
+
+        let reqiured_output_buffer_size =
+            writing_buffer_size + compressor.gzip_compress_bound(compression_buffer_size);
+        debug!(
+            "Compression buffer flush threshold: {}",
+            compression_threshold
+        );
+        debug!("Output buffer flush threshold: {}", writing_threshold);
+
+        Self {
+            writing_buffer_size: writing_buffer_size,
+            compression_buffer_size,
+            compression_level,
+            reqiured_output_buffer_size,
+            compression_threshold,
+            writing_threshold,
+        }
+    }
+
+    pub fn calculate_final_writing_buffer_size(&mut self, max_buffer_size: usize) {
+        if self.writing_buffer_size > max_buffer_size {
+            warn!(
+                "Writing buffer size has been reduced to {} as there is no enough memory!",
+                max_buffer_size
+            );
+            self.reqiured_output_buffer_size = max_buffer_size
+                + Compressor::new(CompressionLvl::new(self.compression_level as i32).unwrap())
+                    .gzip_compress_bound(self.compression_buffer_size);
+
+            self.writing_threshold = max_buffer_size;
+        }
+    }
+}
+
+fn compress_buffer(
+    source: &Vec<u8>,
+    source_size: usize,
+    destination: &mut Vec<u8>,
+    destination_size: usize,
+    compressor: &mut Compressor,
+) -> usize {
+    //debug!("Compressing {} bytes, on {}", source_size, destination_size);
+    if source_size > 0 {
+        return destination_size
+            + compressor
+                .gzip_compress(
+                    &source[0..source_size],
+                    &mut destination[destination_size..],
+                )
+                .unwrap();
+    }
+    return destination_size;
+}
+
+fn write_data(out_buffer: &Vec<u8>, buffer_size: usize, output_file_path: &PathBuf) {
+    //debug!("writing {} bytes into {}", buffer_size, output_file_path.display());
+    if buffer_size > 0 {
+        let mut curr_writer = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(output_file_path)
+            .expect("couldn't create output");
+        curr_writer.write_all(&out_buffer[..buffer_size]).unwrap();
+        curr_writer.flush().expect("couldn't flush output");
+    }
+}
+
+pub fn calculate_reqiured_memory(
+    total_samples: usize,
+    writing_buffer_size: usize,
+    compression_buffer_size: usize,
+    single_read_input: bool,
+) -> f64 {
+    // The writer threads keep one writing and one compression buffer per sample.
+    let sample_memory = (total_samples * (writing_buffer_size + compression_buffer_size)) as f64;
+    if single_read_input {
+        sample_memory
+    } else {
+        2_f64 * sample_memory
+    }
+}
+
+pub fn calculate_largest_buffer_size(
+    available_memory: f64,
+    mut total_samples: usize,
+    compression_buffer_size: usize,
+    single_end: bool,
+) -> usize {
+    /*
+    The buffers are owned by the writer threads, once per sample. The processing threads only keep
+    the reads of the batch they are working on, whatever the number of threads.
+     */
+    if single_end {
+        total_samples *= 2;
+    }
+    (2_usize).pow(
+        (available_memory / (total_samples as f64) - compression_buffer_size as f64)
+            .log2()
+            .floor() as u32,
+    )
+}
+
+pub fn start_output_writers(
+    samples_data: Vec<SampleData>,
+    compression_level: u32,
+    writer_threads: usize,
+    progress: Arc<RunProgress>,
+) -> (OutputWriters, Vec<JoinHandle<()>>) {
+    /*
+    Each sample is owned by a single writer thread (sample index modulo writer threads) so the
+    blocks of a file are compressed and appended in the order they were sent. The emptied blocks
+    go back to the processing threads through the recycling channel.
+     */
+    let writer_threads = writer_threads.max(1);
+    let mut writers_samples: Vec<Vec<Option<SampleData>>> = (0..writer_threads)
+        .map(|_| (0..samples_data.len()).map(|_| None).collect())
+        .collect();
+    for (sample_index, sample_data) in samples_data.into_iter().enumerate() {
+        writers_samples[sample_index % writer_threads][sample_index] = Some(sample_data);
+    }
+    let (recycling_sender, recycled_blocks) = bounded(2 * WRITER_QUEUE_SIZE * writer_threads);
+    let mut block_senders = Vec::new();
+    let mut writer_handlers = Vec::new();
+    for (writer_id, writer_samples) in writers_samples.into_iter().enumerate() {
+        let (block_sender, block_receiver) = bounded(WRITER_QUEUE_SIZE);
+        let recycling_sender = recycling_sender.clone();
+        let progress = progress.clone();
+        block_senders.push(block_sender);
+        writer_handlers.push(
+            thread::Builder::new()
+                .name(format!("writer-{}", writer_id))
+                .spawn(move || {
+                    write_reads_blocks(
+                        block_receiver,
+                        recycling_sender,
+                        writer_samples,
+                        compression_level,
+                        &progress,
+                    )
+                })
+                .unwrap(),
+        );
+    }
+    (
+        OutputWriters {
+            block_senders,
+            recycled_blocks,
+        },
+        writer_handlers,
+    )
+}
+
+fn write_reads_blocks(
+    block_receiver: Receiver<ReadsBlock>,
+    recycling_sender: Sender<Vec<u8>>,
+    mut samples_data: Vec<Option<SampleData>>,
+    compression_level: u32,
+    progress: &RunProgress,
+) {
+    let mut compressor = Compressor::new(CompressionLvl::new(compression_level as i32).unwrap());
+    for (sample_index, barcode_block, paired_block) in block_receiver.iter() {
+        match samples_data[sample_index] {
+            Some(ref mut sample_data) => {
+                progress.add_written_bytes(sample_data.add_reads_block(
+                    &barcode_block,
+                    &paired_block,
+                    &mut compressor,
+                ));
+            }
+            None => panic!(
+                "Sample {} is not owned by this writer thread!",
+                sample_index
+            ),
+        }
+        for mut block in [barcode_block, paired_block] {
+            if block.capacity() > 0 {
+                block.clear();
+                // The block is dropped when the processing threads have enough spare blocks.
+                let _ = recycling_sender.try_send(block);
+            }
+        }
+    }
+    for sample_data in samples_data.iter_mut().flatten() {
+        if progress.run_aborted() {
+            sample_data.delete_sample_files();
+        } else {
+            progress.add_written_bytes(sample_data.compress(&mut compressor));
+            sample_data.write();
+        }
+    }
+}
+
+fn create_output_file_name(
+    sample_name: &String,
+    lane: &String,
+    sample_index: usize,
+    illumina_format: bool,
+    paired_read_input: bool,
+) -> (String, String) {
+    let br_suff = if paired_read_input { "R2" } else { "R1" };
+
+    if illumina_format {
+        if sample_index == usize::MAX {
+            return (
+                format!("{}_{}_R1_001.fastq.gz", sample_name, lane),
+                format!("{}_{}_{}_001.fastq.gz", sample_name, lane, br_suff),
+            );
+        } else {
+            return (
+                format!("{}_S{}_{}_R1_001.fastq.gz", sample_name, sample_index, lane),
+                format!(
+                    "{}_S{}_{}_{}_001.fastq.gz",
+                    sample_name, sample_index, lane, br_suff
+                ),
+            );
+        }
+    } else {
+        return (
+            format!("{}_{}_R1.fastq.gz", sample_name, lane),
+            format!("{}_{}_{}.fastq.gz", sample_name, lane, br_suff),
+        );
+        //return (format!("{}_R1.fastq.gz", sample_name), format!("{}_R2.fastq.gz", sample_name));
+    }
+}
+
+fn write_illumina_header(
+    output_buffer: &mut [u8],
+    mut buffer_end: usize,
+    mgi_read_header: &[u8],
+    umi: &[u8],
+    l_position: usize,
+    sep_position: usize,
+    sb_header: bool,
+) -> usize {
+    output_buffer[buffer_end] = mgi_read_header[l_position + 1];
+    buffer_end += 1;
+
+    output_buffer[buffer_end] = b':';
+    buffer_end += 1;
+
+    for i in l_position + 10..sep_position {
+        if mgi_read_header[i] != b'0' {
+            output_buffer[buffer_end..buffer_end + sep_position - i]
+                .copy_from_slice(&mgi_read_header[i..sep_position]);
+            buffer_end += sep_position - i;
+            break;
+        }
+    }
+
+    output_buffer[buffer_end] = b':';
+    buffer_end += 1;
+
+    if mgi_read_header[l_position + 3] != b'0' {
+        output_buffer[buffer_end] = mgi_read_header[l_position + 3];
+        output_buffer[buffer_end + 1] = mgi_read_header[l_position + 4];
+        output_buffer[buffer_end + 2] = mgi_read_header[l_position + 5];
+        buffer_end += 3;
+    } else if mgi_read_header[l_position + 4] != b'0' {
+        output_buffer[buffer_end] = mgi_read_header[l_position + 4];
+        output_buffer[buffer_end + 1] = mgi_read_header[l_position + 5];
+        buffer_end += 2;
+    } else {
+        output_buffer[buffer_end] = mgi_read_header[l_position + 5];
+        buffer_end += 1;
+    }
+
+    output_buffer[buffer_end] = b':';
+    buffer_end += 1;
+
+    //debug!("")
+    if mgi_read_header[l_position + 7] != b'0' {
+        output_buffer[buffer_end] = mgi_read_header[l_position + 7];
+        output_buffer[buffer_end + 1] = mgi_read_header[l_position + 8];
+        output_buffer[buffer_end + 2] = mgi_read_header[l_position + 9];
+        buffer_end += 3;
+    } else if mgi_read_header[l_position + 8] != b'0' {
+        output_buffer[buffer_end] = mgi_read_header[l_position + 8];
+        output_buffer[buffer_end + 1] = mgi_read_header[l_position + 9];
+        buffer_end += 2;
+    } else {
+        output_buffer[buffer_end] = mgi_read_header[l_position + 9];
+        buffer_end += 1;
+    }
+
+    if sb_header {
+        if umi.len() > 0 {
+            output_buffer[buffer_end] = b':';
+            buffer_end += 1;
+            output_buffer[buffer_end..buffer_end + umi.len()].copy_from_slice(&umi[..]);
+            buffer_end += umi.len();
+        }
+        output_buffer[buffer_end..buffer_end + mgi_read_header.len() - sep_position - 1]
+            .copy_from_slice(&mgi_read_header[sep_position..mgi_read_header.len() - 1]);
+
+        buffer_end += mgi_read_header.len() - sep_position - 1;
+    } else {
+        if umi.len() > 0 {
+            output_buffer[buffer_end..buffer_end + umi.len()].copy_from_slice(&umi[..]);
+            buffer_end += umi.len();
+        }
+        output_buffer[buffer_end] = b' ';
+        buffer_end += 1;
+
+        output_buffer[buffer_end] = mgi_read_header[mgi_read_header.len() - 2];
+        buffer_end += 1;
+
+        output_buffer[buffer_end..buffer_end + 5].copy_from_slice(&HEADER_TAIL[..]);
+        buffer_end += 5;
+    }
+    buffer_end
+}
+
+pub fn create_sample_data_list(
+    sample_manager: &SampleManager,
+    run_manager: &RunManager,
+    buffer_info: &BufferInfo,
+    read2_has_sequence: bool,
+    illumina_format: bool,
+) -> Vec<SampleData> {
+    let mut sample_data_list: Vec<SampleData> = Vec::new();
+    let writing_samples = sample_manager.writing_samples();
+    let unique_samples_ids = sample_manager.unique_samples_ids();
+    let sample_information: &Vec<Vec<String>> = sample_manager.sample_information();
+    let total_samples = sample_manager.get_sample_count();
+    let undetermined_label_id = total_samples - 2;
+    //debug!("creating samples: {} - {} - {} - {}", total_samples, read2_has_sequence, illumina_format, run_manager.output_dir().display());
+    let illumina_header = if illumina_format {
+        run_manager.create_illumina_header_prefix()
+    } else {
+        String::new()
+    };
+    {
+        let mut sample_data: SampleData;
+        for i in 0..total_samples {
+            if writing_samples[i] == i {
+                //debug!("creating complete samples: {} - {}", writing_samples[i], i);
+                sample_data = SampleData::new(
+                    sample_information[i][SAMPLE_COLUMN].clone(),
+                    run_manager.paired_read_input(),
+                    read2_has_sequence || i >= undetermined_label_id,
+                    buffer_info.clone(),
+                    illumina_header.clone(),
+                );
+                if i < undetermined_label_id && illumina_format {
+                    sample_data.create_files(
+                        run_manager.lane(),
+                        unique_samples_ids[i] + 1,
+                        true,
+                        run_manager.output_dir(),
+                        run_manager.paired_read_input(),
+                    );
+                } else if i >= undetermined_label_id && illumina_format {
+                    sample_data.create_files(
+                        run_manager.lane(),
+                        usize::MAX,
+                        true,
+                        run_manager.output_dir(),
+                        run_manager.paired_read_input(),
+                    );
+                } else {
+                    sample_data.create_files(
+                        run_manager.lane(),
+                        unique_samples_ids[i] + 1,
+                        false,
+                        run_manager.output_dir(),
+                        run_manager.paired_read_input(),
+                    );
+                }
+            } else {
+                //debug!("creating empty samples: {} - {}", writing_samples[i], i);
+                sample_data = SampleData::new(
+                    sample_information[i][SAMPLE_COLUMN].clone(),
+                    false,
+                    false,
+                    buffer_info.clone(),
+                    illumina_header.clone(),
+                );
+            }
+            sample_data_list.push(sample_data);
+        }
+        for i in 0..run_manager.filtered_samples_count(total_samples) {
+            sample_data_list.push(create_filtered_sample_data(
+                sample_manager,
+                run_manager,
+                buffer_info,
+                read2_has_sequence,
+                &illumina_header,
+                i,
+            ));
+        }
+    }
+    sample_data_list
+}
+
+pub fn list_output_files(
+    sample_manager: &SampleManager,
+    run_manager: &RunManager,
+    buffer_info: &BufferInfo,
+) -> Vec<PathBuf> {
+    // The output files of the samples, including undetermined, ambiguous and filtered reads.
+    create_sample_data_list(
+        sample_manager,
+        run_manager,
+        buffer_info,
+        run_manager.read2_has_sequence(),
+        run_manager.illumina_format(),
+    )
+    .iter()
+    .flat_map(|sample_data| sample_data.output_files())
+    .collect()
+}
+
+fn create_filtered_sample_data(
+    sample_manager: &SampleManager,
+    run_manager: &RunManager,
+    buffer_info: &BufferInfo,
+    read2_has_sequence: bool,
+    illumina_header: &str,
+    filtered_index: usize,
+) -> SampleData {
+    /*
+    The filtered output is either global, or per sample in which case
+    filtered_index refers to the sample index in the sample sheet.
+     */
+    let illumina_format = run_manager.illumina_format();
+    let mut sample_data: SampleData;
+    if run_manager.filtered_reads_output() == "global" {
+        sample_data = SampleData::new(
+            run_manager.filtered_label().clone(),
+            run_manager.paired_read_input(),
+            read2_has_sequence,
+            buffer_info.clone(),
+            illumina_header.to_string(),
+        );
+        sample_data.create_files(
+            run_manager.lane(),
+            usize::MAX,
+            illumina_format,
+            run_manager.output_dir(),
+            run_manager.paired_read_input(),
+        );
+    } else {
+        let label = format!(
+            "{}_{}",
+            sample_manager.sample_information()[filtered_index][SAMPLE_COLUMN],
+            run_manager.filtered_label()
+        );
+        if sample_manager.writing_samples()[filtered_index] == filtered_index {
+            sample_data = SampleData::new(
+                label,
+                run_manager.paired_read_input(),
+                read2_has_sequence,
+                buffer_info.clone(),
+                illumina_header.to_string(),
+            );
+            sample_data.create_files(
+                run_manager.lane(),
+                sample_manager.unique_samples_ids()[filtered_index] + 1,
+                illumina_format,
+                run_manager.output_dir(),
+                run_manager.paired_read_input(),
+            );
+        } else {
+            sample_data = SampleData::new(
+                label,
+                false,
+                false,
+                buffer_info.clone(),
+                illumina_header.to_string(),
+            );
+        }
+    }
+    sample_data
+}
+
+pub fn clean_output_directory(
+    sample_manager: &SampleManager,
+    run_manager: &RunManager,
+    buffer_info: &BufferInfo,
+    read2_has_sequence: bool,
+    illumina_format: bool,
+) {
+    let writing_samples = sample_manager.writing_samples();
+    let unique_samples_ids = sample_manager.unique_samples_ids();
+    let sample_information: &Vec<Vec<String>> = sample_manager.sample_information();
+    let total_samples = sample_manager.get_sample_count();
+    let undetermined_label_id = total_samples - 2;
+    let illumina_header = if illumina_format {
+        run_manager.create_illumina_header_prefix()
+    } else {
+        String::new()
+    };
+    {
+        let mut sample_data: SampleData;
+        for i in 0..total_samples {
+            if writing_samples[i] == i {
+                sample_data = SampleData::new(
+                    sample_information[i][SAMPLE_COLUMN].clone(),
+                    run_manager.paired_read_input(),
+                    read2_has_sequence || i >= undetermined_label_id,
+                    buffer_info.clone(),
+                    illumina_header.clone(),
+                );
+                if i < undetermined_label_id && illumina_format {
+                    sample_data.create_files(
+                        run_manager.lane(),
+                        unique_samples_ids[i] + 1,
+                        true,
+                        run_manager.output_dir(),
+                        run_manager.paired_read_input(),
+                    );
+                    sample_data.delete_sample_files();
+                } else if i >= undetermined_label_id && illumina_format {
+                    sample_data.create_files(
+                        run_manager.lane(),
+                        usize::MAX,
+                        true,
+                        run_manager.output_dir(),
+                        run_manager.paired_read_input(),
+                    );
+                    sample_data.delete_sample_files();
+                } else {
+                    sample_data.create_files(
+                        run_manager.lane(),
+                        unique_samples_ids[i] + 1,
+                        false,
+                        run_manager.output_dir(),
+                        run_manager.paired_read_input(),
+                    );
+                    sample_data.delete_sample_files();
+                }
+            }
+        }
+        for i in 0..run_manager.filtered_samples_count(total_samples) {
+            create_filtered_sample_data(
+                sample_manager,
+                run_manager,
+                buffer_info,
+                read2_has_sequence,
+                &illumina_header,
+                i,
+            )
+            .delete_sample_files();
+        }
+    }
+}
@@ -1,884 +1,1555 @@
-use getset::Getters;
-use crate::variables::*;
-use crate::file_utils::*;
-use core::panic;
-use std::io;
-use std::fs;
-use std::collections::{ HashMap, HashSet };
-use std::path::Path;
-use std::path::PathBuf;
-use log::info;
-use itertools::Itertools;
-
-#[derive(Getters, Clone, Default)]
-pub struct SampleManager {
-    #[getset(get = "pub")]
-    sample_information: Vec<Vec<String>>,
-    #[getset(get = "pub")]
-    project_samples: HashMap<String, Vec<usize>>,
-    #[getset(get = "pub")]
-    writing_samples: Vec<usize>,
-    #[getset(get = "pub")]
-    unique_samples_ids: Vec<usize>,
-    #[getset(get = "pub")]
-    all_template_data: Vec<
-        (
-            u32,
-            HashSet<String>,
-            HashSet<String>,
-            String,
-            HashMap<String, (usize, HashMap<String, usize>)>,
-            bool,
-            [usize; 10],
-        )
-    >,
-}
-
-impl SampleManager {
-    pub fn new<P: Into<PathBuf>>(
-        sample_sheet_path: P,
-        template: String,
-        i7_rc: bool,
-        i5_rc: bool,
-        undetermined_label: String,
-        ambiguous_label: String
-    ) -> Self {
-        let sample_sheet_path = sample_sheet_path.into();
-        if !sample_sheet_path.exists() {
-            panic!("Sample sheet file is invalid!");
-        }
-        check_file(&sample_sheet_path);
-
-        let mut sample_information = load_sample_sheet(&sample_sheet_path).unwrap();
-        info!("{} Samples were found in the input sample sheet.", sample_information.len());
-
-        let (w_s, u_s) = get_writing_unique_samples(&sample_information).unwrap();
-        // parse sample/index file and get all mismatches
-        if template.len() > 0 {
-            info!("General template is provided and will be used for all samples: {}", &template);
-            if i7_rc {
-                info!("i7 will be converted to the reverse complement!");
-            }
-
-            if i5_rc {
-                info!("i5 will be converted to the reverse complement!");
-            }
-        } else {
-            info!("Template will be used from sample/index map file.");
-            //i7_rc = false;
-            //i5_rc = false;
-        }
-
-        let all_template_data = extract_templates_information(
-            &sample_information,
-            &template,
-            i7_rc,
-            i5_rc
-        ).unwrap();
-        if all_template_data.len() > 1 {
-            info!("Mixed library is detected! different barcode templates for some samples!");
-        } else {
-            info!("Same barcode template is used for all samples!");
-        }
-        sample_information.push(
-            vec![
-                undetermined_label.clone(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::from(".")
-            ]
-        );
-        sample_information.push(
-            vec![
-                ambiguous_label.clone(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::from(".")
-            ]
-        );
-
-        Self {
-            project_samples: extract_project_samples(&sample_information).unwrap(),
-            all_template_data,
-            writing_samples: w_s,
-            unique_samples_ids: u_s,
-            sample_information,
-        }
-    }
-
-    pub fn from_simple_sheet<P: Into<PathBuf>>(sample_sheet_path: P) -> Self {
-        let sample_sheet_path = sample_sheet_path.into();
-        if !sample_sheet_path.exists() {
-            panic!("Sample sheet file is invalid!");
-        }
-        check_file(&sample_sheet_path);
-
-        let sample_information = load_sample_sheet(&sample_sheet_path).unwrap();
-        info!("{} Samples were found in the input sample sheet.", sample_information.len());
-        Self {
-            project_samples: extract_project_samples(&sample_information).unwrap(),
-            all_template_data: Vec::new(),
-            writing_samples: Vec::new(),
-            unique_samples_ids: Vec::new(),
-            sample_information,
-        }
-    }
-
-    pub fn dummy_sample(sample_label: String) -> Self {
-        let mut sample_information = Vec::new();
-        sample_information.push(
-            vec![
-                sample_label.clone(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::from(".")
-            ]
-        );
-        sample_information.push(
-            vec![
-                String::from("Undetermined"),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::from(".")
-            ]
-        );
-        sample_information.push(
-            vec![
-                String::from("Ambiguous"),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::from(".")
-            ]
-        );
-
-        let (w_s, u_s) = get_writing_unique_samples(&sample_information).unwrap();
-        // parse sample/index file and get all mismatches
-        Self {
-            project_samples: extract_project_samples(&sample_information).unwrap(),
-            all_template_data: Vec::new(),
-            writing_samples: w_s,
-            unique_samples_ids: u_s,
-            sample_information,
-        }
-    }
-
-    pub fn add_sample(&mut self, sample_label: &String, project_label: &String) {
-        self.sample_information.push(
-            vec![
-                sample_label.clone(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::new(),
-                String::new(),
-                project_label.clone()
-            ]
-        );
-    }
-
-    pub fn pop_sample(&mut self) {
-        self.sample_information.pop();
-    }
-
-    pub fn add_project_sample(&mut self, project: &String, sample_indx: usize) {
-        match self.project_samples.get_mut(project) {
-            Some(samples) => {
-                if !samples.contains(&sample_indx) {
-                    samples.push(sample_indx);
-                }
-            }
-            None => {
-                self.project_samples.insert(project.clone(), vec![sample_indx]);
-            }
-        };
-    }
-
-    pub fn get_sample_count(&self) -> usize {
-        self.sample_information.len().clone()
-    }
-
-    pub fn get_sample_index(&self, sample_id: &String) -> usize {
-        for i in 0..self.sample_information.len() {
-            if self.sample_information[i][0] == *sample_id {
-                return i;
-            }
-        }
-        return usize::MAX;
-    }
-
-    pub fn get_samples_indices(&self) -> Result<Vec<[String; 4]>, io::Error> {
-        let mut sample_indexes: Vec<[String; 4]> = Vec::new();
-        for sample_info in self.sample_information() {
-            //println!("{:?}", &sample_info);
-            if sample_info[I5_COLUMN] != ".".to_string() {
-                sample_indexes.push([
-                    sample_info[I7_COLUMN].to_string(),
-                    reverse_complement(&sample_info[I7_COLUMN]).unwrap(),
-                    sample_info[I5_COLUMN].to_string(),
-                    reverse_complement(&sample_info[I5_COLUMN]).unwrap(),
-                ]);
-            } else {
-                sample_indexes.push([
-                    sample_info[I7_COLUMN].to_string(),
-                    reverse_complement(&sample_info[I7_COLUMN]).unwrap(),
-                    String::new(),
-                    String::new(),
-                ]);
-            }
-        }
-        //println!("{:?}", &sample_indexes);
-        Ok(sample_indexes)
-    }
-}
-
-pub fn parse_template(template: &String) -> Result<[usize; 10], &'static str> {
-    let mut template_ls = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-    //let vals: Vec<&str> = template.rsplit(':').unwrap();
-    let mut shift = 0;
-    let mut length;
-    //debug!("template: {}", template);
-    for templ_item in template.rsplit(':') {
-        if !["i7", "i5", "um", "--"].contains(&&templ_item[0..2]) {
-            panic!("template ({}) does not match the expected format as explianed in mgikit documenation!", template);
-        }
-        length = templ_item[2..]
-            .parse::<usize>()
-            .expect(
-                &format!("template ({}) does not match the expected format as explianed in mgikit documenation!", template)
-            );
-        shift = shift + length;
-        if templ_item[0..2] == *"i7" {
-            template_ls[0] = 1;
-            template_ls[1] = length;
-            template_ls[2] = shift;
-        } else if templ_item[0..2] == *"i5" {
-            template_ls[3] = 1;
-            template_ls[4] = length;
-            template_ls[5] = shift;
-        } else if templ_item[0..2] == *"um" {
-            template_ls[6] = 1;
-            template_ls[7] = length;
-            template_ls[8] = shift;
-        } else if templ_item[0..2] != *"--" {
-            //println!("Should not be here!");
-            return Err("Wrong format for template!");
-        }
-    }
-    template_ls[9] = shift;
-    /*println!("{} -> {}.{}.{}    {}.{}.{}   {}.{}.{}", template, template_ls[0][0],template_ls[0][1],template_ls[0][2],
-    template_ls[1][0],template_ls[1][1],template_ls[1][2],
-    template_ls[2][0],template_ls[2][1],template_ls[2][2]);*/
-
-    Ok(template_ls)
-}
-
-pub fn load_sample_sheet(filename: &Path) -> Result<Vec<Vec<String>>, io::Error> {
-    let mut sample_information: Vec<Vec<String>> = Vec::new();
-    let mut header: Vec<String> = Vec::new();
-    let file_content = fs::read_to_string(filename)?;
-    let lines = file_content.lines();
-    let mut curr_sample_id = usize::MAX;
-    let mut curr_template_id = usize::MAX;
-    let mut curr_i7 = usize::MAX;
-    let mut curr_i5 = usize::MAX;
-    let mut curr_i7_rc = usize::MAX;
-    let mut curr_i5_rc = usize::MAX;
-    let mut curr_project_id = usize::MAX;
-    let mut curr_sample_info: Vec<String>; //= Vec::with_capacity(7);
-    let mut delimiter = '\t';
-    for line in lines {
-        //println!("ZZZ: {}", line);
-        if line.trim().len() < 5 {
-            continue;
-        }
-        if header.len() == 0 {
-            header = line
-                .to_lowercase()
-                .split('\t')
-                .map(|x| x.trim().to_string())
-                .collect();
-            if header.len() < 2 {
-                header = line
-                    .to_lowercase()
-                    .split(',')
-                    .map(|x| x.trim().to_string())
-                    .collect();
-                delimiter = ',';
-                if header.len() < 2 {
-                    panic!("Sample sheet columns should be separated by ',' or '\t'!");
-                }
-            }
-            for header_itr in 0..header.len() {
-                if header[header_itr] == "sample_id" {
-                    curr_sample_id = header_itr;
-                } else if header[header_itr] == "template" {
-                    curr_template_id = header_itr;
-                } else if header[header_itr] == "i7" {
-                    curr_i7 = header_itr;
-                } else if header[header_itr] == "i5" {
-                    curr_i5 = header_itr;
-                } else if header[header_itr] == "i7_rc" {
-                    curr_i7_rc = header_itr;
-                } else if header[header_itr] == "i5_rc" {
-                    curr_i5_rc = header_itr;
-                } else if header[header_itr] == "job_number" {
-                    curr_project_id = header_itr;
-                }
-            }
-        } else {
-            let vals: Vec<String> = line
-                .split(delimiter)
-                .map(|x| x.trim().to_string())
-                .collect();
-            //println!("values of sample: {:?}", vals);
-            curr_sample_info = Vec::with_capacity(7);
-
-            if curr_sample_id == usize::MAX || vals[curr_sample_id].to_string().len() == 0 {
-                panic!(
-                    "sample_id column is mandatory in the samplesheet and must not be an empty string!"
-                );
-            } else {
-                curr_sample_info.push(vals[curr_sample_id].to_string());
-            }
-
-            if curr_i7 == usize::MAX {
-                curr_sample_info.push(".".to_string());
-            } else {
-                if
-                    !vals[curr_i7]
-                        .to_string()
-                        .chars()
-                        .all(|c| ['A', 'C', 'G', 'T'].contains(&c))
-                {
-                    panic!(
-                        "Index must only contain A, C, G and T letters! found '{}'",
-                        vals[curr_i7].to_string()
-                    );
-                }
-                curr_sample_info.push(vals[curr_i7].to_string());
-            }
-
-            if curr_i5 == usize::MAX {
-                curr_sample_info.push(".".to_string());
-            } else {
-                curr_sample_info.push(vals[curr_i5].to_string());
-            }
-
-            if curr_template_id == usize::MAX {
-                curr_sample_info.push(".".to_string());
-            } else {
-                curr_sample_info.push(vals[curr_template_id].to_string());
-            }
-
-            if curr_i7_rc == usize::MAX {
-                curr_sample_info.push(".".to_string());
-            } else {
-                if
-                    vals[curr_i7_rc].to_string() != "." &&
-                    vals[curr_i7_rc].to_string() != "0" &&
-                    vals[curr_i7_rc].to_string() != "1"
-                {
-                    panic!(
-                        "i7_rc must be either '.', '0' or '1' when reverse complementary! found '{}'",
-                        vals[curr_i7_rc].to_string()
-                    );
-                }
-                curr_sample_info.push(vals[curr_i7_rc].to_string());
-            }
-
-            if curr_i5_rc == usize::MAX {
-                curr_sample_info.push(".".to_string());
-            } else {
-                if
-                    vals[curr_i5_rc].to_string() != "." &&
-                    vals[curr_i5_rc].to_string() != "0" &&
-                    vals[curr_i5_rc].to_string() != "1"
-                {
-                    panic!(
-                        "i5_rc must be either '.', '0' or '1' when reverse complementary! found '{}'",
-                        vals[curr_i5_rc].to_string()
-                    );
-                }
-                curr_sample_info.push(vals[curr_i5_rc].to_string());
-            }
-
-            if curr_project_id == usize::MAX {
-                curr_sample_info.push(".".to_string());
-            } else {
-                curr_sample_info.push(vals[curr_project_id].to_string());
-            }
-
-            if curr_sample_info[I7_COLUMN] == "." || curr_sample_info[I7_COLUMN].len() < 3 {
-                panic!("i7 ({}) should be longer than 3 chars!", vals[I7_COLUMN]);
-            }
-
-            if curr_sample_info[I5_COLUMN] != "." {
-                if curr_sample_info[I5_COLUMN].len() < 3 {
-                    panic!("i5 ({}) should be longer than 3 chars!", vals[I5_COLUMN]);
-                }
-                if
-                    !vals[curr_i5]
-                        .to_string()
-                        .chars()
-                        .all(|c| ['A', 'C', 'G', 'T'].contains(&c))
-                {
-                    panic!(
-                        "Index must only contain A, C, G and T letters! found '{}'",
-                        vals[curr_i5].to_string()
-                    );
-                }
-            }
-
-            sample_information.push(curr_sample_info.to_owned());
-        }
-    }
-
-    if sample_information.len() == 0 {
-        panic!("Sample sheet seems to be empty! No sample is found!");
-    }
-
-    Ok(sample_information)
-}
-
-pub fn extract_project_samples(
-    sample_information: &Vec<Vec<String>>
-) -> Result<HashMap<String, Vec<usize>>, io::Error> {
-    //println!("{} -> {}", template, filename.display());
-
-    let mut sample_itr = 0;
-    let mut project_samples: HashMap<String, Vec<usize>> = HashMap::new();
-    project_samples.insert(".".to_string(), Vec::new().to_owned());
-    let mut report_unassigned_samples = false;
-    for curr_sample_info in sample_information {
-        match project_samples.get_mut(&curr_sample_info[PROJECT_ID_COLUMN]) {
-            Some(curr_project_samples) => {
-                curr_project_samples.push(sample_itr);
-            }
-            None => {
-                project_samples.insert(
-                    curr_sample_info[PROJECT_ID_COLUMN].to_owned(),
-                    vec![sample_itr].to_owned()
-                );
-            }
-        }
-
-        if
-            !report_unassigned_samples &&
-            project_samples.len() > 1 &&
-            project_samples["."].len() > 0
-        {
-            info!(
-                "Some samples are not assigned to any project, they will be in the reports of the whole run!"
-            );
-            report_unassigned_samples = true;
-        }
-
-        sample_itr += 1;
-    }
-
-    match project_samples.get_mut(&String::from(".")) {
-        Some(curr_project_samples) => {
-            curr_project_samples.clear();
-        }
-        None => {
-            panic!("There must be a project for the whole run with the '.' name!");
-        }
-    }
-
-    Ok(project_samples)
-}
-
-pub fn extract_templates_information(
-    sample_information: &Vec<Vec<String>>,
-    template: &String,
-    i7_rc: bool,
-    i5_rc: bool
-) -> Result<
-    Vec<
-        (
-            u32,
-            HashSet<String>,
-            HashSet<String>,
-            String,
-            HashMap<String, (usize, HashMap<String, usize>)>,
-            bool,
-            [usize; 10],
-        )
-    >,
-    io::Error
-> {
-    /*
-    template details = (
-                    1,
-                    all i7s indexes,
-                    all i5s indexes,
-                    template string,
-                    (i7, (i5, sample index)) if no i5, it will be i7 and sample id,
-                    boolean to check i5,
-                    template information as where to find the indexes,
-                );
-     */
-    //println!("{} -> {}", template, filename.display());
-    //debug!("{:?}", sample_information);
-    let mut template_ls: HashMap<
-        String,
-        (
-            u32,
-            HashSet<String>,
-            HashSet<String>,
-            String,
-            HashMap<String, (usize, HashMap<String, usize>)>,
-            bool,
-            [usize; 10],
-        )
-    > = HashMap::new();
-
-    let mut sample_itr = 0;
-    let mut barcode_length: usize = 0;
-    for curr_sample_info in sample_information {
-        let curr_template = if template == "" {
-            curr_sample_info[TEMPLATE_COLUMN].clone()
-        } else {
-            template.clone()
-        };
-        //debug!("curr template {}  -> *{}*", curr_template, template);
-
-        if
-            (curr_sample_info[I5_COLUMN] == "." || curr_sample_info[I5_COLUMN].len() < 3) &&
-            curr_template.contains("i5")
-        {
-            panic!(
-                "i5 ({}) should be longer than 3 chars! or the template should not contains i5",
-                curr_sample_info[I5_COLUMN]
-            );
-        }
-
-        let check_i5 = if curr_sample_info[I5_COLUMN].len() > 1 { true } else { false };
-
-        let i7 = if
-            (template.len() > 0 && i7_rc) ||
-            (template.len() == 0 && curr_sample_info[I7_RC_COLUMN] == "1")
-        {
-            //println!("revi7 {}  {}", template, i7_rc);
-            reverse_complement(&curr_sample_info[I7_COLUMN]).unwrap()
-        } else {
-            curr_sample_info[I7_COLUMN].clone()
-        };
-        //println!("I7s: {} -> {}", vals[I7_COLUMN], i7);
-
-        let i5 = if
-            check_i5 &&
-            ((template.len() > 0 && i5_rc) ||
-                (template.len() == 0 && curr_sample_info[I5_RC_COLUMN] == "1"))
-        {
-            reverse_complement(&curr_sample_info[I5_COLUMN]).unwrap()
-        } else if check_i5 {
-            curr_sample_info[I5_COLUMN].clone()
-        } else {
-            String::new()
-        };
-
-        match template_ls.get_mut(&curr_template) {
-            Some(tmp_data) => {
-                tmp_data.0 += 1;
-                tmp_data.1.insert(i7.clone());
-                tmp_data.2.insert(i5.clone());
-
-                match tmp_data.4.get_mut(&i7) {
-                    Some(i7_item) => {
-                        if check_i5 {
-                            match i7_item.1.get_mut(&i5) {
-                                Some(_) =>
-                                    panic!(
-                                        "Two samples having the same indexes! i7: {} and i5: {}",
-                                        &i7,
-                                        &i5
-                                    ),
-                                None => {
-                                    i7_item.1.insert(i5, sample_itr);
-                                }
-                            }
-                        } else {
-                            panic!("Two samples having the same i7 indexes! i7: {}", &i7);
-                        }
-                    }
-                    None => {
-                        if check_i5 {
-                            let mut tmp = HashMap::new();
-                            tmp.insert(i5, sample_itr);
-                            tmp_data.4.insert(i7, (1000, tmp));
-                        } else {
-                            tmp_data.4.insert(i7, (sample_itr, HashMap::new()));
-                        }
-                    }
-                };
-            }
-            None => {
-                let mut i7s = HashSet::new();
-                let mut i5s = HashSet::new();
-                i7s.insert(i7.clone());
-                if check_i5 {
-                    i5s.insert(i5.clone());
-                }
-                let mut sample_info = HashMap::new();
-                if check_i5 {
-                    let mut tmp = HashMap::new();
-                    //let zzz = i5.clone();
-                    //let zzzz = i7.clone();
-                    tmp.insert(i5, sample_itr);
-                    sample_info.insert(i7, (1000, tmp));
-                    //println!("SSSS {:?}", sample_info.get(&zzzz).unwrap().1.get(&zzz).unwrap());
-                } else {
-                    sample_info.insert(i7, (sample_itr, HashMap::new()));
-                }
-
-                let tmp = (
-                    1,
-                    i7s,
-                    i5s,
-                    curr_template.clone(),
-                    sample_info,
-                    check_i5,
-                    parse_template(&curr_template).unwrap(),
-                );
-                if template_ls.len() > 0 {
-                    if tmp.6[9] != barcode_length {
-                        panic!("The barcode length should be the same for all samples!");
-                    }
-                } else {
-                    barcode_length = tmp.6[9];
-                }
-                template_ls.insert(curr_template, tmp);
-            }
-        }
-
-        sample_itr += 1;
-    }
-
-    let mut out_template_data: Vec<_> = template_ls
-        .values()
-        .map(|x| x.to_owned())
-        .collect();
-    if out_template_data.len() > 1 {
-        out_template_data.sort_by(|a, b| {
-            if a.0 == b.0 {
-                b.0.cmp(&a.0)
-                //b.3.cmp(&a.3)
-            } else {
-                b.0.cmp(&a.0)
-            }
-            //a.0.cmp(&b.0).reverse()
-        });
-    }
-
-    Ok(out_template_data)
-}
-
-pub fn get_writing_unique_samples(
-    sample_information: &Vec<Vec<String>>
-) -> Result<(Vec<usize>, Vec<usize>), io::Error> {
-    //println!("{} -> {}", template, filename.display());
-
-    let mut sample_itr = 0;
-    let mut sample_duplicate: HashMap<String, usize> = HashMap::new();
-    let mut writing_samples: Vec<usize> = Vec::new();
-    let mut unique_sample_id: Vec<usize> = Vec::new();
-    let mut dup_ids = 0;
-    let mut curr_unique_id;
-    for curr_sample_info in sample_information {
-        {
-            let duplicate_sample_id = match sample_duplicate.get(&curr_sample_info[SAMPLE_COLUMN]) {
-                Some(dup_id) => {
-                    curr_unique_id = unique_sample_id[*dup_id];
-                    *dup_id
-                }
-                None => {
-                    sample_duplicate.insert(curr_sample_info[SAMPLE_COLUMN].clone(), sample_itr);
-                    curr_unique_id = dup_ids;
-                    dup_ids += 1;
-                    sample_itr
-                }
-            };
-            writing_samples.push(duplicate_sample_id);
-            unique_sample_id.push(curr_unique_id);
-            sample_itr += 1;
-        }
-    }
-
-    //adding undetrmined and ambiguose samples ids.
-    writing_samples.push(writing_samples.len());
-    unique_sample_id.push(unique_sample_id.len());
-    writing_samples.push(writing_samples.len());
-    unique_sample_id.push(unique_sample_id.len());
-
-    Ok((writing_samples, unique_sample_id))
-}
-
-pub fn get_all_mismatches<'a>(
-    all_indexes: &'a HashSet<String>,
-    allowed_mismatches: usize
-) -> HashMap<Vec<u8>, (Vec<&'a String>, usize)> {
-    if all_indexes.len() == 0 {
-        return HashMap::new();
-    }
-
-    let nec_ls = ['A', 'C', 'T', 'G', 'N'];
-    //let mut index_combinations: HashMap<&String, Vec<HashSet<String>>> = HashMap::new();
-
-    let mut final_output: HashMap<String, Vec<Vec<&String>>> = HashMap::new();
-
-    let mut possible_mismatches: HashSet<String>;
-
-    for index in all_indexes {
-        //println!("index: {} -> {:?}", index, "Z");//, final_output.keys());
-        //index_combinations.insert(&index, Vec::new());
-        let mut tmp: Vec<Vec<&String>> = vec![Vec::new(); allowed_mismatches + 1];
-        tmp[0].push(&index);
-        match final_output.get_mut(index) {
-            Some(x) => {
-                *x = tmp;
-                //println!("ovvoride index");
-                //panic!("duplicated index!")
-            }
-            None => {
-                final_output.insert(index.clone(), tmp);
-            }
-        }
-
-        for mismatch_itr in 1..allowed_mismatches + 1 {
-            //println!("Iter: {}", mismatch_itr);
-            possible_mismatches = HashSet::new();
-            let combination_ls = (0..index.len()).combinations(mismatch_itr);
-            for combination in combination_ls {
-                //println!("Z: {:?}", combination);
-
-                let mut all_combinations: Vec<String> = nec_ls
-                    .iter()
-                    .filter(|&i| *i != index.chars().nth(combination[0]).unwrap())
-                    .map(|x| x.to_string())
-                    .collect();
-                //println!("ALL: {:?}", all_combinations);
-                {
-                    for nec_ind in 1..combination.len() {
-                        let ls_nec: Vec<char> = nec_ls
-                            .iter()
-                            .filter(|&i| *i != index.chars().nth(combination[nec_ind]).unwrap())
-                            .cloned()
-                            .collect();
-                        //println!("inner {} -> {:?}", nec_ind, ls_nec);
-
-                        let mut tmp: Vec<String> = Vec::new();
-                        for x in all_combinations {
-                            for y in &ls_nec {
-                                let mut z = x.clone();
-                                z.push(*y);
-                                //println!("merge: {} - {}  -> {}", x, y, z);
-                                tmp.push(z);
-                            }
-                        }
-                        all_combinations = tmp;
-                    }
-
-                    for combination_inner in all_combinations {
-                        let mut index_edited = index.clone();
-
-                        for nec_ind in 0..combination.len() {
-                            //index_edited[combination[nec_ind]] = combination_inner.chars().nth(nec_ind).unwrap();
-                            index_edited.replace_range(
-                                combination[nec_ind]..combination[nec_ind] + 1,
-                                &combination_inner[nec_ind..nec_ind + 1]
-                            );
-                        }
-                        //println!("{}   {}", index, index_edited);
-
-                        match final_output.get_mut(&index_edited) {
-                            Some(x) => {
-                                x[mismatch_itr].push(index);
-                            }
-                            None => {
-                                let mut tmp = Vec::new();
-                                for mis_itr in 0..allowed_mismatches + 1 {
-                                    if mis_itr == mismatch_itr {
-                                        tmp.push(vec![index]);
-                                    } else {
-                                        tmp.push(Vec::new());
-                                    }
-                                }
-                                final_output.insert(index_edited.clone(), tmp);
-                            }
-                        }
-                        possible_mismatches.insert(index_edited);
-                    }
-                }
-            }
-
-            /*match index_combinations.get_mut(&index){
-                Some(x) => {x.push(possible_mismatches);},
-                None => panic!("Something wrong")
-            };*/
-        }
-    }
-
-    let mut sample_index_map = HashMap::new();
-    for (key, value) in final_output {
-        for i in 0..allowed_mismatches + 1 {
-            if value[i].len() > 0 {
-                //let mut tmp_z = value[i].clone();
-                //tmp_z.sort_by(|a, b| a.cmp(b));
-                sample_index_map.insert(key.as_bytes().to_vec(), (value[i].to_owned(), i));
-                break;
-            }
-        }
-    }
-
-    sample_index_map
-}
-
-pub fn reverse_complement(seq: &String) -> Result<String, &'static str> {
-    //println!("rc: *{}*", seq);
-    let mut out_string = String::new();
-    for nec in seq.chars().rev() {
-        out_string.push(match nec {
-            'n' => 'N',
-            'N' => 'N',
-            'A' => 'T',
-            'a' => 'T',
-            'T' => 'A',
-            't' => 'A',
-            'C' => 'G',
-            'c' => 'G',
-            'G' => 'C',
-            'g' => 'C',
-            _ => panic!("Wrong neucltide!"),
-        });
-    }
-    Ok(out_string)
-}
-
-#[cfg(test)]
-mod tests {
-    use super::reverse_complement;
-
-    #[test]
-    fn test_reverse_complement() {
-        assert_eq!(reverse_complement(&String::from("ACGTGCGC")).unwrap(), "GCGCACGT");
-        assert_eq!(reverse_complement(&String::from("GGGGGGGG")).unwrap(), "CCCCCCCC");
-        assert_eq!(reverse_complement(&String::from("ATATATNN")).unwrap(), "NNATATAT");
-        assert_eq!(reverse_complement(&String::from("agcagccc")).unwrap(), "GGGCTGCT");
-    }
-}
+use getset::Getters;
+use crate::variables::*;
+use crate::file_utils::*;
+use crate::index_kits::IndexKits;
+use crate::hamming_distance;
+use core::panic;
+use std::io;
+use std::fs;
+use std::collections::{ HashMap, HashSet };
+use std::path::Path;
+use std::path::PathBuf;
+use log::{ info, warn };
+use itertools::Itertools;
+
+// (sample count, i7 set, i5 set, template, i7 -> (sample, i5 -> sample), dual index, template info)
+pub type TemplateData = (
+    u32,
+    HashSet<String>,
+    HashSet<String>,
+    String,
+    HashMap<String, (usize, HashMap<String, usize>)>,
+    bool,
+    [usize; 10],
+);
+
+// The (sample, i7 rc, i5 rc, swapped) orientations that give each (first, second) index pair.
+type IndexOrientations = HashMap<(String, String), Vec<(usize, bool, bool, bool)>>;
+
+#[derive(Getters, Clone, Default)]
+pub struct SampleManager {
+    #[getset(get = "pub")]
+    sample_information: Vec<Vec<String>>,
+    #[getset(get = "pub")]
+    project_samples: HashMap<String, Vec<usize>>,
+    #[getset(get = "pub")]
+    writing_samples: Vec<usize>,
+    #[getset(get = "pub")]
+    unique_samples_ids: Vec<usize>,
+    #[getset(get = "pub")]
+    all_template_data: Vec<
+        (
+            u32,
+            HashSet<String>,
+            HashSet<String>,
+            String,
+            HashMap<String, (usize, HashMap<String, usize>)>,
+            bool,
+            [usize; 10],
+        )
+    >,
+    #[getset(get = "pub")]
+    index_kits: IndexKits,
+    #[getset(get = "pub")]
+    sample_sheet: PathBuf,
+}
+
+impl SampleManager {
+    pub fn new<P: Into<PathBuf>>(
+        sample_sheet_path: P,
+        template: String,
+        i7_rc: bool,
+        i5_rc: bool,
+        undetermined_label: String,
+        ambiguous_label: String,
+        index_kits: &IndexKits
+    ) -> Self {
+        let sample_sheet_path = sample_sheet_path.into();
+        if !sample_sheet_path.exists() {
+            panic!("Sample sheet file is invalid!");
+        }
+        check_file(&sample_sheet_path);
+
+        let mut problems = SheetProblems::default();
+        let mut sample_information = load_sample_sheet(
+            &sample_sheet_path,
+            index_kits,
+            &mut problems
+        ).unwrap();
+        problems.check();
+        info!("{} Samples were found in the input sample sheet.", sample_information.len());
+
+        let (w_s, u_s) = get_writing_unique_samples(&sample_information, &mut problems).unwrap();
+        // parse sample/index file and get all mismatches
+        if template.len() > 0 {
+            info!("General template is provided and will be used for all samples: {}", &template);
+            if i7_rc {
+                info!("i7 will be converted to the reverse complement!");
+            }
+
+            if i5_rc {
+                info!("i5 will be converted to the reverse complement!");
+            }
+        } else {
+            info!("Template will be used from sample/index map file.");
+            //i7_rc = false;
+            //i5_rc = false;
+        }
+
+        let all_template_data = extract_templates_information(
+            &sample_information,
+            &template,
+            i7_rc,
+            i5_rc,
+            &mut problems
+        ).unwrap();
+        problems.check();
+        if all_template_data.len() > 1 {
+            info!("Mixed library is detected! different barcode templates for some samples!");
+        } else {
+            info!("Same barcode template is used for all samples!");
+        }
+        sample_information.push(
+            vec![
+                undetermined_label.clone(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::from(".")
+            ]
+        );
+        sample_information.push(
+            vec![
+                ambiguous_label.clone(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::from(".")
+            ]
+        );
+
+        Self {
+            project_samples: extract_project_samples(&sample_information).unwrap(),
+            all_template_data,
+            writing_samples: w_s,
+            unique_samples_ids: u_s,
+            sample_information,
+            index_kits: index_kits.clone(),
+            sample_sheet: sample_sheet_path,
+        }
+    }
+
+    pub fn from_simple_sheet<P: Into<PathBuf>>(sample_sheet_path: P, index_kits: &IndexKits) -> Self {
+        let sample_sheet_path = sample_sheet_path.into();
+        if !sample_sheet_path.exists() {
+            panic!("Sample sheet file is invalid!");
+        }
+        check_file(&sample_sheet_path);
+
+        let mut problems = SheetProblems::default();
+        let sample_information = load_sample_sheet(
+            &sample_sheet_path,
+            index_kits,
+            &mut problems
+        ).unwrap();
+        problems.check();
+        info!("{} Samples were found in the input sample sheet.", sample_information.len());
+        Self {
+            project_samples: extract_project_samples(&sample_information).unwrap(),
+            all_template_data: Vec::new(),
+            writing_samples: Vec::new(),
+            unique_samples_ids: Vec::new(),
+            sample_information,
+            index_kits: index_kits.clone(),
+            sample_sheet: sample_sheet_path,
+        }
+    }
+
+    pub fn dummy_sample(sample_label: String) -> Self {
+        let mut sample_information = Vec::new();
+        sample_information.push(
+            vec![
+                sample_label.clone(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::from(".")
+            ]
+        );
+        sample_information.push(
+            vec![
+                String::from("Undetermined"),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::from(".")
+            ]
+        );
+        sample_information.push(
+            vec![
+                String::from("Ambiguous"),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::from(".")
+            ]
+        );
+
+        let (w_s, u_s) = get_writing_unique_samples(
+            &sample_information,
+            &mut SheetProblems::default()
+        ).unwrap();
+        // parse sample/index file and get all mismatches
+        Self {
+            project_samples: extract_project_samples(&sample_information).unwrap(),
+            all_template_data: Vec::new(),
+            writing_samples: w_s,
+            unique_samples_ids: u_s,
+            sample_information,
+            index_kits: IndexKits::default(),
+            sample_sheet: PathBuf::new(),
+        }
+    }
+
+    pub fn add_sample(&mut self, sample_label: &String, project_label: &String) {
+        self.sample_information.push(
+            vec![
+                sample_label.clone(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                project_label.clone()
+            ]
+        );
+    }
+
+    pub fn pop_sample(&mut self) {
+        self.sample_information.pop();
+    }
+
+    pub fn add_project_sample(&mut self, project: &String, sample_indx: usize) {
+        match self.project_samples.get_mut(project) {
+            Some(samples) => {
+                if !samples.contains(&sample_indx) {
+                    samples.push(sample_indx);
+                }
+            }
+            None => {
+                self.project_samples.insert(project.clone(), vec![sample_indx]);
+            }
+        };
+    }
+
+    pub fn get_sample_count(&self) -> usize {
+        self.sample_information.len().clone()
+    }
+
+    pub fn get_sample_templates(&self) -> Vec<Option<(String, String, String)>> {
+        // The template and the i7 and i5 matched in the reads (after any reverse complement) of every sample.
+        let mut sample_templates = vec![None; self.sample_information.len()];
+        for template_data in &self.all_template_data {
+            for (i7, (sample_index, i5_samples)) in &template_data.4 {
+                if i5_samples.is_empty() {
+                    sample_templates[*sample_index] =
+                        Some((template_data.3.clone(), i7.clone(), String::new()));
+                }
+                for (i5, sample_index) in i5_samples {
+                    sample_templates[*sample_index] =
+                        Some((template_data.3.clone(), i7.clone(), i5.clone()));
+                }
+            }
+        }
+        sample_templates
+    }
+
+    pub fn get_sample_index(&self, sample_id: &String) -> usize {
+        for i in 0..self.sample_information.len() {
+            if self.sample_information[i][0] == *sample_id {
+                return i;
+            }
+        }
+        return usize::MAX;
+    }
+
+    pub fn get_samples_indices(&self) -> Result<Vec<[String; 4]>, io::Error> {
+        let mut sample_indexes: Vec<[String; 4]> = Vec::new();
+        for sample_info in self.sample_information() {
+            //println!("{:?}", &sample_info);
+            if sample_info[I5_COLUMN] != ".".to_string() {
+                sample_indexes.push([
+                    sample_info[I7_COLUMN].to_string(),
+                    reverse_complement(&sample_info[I7_COLUMN]).unwrap(),
+                    sample_info[I5_COLUMN].to_string(),
+                    reverse_complement(&sample_info[I5_COLUMN]).unwrap(),
+                ]);
+            } else {
+                sample_indexes.push([
+                    sample_info[I7_COLUMN].to_string(),
+                    reverse_complement(&sample_info[I7_COLUMN]).unwrap(),
+                    String::new(),
+                    String::new(),
+                ]);
+            }
+        }
+        //println!("{:?}", &sample_indexes);
+        Ok(sample_indexes)
+    }
+
+    pub fn check_cross_length_conflicts(&self, allowed_mismatches: usize) -> Vec<String> {
+        /*
+        Samples of a template with shorter indexes can match the reads of a sample from a template
+        with longer indexes when the short indexes are nested within the longer barcode.
+        A conflict is reported when all index positions of the short sample are covered by the
+        indexes of the long sample and they differ by no more than the allowed mismatches.
+         */
+        let mut conflicts = Vec::new();
+        for long_template in &self.all_template_data {
+            let long_length = long_template.6[1] + long_template.6[4];
+            let long_barcodes = get_expected_barcodes(long_template);
+            for short_template in &self.all_template_data {
+                let short_length = short_template.6[1] + short_template.6[4];
+                if short_length >= long_length {
+                    continue;
+                }
+                let short_barcodes = get_expected_barcodes(short_template);
+                for (long_barcode, long_sample) in &long_barcodes {
+                    for (short_barcode, short_sample) in &short_barcodes {
+                        let mut covered = 0;
+                        let mut mismatches = 0;
+                        for (long_base, short_base) in long_barcode.iter().zip(short_barcode.iter()) {
+                            if *short_base == b'.' || *long_base == b'.' {
+                                continue;
+                            }
+                            covered += 1;
+                            if long_base != short_base {
+                                mismatches += 1;
+                            }
+                        }
+                        if covered == short_length && mismatches <= allowed_mismatches {
+                            conflicts.push(
+                                format!(
+                                    "Sample {} ({}) has indexes nested within the barcode of sample {} ({}) with {} mismatches.",
+                                    self.sample_information[*short_sample][SAMPLE_COLUMN],
+                                    short_template.3,
+                                    self.sample_information[*long_sample][SAMPLE_COLUMN],
+                                    long_template.3,
+                                    mismatches
+                                )
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        conflicts
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn get_index_combinations(
+        &self,
+        barcodes: &HashMap<String, u64>,
+        allowed_mismatches: usize
+    ) -> HashMap<(String, String), (Vec<usize>, Vec<usize>, u64)> {
+        /*
+        Resolves the i7 and i5 of each barcode separately against the dual index templates,
+        each index can have up to the allowed mismatches with a single index of the sheet.
+        Barcodes where both indexes resolve to indexes of the same template, but not of the same
+        sample, are reported with the samples of each index and their counts. Combinations are
+        merged over the barcodes using the indexes of the sheet.
+         */
+        let mut index_samples = Vec::new();
+        for template_details in &self.all_template_data {
+            if !template_details.5 {
+                continue;
+            }
+            let mut i7_samples: HashMap<&String, Vec<usize>> = HashMap::new();
+            let mut i5_samples: HashMap<&String, Vec<usize>> = HashMap::new();
+            for (i7, i7_info) in &template_details.4 {
+                for (i5, sample_index) in &i7_info.1 {
+                    i7_samples.entry(i7).or_default().push(*sample_index);
+                    i5_samples.entry(i5).or_default().push(*sample_index);
+                }
+            }
+            index_samples.push((template_details.6, i7_samples, i5_samples));
+        }
+
+        let mut index_combinations: HashMap<
+            (String, String),
+            (Vec<usize>, Vec<usize>, u64)
+        > = HashMap::new();
+        for (barcode, count) in barcodes {
+            for (indexes_info, i7_samples, i5_samples) in &index_samples {
+                let (i7, i5) = match split_barcode_indexes(barcode, indexes_info) {
+                    Some(indexes) => indexes,
+                    None => {
+                        continue;
+                    }
+                };
+                if
+                    let (Some((sheet_i7, i7_sample_ls)), Some((sheet_i5, i5_sample_ls))) = (
+                        find_sheet_index(&i7, i7_samples, allowed_mismatches),
+                        find_sheet_index(&i5, i5_samples, allowed_mismatches),
+                    )
+                {
+                    if i7_sample_ls.iter().any(|sample_index| i5_sample_ls.contains(sample_index)) {
+                        // the indexes of one sample, the read was undetermined for its mismatches.
+                        break;
+                    }
+                    let combination = index_combinations
+                        .entry((sheet_i7.to_string(), sheet_i5.to_string()))
+                        .or_insert_with(|| {
+                            (
+                                i7_sample_ls.iter().sorted().cloned().collect(),
+                                i5_sample_ls.iter().sorted().cloned().collect(),
+                                0,
+                            )
+                        });
+                    combination.2 += count;
+                    break;
+                }
+            }
+        }
+        index_combinations
+    }
+
+    pub fn get_index_orientations(
+        &self,
+        barcodes: &HashMap<String, u64>
+    ) -> HashMap<(usize, bool, bool, bool), u64> {
+        /*
+        Matches the barcodes exactly against the indexes of each sample with the i7 and/or the i5
+        reverse complemented and/or swapped, compared to how they are expected from the sample sheet.
+        Returns the reads for each (sample, i7 rc, i5 rc, swapped) orientation.
+         */
+        let no_i5 = String::new();
+        let mut template_orientations = Vec::new();
+        for template_details in &self.all_template_data {
+            let mut orientations: IndexOrientations = HashMap::new();
+            for (i7, i7_info) in &template_details.4 {
+                let index_samples: Vec<(&String, usize)> = if template_details.5 {
+                    i7_info.1
+                        .iter()
+                        .map(|(i5, sample_index)| (i5, *sample_index))
+                        .collect()
+                } else {
+                    vec![(&no_i5, i7_info.0)]
+                };
+                let i7_rc = reverse_complement(i7).unwrap();
+                for (i5, sample_index) in index_samples {
+                    let i5_rc = reverse_complement(i5).unwrap();
+                    for (is_i7_rc, is_i5_rc, swapped) in [false, true]
+                        .into_iter()
+                        .cartesian_product([false, true])
+                        .cartesian_product([false, true])
+                        .map(|((a, b), c)| (a, b, c)) {
+                        if !(is_i7_rc || is_i5_rc || swapped) {
+                            continue;
+                        }
+                        if !template_details.5 && (is_i5_rc || swapped) {
+                            continue;
+                        }
+                        let first = if is_i7_rc { i7_rc.clone() } else { i7.clone() };
+                        let second = if is_i5_rc { i5_rc.clone() } else { i5.clone() };
+                        let key = if swapped { (second, first) } else { (first, second) };
+                        orientations
+                            .entry(key)
+                            .or_default()
+                            .push((sample_index, is_i7_rc, is_i5_rc, swapped));
+                    }
+                }
+            }
+            template_orientations.push((template_details.6, orientations));
+        }
+
+        let mut orientation_reads: HashMap<(usize, bool, bool, bool), u64> = HashMap::new();
+        for (barcode, count) in barcodes {
+            for (indexes_info, orientations) in &template_orientations {
+                let (i7, i5) = match split_barcode_indexes(barcode, indexes_info) {
+                    Some(indexes) => indexes,
+                    None => {
+                        continue;
+                    }
+                };
+                if let Some(matches) = orientations.get(&(i7, i5)) {
+                    for orientation in matches {
+                        *orientation_reads.entry(*orientation).or_insert(0) += count;
+                    }
+                    break;
+                }
+            }
+        }
+        orientation_reads
+    }
+}
+
+fn find_sheet_index<'a>(
+    index: &String,
+    index_samples: &'a HashMap<&'a String, Vec<usize>>,
+    allowed_mismatches: usize
+) -> Option<(&'a String, &'a Vec<usize>)> {
+    // The index of the sheet that is the only closest one within the allowed mismatches.
+    if let Some((sheet_index, samples)) = index_samples.get_key_value(index) {
+        return Some((*sheet_index, samples));
+    }
+    let mut closest = None;
+    let mut closest_mismatches = allowed_mismatches + 1;
+    for (sheet_index, samples) in index_samples {
+        let mismatches = match hamming_distance(index.as_bytes(), sheet_index.as_bytes()) {
+            Ok(mismatches) => mismatches,
+            Err(_) => {
+                continue;
+            }
+        };
+        if mismatches < closest_mismatches {
+            closest = Some((*sheet_index, samples));
+            closest_mismatches = mismatches;
+        } else if mismatches == closest_mismatches {
+            closest = None;
+        }
+    }
+    closest
+}
+
+pub fn split_barcode_indexes(barcode: &str, indexes_info: &[usize; 10]) -> Option<(String, String)> {
+    /*
+    Extracts i7 and i5 from a barcode reported in the undetermined or ambiguous reports.
+    The barcode is either `i7+i5` or the full barcode from the end of the read.
+     */
+    if let Some((i7, i5)) = barcode.split_once('+') {
+        if i7.len() != indexes_info[1] || i5.len() != indexes_info[4] {
+            return None;
+        }
+        return Some((i7.to_string(), i5.to_string()));
+    }
+    let barcode_length = barcode.len();
+    if barcode_length < indexes_info[2] || barcode_length < indexes_info[5] {
+        return None;
+    }
+    let i7_start = barcode_length - indexes_info[2];
+    let i5_start = barcode_length - indexes_info[5];
+    Some((
+        barcode[i7_start..i7_start + indexes_info[1]].to_string(),
+        barcode[i5_start..i5_start + indexes_info[4]].to_string(),
+    ))
+}
+
+#[allow(clippy::type_complexity)]
+fn get_expected_barcodes(
+    template_details: &(
+        u32,
+        HashSet<String>,
+        HashSet<String>,
+        String,
+        HashMap<String, (usize, HashMap<String, usize>)>,
+        bool,
+        [usize; 10],
+    )
+) -> Vec<(Vec<u8>, usize)> {
+    // Builds the barcode expected for each sample of the template, '.' marks positions without an index.
+    let indexes_info = template_details.6;
+    let barcode_length = indexes_info[9];
+    let mut expected_barcodes = Vec::new();
+    for (i7, i7_info) in &template_details.4 {
+        let mut barcode = vec![b'.'; barcode_length];
+        let i7_start = barcode_length - indexes_info[2];
+        barcode[i7_start..i7_start + indexes_info[1]].copy_from_slice(i7.as_bytes());
+        if template_details.5 {
+            let i5_start = barcode_length - indexes_info[5];
+            for (i5, sample_index) in &i7_info.1 {
+                let mut curr_barcode = barcode.clone();
+                curr_barcode[i5_start..i5_start + indexes_info[4]].copy_from_slice(i5.as_bytes());
+                expected_barcodes.push((curr_barcode, *sample_index));
+            }
+        } else {
+            expected_barcodes.push((barcode, i7_info.0));
+        }
+    }
+    expected_barcodes
+}
+
+pub fn parse_template(template: &String) -> Result<[usize; 10], &'static str> {
+    let mut template_ls = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    //let vals: Vec<&str> = template.rsplit(':').unwrap();
+    let mut shift = 0;
+    let mut length;
+    //debug!("template: {}", template);
+    for templ_item in template.rsplit(':') {
+        if !["i7", "i5", "um", "--"].contains(&&templ_item[0..2]) {
+            panic!("template ({}) does not match the expected format as explianed in mgikit documenation!", template);
+        }
+        length = templ_item[2..]
+            .parse::<usize>()
+            .expect(
+                &format!("template ({}) does not match the expected format as explianed in mgikit documenation!", template)
+            );
+        shift = shift + length;
+        if templ_item[0..2] == *"i7" {
+            template_ls[0] = 1;
+            template_ls[1] = length;
+            template_ls[2] = shift;
+        } else if templ_item[0..2] == *"i5" {
+            template_ls[3] = 1;
+            template_ls[4] = length;
+            template_ls[5] = shift;
+        } else if templ_item[0..2] == *"um" {
+            template_ls[6] = 1;
+            template_ls[7] = length;
+            template_ls[8] = shift;
+        } else if templ_item[0..2] != *"--" {
+            //println!("Should not be here!");
+            return Err("Wrong format for template!");
+        }
+    }
+    template_ls[9] = shift;
+    /*println!("{} -> {}.{}.{}    {}.{}.{}   {}.{}.{}", template, template_ls[0][0],template_ls[0][1],template_ls[0][2],
+    template_ls[1][0],template_ls[1][1],template_ls[1][2],
+    template_ls[2][0],template_ls[2][1],template_ls[2][2]);*/
+
+    Ok(template_ls)
+}
+
+const SHEET_COLUMNS: [&str; 9] = [
+    "sample_id",
+    "template",
+    "i7",
+    "i5",
+    "i7_rc",
+    "i5_rc",
+    "job_number",
+    "index_kit",
+    "index_id",
+];
+
+#[derive(Default)]
+pub struct SheetProblems {
+    // (line number, message), line 0 is used for problems of the whole sheet.
+    pub errors: Vec<(usize, String)>,
+    pub warnings: Vec<(usize, String)>,
+    // The line of each loaded sample, for the problems found after loading the sheet.
+    pub sample_lines: Vec<usize>,
+}
+
+impl SheetProblems {
+    pub fn sample_line(&self, sample_itr: usize) -> usize {
+        self.sample_lines.get(sample_itr).copied().unwrap_or(0)
+    }
+
+    pub fn add_sample_error(&mut self, sample_itr: usize, message: String) {
+        self.errors.push((self.sample_line(sample_itr), message));
+    }
+
+    pub fn add_sample_warning(&mut self, sample_itr: usize, message: String) {
+        self.warnings.push((self.sample_line(sample_itr), message));
+    }
+
+    pub fn check(&self) {
+        // Loading for demultiplexing stops at the first error.
+        for (line, message) in &self.warnings {
+            warn!("{}: {}.", sheet_location(*line), message);
+        }
+        if let Some((line, message)) = self.errors.first() {
+            panic!("{}: {}!", sheet_location(*line), message);
+        }
+    }
+}
+
+pub fn sheet_location(line: usize) -> String {
+    if line == 0 { String::from("Sample sheet") } else { format!("Line {}", line) }
+}
+
+fn check_index(index: &String, label: &str) -> Option<String> {
+    if index.len() < 3 {
+        return Some(format!("{} ({}) should be longer than 3 chars", label, index));
+    }
+    let invalid: String = index
+        .chars()
+        .filter(|c| !['A', 'C', 'G', 'T'].contains(c))
+        .unique()
+        .collect();
+    if !invalid.is_empty() {
+        if index.chars().all(|c| "ACGTacgt".contains(c)) {
+            return Some(
+                format!(
+                    "{} ({}) is in lower case, only upper case A, C, G and T are accepted",
+                    label,
+                    index
+                )
+            );
+        }
+        return Some(
+            format!(
+                "{} ({}) contains invalid characters ({}), only A, C, G and T are accepted",
+                label,
+                index,
+                invalid
+            )
+        );
+    }
+    None
+}
+
+fn check_template(template: &String) -> Result<[usize; 10], String> {
+    // parse_template panics on wrong templates, so the format is checked here first.
+    if template.is_empty() || template == "." {
+        return Err(String::from("no template is given in the sample sheet or with `--template`"));
+    }
+    for templ_item in template.split(':') {
+        if
+            templ_item.len() < 3 ||
+            !["i7", "i5", "um", "--"].contains(&&templ_item[0..2]) ||
+            templ_item[2..].parse::<usize>().unwrap_or(0) == 0
+        {
+            return Err(
+                format!(
+                    "template ({}) does not match the expected format, `{}` is not valid",
+                    template,
+                    templ_item
+                )
+            );
+        }
+    }
+    let indexes_info = parse_template(template).map_err(|err| err.to_string())?;
+    if indexes_info[0] == 0 {
+        return Err(format!("template ({}) does not contain i7", template));
+    }
+    Ok(indexes_info)
+}
+
+pub fn load_sample_sheet(
+    filename: &Path,
+    index_kits: &IndexKits,
+    problems: &mut SheetProblems
+) -> Result<Vec<Vec<String>>, io::Error> {
+    /*
+    All the problems of the sheet are added to `problems` instead of stopping at the first one,
+    and the samples with errors are not loaded.
+     */
+    let mut sample_information: Vec<Vec<String>> = Vec::new();
+    let mut header: Vec<String> = Vec::new();
+    let mut columns: HashMap<String, usize> = HashMap::new();
+    let file_content = fs::read_to_string(filename)?;
+    let mut delimiter = '\t';
+    for (line_itr, line) in file_content.lines().enumerate() {
+        let line_number = line_itr + 1;
+        if line.trim().len() < 5 {
+            if !line.trim().is_empty() {
+                problems.warnings.push((
+                    line_number,
+                    format!("Line '{}' is too short and will be ignored", line.trim()),
+                ));
+            }
+            continue;
+        }
+        if header.len() == 0 {
+            if !line.contains('\t') {
+                delimiter = ',';
+            }
+            header = line
+                .to_lowercase()
+                .split(delimiter)
+                .map(|x| x.trim().to_string())
+                .collect();
+            if header.len() < 2 {
+                problems.errors.push((
+                    line_number,
+                    String::from("Sample sheet columns should be separated by ',' or '\\t'"),
+                ));
+                return Ok(sample_information);
+            }
+            for (column_itr, column) in header.iter().enumerate() {
+                if !SHEET_COLUMNS.contains(&&column[..]) {
+                    problems.warnings.push((
+                        line_number,
+                        format!("Unknown column '{}' will be ignored", column),
+                    ));
+                } else if columns.contains_key(column) {
+                    problems.errors.push((
+                        line_number,
+                        format!("Column '{}' is found more than once", column),
+                    ));
+                } else {
+                    columns.insert(column.to_string(), column_itr);
+                }
+            }
+            if !columns.contains_key("sample_id") {
+                problems.errors.push((line_number, String::from("sample_id column is mandatory")));
+            }
+            if columns.contains_key("index_kit") != columns.contains_key("index_id") {
+                problems.errors.push((
+                    line_number,
+                    String::from("index_kit and index_id columns must be used together"),
+                ));
+            }
+            if columns.contains_key("index_kit") && index_kits.is_empty() {
+                problems.errors.push((
+                    line_number,
+                    String::from(
+                        "index_kit and index_id columns are used, the index kit file must be provided"
+                    ),
+                ));
+            }
+            if !columns.contains_key("i7") && !columns.contains_key("index_kit") {
+                problems.errors.push((
+                    line_number,
+                    String::from("i7 column (or index_kit and index_id columns) is mandatory"),
+                ));
+            }
+            if !problems.errors.is_empty() {
+                return Ok(sample_information);
+            }
+            continue;
+        }
+
+        let raw_vals: Vec<&str> = line.split(delimiter).collect();
+        if raw_vals.len() != header.len() {
+            let message = format!(
+                "Line has {} columns while the header has {} columns",
+                raw_vals.len(),
+                header.len()
+            );
+            // Only the columns used by mgikit are needed when loading the sheet.
+            if columns.values().any(|column_itr| *column_itr >= raw_vals.len()) {
+                problems.errors.push((line_number, message));
+            } else {
+                problems.warnings.push((line_number, message));
+            }
+        }
+        for (column_itr, raw_val) in raw_vals.iter().enumerate() {
+            if column_itr < header.len() && raw_val.trim() != *raw_val {
+                problems.warnings.push((
+                    line_number,
+                    format!(
+                        "Value '{}' of column {} has leading or trailing whitespace",
+                        raw_val,
+                        header[column_itr]
+                    ),
+                ));
+            }
+        }
+        let get_value = |column: &str| -> String {
+            match columns.get(column) {
+                Some(column_itr) if *column_itr < raw_vals.len() => {
+                    raw_vals[*column_itr].trim().to_string()
+                }
+                _ => String::new(),
+            }
+        };
+        let line_errors = problems.errors.len();
+
+        let sample_id = get_value("sample_id");
+        if sample_id.is_empty() {
+            problems.errors.push((line_number, String::from("sample_id is empty")));
+        } else if sample_id.contains(char::is_whitespace) {
+            problems.warnings.push((
+                line_number,
+                format!("Sample id '{}' contains whitespace", sample_id),
+            ));
+        }
+
+        let mut i7 = get_value("i7");
+        let mut i5 = get_value("i5");
+        let index_kit = get_value("index_kit");
+        if !index_kit.is_empty() && index_kit != "." {
+            let index_id = get_value("index_id");
+            match index_kits.get_indexes(&index_kit, &index_id) {
+                Some((kit_i7, kit_i5)) => {
+                    for (index, kit_index, label) in [
+                        (&mut i7, kit_i7, "i7"),
+                        (&mut i5, kit_i5, "i5"),
+                    ] {
+                        if index.is_empty() || index == "." {
+                            *index = kit_index.to_string();
+                        } else if index != kit_index {
+                            problems.errors.push((
+                                line_number,
+                                format!(
+                                    "{} ({}) does not match {} of {}:{} in the index kit ({})",
+                                    label,
+                                    index,
+                                    label,
+                                    index_kit,
+                                    index_id,
+                                    kit_index
+                                ),
+                            ));
+                        }
+                    }
+                }
+                None =>
+                    problems.errors.push((
+                        line_number,
+                        format!("Index {} is not found in the index kit {}", index_id, index_kit),
+                    )),
+            }
+        }
+        if i5.is_empty() {
+            i5 = String::from(".");
+        }
+        if i7.is_empty() || i7 == "." {
+            problems.errors.push((line_number, String::from("i7 is missing")));
+        } else if let Some(message) = check_index(&i7, "i7") {
+            problems.errors.push((line_number, message));
+        }
+        if i5 != "." {
+            if let Some(message) = check_index(&i5, "i5") {
+                problems.errors.push((line_number, message));
+            }
+        }
+
+        for column in ["i7_rc", "i5_rc"] {
+            let rc_value = get_value(column);
+            if ![".", "0", "1", ""].contains(&&rc_value[..]) {
+                problems.errors.push((
+                    line_number,
+                    format!("{} must be either '.', '0' or '1', found '{}'", column, rc_value),
+                ));
+            }
+        }
+
+        if problems.errors.len() == line_errors {
+            let get_value_or_dot = |column: &str| -> String {
+                if columns.contains_key(column) { get_value(column) } else { String::from(".") }
+            };
+            sample_information.push(
+                vec![
+                    sample_id,
+                    i7,
+                    i5,
+                    get_value_or_dot("template"),
+                    get_value_or_dot("i7_rc"),
+                    get_value_or_dot("i5_rc"),
+                    get_value_or_dot("job_number")
+                ]
+            );
+            problems.sample_lines.push(line_number);
+        }
+    }
+
+    if header.is_empty() {
+        problems.errors.push((0, String::from("Sample sheet seems to be empty!")));
+    } else if sample_information.is_empty() && problems.errors.is_empty() {
+        problems.errors.push((0, String::from("Sample sheet seems to be empty! No sample is found!")));
+    }
+
+    Ok(sample_information)
+}
+
+pub fn extract_project_samples(
+    sample_information: &Vec<Vec<String>>
+) -> Result<HashMap<String, Vec<usize>>, io::Error> {
+    //println!("{} -> {}", template, filename.display());
+
+    let mut sample_itr = 0;
+    let mut project_samples: HashMap<String, Vec<usize>> = HashMap::new();
+    project_samples.insert(".".to_string(), Vec::new().to_owned());
+    let mut report_unassigned_samples = false;
+    for curr_sample_info in sample_information {
+        match project_samples.get_mut(&curr_sample_info[PROJECT_ID_COLUMN]) {
+            Some(curr_project_samples) => {
+                curr_project_samples.push(sample_itr);
+            }
+            None => {
+                project_samples.insert(
+                    curr_sample_info[PROJECT_ID_COLUMN].to_owned(),
+                    vec![sample_itr].to_owned()
+                );
+            }
+        }
+
+        if
+            !report_unassigned_samples &&
+            project_samples.len() > 1 &&
+            project_samples["."].len() > 0
+        {
+            info!(
+                "Some samples are not assigned to any project, they will be in the reports of the whole run!"
+            );
+            report_unassigned_samples = true;
+        }
+
+        sample_itr += 1;
+    }
+
+    match project_samples.get_mut(&String::from(".")) {
+        Some(curr_project_samples) => {
+            curr_project_samples.clear();
+        }
+        None => {
+            panic!("There must be a project for the whole run with the '.' name!");
+        }
+    }
+
+    Ok(project_samples)
+}
+
+pub fn extract_templates_information(
+    sample_information: &[Vec<String>],
+    template: &String,
+    i7_rc: bool,
+    i5_rc: bool,
+    problems: &mut SheetProblems
+) -> Result<
+    Vec<
+        (
+            u32,
+            HashSet<String>,
+            HashSet<String>,
+            String,
+            HashMap<String, (usize, HashMap<String, usize>)>,
+            bool,
+            [usize; 10],
+        )
+    >,
+    io::Error
+> {
+    /*
+    template details = (
+                    1,
+                    all i7s indexes,
+                    all i5s indexes,
+                    template string,
+                    (i7, (i5, sample index)) if no i5, it will be i7 and sample id,
+                    boolean to check i5,
+                    template information as where to find the indexes,
+                );
+    The samples with a wrong template or indexes that do not fit it are added to `problems` and
+    left out of the templates.
+     */
+    //println!("{} -> {}", template, filename.display());
+    //debug!("{:?}", sample_information);
+    let mut template_ls: HashMap<
+        String,
+        (
+            u32,
+            HashSet<String>,
+            HashSet<String>,
+            String,
+            HashMap<String, (usize, HashMap<String, usize>)>,
+            bool,
+            [usize; 10],
+        )
+    > = HashMap::new();
+
+    // (barcode length, sample of the first template)
+    let mut barcode_length: (usize, usize) = (0, 0);
+    if template.is_empty() && sample_information.iter().all(|curr_sample_info| {
+        curr_sample_info[TEMPLATE_COLUMN].is_empty() || curr_sample_info[TEMPLATE_COLUMN] == "."
+    }) {
+        problems.errors.push((
+            0,
+            String::from(
+                "template column is missing or empty, the template must be provided with `--template`"
+            ),
+        ));
+        return Ok(Vec::new());
+    }
+    for (sample_itr, curr_sample_info) in sample_information.iter().enumerate() {
+        let curr_template = if template == "" {
+            curr_sample_info[TEMPLATE_COLUMN].clone()
+        } else {
+            template.clone()
+        };
+        //debug!("curr template {}  -> *{}*", curr_template, template);
+        let indexes_info = match check_template(&curr_template) {
+            Ok(indexes_info) => indexes_info,
+            Err(message) => {
+                problems.add_sample_error(sample_itr, message);
+                continue;
+            }
+        };
+        let sample_errors = problems.errors.len();
+        if barcode_length.0 == 0 {
+            barcode_length = (indexes_info[9], sample_itr);
+        } else if indexes_info[9] != barcode_length.0 {
+            problems.add_sample_error(
+                sample_itr,
+                format!(
+                    "The barcode length of template {} ({}) is different from the barcode length at line {} ({}), it should be the same for all samples",
+                    curr_template,
+                    indexes_info[9],
+                    problems.sample_line(barcode_length.1),
+                    barcode_length.0
+                )
+            );
+        }
+        if curr_sample_info[I7_COLUMN].len() != indexes_info[1] {
+            problems.add_sample_error(
+                sample_itr,
+                format!(
+                    "i7 ({}) length is {} while the template {} expects {}",
+                    curr_sample_info[I7_COLUMN],
+                    curr_sample_info[I7_COLUMN].len(),
+                    curr_template,
+                    indexes_info[1]
+                )
+            );
+        }
+        if indexes_info[3] == 1 && curr_sample_info[I5_COLUMN] == "." {
+            problems.add_sample_error(
+                sample_itr,
+                format!("template {} contains i5 but i5 is missing", curr_template)
+            );
+        } else if indexes_info[3] == 0 && curr_sample_info[I5_COLUMN] != "." {
+            problems.add_sample_error(
+                sample_itr,
+                format!(
+                    "i5 ({}) is given but the template {} does not contain i5",
+                    curr_sample_info[I5_COLUMN],
+                    curr_template
+                )
+            );
+        } else if
+            curr_sample_info[I5_COLUMN] != "." &&
+            curr_sample_info[I5_COLUMN].len() != indexes_info[4]
+        {
+            problems.add_sample_error(
+                sample_itr,
+                format!(
+                    "i5 ({}) length is {} while the template {} expects {}",
+                    curr_sample_info[I5_COLUMN],
+                    curr_sample_info[I5_COLUMN].len(),
+                    curr_template,
+                    indexes_info[4]
+                )
+            );
+        }
+        if problems.errors.len() > sample_errors {
+            continue;
+        }
+
+        let check_i5 = indexes_info[3] == 1;
+
+        let i7 = if
+            (template.len() > 0 && i7_rc) ||
+            (template.len() == 0 && curr_sample_info[I7_RC_COLUMN] == "1")
+        {
+            //println!("revi7 {}  {}", template, i7_rc);
+            reverse_complement(&curr_sample_info[I7_COLUMN]).unwrap()
+        } else {
+            curr_sample_info[I7_COLUMN].clone()
+        };
+        //println!("I7s: {} -> {}", vals[I7_COLUMN], i7);
+
+        let i5 = if
+            check_i5 &&
+            ((template.len() > 0 && i5_rc) ||
+                (template.len() == 0 && curr_sample_info[I5_RC_COLUMN] == "1"))
+        {
+            reverse_complement(&curr_sample_info[I5_COLUMN]).unwrap()
+        } else if check_i5 {
+            curr_sample_info[I5_COLUMN].clone()
+        } else {
+            String::new()
+        };
+
+        match template_ls.get_mut(&curr_template) {
+            Some(tmp_data) => {
+                let duplicate_sample = match tmp_data.4.get(&i7) {
+                    Some(i7_item) if check_i5 => i7_item.1.get(&i5).copied(),
+                    Some(i7_item) => Some(i7_item.0),
+                    None => None,
+                };
+                if let Some(duplicate_sample) = duplicate_sample {
+                    problems.add_sample_error(
+                        sample_itr,
+                        format!(
+                            "Sample {} has the same indexes as sample {} at line {} (i7: {}, i5: {} as expected in the reads)",
+                            curr_sample_info[SAMPLE_COLUMN],
+                            sample_information[duplicate_sample][SAMPLE_COLUMN],
+                            problems.sample_line(duplicate_sample),
+                            i7,
+                            if check_i5 { &i5 } else { "." }
+                        )
+                    );
+                    continue;
+                }
+                tmp_data.0 += 1;
+                tmp_data.1.insert(i7.clone());
+                tmp_data.2.insert(i5.clone());
+
+                match tmp_data.4.get_mut(&i7) {
+                    Some(i7_item) => {
+                        i7_item.1.insert(i5, sample_itr);
+                    }
+                    None => {
+                        if check_i5 {
+                            let mut tmp = HashMap::new();
+                            tmp.insert(i5, sample_itr);
+                            tmp_data.4.insert(i7, (1000, tmp));
+                        } else {
+                            tmp_data.4.insert(i7, (sample_itr, HashMap::new()));
+                        }
+                    }
+                };
+            }
+            None => {
+                let mut i7s = HashSet::new();
+                let mut i5s = HashSet::new();
+                i7s.insert(i7.clone());
+                if check_i5 {
+                    i5s.insert(i5.clone());
+                }
+                let mut sample_info = HashMap::new();
+                if check_i5 {
+                    let mut tmp = HashMap::new();
+                    //let zzz = i5.clone();
+                    //let zzzz = i7.clone();
+                    tmp.insert(i5, sample_itr);
+                    sample_info.insert(i7, (1000, tmp));
+                    //println!("SSSS {:?}", sample_info.get(&zzzz).unwrap().1.get(&zzz).unwrap());
+                } else {
+                    sample_info.insert(i7, (sample_itr, HashMap::new()));
+                }
+
+                let tmp = (1, i7s, i5s, curr_template.clone(), sample_info, check_i5, indexes_info);
+                template_ls.insert(curr_template, tmp);
+            }
+        }
+    }
+
+    let mut out_template_data: Vec<_> = template_ls
+        .values()
+        .map(|x| x.to_owned())
+        .collect();
+    if out_template_data.len() > 1 {
+        // Longer indexes are checked first so that a short index nested inside a longer one
+        // does not claim the reads of the longer sample.
+        out_template_data.sort_by(|a, b| {
+            (b.6[1] + b.6[4])
+                .cmp(&(a.6[1] + a.6[4]))
+                .then(b.0.cmp(&a.0))
+                .then(a.3.cmp(&b.3))
+        });
+    }
+
+    Ok(out_template_data)
+}
+
+pub fn get_writing_unique_samples(
+    sample_information: &Vec<Vec<String>>,
+    problems: &mut SheetProblems
+) -> Result<(Vec<usize>, Vec<usize>), io::Error> {
+    //println!("{} -> {}", template, filename.display());
+
+    let mut sample_itr = 0;
+    let mut sample_duplicate: HashMap<String, usize> = HashMap::new();
+    let mut writing_samples: Vec<usize> = Vec::new();
+    let mut unique_sample_id: Vec<usize> = Vec::new();
+    let mut dup_ids = 0;
+    let mut curr_unique_id;
+    for curr_sample_info in sample_information {
+        {
+            let duplicate_sample_id = match sample_duplicate.get(&curr_sample_info[SAMPLE_COLUMN]) {
+                Some(dup_id) => {
+                    if
+                        sample_information[*dup_id][I7_COLUMN] != curr_sample_info[I7_COLUMN] ||
+                        sample_information[*dup_id][I5_COLUMN] != curr_sample_info[I5_COLUMN]
+                    {
+                        problems.add_sample_warning(
+                            sample_itr,
+                            format!(
+                                "Sample id {} is also used at line {} with different indexes, the reads of both will be written to the same files",
+                                curr_sample_info[SAMPLE_COLUMN],
+                                problems.sample_line(*dup_id)
+                            )
+                        );
+                    }
+                    curr_unique_id = unique_sample_id[*dup_id];
+                    *dup_id
+                }
+                None => {
+                    sample_duplicate.insert(curr_sample_info[SAMPLE_COLUMN].clone(), sample_itr);
+                    curr_unique_id = dup_ids;
+                    dup_ids += 1;
+                    sample_itr
+                }
+            };
+            writing_samples.push(duplicate_sample_id);
+            unique_sample_id.push(curr_unique_id);
+            sample_itr += 1;
+        }
+    }
+
+    //adding undetrmined and ambiguose samples ids.
+    writing_samples.push(writing_samples.len());
+    unique_sample_id.push(unique_sample_id.len());
+    writing_samples.push(writing_samples.len());
+    unique_sample_id.push(unique_sample_id.len());
+
+    Ok((writing_samples, unique_sample_id))
+}
+
+pub fn get_all_mismatches<'a>(
+    all_indexes: &'a HashSet<String>,
+    allowed_mismatches: usize
+) -> HashMap<Vec<u8>, (Vec<&'a String>, usize)> {
+    if all_indexes.len() == 0 {
+        return HashMap::new();
+    }
+
+    let nec_ls = ['A', 'C', 'T', 'G', 'N'];
+    //let mut index_combinations: HashMap<&String, Vec<HashSet<String>>> = HashMap::new();
+
+    let mut final_output: HashMap<String, Vec<Vec<&String>>> = HashMap::new();
+
+    let mut possible_mismatches: HashSet<String>;
+
+    for index in all_indexes {
+        //println!("index: {} -> {:?}", index, "Z");//, final_output.keys());
+        //index_combinations.insert(&index, Vec::new());
+        let mut tmp: Vec<Vec<&String>> = vec![Vec::new(); allowed_mismatches + 1];
+        tmp[0].push(&index);
+        match final_output.get_mut(index) {
+            Some(x) => {
+                *x = tmp;
+                //println!("ovvoride index");
+                //panic!("duplicated index!")
+            }
+            None => {
+                final_output.insert(index.clone(), tmp);
+            }
+        }
+
+        for mismatch_itr in 1..allowed_mismatches + 1 {
+            //println!("Iter: {}", mismatch_itr);
+            possible_mismatches = HashSet::new();
+            let combination_ls = (0..index.len()).combinations(mismatch_itr);
+            for combination in combination_ls {
+                //println!("Z: {:?}", combination);
+
+                let mut all_combinations: Vec<String> = nec_ls
+                    .iter()
+                    .filter(|&i| *i != index.chars().nth(combination[0]).unwrap())
+                    .map(|x| x.to_string())
+                    .collect();
+                //println!("ALL: {:?}", all_combinations);
+                {
+                    for nec_ind in 1..combination.len() {
+                        let ls_nec: Vec<char> = nec_ls
+                            .iter()
+                            .filter(|&i| *i != index.chars().nth(combination[nec_ind]).unwrap())
+                            .cloned()
+                            .collect();
+                        //println!("inner {} -> {:?}", nec_ind, ls_nec);
+
+                        let mut tmp: Vec<String> = Vec::new();
+                        for x in all_combinations {
+                            for y in &ls_nec {
+                                let mut z = x.clone();
+                                z.push(*y);
+                                //println!("merge: {} - {}  -> {}", x, y, z);
+                                tmp.push(z);
+                            }
+                        }
+                        all_combinations = tmp;
+                    }
+
+                    for combination_inner in all_combinations {
+                        let mut index_edited = index.clone();
+
+                        for nec_ind in 0..combination.len() {
+                            //index_edited[combination[nec_ind]] = combination_inner.chars().nth(nec_ind).unwrap();
+                            index_edited.replace_range(
+                                combination[nec_ind]..combination[nec_ind] + 1,
+                                &combination_inner[nec_ind..nec_ind + 1]
+                            );
+                        }
+                        //println!("{}   {}", index, index_edited);
+
+                        match final_output.get_mut(&index_edited) {
+                            Some(x) => {
+                                x[mismatch_itr].push(index);
+                            }
+                            None => {
+                                let mut tmp = Vec::new();
+                                for mis_itr in 0..allowed_mismatches + 1 {
+                                    if mis_itr == mismatch_itr {
+                                        tmp.push(vec![index]);
+                                    } else {
+                                        tmp.push(Vec::new());
+                                    }
+                                }
+                                final_output.insert(index_edited.clone(), tmp);
+                            }
+                        }
+                        possible_mismatches.insert(index_edited);
+                    }
+                }
+            }
+
+            /*match index_combinations.get_mut(&index){
+                Some(x) => {x.push(possible_mismatches);},
+                None => panic!("Something wrong")
+            };*/
+        }
+    }
+
+    let mut sample_index_map = HashMap::new();
+    for (key, value) in final_output {
+        for i in 0..allowed_mismatches + 1 {
+            if value[i].len() > 0 {
+                //let mut tmp_z = value[i].clone();
+                //tmp_z.sort_by(|a, b| a.cmp(b));
+                sample_index_map.insert(key.as_bytes().to_vec(), (value[i].to_owned(), i));
+                break;
+            }
+        }
+    }
+
+    sample_index_map
+}
+
+pub fn reverse_complement(seq: &String) -> Result<String, &'static str> {
+    //println!("rc: *{}*", seq);
+    let mut out_string = String::new();
+    for nec in seq.chars().rev() {
+        out_string.push(match nec {
+            'n' => 'N',
+            'N' => 'N',
+            'A' => 'T',
+            'a' => 'T',
+            'T' => 'A',
+            't' => 'A',
+            'C' => 'G',
+            'c' => 'G',
+            'G' => 'C',
+            'g' => 'C',
+            _ => panic!("Wrong neucltide!"),
+        });
+    }
+    Ok(out_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        extract_templates_information,
+        parse_template,
+        reverse_complement,
+        split_barcode_indexes,
+        SampleManager,
+        SheetProblems,
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_reverse_complement() {
+        assert_eq!(reverse_complement(&String::from("ACGTGCGC")).unwrap(), "GCGCACGT");
+        assert_eq!(reverse_complement(&String::from("GGGGGGGG")).unwrap(), "CCCCCCCC");
+        assert_eq!(reverse_complement(&String::from("ATATATNN")).unwrap(), "NNATATAT");
+        assert_eq!(reverse_complement(&String::from("agcagccc")).unwrap(), "GGGCTGCT");
+    }
+
+    #[test]
+    fn test_split_barcode_indexes() {
+        let indexes_info = parse_template(&String::from("i78:i58")).unwrap();
+        assert_eq!(
+            split_barcode_indexes("ACGGGCAT+GGCTAGAG", &indexes_info),
+            Some((String::from("ACGGGCAT"), String::from("GGCTAGAG")))
+        );
+        assert_eq!(
+            split_barcode_indexes("TTACGGGCATGGCTAGAG", &indexes_info),
+            Some((String::from("ACGGGCAT"), String::from("GGCTAGAG")))
+        );
+        assert_eq!(split_barcode_indexes("ACGGGCAT+GGCT", &indexes_info), None);
+        assert_eq!(split_barcode_indexes("GGCTAGAG", &indexes_info), None);
+    }
+
+    #[test]
+    fn test_check_cross_length_conflicts() {
+        let sample_information: Vec<Vec<String>> = [
+            ("S0", "AAACGTACGT", "i710"),
+            ("S1", "ACGTACGT", "--2:i78"),
+            ("S2", "ACGTACTT", "--2:i78"),
+            ("S3", "GGTTCCAA", "--2:i78"),
+            ("S4", "CCGGAATTCC", "i710"),
+        ]
+            .iter()
+            .map(|(sample_id, i7, template)| {
+                [*sample_id, *i7, ".", *template, "0", "0", "."]
+                    .iter()
+                    .map(|item| item.to_string())
+                    .collect()
+            })
+            .collect();
+        let all_template_data = extract_templates_information(
+            &sample_information,
+            &String::new(),
+            false,
+            false,
+            &mut SheetProblems::default()
+        ).unwrap();
+        let sample_manager = SampleManager {
+            sample_information,
+            all_template_data,
+            ..Default::default()
+        };
+        let conflicts = sample_manager.check_cross_length_conflicts(0);
+        assert_eq!(conflicts.len(), 1);
+        assert!(conflicts[0].starts_with("Sample S1 (--2:i78)"));
+        assert!(conflicts[0].contains("sample S0 (i710) with 0 mismatches"));
+        let mut conflicts = sample_manager.check_cross_length_conflicts(1);
+        conflicts.sort();
+        assert_eq!(conflicts.len(), 2);
+        assert!(conflicts[1].starts_with("Sample S2 (--2:i78)"));
+        assert!(conflicts[1].contains("sample S0 (i710) with 1 mismatches"));
+    }
+
+    #[test]
+    fn test_get_index_combinations() {
+        let sample_information: Vec<Vec<String>> = [
+            ("S0", "ACGGGCAT", "GGCTAGAG"),
+            ("S1", "TTCCAAGG", "CATGCATG"),
+        ]
+            .iter()
+            .map(|(sample_id, i7, i5)| {
+                [*sample_id, *i7, *i5, "i78:i58", "0", "0", "."]
+                    .iter()
+                    .map(|item| item.to_string())
+                    .collect()
+            })
+            .collect();
+        let all_template_data = extract_templates_information(
+            &sample_information,
+            &String::new(),
+            false,
+            false,
+            &mut SheetProblems::default()
+        ).unwrap();
+        let sample_manager = SampleManager {
+            sample_information,
+            all_template_data,
+            ..Default::default()
+        };
+        let barcodes = HashMap::from([
+            (String::from("ACGGGCAT+CATGCATG"), 5),
+            (String::from("ACGGGCAA+CATGCATG"), 3),
+            (String::from("ACGGGCAT+GGCTAGAA"), 7),
+            (String::from("AAAAAAAA+CATGCATG"), 11),
+        ]);
+        let exact = sample_manager.get_index_combinations(&barcodes, 0);
+        assert_eq!(exact.len(), 1);
+        assert_eq!(
+            exact[&(String::from("ACGGGCAT"), String::from("CATGCATG"))],
+            (vec![0], vec![1], 5)
+        );
+        let tolerant = sample_manager.get_index_combinations(&barcodes, 1);
+        assert_eq!(tolerant.len(), 1);
+        assert_eq!(
+            tolerant[&(String::from("ACGGGCAT"), String::from("CATGCATG"))],
+            (vec![0], vec![1], 8)
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use crate::hamming_distance;
+use crate::index_kits::IndexKits;
+use crate::sample_manager::{
+    extract_templates_information, get_writing_unique_samples, load_sample_sheet, SheetProblems,
+};
+use crate::variables::SAMPLE_COLUMN;
+use std::path::Path;
+
+#[derive(Default)]
+pub struct SheetValidation {
+    // (line number, message), line 0 is used for problems of the whole sheet.
+    pub errors: Vec<(usize, String)>,
+    pub warnings: Vec<(usize, String)>,
+    pub distances: Vec<String>,
+}
+
+pub fn validate_sample_sheet(
+    sample_sheet_path: &Path,
+    template: &String,
+    i7_rc: bool,
+    i5_rc: bool,
+    index_kits: &IndexKits,
+    allowed_mismatches: usize,
+    all_index_error: bool,
+) -> SheetValidation {
+    /*
+    The sheet is loaded with the same functions used for demultiplexing, which report all the
+    problems found instead of stopping at the first one.
+    The minimum distance between the samples of each template is reported for each number of
+    mismatches up to the allowed mismatches.
+     */
+    let mut problems = SheetProblems::default();
+    let sample_information = match load_sample_sheet(sample_sheet_path, index_kits, &mut problems) {
+        Ok(sample_information) => sample_information,
+        Err(err) => {
+            problems.errors.push((
+                0,
+                format!(
+                    "Sample sheet {} can not be read: {}",
+                    sample_sheet_path.display(),
+                    err
+                ),
+            ));
+            Vec::new()
+        }
+    };
+    let mut all_template_data = Vec::new();
+    if !sample_information.is_empty() {
+        get_writing_unique_samples(&sample_information, &mut problems).unwrap();
+        all_template_data = extract_templates_information(
+            &sample_information,
+            template,
+            i7_rc,
+            i5_rc,
+            &mut problems,
+        )
+        .unwrap();
+    }
+    let mut validation = SheetValidation {
+        errors: problems.errors,
+        warnings: problems.warnings,
+        distances: Vec::new(),
+    };
+
+    all_template_data.sort_by(|a, b| a.3.cmp(&b.3));
+    let no_index = String::from(".");
+    for template_details in &all_template_data {
+        let template_name = &template_details.3;
+        let dual_index = template_details.5;
+        // (sample, i7, i5) as expected in the reads, in the order of the sample sheet.
+        let mut template_samples: Vec<(usize, &String, &String)> = Vec::new();
+        for (i7, i7_info) in &template_details.4 {
+            if dual_index {
+                for (i5, sample_itr) in &i7_info.1 {
+                    template_samples.push((*sample_itr, i7, i5));
+                }
+            } else {
+                template_samples.push((i7_info.0, i7, &no_index));
+            }
+        }
+        template_samples.sort();
+        let mut min_distance = (usize::MAX, usize::MAX, usize::MAX);
+        let mut closest_samples = (0, 0);
+        let mut ambiguous_pairs = vec![0; allowed_mismatches + 1];
+        for first_itr in 0..template_samples.len() {
+            for second_itr in first_itr + 1..template_samples.len() {
+                let first = template_samples[first_itr];
+                let second = template_samples[second_itr];
+                let i7_distance =
+                    hamming_distance(first.1.as_bytes(), second.1.as_bytes()).unwrap();
+                let i5_distance = if dual_index {
+                    hamming_distance(first.2.as_bytes(), second.2.as_bytes()).unwrap()
+                } else {
+                    0
+                };
+                if i7_distance + i5_distance < min_distance.2 {
+                    closest_samples = (first.0, second.0);
+                }
+                min_distance = (
+                    min_distance.0.min(i7_distance),
+                    min_distance.1.min(i5_distance),
+                    min_distance.2.min(i7_distance + i5_distance),
+                );
+                // A read can be within the allowed mismatches of both samples.
+                for (mismatches, ambiguous) in ambiguous_pairs.iter_mut().enumerate() {
+                    if (all_index_error && i7_distance + i5_distance <= 2 * mismatches)
+                        || (!all_index_error
+                            && i7_distance <= 2 * mismatches
+                            && i5_distance <= 2 * mismatches)
+                    {
+                        *ambiguous += 1;
+                    }
+                }
+            }
+        }
+        if template_samples.len() < 2 {
+            validation.distances.push(format!(
+                "Template {}: 1 sample, no distances to report.",
+                template_name
+            ));
+            continue;
+        }
+        validation.distances.push(format!(
+            "Template {}: {} samples, minimum distance i7: {}{}, closest samples: {} and {} ({} mismatches).",
+            template_name,
+            template_samples.len(),
+            min_distance.0,
+            if dual_index {
+                format!(", i5: {}, combined: {}", min_distance.1, min_distance.2)
+            } else {
+                String::new()
+            },
+            sample_information[closest_samples.0][SAMPLE_COLUMN],
+            sample_information[closest_samples.1][SAMPLE_COLUMN],
+            min_distance.2
+        ));
+        for (mismatches, ambiguous) in ambiguous_pairs.iter().enumerate() {
+            validation.distances.push(format!(
+                "  {} mismatches: {} sample pairs can match the same read.",
+                mismatches, ambiguous
+            ));
+        }
+        if ambiguous_pairs[allowed_mismatches] > 0 {
+            validation.warnings.push((
+                0,
+                format!(
+                    "{} sample pairs of template {} can match the same read with {} mismatches, these reads will be ambiguous",
+                    ambiguous_pairs[allowed_mismatches], template_name, allowed_mismatches
+                ),
+            ));
+        }
+    }
+    validation.errors.sort_by_key(|issue| issue.0);
+    validation.warnings.sort_by_key(|issue| issue.0);
+    validation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_validate_sample_sheet() {
+        let path = std::env::temp_dir().join("mgikit_test_validate_sheet.tsv");
+        fs::write(
+            &path,
+            "sample_id\ti7\ti5\ttemplate\n\
+             S1\tACGGGCAT\tGGCTAGAG\ti78:i58\n\
+             S2\tACGGGCAT\tGGCTAGAG\ti78:i58\n\
+             S3\tACGGNCAT\tGGCTAGAG\ti78:i58\n\
+             S4\tTTGGGCAT\tGGCTAGAG\ti78:i5x\n\
+             S5\tTTGGGCAT\tGGCTAG\ti78:i58\n\
+             S1\tTTGGGCAT\tGGCTAGAG\ti78:i58\n",
+        )
+        .unwrap();
+        let validation = validate_sample_sheet(
+            &path,
+            &String::new(),
+            false,
+            false,
+            &IndexKits::default(),
+            1,
+            true,
+        );
+        fs::remove_file(&path).unwrap();
+        let error_lines: Vec<usize> = validation.errors.iter().map(|error| error.0).collect();
+        assert_eq!(error_lines, vec![3, 4, 5, 6]);
+        assert!(validation.errors[0]
+            .1
+            .starts_with("Sample S2 has the same indexes as sample S1 at line 2"));
+        assert!(validation.errors[3]
+            .1
+            .starts_with("i5 (GGCTAG) length is 6"));
+        let warning_lines: Vec<usize> = validation
+            .warnings
+            .iter()
+            .map(|warning| warning.0)
+            .collect();
+        assert_eq!(warning_lines, vec![0, 7]);
+        assert!(validation.warnings[1]
+            .1
+            .starts_with("Sample id S1 is also used at line 2"));
+        assert_eq!(
+            validation.distances[0],
+            "Template i78:i58: 2 samples, minimum distance i7: 2, i5: 0, combined: 2, closest samples: S1 and S1 (2 mismatches)."
+        );
+    }
+}
@@ -6,3 +6,8 @@ pub const TEMPLATE_COLUMN: usize = 3;
 pub const I7_RC_COLUMN: usize = 4;
 pub const I5_RC_COLUMN: usize = 5;
 pub const PROJECT_ID_COLUMN: usize = 6;
+
+// undetermined barcodes diagnostics
+pub const MIN_DIAGNOSTIC_PERCENTAGE: f64 = 1.0;
+pub const MAX_DIAGNOSTIC_WARNINGS: usize = 10;
+pub const MAX_ABORT_DIAGNOSTIC_BARCODES: usize = 20;
@@ -1,12 +1,15 @@
 //use mgikit::*;
 use md5;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::fs;
 use std::io::Read;
 use std::process::Command;
 use std::path::{Path, PathBuf};
 use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::Write;
 use walkdir::WalkDir;
 
 fn get_hash(file_path: &String) -> Vec<u8> {
@@ -58,7 +61,8 @@ fn count_files_recursive(path: &String) -> u64 {
     for entry in WalkDir::new(path).follow_links(true) {
         match entry {
             Ok(entry) => {
-                if entry.file_type().is_file() {
+                // The provenance report is not part of the expected outputs.
+                if entry.file_type().is_file() && !entry.file_name().to_str().unwrap().ends_with(".provenance.json") {
                     count += 1;
                 }
             }
@@ -69,6 +73,74 @@ fn count_files_recursive(path: &String) -> u64 {
     count
 }
 
+fn run_mgikit(args: &Vec<String>) -> String {
+    println!("{:?}", args);
+    let output = Command::new("target/debug/mgikit")
+        .args(args)
+        .output()
+        .expect("Failed to execute command");
+    if !output.status.success() {
+        panic!(
+            "Command failed with exit code: {}\nError message: {}\nOutput:{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr),
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn run_mgikit_failure(args: &Vec<String>) -> String {
+    // Returns the error output of a command that is expected to fail.
+    println!("{:?}", args);
+    let output = Command::new("target/debug/mgikit")
+        .args(args)
+        .output()
+        .expect("Failed to execute command");
+    assert!(!output.status.success(), "Command should fail: {:?}", args);
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+fn write_gzip_file(file_path: &String, content: &str) {
+    let mut encoder = GzEncoder::new(File::create(file_path).unwrap(), Compression::default());
+    encoder.write_all(content.as_bytes()).unwrap();
+    encoder.finish().unwrap();
+}
+
+fn get_report_path(report_dir: &String, suffix: &str) -> PathBuf {
+    fs::read_dir(report_dir).unwrap()
+        .map(|path| path.unwrap().path())
+        .find(|path| path.to_str().unwrap().ends_with(suffix))
+        .unwrap_or_else(|| panic!("No report ending with {} in {}", suffix, report_dir))
+}
+
+fn read_sample_stats(report_dir: &String) -> HashMap<String, HashMap<String, u64>> {
+    // sample id -> column -> value of the sample_stats report.
+    let content = fs::read_to_string(get_report_path(report_dir, ".mgikit.sample_stats")).unwrap();
+    let mut lines = content.lines();
+    let header: Vec<&str> = lines.next().unwrap().split('\t').collect();
+    lines.map(|line| {
+        let vals: Vec<&str> = line.split('\t').collect();
+        (
+            vals[1].to_string(),
+            header.iter().zip(vals.iter()).skip(2)
+                .map(|(column, val)| (column.to_string(), val.parse::<u64>().unwrap()))
+                .collect()
+        )
+    }).collect()
+}
+
+fn find_r1_output(ouput_dir: &String, prefix: &str) -> Option<String> {
+    fs::read_dir(ouput_dir).unwrap()
+        .map(|path| path.unwrap().file_name().to_str().unwrap().to_string())
+        .find(|file_name| file_name.starts_with(prefix) && file_name.contains("_R1_"))
+        .map(|file_name| format!("{}{}", ouput_dir, file_name))
+}
+
+fn count_fastq_reads(file_path: &String) -> usize {
+    get_gzip_hash(file_path).lines().count() / 4
+}
+
 #[test]
 fn testing_template() { 
     for ds_itr_tmp in 1..8{
@@ -264,6 +336,11 @@ fn testing_demultiplex() {
                     if ds_itr_tmp >=  11{
                         my_args.push("--per-index-error".to_string());                
                     }
+                    // The checksums are only calculated when they are asked for.
+                    let provenance_checksums = ds_itr_tmp % 2 == 0;
+                    if provenance_checksums {
+                        my_args.push("--provenance-checksums".to_string());
+                    }
                     println!("{:?}", &my_args);
 
 
@@ -312,6 +389,25 @@ fn testing_demultiplex() {
                     assert_eq!(count_files_recursive(&ouput_dir),
                             count_files_recursive(&original_path));
 
+                    println!("Checking provenance report");
+                    let provenance_path = fs::read_dir(&ouput_dir).unwrap()
+                        .map(|path| path.unwrap().path())
+                        .find(|path| path.to_str().unwrap().ends_with(".mgikit.provenance.json"))
+                        .unwrap();
+                    let provenance: serde_json::Value = serde_json::from_str(&fs::read_to_string(&provenance_path).unwrap()).unwrap();
+                    assert_eq!(provenance["mismatches"]["allowed_mismatches"], allowed_mismatches);
+                    // The sample sheet checksum is recorded with and without --provenance-checksums.
+                    assert_eq!(provenance["sample_sheet"]["md5"], format!("{:x}", md5::compute(get_hash(&sample_sheet_file_path))));
+                    for input_file in provenance["input_files"].as_array().unwrap() {
+                        let input_path = input_file["path"].as_str().unwrap().to_string();
+                        assert_eq!(input_file["size"], fs::metadata(&input_path).unwrap().len());
+                        if provenance_checksums {
+                            assert_eq!(input_file["md5"], format!("{:x}", md5::compute(get_hash(&input_path))));
+                        } else {
+                            assert!(input_file.get("md5").is_none());
+                        }
+                    }
+
                     if [7, 8, 9, 10].contains(&ds_itr_tmp){
                         break;
                     }
@@ -1065,6 +1161,148 @@ fn testing_demultiplex_large() {
     }
 }
 
+#[test]
+fn testing_demultiplex_writer_threads() {
+    let original_path = String::from("testing_data/expected/large_ds/");
+    let sort_records = |file_path: &String| {
+        let lines: Vec<String> = get_gzip_hash(file_path).lines().map(|line| line.to_string()).collect();
+        let mut records: Vec<String> = lines.chunks(4).map(|record| record.join("\n")).collect();
+        records.sort();
+        records
+    };
+    // A small compression buffer makes every file span several blocks and gzip members.
+    for (threads, writer_threads) in [(1, 3), (4, 3), (4, 1)] {
+        let ouput_dir = format!("testing_data/output/writer_threads/{}-{}/", threads, writer_threads);
+        let my_args: Vec<String> = vec![
+            "demultiplex",
+            "-f", "testing_data/input/large_ds/ZFC01_L01_read_1.fq.gz",
+            "-r", "testing_data/input/large_ds/ZFC01_L01_read_2.fq.gz",
+            "-s", "testing_data/expected/ds01/sample_sheet_expected.tsv",
+            "--lane", "L01", "--instrument", "instrument_1", "--run", "20231212",
+            "--writing-buffer-size", "131072", "--compression-buffer-size", "8192",
+            "--threads", &threads.to_string(), "--writer-threads", &writer_threads.to_string(),
+            "-m", "0", "--force", "-o", &ouput_dir
+        ].iter().map(|arg| arg.to_string()).collect();
+        run_mgikit(&my_args);
+
+        for path in fs::read_dir(&original_path).unwrap() {
+            let file_name = path.as_ref().unwrap().file_name().to_str().unwrap().to_string();
+            let original_file = format!("{}", path.unwrap().path().display());
+            let new_file = format!("{}{}", ouput_dir, file_name);
+            if file_name.starts_with("se-") {
+                continue;
+            }
+            if !file_name.ends_with(".gz") {
+                assert_eq!(get_hash(&new_file), get_hash(&original_file), "{}", new_file);
+            } else if threads == 1 {
+                assert_eq!(get_gzip_hash(&new_file), get_gzip_hash(&original_file), "{}", new_file);
+            } else {
+                // The batches of several processing threads are written in any order.
+                assert_eq!(sort_records(&new_file), sort_records(&original_file), "{}", new_file);
+            }
+        }
+
+        // The reads stay paired whatever thread wrote them.
+        for r1_file in WalkDir::new(&ouput_dir).into_iter().map(|entry| entry.unwrap().path().display().to_string()) {
+            if !r1_file.ends_with("_R1_001.fastq.gz") {
+                continue;
+            }
+            let read_names = |file_path: &String| -> Vec<String> {
+                get_gzip_hash(file_path).lines().step_by(4)
+                    .map(|header| header.split(' ').next().unwrap().to_string()).collect()
+            };
+            assert_eq!(read_names(&r1_file), read_names(&r1_file.replace("_R1_001", "_R2_001")));
+        }
+    }
+}
+
+#[test]
+fn testing_demultiplex_max_reads_per_sample() {
+    // The reports count every read while the outputs only have the first reads of every sample.
+    let original_path = String::from("testing_data/expected/large_ds/");
+    let max_reads: usize = 1000;
+    let read_records = |file_path: &String| -> Vec<String> {
+        let lines: Vec<String> = get_gzip_hash(file_path).lines().map(|line| line.to_string()).collect();
+        lines.chunks(4).map(|record| record.join("\n")).collect()
+    };
+    for threads in [1, 4] {
+        let ouput_dir = format!("testing_data/output/max_reads/{}/", threads);
+        let my_args: Vec<String> = vec![
+            "demultiplex",
+            "-f", "testing_data/input/large_ds/ZFC01_L01_read_1.fq.gz",
+            "-r", "testing_data/input/large_ds/ZFC01_L01_read_2.fq.gz",
+            "-s", "testing_data/expected/ds01/sample_sheet_expected.tsv",
+            "--lane", "L01", "--instrument", "instrument_1", "--run", "20231212",
+            "--threads", &threads.to_string(), "--max-reads-per-sample", &max_reads.to_string(),
+            "-m", "0", "--force", "-o", &ouput_dir
+        ].iter().map(|arg| arg.to_string()).collect();
+        run_mgikit(&my_args);
+        assert_eq!(read_sample_stats(&ouput_dir), read_sample_stats(&original_path));
+
+        for path in fs::read_dir(&original_path).unwrap() {
+            let file_name = path.as_ref().unwrap().file_name().to_str().unwrap().to_string();
+            if !file_name.ends_with(".gz") {
+                continue;
+            }
+            let original_records = read_records(&format!("{}", path.unwrap().path().display()));
+            let new_file = format!("{}{}", ouput_dir, file_name);
+            let new_records = read_records(&new_file);
+            assert!(original_records.len() > max_reads, "{}", new_file);
+            assert_eq!(new_records.len(), max_reads, "{}", new_file);
+            if threads == 1 {
+                // A single processing thread handles the batches in the input order.
+                assert_eq!(new_records, original_records[..max_reads].to_vec(), "{}", new_file);
+            } else {
+                // Which reads are written depends on the order the threads process the batches.
+                let original_records: HashSet<String> = original_records.into_iter().collect();
+                assert!(new_records.iter().all(|record| original_records.contains(record)), "{}", new_file);
+            }
+            if file_name.contains("_R1_") {
+                let read_names = |records: &Vec<String>| -> Vec<String> {
+                    records.iter()
+                        .map(|record| record.split([' ', '\n']).next().unwrap())
+                        .map(|header| header.trim_end_matches("/1").trim_end_matches("/2").to_string())
+                        .collect()
+                };
+                assert_eq!(read_names(&new_records), read_names(&read_records(&new_file.replace("_R1_", "_R2_"))));
+            }
+        }
+    }
+}
+
+#[test]
+fn testing_demultiplex_abort_joins_readers() {
+    // The input spans several batches, so the readers are still running when the run is aborted.
+    let ouput_dir = String::from("testing_data/output/abort_readers/");
+    fs::create_dir_all(&ouput_dir).unwrap();
+    let r1_path = format!("{}input_R1.fq.gz", ouput_dir);
+    let r2_path = format!("{}input_R2.fq.gz", ouput_dir);
+    write_gzip_file(&r1_path, &get_gzip_hash(&String::from("testing_data/input/large_ds/ZFC01_L01_read_1.fq.gz")).repeat(6));
+    write_gzip_file(&r2_path, &get_gzip_hash(&String::from("testing_data/input/large_ds/ZFC01_L01_read_2.fq.gz")).repeat(6));
+    let sample_sheet_path = format!("{}sample_sheet.tsv", ouput_dir);
+    fs::write(
+        &sample_sheet_path,
+        "sample_id\ti7\ti5\ttemplate\nWrong01\tTTTTTTTT\tTTTTTTTT\ti78:i58\nWrong02\tCCCCCCCC\tCCCCCCCC\ti78:i58\n"
+    ).unwrap();
+
+    for (reader_threads, processing_threads) in [(0, 1), (1, 3), (2, 3), (4, 3)] {
+        let run_dir = format!("{}{}-{}/", ouput_dir, reader_threads, processing_threads);
+        let my_args: Vec<String> = vec![
+            "demultiplex",
+            "-f", &r1_path, "-r", &r2_path, "-s", &sample_sheet_path,
+            "--lane", "L01", "--instrument", "instrument_1", "--run", "20231212",
+            "--reader-threads", &reader_threads.to_string(),
+            "--processing-threads", &processing_threads.to_string(),
+            "--force", "-o", &run_dir
+        ].iter().map(|arg| arg.to_string()).collect();
+        let error = run_mgikit_failure(&my_args);
+        assert!(error.contains("The run was aborted"), "{}", error);
+        assert!(!error.contains("panicked"), "{}", error);
+        assert!(get_report_path(&run_dir, ".mgikit.abort_diagnostics").exists());
+        assert_eq!(count_files_recursive(&run_dir), 1, "{}", run_dir);
+    }
+}
+
 #[test]
 fn testing_demultiplex_large_se() {
     for thread_cnt in 1..4{
@@ -1456,6 +1694,582 @@ fn testing_mgi_full_header() {
       
 }
 
+#[test]
+fn testing_demultiplex_filtering() {
+    let input_dir = "testing_data/input/ds02/L01/";
+    let common_args: Vec<String> = vec![
+        "demultiplex", "-f", &format!("{}FC02_L01_read_1.fq.gz", input_dir),
+        "-r", &format!("{}FC02_L01_read_2.fq.gz", input_dir),
+        "-s", "testing_data/expected/ds02/sample_sheet_expected.tsv",
+        "--lane", "L01", "--instrument", "instrument_1", "--run", "20231212", "--force"
+    ].iter().map(|arg| arg.to_string()).collect();
+
+    let ouput_dir = String::from("testing_data/output/filtering/unfiltered/");
+    let mut my_args = common_args.clone();
+    my_args.extend(["-o".to_string(), ouput_dir.clone()]);
+    run_mgikit(&my_args);
+    let unfiltered_stats = read_sample_stats(&ouput_dir);
+
+    for (filter_args, filtered_output) in [
+        (vec!["--min-mean-quality", "30"], "global"),
+        (vec!["--min-length", "100"], "sample"),
+        (vec!["--min-length", "100"], "drop"),
+    ] {
+        let ouput_dir = format!("testing_data/output/filtering/{}-{}/", filter_args[0].trim_start_matches('-'), filtered_output);
+        let mut my_args = common_args.clone();
+        my_args.extend(filter_args.iter().map(|arg| arg.to_string()));
+        my_args.extend(["--filtered-output", filtered_output, "-o", &ouput_dir].iter().map(|arg| arg.to_string()));
+        run_mgikit(&my_args);
+        let sample_stats = read_sample_stats(&ouput_dir);
+
+        // Filtered reads are not part of the sample counts, they are reported separately.
+        let mut total_filtered = 0;
+        for (sample_id, stats) in &sample_stats {
+            let filtered = stats["filtered_short"] + stats["filtered_low_quality"] + stats["filtered_n"];
+            assert_eq!(stats["all_reads"] + filtered, unfiltered_stats[sample_id]["all_reads"], "{}", sample_id);
+            if filtered == unfiltered_stats[sample_id]["all_reads"] {
+                assert_eq!(stats["r1_bases"], 0);
+            }
+            if filtered_output == "sample" && filtered > 0 {
+                let filtered_output = find_r1_output(&ouput_dir, &format!("{}_Filtered_S", sample_id)).unwrap();
+                assert_eq!(count_fastq_reads(&filtered_output), filtered as usize);
+            }
+            if let Some(sample_output) = find_r1_output(&ouput_dir, &format!("{}_S", sample_id)) {
+                assert_eq!(count_fastq_reads(&sample_output), stats["all_reads"] as usize);
+            }
+            total_filtered += filtered;
+        }
+        assert!(total_filtered > 0);
+        let global_output = format!("{}Filtered_L01_R1_001.fastq.gz", ouput_dir);
+        if filtered_output == "global" {
+            assert_eq!(count_fastq_reads(&global_output), total_filtered as usize);
+        } else {
+            assert!(!PathBuf::from(&global_output).exists());
+        }
+    }
+}
+
+#[test]
+fn testing_demultiplex_adapter_trimming() {
+    // The adapter is planted in every other read pair of ds02, inside the insert of both reads.
+    let adapter = "AGATCGGAAGAGC";
+    let (r1_position, r2_position) = (60, 40);
+    let input_dir = String::from("testing_data/output/adapter_trimming/input/");
+    fs::create_dir_all(&input_dir).unwrap();
+    let mut planted = 0;
+    for (read, position) in [("1", r1_position), ("2", r2_position)] {
+        let mut lines: Vec<String> = get_gzip_hash(&format!("testing_data/input/ds02/L01/FC02_L01_read_{}.fq.gz", read))
+            .lines().map(|line| line.to_string()).collect();
+        for record_start in (0..lines.len()).step_by(8) {
+            planted += 1;
+            lines[record_start + 1].replace_range(position..position + adapter.len(), adapter);
+        }
+        write_gzip_file(&format!("{}FC02_L01_read_{}.fq.gz", input_dir, read), &format!("{}\n", lines.join("\n")));
+    }
+
+    let mut outputs: Vec<String> = Vec::new();
+    for trim in [false, true] {
+        let ouput_dir = format!("testing_data/output/adapter_trimming/{}/", trim);
+        let mut my_args: Vec<String> = vec![
+            "demultiplex", "-f", &format!("{}FC02_L01_read_1.fq.gz", input_dir),
+            "-r", &format!("{}FC02_L01_read_2.fq.gz", input_dir),
+            "-s", "testing_data/expected/ds02/sample_sheet_expected.tsv",
+            "--lane", "L01", "--instrument", "instrument_1", "--run", "20231212",
+            "--threads", "1", "--force", "-o", &ouput_dir
+        ].iter().map(|arg| arg.to_string()).collect();
+        if trim {
+            my_args.extend(["--adapter-r1", adapter, "--adapter-r2", adapter].iter().map(|arg| arg.to_string()));
+        }
+        run_mgikit(&my_args);
+        outputs.push(ouput_dir);
+    }
+
+    let read_records = |file_path: &String| -> Vec<Vec<String>> {
+        let lines: Vec<String> = get_gzip_hash(file_path).lines().map(|line| line.to_string()).collect();
+        lines.chunks(4).map(|record| record.to_vec()).collect()
+    };
+    // sample id -> r1 trimmed reads, r1 trimmed bases, r2 trimmed reads and r2 trimmed bases.
+    let trimming: HashMap<String, Vec<u64>> = fs::read_to_string(get_report_path(&outputs[1], ".mgikit.trimming")).unwrap()
+        .lines().skip(1)
+        .map(|line| {
+            let vals: Vec<&str> = line.split('\t').collect();
+            (vals[1].to_string(), vals[2..].iter().map(|val| val.parse::<u64>().unwrap()).collect())
+        }).collect();
+    let untrimmed_stats = read_sample_stats(&outputs[0]);
+    let trimmed_stats = read_sample_stats(&outputs[1]);
+    let mut planted_samples = 0;
+    let mut planted_undetermined = 0;
+    for (sample_id, sample_trimming) in &trimming {
+        let mut expected_trimming = vec![0; 4];
+        for (read_index, (read, position)) in [("R1", r1_position), ("R2", r2_position)].iter().enumerate() {
+            let file_name = match find_r1_output(&outputs[0], &format!("{}_", sample_id)) {
+                Some(file_path) => file_path[outputs[0].len()..].replace("_R1_", &format!("_{}_", read)),
+                None => continue
+            };
+            let untrimmed = read_records(&format!("{}{}", outputs[0], file_name));
+            let trimmed = read_records(&format!("{}{}", outputs[1], file_name));
+            assert_eq!(untrimmed.len(), trimmed.len(), "{}", file_name);
+            for (untrimmed_record, trimmed_record) in untrimmed.iter().zip(trimmed.iter()) {
+                assert_eq!(untrimmed_record[0], trimmed_record[0]);
+                assert!(untrimmed_record[1].starts_with(&trimmed_record[1]), "{}", trimmed_record[0]);
+                assert!(untrimmed_record[3].starts_with(&trimmed_record[3]), "{}", trimmed_record[0]);
+                let is_planted = untrimmed_record[1][*position..].starts_with(adapter);
+                if sample_id == "Undetermined" {
+                    // Undetermined reads are not trimmed.
+                    assert_eq!(untrimmed_record, trimmed_record);
+                    planted_undetermined += is_planted as usize;
+                } else if is_planted {
+                    assert_eq!(trimmed_record[1].len(), *position, "{}", trimmed_record[0]);
+                    planted_samples += 1;
+                }
+                let trimmed_bases = untrimmed_record[1].len() - trimmed_record[1].len();
+                if trimmed_bases > 0 {
+                    expected_trimming[read_index * 2] += 1;
+                    expected_trimming[read_index * 2 + 1] += trimmed_bases as u64;
+                }
+            }
+        }
+        assert_eq!(sample_trimming, &expected_trimming, "{}", sample_id);
+        // The yields of the samples are calculated on the trimmed reads.
+        assert_eq!(untrimmed_stats[sample_id]["r1_bases"] - trimmed_stats[sample_id]["r1_bases"], sample_trimming[1], "{}", sample_id);
+        assert_eq!(untrimmed_stats[sample_id]["r2_bases"] - trimmed_stats[sample_id]["r2_bases"], sample_trimming[3], "{}", sample_id);
+        assert_eq!(untrimmed_stats[sample_id]["r3_bases"], trimmed_stats[sample_id]["r3_bases"], "{}", sample_id);
+    }
+    assert!(planted_samples > 0);
+    assert_eq!(planted_samples + planted_undetermined, planted);
+}
+
+#[test]
+fn testing_demultiplex_flexible_input() {
+    // The same reads with CRLF line endings and the read name repeated on the `+` line.
+    let mut outputs: Vec<String> = Vec::new();
+    for (input_dir, flexible) in [("testing_data/input/ds02/L01/", false), ("testing_data/input/ds02_crlf/L01/", true)] {
+        let ouput_dir = format!("testing_data/output/flexible/{}/", flexible);
+        let mut my_args: Vec<String> = vec![
+            "demultiplex", "-f", &format!("{}FC02_L01_read_1.fq.gz", input_dir),
+            "-r", &format!("{}FC02_L01_read_2.fq.gz", input_dir),
+            "-s", "testing_data/expected/ds02/sample_sheet_expected.tsv",
+            "--lane", "L01", "--instrument", "instrument_1", "--run", "20231212", "--force",
+            "-o", &ouput_dir
+        ].iter().map(|arg| arg.to_string()).collect();
+        if flexible {
+            my_args.push("--flexible".to_string());
+        }
+        run_mgikit(&my_args);
+        outputs.push(ouput_dir);
+    }
+
+    let mut output_files: Vec<String> = fs::read_dir(&outputs[0]).unwrap()
+        .map(|path| path.unwrap().file_name().to_str().unwrap().to_string())
+        .filter(|file_name| file_name.ends_with(".fastq.gz"))
+        .collect();
+    output_files.sort();
+    assert!(!output_files.is_empty());
+    for output_file in &output_files {
+        let flexible_output = get_gzip_hash(&format!("{}{}", outputs[1], output_file));
+        assert!(!flexible_output.contains('\r'));
+        assert_eq!(get_gzip_hash(&format!("{}{}", outputs[0], output_file)), flexible_output);
+    }
+    assert_eq!(read_sample_stats(&outputs[0]), read_sample_stats(&outputs[1]));
+}
+
+#[test]
+fn testing_report_merge_yield_thresholds() {
+    let input_dir = "testing_data/input/ds02/L01/";
+    let lane_dir = String::from("testing_data/output/yield_thresholds/lane/");
+    run_mgikit(&vec![
+        "demultiplex", "-f", &format!("{}FC02_L01_read_1.fq.gz", input_dir),
+        "-r", &format!("{}FC02_L01_read_2.fq.gz", input_dir),
+        "-s", "testing_data/expected/ds02/sample_sheet_expected.tsv",
+        "--lane", "L01", "--instrument", "instrument_1", "--run", "20231212", "--force",
+        "--yield-thresholds", "30,20", "-o", &lane_dir
+    ].iter().map(|arg| arg.to_string()).collect());
+
+    // The legacy Q30 columns keep their position and the extra threshold is appended.
+    let lane_report = get_report_path(&lane_dir, ".mgikit.sample_stats");
+    let header = fs::read_to_string(&lane_report).unwrap().lines().next().unwrap().to_string();
+    assert!(header.starts_with("job_number\tsample_id\tr1_qc_30\tr2_qc_30\tr3_qc_30\tr1_bases"));
+    assert!(header.ends_with("\tr1_qc_20\tr2_qc_20\tr3_qc_20"));
+    let lane_stats = read_sample_stats(&lane_dir);
+
+    for lanes in [1, 2] {
+        let merged_dir = format!("testing_data/output/yield_thresholds/merged-{}/", lanes);
+        fs::create_dir_all(&merged_dir).unwrap();
+        let mut my_args = vec!["report".to_string(), "-o".to_string(), merged_dir.clone()];
+        for _ in 0..lanes {
+            my_args.extend(["--qc-report".to_string(), lane_report.to_str().unwrap().to_string()]);
+        }
+        run_mgikit(&my_args);
+
+        let merged_stats = read_sample_stats(&merged_dir);
+        assert_eq!(merged_stats.len(), lane_stats.len());
+        for (sample_id, stats) in &merged_stats {
+            for (column, val) in stats {
+                assert_eq!(*val, lanes * lane_stats[sample_id][column], "{} {}", sample_id, column);
+            }
+        }
+        let merged_general = fs::read_to_string(get_report_path(&merged_dir, ".mgikit.general")).unwrap();
+        assert!(merged_general.contains("\t% bases ≥ Q30\t% bases ≥ Q20\t"));
+        if lanes == 1 {
+            let lane_general = fs::read_to_string(get_report_path(&lane_dir, ".mgikit.general")).unwrap();
+            assert_eq!(merged_general.replace("FC02-all", "FC02-L01"), lane_general);
+        }
+    }
+}
+
+#[test]
+fn testing_demultiplex_validate_pairs() {
+    let input_dir = "testing_data/input/ds02/L01/";
+    let test_dir = String::from("testing_data/output/validate_pairs/");
+    fs::create_dir_all(&test_dir).unwrap();
+    let r1_path = format!("{}FC02_L01_read_1.fq.gz", input_dir);
+    let r2_reads: Vec<String> = get_gzip_hash(&format!("{}FC02_L01_read_2.fq.gz", input_dir))
+        .lines().map(|line| line.to_string()).collect();
+
+    // The read name of the fifth record differs, and the last record is missing.
+    let mut renamed = r2_reads.clone();
+    renamed[16] = renamed[16].replace("@FC02L1C001R", "@FC02L1C002R");
+    let renamed_path = format!("{}renamed_read_2.fq.gz", test_dir);
+    write_gzip_file(&renamed_path, &format!("{}\n", renamed.join("\n")));
+    let truncated_path = format!("{}truncated_read_2.fq.gz", test_dir);
+    write_gzip_file(&truncated_path, &format!("{}\n", r2_reads[..r2_reads.len() - 4].join("\n")));
+
+    let get_args = |r2_path: &String, ouput_dir: &str, validate_pairs: bool| {
+        let mut my_args: Vec<String> = vec![
+            "demultiplex", "-f", &r1_path, "-r", r2_path,
+            "-s", "testing_data/expected/ds02/sample_sheet_expected.tsv",
+            "--lane", "L01", "--instrument", "instrument_1", "--run", "20231212", "--force",
+            "-o", &format!("{}{}", test_dir, ouput_dir)
+        ].iter().map(|arg| arg.to_string()).collect();
+        if validate_pairs {
+            my_args.push("--validate-pairs".to_string());
+        }
+        my_args
+    };
+
+    run_mgikit(&get_args(&format!("{}FC02_L01_read_2.fq.gz", input_dir), "valid", true));
+    run_mgikit(&get_args(&renamed_path, "renamed-unchecked", false));
+    let error = run_mgikit_failure(&get_args(&renamed_path, "renamed", true));
+    assert!(error.contains("Read names do not match at record 5"), "{}", error);
+    let error = run_mgikit_failure(&get_args(&truncated_path, "truncated", true));
+    assert!(error.contains("different number of reads") || error.contains("seems to be truncated"), "{}", error);
+}
+
+#[test]
+fn testing_demultiplex_dry_run() {
+    let input_dir = "testing_data/input/ds02/L01/";
+    let test_dir = String::from("testing_data/output/dry_run/");
+    fs::create_dir_all(&test_dir).unwrap();
+    let get_args = |output_dir: &String| -> Vec<String> {
+        vec![
+            "demultiplex", "-f", &format!("{}FC02_L01_read_1.fq.gz", input_dir),
+            "-r", &format!("{}FC02_L01_read_2.fq.gz", input_dir),
+            "-s", "testing_data/expected/ds02/sample_sheet_expected.tsv",
+            "--lane", "L01", "--instrument", "instrument_1", "--run", "20231212",
+            "--dry-run", "-o", output_dir
+        ].iter().map(|arg| arg.to_string()).collect()
+    };
+
+    // Returns the exit status and the JSON printed between the log lines.
+    let dry_run = |args: &Vec<String>| -> (bool, serde_json::Value) {
+        println!("{:?}", args);
+        let output = Command::new("target/debug/mgikit").args(args).output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        let json_start = stdout.find("\n{\n").unwrap() + 1;
+        let json_end = stdout.rfind("\n}\n").unwrap() + 2;
+        (output.status.success(), serde_json::from_str(&stdout[json_start..json_end]).unwrap())
+    };
+
+    let output_dir = format!("{}new_output/", test_dir);
+    let (success, plan) = dry_run(&get_args(&output_dir));
+    assert!(success);
+    for key in [
+        "input", "flowcell", "lane", "samples", "templates", "threads", "memory",
+        "output_dir", "report_dir", "output_files", "existing_files", "errors"
+    ] {
+        assert!(plan.get(key).is_some(), "{} is missing: {}", key, plan);
+    }
+    assert_eq!(plan["samples"], 5);
+    assert_eq!(plan["templates"].as_array().unwrap().len(), 3);
+    assert_eq!(plan["output_files"].as_array().unwrap().len(), 14);
+    assert!(plan["existing_files"].as_array().unwrap().is_empty());
+    assert!(plan["errors"].as_array().unwrap().is_empty());
+    // Nothing is written by a dry run.
+    assert!(!Path::new(&output_dir).exists());
+
+    // The output directory exists and --force is not used, so the run would fail.
+    let existing_dir = format!("{}existing_output/", test_dir);
+    fs::create_dir_all(&existing_dir).unwrap();
+    let existing_file = format!("{}SAM-01_S1_L01_R1_001.fastq.gz", existing_dir);
+    fs::write(&existing_file, "").unwrap();
+    let (success, plan) = dry_run(&get_args(&existing_dir));
+    assert!(!success);
+    let errors = plan["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].as_str().unwrap().starts_with("Output directory exists, use --force to overwrite its data"));
+    assert_eq!(plan["existing_files"], serde_json::json!([existing_file]));
+
+    // With --force, the existing file is only reported as overwritten.
+    let mut args = get_args(&existing_dir);
+    args.push("--force".to_string());
+    let (success, plan) = dry_run(&args);
+    assert!(success);
+    assert!(plan["errors"].as_array().unwrap().is_empty());
+    assert_eq!(plan["existing_files"], serde_json::json!([existing_file]));
+}
+
+#[test]
+fn testing_template_confidence() {
+    let input_dir = "testing_data/input/ds02/";
+    let test_dir = String::from("testing_data/output/template_confidence/");
+    fs::create_dir_all(&test_dir).unwrap();
+    // SAM-06 has indexes that are not in the reads.
+    let sample_sheet = format!("{}sample_sheet.tsv", test_dir);
+    fs::write(
+        &sample_sheet,
+        format!("{}SAM-06\tGGGGGGGG\tAAAAAAAA\n", fs::read_to_string(format!("{}sample_sheet.tsv", input_dir)).unwrap())
+    ).unwrap();
+    // The most frequent templates of the dual and single index samples.
+    let dual_index_template = vec!["i78:um8:i58", "1", "1"];
+    let single_index_template = vec!["um16:i78", "1", "."];
+
+    for min_margin in ["0.5", "1.1"] {
+        let out_prefix = format!("{}margin-{}", test_dir, min_margin);
+        run_mgikit(&vec![
+            "template", "-f", &format!("{}L01/FC02_L01_read_1.fq.gz", input_dir),
+            "-r", &format!("{}L01/FC02_L01_read_2.fq.gz", input_dir),
+            "-s", &sample_sheet, "--barcode-length", "0", "--min-margin", min_margin,
+            "--write-sample-sheet", "-o", &out_prefix
+        ].iter().map(|arg| arg.to_string()).collect());
+
+        let read_rows = |suffix: &str| -> Vec<Vec<String>> {
+            fs::read_to_string(format!("{}_{}", out_prefix, suffix)).unwrap().lines().skip(1)
+                .map(|line| line.split('\t').map(|val| val.to_string()).collect())
+                .collect()
+        };
+        let confidence = read_rows("confidence.tsv");
+        let templates = read_rows("template.tsv");
+        let sheet = read_rows("sample_sheet.tsv");
+        assert_eq!(confidence.len(), 6);
+        for ((confidence_row, template_row), sheet_row) in confidence.iter().zip(templates.iter()).zip(sheet.iter()) {
+            assert_eq!(confidence_row[0], sheet_row[0]);
+            assert_eq!(template_row[..4], sheet_row[..4]);
+            let status = confidence_row[14].as_str();
+            if confidence_row[0] == "SAM-06" {
+                assert_eq!(status, "no_matches");
+                assert_eq!(template_row[4], "No matches with this sample");
+            } else {
+                let matches: f64 = confidence_row[6].parse().unwrap();
+                let all_matches: f64 = confidence_row[7].parse().unwrap();
+                let second_matches: f64 = confidence_row[12].parse().unwrap();
+                let margin: f64 = confidence_row[13].parse().unwrap();
+                assert_eq!(confidence_row[8], format!("{:.3}", matches / all_matches));
+                assert_eq!(confidence_row[13], format!("{:.3}", (matches - second_matches) / matches));
+                assert_eq!(status, if margin < min_margin.parse().unwrap() { "conflicting" } else { "confident" });
+                assert_eq!(template_row[4..7], confidence_row[3..6]);
+            }
+            // Samples that are not confident get the most frequent template in the sample sheet.
+            if status == "confident" {
+                assert_eq!(sheet_row, template_row);
+            } else if sheet_row[2] == "." {
+                assert_eq!(sheet_row[4..7], single_index_template);
+            } else {
+                assert_eq!(sheet_row[4..7], dual_index_template);
+            }
+        }
+    }
+}
+
+#[test]
+fn testing_template_sampling() {
+    let input_dir = "testing_data/input/ds02/L01/";
+    let test_dir = String::from("testing_data/output/template_sampling/");
+    fs::create_dir_all(&test_dir).unwrap();
+    let r2_reads: Vec<String> = get_gzip_hash(&format!("{}FC02_L01_read_2.fq.gz", input_dir))
+        .lines().map(|line| line.to_string()).collect();
+
+    // The fifth R2 read loses a base, and the read name of the sixth record differs.
+    let mut shortened = r2_reads.clone();
+    shortened[17].pop();
+    shortened[19].pop();
+    let shortened_path = format!("{}shortened_read_2.fq.gz", test_dir);
+    write_gzip_file(&shortened_path, &format!("{}\n", shortened.join("\n")));
+    let mut renamed = r2_reads.clone();
+    renamed[20] = renamed[20].replace("@FC02L1C001R", "@FC02L1C002R");
+    let renamed_path = format!("{}renamed_read_2.fq.gz", test_dir);
+    write_gzip_file(&renamed_path, &format!("{}\n", renamed.join("\n")));
+
+    let get_args = |r2_path: &String, out_prefix: &String, sampling: &str, seed: &str| -> Vec<String> {
+        vec![
+            "template", "-f", &format!("{}FC02_L01_read_1.fq.gz", input_dir), "-r", r2_path,
+            "-s", "testing_data/input/ds02/sample_sheet.tsv", "--barcode-length", "0",
+            "--testing-reads", "10", "--sampling", sampling, "--seed", seed, "-o", out_prefix
+        ].iter().map(|arg| arg.to_string()).collect()
+    };
+    let r2_path = format!("{}FC02_L01_read_2.fq.gz", input_dir);
+    for sampling in ["first", "stride", "reservoir"] {
+        // The same seed samples the same reads.
+        let first_prefix = format!("{}{}-1", test_dir, sampling);
+        let second_prefix = format!("{}{}-2", test_dir, sampling);
+        run_mgikit(&get_args(&r2_path, &first_prefix, sampling, "7"));
+        run_mgikit(&get_args(&r2_path, &second_prefix, sampling, "7"));
+        for suffix in ["template.tsv", "details.tsv"] {
+            assert_eq!(
+                get_hash(&format!("{}_{}", first_prefix, suffix)),
+                get_hash(&format!("{}_{}", second_prefix, suffix))
+            );
+        }
+    }
+
+    let output = run_mgikit(&get_args(&shortened_path, &format!("{}shortened", test_dir), "first", "7"));
+    assert!(output.contains("1 reads were not sampled"), "{}", output);
+    let error = run_mgikit_failure(&get_args(&renamed_path, &format!("{}renamed", test_dir), "first", "7"));
+    assert!(error.contains("Read names do not match between R2 and R1"), "{}", error);
+}
+
+#[test]
+fn testing_index_kits() {
+    let input_dir = "testing_data/input/ds02/L01/";
+    let test_dir = String::from("testing_data/output/index_kits/");
+    fs::create_dir_all(&test_dir).unwrap();
+    let index_kits = format!("{}index_kits.tsv", test_dir);
+    fs::write(
+        &index_kits,
+        "kit\tindex_id\ti7\ti5\nplate_a\tA01\tTGCTGTGA\tCACGATTC\nplate_a\tA02\tCCACATTG\tTACTCCAG\n\
+         plate_a\tA03\tTAGTGCCA\tCGTCAAGA\nplate_a\tA04\tTCCTGTGA\tCACGACCC\n"
+    ).unwrap();
+    // SAM-03 is left out of the sample sheets, so its reads are undetermined and match plate_a:A03.
+    let sheet = format!("{}sample_sheet.tsv", test_dir);
+    fs::write(
+        &sheet,
+        "sample_id\ti7\ti5\tindex_kit\tindex_id\ttemplate\ti7_rc\ti5_rc\n\
+         SAM-01\t\t\tplate_a\tA01\ti78:um8:i58\t1\t1\n\
+         SAM-02\t\t\tplate_a\tA02\ti78:um8:i58\t1\t1\n\
+         SAM-04\t\t\tplate_a\tA04\ti58:i78:um8\t0\t1\n\
+         SAM-05\tAGAACCAG\t.\t.\t.\tum16:i78\t1\t.\n"
+    ).unwrap();
+    let template_sheet = format!("{}template_sample_sheet.tsv", test_dir);
+    fs::write(
+        &template_sheet,
+        "sample_id\ti7\ti5\tindex_kit\tindex_id\nSAM-01\t\t\tplate_a\tA01\nSAM-02\t\t\tplate_a\tA02\n\
+         SAM-04\t\t\tplate_a\tA04\nSAM-05\tAGAACCAG\t.\t.\t.\n"
+    ).unwrap();
+    let has_sam_03 = |report: &String| -> bool {
+        let mut lines = report.lines();
+        assert_eq!(lines.next().unwrap(), "barcode\treads\ti7\ti5\ti7_kit_match\ti5_kit_match");
+        lines.any(|line| {
+            let vals: Vec<&str> = line.split('\t').collect();
+            vals[4].contains("plate_a:A03") && vals[5].contains("plate_a:A03")
+        })
+    };
+
+    let demultiplex_dir = format!("{}demultiplex/", test_dir);
+    run_mgikit(&vec![
+        "demultiplex", "-f", &format!("{}FC02_L01_read_1.fq.gz", input_dir),
+        "-r", &format!("{}FC02_L01_read_2.fq.gz", input_dir), "-s", &sheet,
+        "--index-kits", &index_kits, "--report-level", "2", "--lane", "L01", "--instrument", "instrument_1",
+        "--run", "20231212", "--force", "-o", &demultiplex_dir
+    ].iter().map(|arg| arg.to_string()).collect());
+    let sample_stats = read_sample_stats(&demultiplex_dir);
+    assert!(sample_stats.contains_key("SAM-01") && !sample_stats.contains_key("SAM-03"));
+    let report = fs::read_to_string(get_report_path(&demultiplex_dir, ".mgikit.undetermined_barcode.kits")).unwrap();
+    assert!(has_sam_03(&report), "{}", report);
+
+    let out_prefix = format!("{}template", test_dir);
+    run_mgikit(&vec![
+        "template", "-f", &format!("{}FC02_L01_read_1.fq.gz", input_dir),
+        "-r", &format!("{}FC02_L01_read_2.fq.gz", input_dir), "-s", &template_sheet,
+        "--index-kits", &index_kits, "--barcode-length", "0", "-o", &out_prefix
+    ].iter().map(|arg| arg.to_string()).collect());
+    let report = fs::read_to_string(format!("{}_undetermined.tsv", out_prefix)).unwrap();
+    assert!(has_sam_03(&report), "{}", report);
+}
+
+#[test]
+fn testing_discover() {
+    let test_dir = String::from("testing_data/output/discover/");
+    fs::create_dir_all(&test_dir).unwrap();
+    // The barcode is a constant region (8) + i7 (8) + UMI (8) + i5 (8) after 20 bases of insert.
+    let samples = [("AACCGGTT", "TTGGCCAA"), ("CCGGTTAA", "GGCCAATT"), ("GGTTAACC", "CCAATTGG")];
+    let bases = ['A', 'C', 'G', 'T'];
+    let mut r1_content = String::new();
+    let mut r2_content = String::new();
+    for read_itr in 0..60 {
+        // Every tenth read has an error in the constant region.
+        let constant = if read_itr % 10 == 0 { "ACGAACGT" } else { "ACGTACGT" };
+        let umi: String = (0..8).map(|base_itr| bases[(read_itr * 7 + base_itr * 3 + read_itr / 4) % 4]).collect();
+        let (i7, i5) = samples[read_itr % samples.len()];
+        let insert = "TGCATGCATGCATGCATGCA";
+        let read_name = format!("@FC01L1C001R001{:07}", read_itr);
+        r1_content.push_str(&format!("{}/1\n{}\n+\n{}\n", read_name, insert, "F".repeat(20)));
+        r2_content.push_str(&format!(
+            "{}/2\n{}{}{}{}{}\n+\n{}\n", read_name, insert, constant, i7, umi, i5, "F".repeat(52)
+        ));
+    }
+    let r1_path = format!("{}FC01_L01_read_1.fq.gz", test_dir);
+    let r2_path = format!("{}FC01_L01_read_2.fq.gz", test_dir);
+    write_gzip_file(&r1_path, &r1_content);
+    write_gzip_file(&r2_path, &r2_content);
+
+    let out_prefix = format!("{}ds", test_dir);
+    run_mgikit(&[
+        "discover", "-f", &r1_path, "-r", &r2_path, "-o", &out_prefix
+    ].iter().map(|arg| arg.to_string()).collect());
+
+    // The constant region has the lowest entropy but it is not taken as the index.
+    let discovered = fs::read_to_string(format!("{}_discovered.tsv", out_prefix)).unwrap();
+    let mut expected_discovered = String::from("i7\ti5\treads\tfraction\tmerged_barcodes\n");
+    for (i7, i5) in samples {
+        expected_discovered.push_str(&format!("{}\t{}\t20\t0.33333\t1\n", i7, i5));
+    }
+    assert_eq!(discovered, expected_discovered);
+    let sample_sheet = fs::read_to_string(format!("{}_sample_sheet.tsv", out_prefix)).unwrap();
+    for line in sample_sheet.lines().skip(1) {
+        assert_eq!(line.split('\t').nth(4).unwrap(), "--8:i78:--8:i58");
+    }
+}
+
+#[test]
+fn testing_validate_sheet() {
+    let test_dir = String::from("testing_data/output/validate_sheet/");
+    fs::create_dir_all(&test_dir).unwrap();
+    let valid_sheet = "testing_data/expected/ds02/sample_sheet_expected.tsv";
+    // SAM-06 has the same indexes and template as SAM-01.
+    let broken_sheet = format!("{}sample_sheet.tsv", test_dir);
+    fs::write(
+        &broken_sheet,
+        format!("{}SAM-06\tTGCTGTGA\tCACGATTC\t.\ti78:um8:i58\t1\t1\n", fs::read_to_string(valid_sheet).unwrap())
+    ).unwrap();
+    let read_problems = |report_path: &String| -> Vec<Vec<String>> {
+        fs::read_to_string(report_path).unwrap().lines().skip(1)
+            .map(|line| line.split('\t').map(|val| val.to_string()).collect())
+            .collect()
+    };
+
+    let valid_report = format!("{}valid.tsv", test_dir);
+    run_mgikit(&["validate-sheet", "-s", valid_sheet, "-o", &valid_report]
+        .iter().map(|arg| arg.to_string()).collect());
+    assert!(read_problems(&valid_report).iter().all(|problem| problem[0] == "warning"));
+
+    let broken_report = format!("{}broken.tsv", test_dir);
+    run_mgikit_failure(&["validate-sheet", "-s", &broken_sheet, "-o", &broken_report]
+        .iter().map(|arg| arg.to_string()).collect());
+    let errors: Vec<Vec<String>> = read_problems(&broken_report).into_iter()
+        .filter(|problem| problem[0] == "error").collect();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0][1], "7");
+    assert!(errors[0][2].starts_with("Sample SAM-06 has the same indexes as sample SAM-01 at line 2"));
+
+    // Demultiplexing loads the sheet the same way and stops at the same error.
+    let error = run_mgikit_failure(&vec![
+        "demultiplex", "-f", "testing_data/input/ds02/L01/FC02_L01_read_1.fq.gz",
+        "-r", "testing_data/input/ds02/L01/FC02_L01_read_2.fq.gz", "-s", &broken_sheet,
+        "--lane", "L01", "--instrument", "instrument_1", "--run", "20231212",
+        "-o", &format!("{}demultiplex", test_dir)
+    ].iter().map(|arg| arg.to_string()).collect());
+    assert!(error.contains("Line 7: Sample SAM-06 has the same indexes as sample SAM-01 at line 2"), "{}", error);
+}
+
 struct TestCleanup;
 
 impl Drop for TestCleanup {
@@ -1474,4 +2288,4 @@ impl Drop for TestCleanup {
             }
         }
     }
-}
\ No newline at end of file
+}